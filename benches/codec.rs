@@ -0,0 +1,201 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use mini_quiche::{
+    packet::{
+        frame::Frame,
+        header::{Header, LongHeader, ShortHeader},
+        one_rtt::{PlaintextOpener, ShortPacket},
+        packet::Packet,
+        ConnectionId, FourBits, PacketNumber, PnLen, SingleBit, TwoBits,
+    },
+    BitsExt, VarInt,
+};
+
+// one value per encoded size, so every branch of encode / decode is hit
+fn varints() -> [VarInt; 4] {
+    [
+        VarInt::new_u32(37),
+        VarInt::new_u32(15_293),
+        VarInt::new_u32(494_878_333),
+        VarInt::new_u64(151_288_809_941_952_652).unwrap(),
+    ]
+}
+
+fn frames() -> Vec<(&'static str, Frame)> {
+    vec![
+        ("ping", Frame::Ping),
+        (
+            "ack",
+            Frame::Ack {
+                largest_acknowledged: VarInt::new_u32(10_000),
+                ack_delay: VarInt::new_u32(25),
+                ack_range_count: VarInt::new_u32(4),
+                first_ack_range: VarInt::new_u32(10),
+                ack_ranges: vec![(VarInt::new_u32(2), VarInt::new_u32(5)); 4],
+            },
+        ),
+        (
+            "crypto",
+            Frame::Crypto {
+                offset: VarInt::zero(),
+                crypto_length: VarInt::new_u32(1_000),
+                crypto_data: vec![0xAB; 1_000].into(),
+            },
+        ),
+        (
+            "stream",
+            Frame::Stream {
+                stream_id: VarInt::new_u32(4),
+                offset: VarInt::new_u32(1_024),
+                length: VarInt::new_u32(1_000),
+                fin: SingleBit::zero(),
+                stream_data: vec![0xCD; 1_000].into(),
+            },
+        ),
+    ]
+}
+
+fn cid() -> ConnectionId {
+    ConnectionId::new(8, vec![0x42; 8])
+}
+
+fn short_header() -> ShortHeader {
+    ShortHeader::new(
+        SingleBit::zero(),
+        TwoBits::zero(),
+        SingleBit::zero(),
+        PnLen::from_len(4).unwrap(),
+        cid(),
+        vec![0, 0, 0, 1],
+    )
+}
+
+fn headers() -> Vec<(&'static str, Header)> {
+    vec![
+        (
+            "initial",
+            Header::Initial(LongHeader::initial(
+                1,
+                cid(),
+                cid(),
+                FourBits::zero(),
+                VarInt::new_u32(16),
+                vec![0x11; 16],
+                VarInt::new_u32(1_150),
+                PacketNumber(VarInt::zero()),
+            )),
+        ),
+        ("short", Header::Short(short_header())),
+    ]
+}
+
+// a full 1200 byte (the minimum client initial size) 1-RTT packet
+fn full_packet() -> Packet {
+    let header = Header::Short(short_header());
+    let header_len = header.encode().unwrap().len();
+    let stream = |stream_data: Vec<u8>| Frame::Stream {
+        stream_id: VarInt::new_u32(4),
+        offset: VarInt::zero(),
+        // no length, the data runs to the end of the packet
+        length: VarInt::zero(),
+        fin: SingleBit::zero(),
+        stream_data: stream_data.into(),
+    };
+    let overhead = header_len + Frame::Ping.encode().len() + stream(Vec::new()).encode().len();
+    let payload = vec![Frame::Ping, stream(vec![0xEF; 1_200 - overhead])];
+    let packet = Packet {
+        header,
+        payload,
+        warnings: Vec::new(),
+    };
+    assert_eq!(packet.encode().unwrap().len(), 1_200);
+    packet
+}
+
+fn bench_varint(c: &mut Criterion) {
+    let mut group = c.benchmark_group("varint");
+    for varint in varints() {
+        let size = varint.size();
+        group.bench_function(format!("encode/{}", size), |b| {
+            b.iter(|| black_box(varint).encode())
+        });
+        let encoded = varint.encode();
+        group.bench_function(format!("decode/{}", size), |b| {
+            b.iter_batched(
+                || encoded.clone(),
+                |mut bytes| VarInt::decode(&mut bytes).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_frame(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame");
+    for (name, frame) in frames() {
+        group.bench_function(format!("encode/{}", name), |b| {
+            b.iter(|| black_box(&frame).encode())
+        });
+        let encoded = frame.encode();
+        group.bench_function(format!("decode/{}", name), |b| {
+            b.iter_batched(
+                || encoded.clone(),
+                |mut bytes| Frame::decode(&mut bytes).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header");
+    for (name, header) in headers() {
+        group.bench_function(format!("encode/{}", name), |b| {
+            b.iter(|| black_box(&header).encode().unwrap())
+        });
+        let encoded = header.encode().unwrap();
+        group.bench_function(format!("decode/{}", name), |b| {
+            b.iter_batched(
+                || encoded.clone(),
+                |mut bytes| Header::decode(&mut bytes),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_packet(c: &mut Criterion) {
+    let packet = full_packet();
+    let mut group = c.benchmark_group("packet");
+    group.bench_function("round_trip/1200", |b| {
+        b.iter(|| {
+            let mut bytes = black_box(&packet).encode().unwrap();
+            Packet::decode(&mut bytes).unwrap()
+        })
+    });
+    // the 1-RTT fast path, reading in place
+    let encoded = packet.encode().unwrap();
+    group.bench_function("decode_in_place/1200", |b| {
+        b.iter_batched(
+            || encoded.clone(),
+            |mut bytes| {
+                let short = ShortPacket::open(&mut bytes, &PlaintextOpener).unwrap();
+                short.frames().map(Result::unwrap).count()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_varint,
+    bench_frame,
+    bench_header,
+    bench_packet
+);
+criterion_main!(benches);
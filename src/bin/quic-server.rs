@@ -0,0 +1,5 @@
+use mini_quiche::interop::{run, Role};
+
+fn main() {
+    std::process::exit(run(Role::Server));
+}
@@ -0,0 +1,301 @@
+use std::collections::BTreeMap;
+
+use crate::{packet::frame::Frame, VarInt};
+
+// how many ranges an ACK frame carries by default.  under heavy reordering the set can hold far
+// more, but the oldest ones matter least and an ACK has to fit in a packet
+pub const DEFAULT_MAX_ACK_RANGES: usize = 32;
+
+// the packet numbers we've received in one packet number space, as disjoint ranges
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AckRangeSet {
+    // smallest -> largest, both inclusive
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl AckRangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns false if `packet_number` was already in the set
+    pub fn insert(&mut self, packet_number: u64) -> bool {
+        let mut start = packet_number;
+        let mut end = packet_number;
+        if let Some((&smallest, &largest)) = self.ranges.range(..=packet_number).next_back() {
+            if largest >= packet_number {
+                return false;
+            }
+            if largest + 1 == packet_number {
+                start = smallest;
+            }
+        }
+        if let Some(largest) = self.ranges.remove(&(packet_number + 1)) {
+            end = largest;
+        }
+        self.ranges.insert(start, end);
+        true
+    }
+
+    pub fn contains(&self, packet_number: u64) -> bool {
+        self.ranges
+            .range(..=packet_number)
+            .next_back()
+            .is_some_and(|(_, &largest)| largest >= packet_number)
+    }
+
+    pub fn largest(&self) -> Option<u64> {
+        self.ranges.values().next_back().copied()
+    }
+
+    // the number of disjoint ranges, which is what an ACK frame's size grows with
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // (smallest, largest) pairs, largest first
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges
+            .iter()
+            .rev()
+            .map(|(&smallest, &largest)| (smallest, largest))
+    }
+
+    // forgets every packet number up to and including `packet_number`
+    pub fn remove_until(&mut self, packet_number: u64) {
+        let mut kept = self.ranges.split_off(&(packet_number + 1));
+        if let Some((_, &largest)) = self.ranges.iter().next_back() {
+            if largest > packet_number {
+                kept.insert(packet_number + 1, largest);
+            }
+        }
+        self.ranges = kept;
+    }
+
+    // an ACK frame of at most `max_ranges` ranges and `max_size` bytes, the newest ranges first
+    // and the oldest left out.  None if there's nothing to acknowledge or it can't fit at all
+    pub fn to_frame(&self, ack_delay: u64, max_ranges: usize, max_size: usize) -> Option<Frame> {
+        let mut ranges = self.iter();
+        let (first_smallest, largest_acknowledged) = ranges.next()?;
+        let largest_acknowledged = VarInt(largest_acknowledged);
+        let ack_delay = VarInt(ack_delay);
+        let first_ack_range = VarInt(largest_acknowledged.0 - first_smallest);
+        let fixed_size =
+            1 + largest_acknowledged.size() + ack_delay.size() + first_ack_range.size();
+
+        if max_ranges == 0 || fixed_size + VarInt(0).size() > max_size {
+            return None;
+        }
+        let mut ack_ranges = Vec::new();
+        let mut ranges_size = 0;
+        let mut previous_smallest = first_smallest;
+        for (smallest, largest) in ranges.take(max_ranges - 1) {
+            let gap = VarInt(previous_smallest - largest - 2);
+            let length = VarInt(largest - smallest);
+            let count = VarInt(ack_ranges.len() as u64 + 1);
+            if fixed_size + count.size() + ranges_size + gap.size() + length.size() > max_size {
+                break;
+            }
+            ranges_size += gap.size() + length.size();
+            ack_ranges.push((gap, length));
+            previous_smallest = smallest;
+        }
+
+        Some(Frame::Ack {
+            largest_acknowledged,
+            ack_delay,
+            ack_range_count: VarInt(ack_ranges.len() as u64),
+            first_ack_range,
+            ack_ranges,
+        })
+    }
+}
+
+// what we've received in a packet number space, and which of our packets carried ACKs for it.
+// once one of those packets is itself acknowledged the peer knows about everything that ACK
+// covered, so it doesn't need repeating (RFC 9000 section 13.2.4)
+#[derive(Debug, Clone)]
+pub struct AckTracker {
+    received: AckRangeSet,
+    // our packet number -> the largest acknowledged in the ACK it carried
+    sent_acks: BTreeMap<u64, u64>,
+    // everything up to here has been pruned from `received`, so a packet number at or below it
+    // is taken for a duplicate, whether it was one or just arrived too late to tell
+    pruned_until: Option<u64>,
+    max_ranges: usize,
+}
+
+impl AckTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the most ranges a generated ACK frame carries
+    pub fn set_max_ranges(&mut self, max_ranges: usize) {
+        self.max_ranges = max_ranges;
+    }
+
+    pub fn received(&self) -> &AckRangeSet {
+        &self.received
+    }
+
+    // returns false for a duplicate
+    pub fn on_packet_received(&mut self, packet_number: u64) -> bool {
+        if self.pruned_until >= Some(packet_number) {
+            return false;
+        }
+        self.received.insert(packet_number)
+    }
+
+    // the ACK that goes out next, in no more than `max_size` bytes of the packet.  None if
+    // there's nothing left to acknowledge or no room for it
+    pub fn ack_frame(&self, ack_delay: u64, max_size: usize) -> Option<Frame> {
+        self.received.to_frame(ack_delay, self.max_ranges, max_size)
+    }
+
+    // our packet `packet_number` carried an ACK up to `largest_acknowledged`
+    pub fn on_ack_sent(&mut self, packet_number: u64, largest_acknowledged: u64) {
+        self.sent_acks.insert(packet_number, largest_acknowledged);
+    }
+
+    // the peer acknowledged our packet `packet_number`.  if it carried an ACK, the ranges that ACK
+    // covered are dropped, along with any older ACKs that are now redundant
+    pub fn on_packet_acked(&mut self, packet_number: u64) {
+        let Some(&largest_acknowledged) = self.sent_acks.get(&packet_number) else {
+            return;
+        };
+        self.sent_acks = self.sent_acks.split_off(&(packet_number + 1));
+        self.received.remove_until(largest_acknowledged);
+        self.pruned_until = self.pruned_until.max(Some(largest_acknowledged));
+    }
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        Self {
+            received: AckRangeSet::default(),
+            sent_acks: BTreeMap::new(),
+            pruned_until: None,
+            max_ranges: DEFAULT_MAX_ACK_RANGES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ack_range_set() {
+        let mut ranges = AckRangeSet::new();
+        for packet_number in [0, 1, 2, 5, 6, 9, 4] {
+            assert!(ranges.insert(packet_number));
+        }
+        assert!(!ranges.insert(5));
+        assert!(ranges.contains(4) && !ranges.contains(3));
+        assert_eq!(
+            ranges.iter().collect::<Vec<_>>(),
+            vec![(9, 9), (4, 6), (0, 2)]
+        );
+        // filling the hole joins both sides
+        ranges.insert(3);
+        assert_eq!(ranges.iter().collect::<Vec<_>>(), vec![(9, 9), (0, 6)]);
+
+        ranges.insert(12);
+        let frame = ranges.to_frame(25, usize::MAX, usize::MAX).unwrap();
+        assert_eq!(Frame::decode(&mut frame.encode()).unwrap(), frame);
+        let Frame::Ack {
+            largest_acknowledged,
+            first_ack_range,
+            ack_ranges,
+            ..
+        } = frame
+        else {
+            panic!("expected an ACK, got {:?}", frame);
+        };
+        assert_eq!((largest_acknowledged.0, first_ack_range.0), (12, 0));
+        assert_eq!(
+            ack_ranges,
+            vec![(VarInt(1), VarInt(0)), (VarInt(1), VarInt(6))]
+        );
+
+        ranges.remove_until(4);
+        assert_eq!(
+            ranges.iter().collect::<Vec<_>>(),
+            vec![(12, 12), (9, 9), (5, 6)]
+        );
+    }
+
+    #[test]
+    fn test_ack_of_ack() {
+        let mut tracker = AckTracker::new();
+        for packet_number in [0, 1, 3, 4, 7] {
+            tracker.on_packet_received(packet_number);
+        }
+        // our packets 10 and 11 carried ACKs up to 4 and 7
+        tracker.on_ack_sent(10, 4);
+        tracker.on_ack_sent(11, 7);
+        tracker.on_packet_received(9);
+
+        // acking a packet without an ACK in it changes nothing
+        tracker.on_packet_acked(8);
+        assert_eq!(tracker.received().len(), 4);
+
+        tracker.on_packet_acked(10);
+        assert_eq!(
+            tracker.received().iter().collect::<Vec<_>>(),
+            vec![(9, 9), (7, 7)]
+        );
+        tracker.on_packet_acked(11);
+        assert_eq!(tracker.received().iter().collect::<Vec<_>>(), vec![(9, 9)]);
+        // a late ack for an older ACK can't bring anything back or prune further
+        tracker.on_packet_acked(10);
+        assert_eq!(tracker.received().len(), 1);
+
+        // a retransmitted copy of a pruned packet is still a duplicate, and so is anything else
+        // that old, rather than going back in the next ACK
+        assert!(!tracker.on_packet_received(3));
+        assert!(!tracker.on_packet_received(5));
+        assert!(!tracker.on_packet_received(9));
+        assert!(tracker.on_packet_received(8));
+        assert_eq!(tracker.received().iter().collect::<Vec<_>>(), vec![(8, 9)]);
+    }
+
+    #[test]
+    fn test_ack_range_cap() {
+        let mut ranges = AckRangeSet::new();
+        // every other packet lost, so each one is its own range
+        for packet_number in (0..200).step_by(2) {
+            ranges.insert(packet_number);
+        }
+        assert_eq!(ranges.len(), 100);
+
+        let count = |frame: Option<Frame>| match frame {
+            Some(Frame::Ack { ack_ranges, .. }) => ack_ranges.len(),
+            frame => panic!("expected an ACK, got {:?}", frame),
+        };
+        assert_eq!(count(ranges.to_frame(0, usize::MAX, usize::MAX)), 99);
+        // the first range is always the newest, the oldest are the ones left out
+        let frame = ranges.to_frame(0, 10, usize::MAX);
+        assert_eq!(count(frame.clone()), 9);
+        let Some(Frame::Ack {
+            largest_acknowledged,
+            ..
+        }) = frame
+        else {
+            unreachable!()
+        };
+        assert_eq!(largest_acknowledged.0, 198);
+
+        // 1 type + 2 largest + 1 delay + 1 count + 1 first range, then 2 per range
+        let frame = ranges.to_frame(0, usize::MAX, 20).unwrap();
+        assert_eq!(frame.encode().len(), 20);
+        assert_eq!(count(Some(frame)), 7);
+        assert!(ranges.to_frame(0, usize::MAX, 5).is_none());
+        assert!(ranges.to_frame(0, 0, usize::MAX).is_none());
+    }
+}
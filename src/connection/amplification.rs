@@ -0,0 +1,61 @@
+// until the peer's address is validated, a server can send at most three times what it's received
+// from it (RFC 9000 section 8.1), so it can't be used to flood a spoofed address
+pub const AMPLIFICATION_FACTOR: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmplificationLimit {
+    received: usize,
+    sent: usize,
+    validated: bool,
+}
+
+impl AmplificationLimit {
+    // a client, or a server that already trusts the address (e.g. from a Retry token)
+    pub fn validated() -> Self {
+        Self {
+            received: 0,
+            sent: 0,
+            validated: true,
+        }
+    }
+
+    pub fn unvalidated() -> Self {
+        Self {
+            validated: false,
+            ..Self::validated()
+        }
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.validated
+    }
+
+    // receiving a Handshake packet, or a token we issued, proves the peer owns the address
+    pub fn validate(&mut self) {
+        self.validated = true;
+    }
+
+    pub fn on_received(&mut self, bytes: usize) {
+        self.received = self.received.saturating_add(bytes);
+    }
+
+    pub fn on_sent(&mut self, bytes: usize) {
+        self.sent = self.sent.saturating_add(bytes);
+    }
+
+    // how many more bytes can go out right now
+    pub fn budget(&self) -> usize {
+        if self.validated {
+            return usize::MAX;
+        }
+        self.received
+            .saturating_mul(AMPLIFICATION_FACTOR)
+            .saturating_sub(self.sent)
+    }
+}
+
+impl Default for AmplificationLimit {
+    fn default() -> Self {
+        Self::validated()
+    }
+}
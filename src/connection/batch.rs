@@ -0,0 +1,491 @@
+use std::io;
+
+use tokio::net::UdpSocket;
+
+use super::EcnCodepoint;
+
+// how many datagrams are read / written per syscall by default
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+// the kernel won't segment a gso send into more than this many datagrams (UDP_MAX_SEGMENTS)
+pub const MAX_GSO_SEGMENTS: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoStats {
+    pub send_syscalls: u64,
+    pub recv_syscalls: u64,
+    pub datagrams_sent: u64,
+    pub datagrams_recv: u64,
+    // received, but shed because the application wasn't keeping up
+    pub datagrams_dropped: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvMeta {
+    // a buffer holding a gro aggregate is longer than its segment size, see `segments`
+    pub segment_size: usize,
+    // only reported once ecn has been turned on with `SocketConfig`
+    pub ecn: Option<EcnCodepoint>,
+}
+
+// batched udp i/o over a connected socket.  on linux this uses sendmmsg / recvmmsg,
+// everywhere else it falls back to one send / recv per datagram
+#[derive(Debug)]
+pub struct BatchIo {
+    batch_size: usize,
+    // max datagrams coalesced into a single gso send, 1 means gso is off
+    max_segments: usize,
+    stats: IoStats,
+}
+
+impl BatchIo {
+    pub fn new(batch_size: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            max_segments: 1,
+            stats: IoStats::default(),
+        }
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    pub fn max_segments(&self) -> usize {
+        self.max_segments
+    }
+
+    pub fn stats(&self) -> IoStats {
+        self.stats
+    }
+
+    // turns on gso if the kernel supports it for this socket, returns whether it did
+    pub fn probe_gso(&mut self, socket: &UdpSocket) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            if linux::gso_supported(socket.as_raw_fd()) {
+                self.max_segments = MAX_GSO_SEGMENTS;
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = socket;
+
+        self.max_segments > 1
+    }
+
+    // asks the kernel to coalesce received datagrams.  aggregates can be up to 64k, so
+    // only turn this on if the buffers passed to `recv` are big enough to hold them
+    pub fn enable_gro(&mut self, socket: &UdpSocket) -> bool {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            linux::enable_gro(socket.as_raw_fd())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = socket;
+            false
+        }
+    }
+
+    // sends every datagram, `batch_size` messages at a time.  with gso on, runs of equal sized
+    // datagrams (the last one may be shorter) go out as a single message
+    pub async fn send(&mut self, socket: &UdpSocket, datagrams: &[Vec<u8>]) -> io::Result<()> {
+        for batch in datagrams.chunks(self.batch_size * self.max_segments) {
+            let mut sent = 0;
+            while sent < batch.len() {
+                sent += self.send_batch(socket, &batch[sent..]).await?;
+            }
+            self.stats.datagrams_sent += batch.len() as u64;
+        }
+        Ok(())
+    }
+
+    // reads up to `batch_size` messages into `bufs`, waiting for at least one.  each buffer that
+    // was filled is truncated to the message's length, and its metadata is returned
+    pub async fn recv(
+        &mut self,
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+    ) -> io::Result<Vec<RecvMeta>> {
+        let batch_size = bufs.len().min(self.batch_size);
+        let metas = self.recv_batch(socket, &mut bufs[..batch_size]).await?;
+        self.stats.datagrams_recv += bufs
+            .iter()
+            .zip(metas.iter())
+            .map(|(buf, meta)| segments(buf, meta.segment_size).count() as u64)
+            .sum::<u64>();
+        Ok(metas)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_batch(&mut self, socket: &UdpSocket, batch: &[Vec<u8>]) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        socket
+            .async_io(tokio::io::Interest::WRITABLE, || {
+                self.stats.send_syscalls += 1;
+                match linux::sendmmsg(socket.as_raw_fd(), batch, self.max_segments) {
+                    // the nic can't checksum segmented sends, stop using gso and let the caller retry
+                    Err(e) if e.raw_os_error() == Some(libc::EIO) && self.max_segments > 1 => {
+                        self.max_segments = 1;
+                        Ok(0)
+                    }
+                    result => result,
+                }
+            })
+            .await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn send_batch(&mut self, socket: &UdpSocket, batch: &[Vec<u8>]) -> io::Result<usize> {
+        self.stats.send_syscalls += 1;
+        socket.send(&batch[0]).await?;
+        Ok(1)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_batch(
+        &mut self,
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+    ) -> io::Result<Vec<RecvMeta>> {
+        use std::os::fd::AsRawFd;
+
+        let received = socket
+            .async_io(tokio::io::Interest::READABLE, || {
+                self.stats.recv_syscalls += 1;
+                linux::recvmmsg(socket.as_raw_fd(), bufs)
+            })
+            .await?;
+        bufs.iter_mut()
+            .zip(received.iter())
+            .for_each(|(buf, (len, _))| buf.truncate(*len));
+        Ok(received.into_iter().map(|(_, meta)| meta).collect())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn recv_batch(
+        &mut self,
+        socket: &UdpSocket,
+        bufs: &mut [Vec<u8>],
+    ) -> io::Result<Vec<RecvMeta>> {
+        let meta = |segment_size| RecvMeta {
+            segment_size,
+            ecn: None,
+        };
+
+        // block for the first datagram, then pick up whatever else is already queued
+        self.stats.recv_syscalls += 1;
+        let len = socket.recv(&mut bufs[0]).await?;
+        bufs[0].truncate(len);
+
+        let mut metas = vec![meta(len)];
+        for buf in bufs[1..].iter_mut() {
+            self.stats.recv_syscalls += 1;
+            match socket.try_recv(buf) {
+                Ok(len) => {
+                    buf.truncate(len);
+                    metas.push(meta(len));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(metas)
+    }
+}
+
+impl Default for BatchIo {
+    fn default() -> Self {
+        Self::new(DEFAULT_BATCH_SIZE)
+    }
+}
+
+// splits a received buffer back into the datagrams it was coalesced from
+pub fn segments(buf: &[u8], segment_size: usize) -> std::slice::Chunks<'_, u8> {
+    buf.chunks(segment_size.max(1))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io,
+        mem::{self, size_of},
+        ops::Range,
+        os::fd::RawFd,
+    };
+
+    use super::{EcnCodepoint, RecvMeta};
+
+    // room for a couple of cmsgs carrying an int each, 8-byte aligned like cmsghdr wants
+    type CmsgBuf = [u64; 8];
+
+    // a gso send has to fit in a single udp datagram (64k minus the ip / udp headers)
+    const MAX_GSO_BYTES: usize = 65_000;
+
+    fn iovec(bytes: &[u8]) -> libc::iovec {
+        libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        }
+    }
+
+    // groups the batch into runs that can be sent as one gso message
+    fn gso_runs(batch: &[Vec<u8>], max_segments: usize) -> Vec<Range<usize>> {
+        let mut runs = Vec::new();
+        let mut start = 0;
+        while start < batch.len() {
+            let segment_size = batch[start].len();
+            let mut end = start + 1;
+            while segment_size > 0
+                && end < batch.len()
+                && end - start < max_segments
+                && (end - start + 1) * segment_size <= MAX_GSO_BYTES
+                && (1..=segment_size).contains(&batch[end].len())
+            {
+                end += 1;
+                // only the last segment may be short
+                if batch[end - 1].len() < segment_size {
+                    break;
+                }
+            }
+            runs.push(start..end);
+            start = end;
+        }
+        runs
+    }
+
+    // the socket is connected, so none of the messages need an address
+    pub(super) fn sendmmsg(fd: RawFd, batch: &[Vec<u8>], max_segments: usize) -> io::Result<usize> {
+        let runs = gso_runs(batch, max_segments);
+        let mut iovecs = batch.iter().map(|d| iovec(d)).collect::<Vec<_>>();
+        let mut cmsgs = vec![CmsgBuf::default(); runs.len()];
+        let mut hdrs = runs
+            .iter()
+            .zip(cmsgs.iter_mut())
+            .map(|(run, cmsg)| {
+                // SAFETY: mmsghdr is plain old data, all zeroes is a valid (empty) message
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_iov = iovecs[run.clone()].as_mut_ptr();
+                hdr.msg_hdr.msg_iovlen = run.len() as _;
+                if run.len() > 1 {
+                    let segment_size = batch[run.start].len() as u16;
+                    // SAFETY: `cmsg` outlives the call and has room for the header and a u16
+                    unsafe { write_cmsg(&mut hdr.msg_hdr, cmsg, libc::UDP_SEGMENT, segment_size) };
+                }
+                hdr
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: every header points at iovecs / cmsgs pointing at live buffers, all of which outlive the call
+        let sent = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), hdrs.len() as _, 0) };
+        match sent {
+            -1 => Err(io::Error::last_os_error()),
+            sent => Ok(runs[..sent as usize].iter().map(|run| run.len()).sum()),
+        }
+    }
+
+    // returns the length and metadata of each message received
+    pub(super) fn recvmmsg(fd: RawFd, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, RecvMeta)>> {
+        let mut iovecs = bufs.iter().map(|buf| iovec(buf)).collect::<Vec<_>>();
+        let mut cmsgs = vec![CmsgBuf::default(); bufs.len()];
+        let mut hdrs = iovecs
+            .iter_mut()
+            .zip(cmsgs.iter_mut())
+            .map(|(iovec, cmsg)| {
+                // SAFETY: see sendmmsg
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_iov = iovec;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr.msg_hdr.msg_control = cmsg.as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_hdr.msg_controllen = size_of::<CmsgBuf>() as _;
+                hdr
+            })
+            .collect::<Vec<_>>();
+
+        // SAFETY: same as above, and the socket is non-blocking so no timeout is needed
+        let received = unsafe {
+            libc::recvmmsg(
+                fd,
+                hdrs.as_mut_ptr(),
+                hdrs.len() as _,
+                0,
+                std::ptr::null_mut(),
+            )
+        };
+        if received == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(hdrs[..received as usize]
+            .iter()
+            .map(|hdr| {
+                let len = hdr.msg_len as usize;
+                // SAFETY: the kernel filled in the control buffer, and msg_controllen with how much of it it used
+                let (segment_size, tos) = unsafe { read_cmsgs(&hdr.msg_hdr) };
+                let meta = RecvMeta {
+                    segment_size: segment_size.unwrap_or(len),
+                    ecn: tos.map(EcnCodepoint::from_tos),
+                };
+                (len, meta)
+            })
+            .collect())
+    }
+
+    unsafe fn write_cmsg(msg: &mut libc::msghdr, buf: &mut CmsgBuf, ty: libc::c_int, value: u16) {
+        msg.msg_control = buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(size_of::<u16>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = ty;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, value);
+    }
+
+    // the gro segment size and the tos / traffic class byte, if the kernel sent them
+    unsafe fn read_cmsgs(msg: &libc::msghdr) -> (Option<usize>, Option<u8>) {
+        let (mut segment_size, mut tos) = (None, None);
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_UDP, libc::UDP_GRO) => {
+                    segment_size =
+                        Some(std::ptr::read_unaligned(data as *const libc::c_int) as usize)
+                }
+                // ipv4 sends the tos as a single byte, ipv6 sends the traffic class as an int
+                (libc::IPPROTO_IP, libc::IP_TOS) => tos = Some(*data),
+                (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                    tos = Some(std::ptr::read_unaligned(data as *const libc::c_int) as u8)
+                }
+                _ => {}
+            }
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        (segment_size, tos)
+    }
+
+    pub(super) fn gso_supported(fd: RawFd) -> bool {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for the duration of the call
+        unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_SEGMENT,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            ) == 0
+        }
+    }
+
+    pub(super) fn enable_gro(fd: RawFd) -> bool {
+        let value: libc::c_int = 1;
+        // SAFETY: value is valid for the duration of the call
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &value as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            ) == 0
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_gso_runs() {
+            let batch = [100, 100, 100, 50, 100, 100, 200, 0, 0]
+                .iter()
+                .map(|len| vec![0; *len])
+                .collect::<Vec<_>>();
+            assert_eq!(gso_runs(&batch, 64), vec![0..4, 4..6, 6..7, 7..8, 8..9]);
+            assert_eq!(
+                gso_runs(&batch, 2),
+                vec![0..2, 2..4, 4..6, 6..7, 7..8, 8..9]
+            );
+            assert_eq!(gso_runs(&batch, 1).len(), batch.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+        (a, b)
+    }
+
+    async fn recv_all(io: &mut BatchIo, socket: &UdpSocket, count: usize) -> Vec<Vec<u8>> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            let mut bufs = vec![vec![0; u16::MAX as usize]; 8];
+            let metas = io.recv(socket, &mut bufs).await.unwrap();
+            assert!((1..=io.batch_size()).contains(&metas.len()));
+            for (buf, meta) in bufs.iter().zip(metas) {
+                received.extend(segments(buf, meta.segment_size).map(|d| d.to_vec()));
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_batch_io() {
+        let (a, b) = socket_pair().await;
+
+        let datagrams = (0..10u8)
+            .map(|i| vec![i; 100 + i as usize])
+            .collect::<Vec<_>>();
+        let mut sender = BatchIo::new(4);
+        sender.send(&a, &datagrams).await.unwrap();
+        assert_eq!(sender.stats().datagrams_sent, 10);
+        #[cfg(target_os = "linux")]
+        assert_eq!(sender.stats().send_syscalls, 3);
+
+        let mut receiver = BatchIo::new(4);
+        assert_eq!(recv_all(&mut receiver, &b, 10).await, datagrams);
+        assert_eq!(receiver.stats().datagrams_recv, 10);
+    }
+
+    #[tokio::test]
+    async fn test_gso_gro() {
+        let (a, b) = socket_pair().await;
+
+        let mut sender = BatchIo::new(4);
+        if !sender.probe_gso(&a) {
+            return;
+        }
+        let mut receiver = BatchIo::new(4);
+        receiver.enable_gro(&b);
+
+        // equal sized runs with a short tail, like a burst of full packets
+        let mut datagrams = (0..20u8).map(|i| vec![i; 1_200]).collect::<Vec<_>>();
+        datagrams.push(vec![0xFF; 300]);
+        sender.send(&a, &datagrams).await.unwrap();
+        // everything fits in one gso message
+        assert_eq!(sender.stats().send_syscalls, 1);
+
+        assert_eq!(
+            recv_all(&mut receiver, &b, datagrams.len()).await,
+            datagrams
+        );
+        assert_eq!(receiver.stats().datagrams_recv, datagrams.len() as u64);
+    }
+}
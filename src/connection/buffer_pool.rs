@@ -0,0 +1,111 @@
+// 1500 byte ethernet mtu, minus the ipv4 and udp headers
+pub const DEFAULT_MAX_UDP_PAYLOAD_SIZE: usize = 1_472;
+
+// how many idle buffers are kept around before recycled ones are just dropped
+pub const DEFAULT_POOL_CAPACITY: usize = 64;
+
+// hands out fixed-size datagram buffers and takes them back once they've been sent / decoded,
+// so the send and receive paths don't allocate per packet once the pool is warm
+#[derive(Debug)]
+pub struct BufferPool {
+    buf_size: usize,
+    capacity: usize,
+    free: Vec<Vec<u8>>,
+    allocations: usize,
+}
+
+impl BufferPool {
+    pub fn new(buf_size: usize, capacity: usize) -> Self {
+        Self {
+            buf_size,
+            capacity,
+            free: Vec::with_capacity(capacity),
+            allocations: 0,
+        }
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    // idle buffers too small for the new size are let go
+    pub fn set_buf_size(&mut self, buf_size: usize) {
+        self.buf_size = buf_size;
+        self.free.retain(|buf| buf.capacity() >= buf_size);
+    }
+
+    // number of idle buffers ready to be handed out
+    pub fn len(&self) -> usize {
+        self.free.len()
+    }
+
+    // number of times the pool had to go to the allocator, should stop growing under steady load
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    // an empty buffer with room for a full datagram, for encoding into
+    pub fn take(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buf) => buf,
+            None => {
+                self.allocations += 1;
+                Vec::with_capacity(self.buf_size)
+            }
+        }
+    }
+
+    // a buffer that's `buf_size` long, for reading a datagram into
+    pub fn take_zeroed(&mut self) -> Vec<u8> {
+        let mut buf = self.take();
+        buf.resize(self.buf_size, 0);
+        buf
+    }
+
+    pub fn recycle(&mut self, mut buf: Vec<u8>) {
+        // buffers that were shrunk or swapped out for a smaller one aren't worth keeping
+        if self.free.len() < self.capacity && buf.capacity() >= self.buf_size {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_UDP_PAYLOAD_SIZE, DEFAULT_POOL_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool_recycles() {
+        let mut pool = BufferPool::new(1_200, 2);
+
+        let buf = pool.take_zeroed();
+        assert_eq!(buf.len(), 1_200);
+        pool.recycle(buf);
+        assert_eq!(pool.len(), 1);
+
+        // steady state, one buffer in flight at a time never allocates again
+        for _ in 0..100 {
+            let mut buf = pool.take();
+            assert!(buf.is_empty());
+            assert!(buf.capacity() >= 1_200);
+            buf.extend_from_slice(&[0xAB; 1_200]);
+            pool.recycle(buf);
+        }
+        assert_eq!(pool.allocations(), 1);
+
+        // the pool never holds more than its capacity, and doesn't keep undersized buffers
+        let bufs = (0..4).map(|_| pool.take()).collect::<Vec<_>>();
+        bufs.into_iter().for_each(|buf| pool.recycle(buf));
+        assert_eq!(pool.len(), 2);
+        pool.take();
+        pool.recycle(Vec::new());
+        assert_eq!(pool.len(), 1);
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    consts::{DEFAULT_ACTIVE_CONNECTION_ID_LIMIT, STATELESS_RESET_TOKEN_LEN},
+    packet::{error::ProtocolError, frame::Frame, types::ConnectionId},
+    result::QuicheResult,
+    VarInt,
+};
+
+// a cid the peer issued with NEW_CONNECTION_ID
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCid {
+    pub sequence_number: u64,
+    pub cid: ConnectionId,
+    pub reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
+}
+
+// a cid we issued, as it's kept in a snapshot.  the handshake cid went out without a reset token
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalCid {
+    pub sequence_number: u64,
+    pub cid: ConnectionId,
+    pub reset_token: Option<[u8; STATELESS_RESET_TOKEN_LEN]>,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    cid: ConnectionId,
+    reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
+    // whether it's been sent on, each cid only gets used for one path
+    used: bool,
+}
+
+// how many retired sequence numbers are remembered, so each is only retired once.  past this the
+// lowest are forgotten, and a NEW_CONNECTION_ID for one of them that's late by that many more gets
+// a second RETIRE_CONNECTION_ID, which the peer ignores
+const MAX_RETIRED: usize = 256;
+
+// the cids the peer has issued us, and the ones we've retired (RFC 9000 section 5.1)
+#[derive(Debug, Clone)]
+pub struct PeerCids {
+    // issued and not retired yet, by sequence number
+    cids: BTreeMap<u64, Entry>,
+    // the active_connection_id_limit we advertised, more cids than this at once is an error
+    limit: u64,
+    // the sequence number of the dst_cid currently in use.  the one from the handshake is 0
+    active: u64,
+    // the largest Retire Prior To seen so far
+    retire_prior_to: u64,
+    // everything we've sent (or queued) RETIRE_CONNECTION_ID for, so each is only retired once
+    retired: BTreeSet<u64>,
+    pending_retirements: Vec<u64>,
+}
+
+impl Default for PeerCids {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_ACTIVE_CONNECTION_ID_LIMIT)
+    }
+}
+
+impl PeerCids {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(limit: u64) -> Self {
+        Self {
+            cids: BTreeMap::new(),
+            limit,
+            active: 0,
+            retire_prior_to: 0,
+            retired: BTreeSet::new(),
+            pending_retirements: Vec::new(),
+        }
+    }
+
+    // picks back up from a snapshot: the cid in use, the spare ones and the largest Retire Prior To
+    pub fn restore(active: u64, retire_prior_to: u64, spare: Vec<PeerCid>) -> Self {
+        let cids = spare
+            .into_iter()
+            .map(|peer_cid| {
+                let entry = Entry {
+                    cid: peer_cid.cid,
+                    reset_token: peer_cid.reset_token,
+                    used: false,
+                };
+                (peer_cid.sequence_number, entry)
+            })
+            .collect();
+        Self {
+            cids,
+            active,
+            retire_prior_to,
+            ..Self::default()
+        }
+    }
+
+    pub fn active(&self) -> u64 {
+        self.active
+    }
+
+    pub fn retire_prior_to(&self) -> u64 {
+        self.retire_prior_to
+    }
+
+    // issued cids that haven't been used or retired yet
+    pub fn spare(&self) -> Vec<PeerCid> {
+        self.cids
+            .iter()
+            .filter(|(_, entry)| !entry.used)
+            .map(|(sequence_number, entry)| PeerCid {
+                sequence_number: *sequence_number,
+                cid: entry.cid.clone(),
+                reset_token: entry.reset_token,
+            })
+            .collect()
+    }
+
+    // a NEW_CONNECTION_ID frame.  if it retires the cid currently in use, returns the one to switch to.
+    // a sequence number reissued with a different cid or reset token, or a cid reissued under a
+    // different sequence number, is a PROTOCOL_VIOLATION; an exact repeat is just a retransmission.
+    // more active cids than our limit once it's retired what it asked to is a
+    // CONNECTION_ID_LIMIT_ERROR (RFC 9000 section 5.1.1)
+    pub fn on_new_connection_id(
+        &mut self,
+        sequence_number: u64,
+        retire_prior_to: u64,
+        cid: ConnectionId,
+        reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
+    ) -> Result<Option<ConnectionId>, ProtocolError> {
+        if let Some(entry) = self.cids.get(&sequence_number) {
+            if entry.cid != cid || entry.reset_token != reset_token {
+                return Err(ProtocolError::ProtocolViolation);
+            }
+        } else if self
+            .cids
+            .iter()
+            .any(|(_, entry)| entry.cid == cid || entry.reset_token == reset_token)
+        {
+            return Err(ProtocolError::ProtocolViolation);
+        } else if sequence_number < self.retire_prior_to.max(retire_prior_to) {
+            // already out of date by the time it got here
+            self.retire(sequence_number);
+        } else if !self.retired.contains(&sequence_number) {
+            self.cids.insert(
+                sequence_number,
+                Entry {
+                    cid,
+                    reset_token,
+                    used: false,
+                },
+            );
+        }
+
+        let next = self.on_retire_prior_to(retire_prior_to);
+        if self.active_count() > self.limit as usize {
+            return Err(ProtocolError::ConnectionIdLimitError);
+        }
+        Ok(next)
+    }
+
+    // the cids the peer can expect us to use: every one issued and not retired, the handshake
+    // cid included until the first Retire Prior To retires it
+    fn active_count(&self) -> usize {
+        let handshake = self.retire_prior_to == 0 && !self.cids.contains_key(&0);
+        self.cids.len() + handshake as usize
+    }
+
+    // retires everything below `retire_prior_to`, returns the cid to move to if that was the
+    // one in use
+    fn on_retire_prior_to(&mut self, retire_prior_to: u64) -> Option<ConnectionId> {
+        if retire_prior_to <= self.retire_prior_to {
+            return None;
+        }
+        self.retire_prior_to = retire_prior_to;
+        // the handshake cid is never in `cids`, but it's sequence number 0 all the same
+        self.retire(0);
+        let stale = self
+            .cids
+            .range(..retire_prior_to)
+            .map(|(sequence_number, _)| *sequence_number)
+            .collect::<Vec<_>>();
+        for sequence_number in stale {
+            self.cids.remove(&sequence_number);
+            self.retire(sequence_number);
+        }
+
+        if self.active >= retire_prior_to {
+            return None;
+        }
+        self.retire(self.active);
+        // the frame's own cid is at or above retire_prior_to, so there's always one to move to
+        self.take_spare()
+    }
+
+    // the next unused cid, which becomes the active one
+    pub fn take_spare(&mut self) -> Option<ConnectionId> {
+        let (sequence_number, entry) = self.cids.iter_mut().find(|(_, entry)| !entry.used)?;
+        entry.used = true;
+        self.active = *sequence_number;
+        Some(entry.cid.clone())
+    }
+
+    // RETIRE_CONNECTION_ID frames that still need to go out
+    pub fn take_retirements(&mut self) -> Vec<Frame> {
+        self.pending_retirements
+            .drain(..)
+            .map(|sequence_number| Frame::RetireConnectionId(VarInt(sequence_number)))
+            .collect()
+    }
+
+    fn retire(&mut self, sequence_number: u64) {
+        if self.retired.insert(sequence_number) {
+            self.pending_retirements.push(sequence_number);
+        }
+        if self.retired.len() > MAX_RETIRED {
+            self.retired.pop_first();
+        }
+    }
+}
+
+// the cids we've issued to the peer, the source cid from the handshake being sequence number 0
+#[derive(Debug, Clone)]
+pub struct LocalCids {
+    // issued and not retired yet, by sequence number
+    cids: BTreeMap<u64, ConnectionId>,
+    // the stateless reset token issued with each of `cids`
+    tokens: BTreeMap<u64, [u8; STATELESS_RESET_TOKEN_LEN]>,
+    // the largest retire prior to we've sent
+    retire_prior_to: u64,
+    next_sequence_number: u64,
+}
+
+impl LocalCids {
+    pub fn new(initial: ConnectionId) -> Self {
+        Self {
+            cids: BTreeMap::from([(0, initial)]),
+            tokens: BTreeMap::new(),
+            retire_prior_to: 0,
+            next_sequence_number: 1,
+        }
+    }
+
+    // picks back up from a snapshot: the cids still issued, the largest Retire Prior To we'd sent
+    // and the sequence number the next one gets
+    pub fn restore(cids: Vec<LocalCid>, retire_prior_to: u64, next_sequence_number: u64) -> Self {
+        let tokens = cids
+            .iter()
+            .filter_map(|local| Some((local.sequence_number, local.reset_token?)))
+            .collect();
+        Self {
+            cids: cids
+                .into_iter()
+                .map(|local| (local.sequence_number, local.cid))
+                .collect(),
+            tokens,
+            retire_prior_to,
+            next_sequence_number,
+        }
+    }
+
+    // every cid that's issued and not retired, with its reset token
+    pub fn issued(&self) -> Vec<LocalCid> {
+        self.cids
+            .iter()
+            .map(|(sequence_number, cid)| LocalCid {
+                sequence_number: *sequence_number,
+                cid: cid.clone(),
+                reset_token: self.tokens.get(sequence_number).copied(),
+            })
+            .collect()
+    }
+
+    pub fn retire_prior_to(&self) -> u64 {
+        self.retire_prior_to
+    }
+
+    pub fn next_sequence_number(&self) -> u64 {
+        self.next_sequence_number
+    }
+
+    // the cid from the handshake, while it's still around
+    pub fn initial(&self) -> Option<&ConnectionId> {
+        self.cids.get(&0)
+    }
+
+    pub fn get(&self, sequence_number: u64) -> Option<&ConnectionId> {
+        self.cids.get(&sequence_number)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cids.is_empty()
+    }
+
+    // hands `connection_id` to the peer, returns the NEW_CONNECTION_ID frame that does it
+    pub fn issue(
+        &mut self,
+        connection_id: ConnectionId,
+        retire_prior_to: u64,
+        stateless_reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
+    ) -> Frame {
+        let sequence_number = self.next_sequence_number;
+        self.next_sequence_number += 1;
+        self.cids.insert(sequence_number, connection_id);
+        self.tokens.insert(sequence_number, stateless_reset_token);
+        self.retire_prior_to = self.retire_prior_to.max(retire_prior_to);
+        self.new_connection_id(sequence_number).unwrap()
+    }
+
+    // the NEW_CONNECTION_ID for `sequence_number` as it stands now, to send again when the last
+    // one was lost.  None once it's been retired, there's nothing left to tell the peer
+    pub fn new_connection_id(&self, sequence_number: u64) -> Option<Frame> {
+        Some(Frame::NewConnectionId {
+            sequence_number: VarInt(sequence_number),
+            retire_prior_to: VarInt(self.retire_prior_to),
+            connection_id: self.cids.get(&sequence_number)?.clone(),
+            stateless_reset_token: *self.tokens.get(&sequence_number)?,
+        })
+    }
+
+    // the peer's RETIRE_CONNECTION_ID, carried in a packet sent to `packet_dst_cid`.  returns the
+    // retired cid, or None if it was retired already.  it's a PROTOCOL_VIOLATION if we're using
+    // zero-length cids, if we never issued the sequence number, or if it's the cid the frame came in on
+    pub fn on_retire_connection_id(
+        &mut self,
+        sequence_number: u64,
+        packet_dst_cid: &ConnectionId,
+    ) -> QuicheResult<Option<ConnectionId>> {
+        if packet_dst_cid.cid.is_empty() || sequence_number >= self.next_sequence_number {
+            return Err(ProtocolError::ProtocolViolation.into());
+        }
+        match self.cids.get(&sequence_number) {
+            Some(cid) if cid == packet_dst_cid => Err(ProtocolError::ProtocolViolation.into()),
+            _ => {
+                self.tokens.remove(&sequence_number);
+                Ok(self.cids.remove(&sequence_number))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cid(byte: u8) -> ConnectionId {
+        ConnectionId::new(4, vec![byte; 4])
+    }
+
+    fn retirements(cids: &mut PeerCids) -> Vec<u64> {
+        cids.take_retirements()
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::RetireConnectionId(sequence_number) => sequence_number.0,
+                frame => panic!("expected a RETIRE_CONNECTION_ID, got {:?}", frame),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reissued_sequence_numbers() {
+        let mut cids = PeerCids::new();
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        // a retransmission is fine
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        // the same sequence number with a different cid or token isn't
+        assert!(cids.on_new_connection_id(1, 0, cid(2), [1; 16]).is_err());
+        assert!(cids.on_new_connection_id(1, 0, cid(1), [2; 16]).is_err());
+        // nor is the same cid under another sequence number
+        assert!(cids.on_new_connection_id(2, 0, cid(1), [2; 16]).is_err());
+        assert_eq!(cids.spare().len(), 1);
+    }
+
+    #[test]
+    fn test_retire_prior_to() {
+        let mut cids = PeerCids::with_limit(4);
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        cids.on_new_connection_id(2, 0, cid(2), [2; 16]).unwrap();
+        assert_eq!(cids.take_spare(), Some(cid(1)));
+
+        // retires the handshake cid and the one in use, and moves us onto 2
+        let next = cids.on_new_connection_id(3, 2, cid(3), [3; 16]).unwrap();
+        assert_eq!(next, Some(cid(2)));
+        assert_eq!(cids.active(), 2);
+        assert_eq!(retirements(&mut cids), vec![0, 1]);
+
+        // a frame that was reordered behind the one above is retired on arrival, but only once
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        assert!(retirements(&mut cids).is_empty());
+        cids.on_new_connection_id(0, 0, cid(9), [9; 16]).unwrap();
+        assert!(retirements(&mut cids).is_empty());
+
+        // a lower retire prior to than before changes nothing
+        assert_eq!(
+            cids.on_new_connection_id(4, 1, cid(4), [4; 16]).unwrap(),
+            None
+        );
+        assert_eq!(
+            cids.spare()
+                .iter()
+                .map(|peer_cid| peer_cid.sequence_number)
+                .collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+    }
+
+    #[test]
+    fn test_active_connection_id_limit() {
+        // the handshake cid and one more is all the default limit takes
+        let mut cids = PeerCids::new();
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        assert!(matches!(
+            cids.on_new_connection_id(2, 0, cid(2), [2; 16]),
+            Err(ProtocolError::ConnectionIdLimitError)
+        ));
+
+        // unless the frame retires enough to make room
+        let mut cids = PeerCids::new();
+        cids.on_new_connection_id(1, 0, cid(1), [1; 16]).unwrap();
+        cids.on_new_connection_id(2, 1, cid(2), [2; 16]).unwrap();
+        assert_eq!(retirements(&mut cids), vec![0]);
+
+        // and a peer cycling through cids can't grow what's kept about the retired ones
+        for sequence_number in 3..1_000 {
+            let byte = sequence_number as u8;
+            cids.on_new_connection_id(
+                sequence_number,
+                sequence_number - 1,
+                ConnectionId::new(8, sequence_number.to_be_bytes().to_vec()),
+                [byte; 16],
+            )
+            .unwrap();
+        }
+        assert_eq!(cids.spare().len(), 1);
+        assert!(cids.retired.len() <= MAX_RETIRED);
+    }
+
+    #[test]
+    fn test_retire_connection_id() {
+        let mut cids = LocalCids::new(cid(0));
+        let connection_id = cid(1);
+        cids.issue(connection_id.clone(), 0, [0; 16]);
+        assert_eq!(cids.get(1), Some(&connection_id));
+
+        // never issued
+        assert!(cids.on_retire_connection_id(2, &cid(0)).is_err());
+        // retiring the cid the packet was sent to
+        assert!(cids.on_retire_connection_id(1, &connection_id).is_err());
+        // anything at all when we use zero-length cids
+        assert!(cids
+            .on_retire_connection_id(0, &ConnectionId::new(0, Vec::new()))
+            .is_err());
+
+        assert_eq!(
+            cids.on_retire_connection_id(0, &connection_id).unwrap(),
+            Some(cid(0))
+        );
+        // a retransmission is harmless
+        assert_eq!(
+            cids.on_retire_connection_id(0, &connection_id).unwrap(),
+            None
+        );
+        assert_eq!(cids.initial(), None);
+        assert_eq!(cids.len(), 1);
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::Instant;
+
+// the window we start with and never drop below, in datagrams (RFC 9002 section 7.2)
+pub const INITIAL_WINDOW_PACKETS: usize = 10;
+pub const MINIMUM_WINDOW_PACKETS: usize = 2;
+
+// why the window was cut
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionCause {
+    Loss,
+    // the peer reported more packets marked ECN-CE, the path queueing before it has to drop
+    EcnCe,
+}
+
+// a congestion event, with what the window and delivery rate are once it's been taken into
+// account.  an application choosing its own sending rate, e.g. a media encoder picking a bitrate,
+// can back off alongside us instead of waiting to find out from its queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionEvent {
+    pub at: Instant,
+    pub cause: CongestionCause,
+    // bytes
+    pub cwnd: usize,
+    // bytes per second, None until there's been a sample
+    pub delivery_rate: Option<u64>,
+}
+
+// called with every congestion event as it happens
+pub type CongestionCallback = dyn Fn(&CongestionEvent) + Send + Sync;
+
+// NewReno (RFC 9002 section 7): slow start doubles the window every rtt until the first loss,
+// then it grows by a datagram per window's worth acknowledged.  a loss or ECN-CE mark halves
+// it, once per round trip, anything sent before the cut is part of the same episode.  only
+// ack-eliciting packets count towards bytes in flight, an ACK-only packet may never be
+// acknowledged, so it neither takes up the window nor cuts it when it's lost
+#[derive(Debug, Clone)]
+pub struct NewReno {
+    max_datagram_size: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    bytes_in_flight: usize,
+    // bytes acknowledged towards the next datagram of growth in congestion avoidance
+    acked_bytes: usize,
+    // when the window was last cut, packets sent before it don't cut it again
+    recovery_start: Option<Instant>,
+    // when the most recently acknowledged packet was sent, what an ECN-CE mark is blamed on
+    largest_acked_sent_at: Option<Instant>,
+    // the ECN-CE count the peer last reported
+    ecn_ce_count: u64,
+}
+
+impl NewReno {
+    pub fn new(max_datagram_size: usize) -> Self {
+        Self {
+            max_datagram_size,
+            cwnd: INITIAL_WINDOW_PACKETS * max_datagram_size,
+            ssthresh: usize::MAX,
+            bytes_in_flight: 0,
+            acked_bytes: 0,
+            recovery_start: None,
+            largest_acked_sent_at: None,
+            ecn_ce_count: 0,
+        }
+    }
+
+    pub fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    pub fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    // how much more may be sent right now
+    pub fn available(&self) -> usize {
+        self.cwnd.saturating_sub(self.bytes_in_flight)
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    pub fn on_sent(&mut self, size: usize, ack_eliciting: bool) {
+        if ack_eliciting {
+            self.bytes_in_flight += size;
+        }
+    }
+
+    pub fn on_acked(&mut self, size: usize, ack_eliciting: bool, sent_at: Instant) {
+        if !ack_eliciting {
+            return;
+        }
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(size);
+        if self
+            .largest_acked_sent_at
+            .is_none_or(|largest| sent_at > largest)
+        {
+            self.largest_acked_sent_at = Some(sent_at);
+        }
+        if self.in_recovery(sent_at) {
+            return;
+        }
+        if self.in_slow_start() {
+            self.cwnd += size;
+            return;
+        }
+        self.acked_bytes += size;
+        if self.acked_bytes >= self.cwnd {
+            self.acked_bytes -= self.cwnd;
+            self.cwnd += self.max_datagram_size;
+        }
+    }
+
+    // a packet sent at `sent_at` was declared lost.  returns whether it cut the window, a loss
+    // in an episode that already has doesn't, and neither does one that wasn't ack-eliciting
+    pub fn on_lost(
+        &mut self,
+        size: usize,
+        ack_eliciting: bool,
+        sent_at: Instant,
+        now: Instant,
+    ) -> bool {
+        if !ack_eliciting {
+            return false;
+        }
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(size);
+        self.on_congestion_event(sent_at, now)
+    }
+
+    // the peer's ECN-CE count from an ACK_ECN frame.  returns whether it went up and cut the
+    // window (RFC 9002 section 7.6.1)
+    pub fn on_ecn_ce(&mut self, ecn_ce_count: u64, now: Instant) -> bool {
+        if ecn_ce_count <= self.ecn_ce_count {
+            return false;
+        }
+        self.ecn_ce_count = ecn_ce_count;
+        let sent_at = self.largest_acked_sent_at.unwrap_or(now);
+        self.on_congestion_event(sent_at, now)
+    }
+
+    fn in_recovery(&self, sent_at: Instant) -> bool {
+        self.recovery_start
+            .is_some_and(|recovery_start| sent_at <= recovery_start)
+    }
+
+    fn on_congestion_event(&mut self, sent_at: Instant, now: Instant) -> bool {
+        if self.in_recovery(sent_at) {
+            return false;
+        }
+        self.recovery_start = Some(now);
+        self.ssthresh = (self.cwnd / 2).max(MINIMUM_WINDOW_PACKETS * self.max_datagram_size);
+        self.cwnd = self.ssthresh;
+        self.acked_bytes = 0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_new_reno() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut reno = NewReno::new(1_000);
+        assert_eq!(reno.cwnd(), 10_000);
+
+        // slow start, every acknowledged byte grows the window by one
+        for _ in 0..10 {
+            reno.on_sent(1_000, true);
+        }
+        assert_eq!(reno.available(), 0);
+        for _ in 0..5 {
+            reno.on_acked(1_000, true, at(0));
+        }
+        assert_eq!((reno.cwnd(), reno.bytes_in_flight()), (15_000, 5_000));
+
+        // one loss halves it, the rest of the episode doesn't
+        assert!(reno.on_lost(1_000, true, at(0), at(10)));
+        assert_eq!(reno.cwnd(), 7_500);
+        assert!(!reno.on_lost(1_000, true, at(5), at(20)));
+        assert_eq!(reno.cwnd(), 7_500);
+        reno.on_acked(1_000, true, at(5));
+        assert_eq!(reno.cwnd(), 7_500);
+
+        // out of slow start, a datagram per window acknowledged
+        reno.on_sent(8_000, true);
+        for _ in 0..8 {
+            reno.on_acked(1_000, true, at(30));
+        }
+        assert_eq!(reno.cwnd(), 8_500);
+
+        // an ECN-CE mark on something sent after the cut starts a new episode
+        assert!(reno.on_ecn_ce(1, at(40)));
+        assert_eq!(reno.cwnd(), 4_250);
+        assert!(!reno.on_ecn_ce(1, at(50)));
+
+        // never below the minimum
+        for ms in 0..10 {
+            reno.on_sent(1_000, true);
+            reno.on_lost(1_000, true, at(100 + ms * 20), at(110 + ms * 20));
+        }
+        assert_eq!(reno.cwnd(), 2_000);
+
+        // an ACK-only packet isn't in flight, and losing it isn't congestion
+        let in_flight = reno.bytes_in_flight();
+        reno.on_sent(50, false);
+        assert_eq!(reno.bytes_in_flight(), in_flight);
+        assert!(!reno.on_lost(50, false, at(400), at(500)));
+        reno.on_sent(50, false);
+        reno.on_acked(50, false, at(400));
+        assert_eq!((reno.cwnd(), reno.bytes_in_flight()), (2_000, in_flight));
+    }
+}
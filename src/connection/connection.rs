@@ -1,59 +1,1633 @@
-use std::net::SocketAddr;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufWriter,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc::Sender, watch, Notify};
 
 use crate::{
-    packet::{error::ProtocolError, packet::Packet},
-    result::{QuicheError, QuicheResult},
+    consts::{MIN_INITIAL_SIZE, STATELESS_RESET_TOKEN_LEN},
+    crypto::{
+        CryptoRecvBuffer, CryptoSendBuffer, KeyGeneration, KeyPhaseError, KeyUsage, KeyUsageStatus,
+        PendingPackets, SessionTicket, ZeroRttLimits,
+    },
+    endpoint::{
+        CidCodec, DropReason, Forwarding, PreSendHook, RandomCidCodec, StatelessResetKey, TokenKey,
+    },
+    metrics,
+    packet::{
+        error::ProtocolError,
+        frame::{Frame, MAX_REASON_PHRASE_LEN},
+        header::{Header, LongHeaderExtension},
+        one_rtt::{write_short_packet, PlaintextSealer},
+        packet::Packet,
+        transport_parameters::{
+            TransportParameterError, TransportParameters, MAX_UDP_PAYLOAD_SIZE,
+            MIN_UDP_PAYLOAD_SIZE,
+        },
+        types::ConnectionId,
+        EncryptionLevel, LongPacketType, PacketNumber, PacketSpace, PnLen, SingleBit, TwoBits,
+    },
+    pcap::PcapWriter,
+    result::{require, QuicheError, QuicheResult},
+    secure_bytes,
+    stream::{FlowControl, FlowControlConfig, RecvWindow, SendWindow},
+    BitsExt, SmallBytes, VarInt, MINI_QUICHE_VERSION,
+};
+
+#[cfg(feature = "dangerous-snapshot")]
+use super::ConnectionSnapshot;
+use super::{
+    segments, AckTracker, AmplificationLimit, BatchIo, Blocked, BlockedCallback, BlockedEvent,
+    BufferPool, CloseCause, CloseInitiator, CloseReason, CongestionCallback, CongestionCause,
+    CongestionEvent, ConnectionEvent, ConnectionState, ConnectionStats, ControlFrame,
+    DeliveryRateEstimator, Direction, FaultInjector, GoAwayNotice, IoStats, LocalCids, NewReno,
+    PacketNumbers, Path, PeerCids, PendingControlFrames, RecoveryConfig, RecvQueue, RttEstimator,
+    SendQueue, Side, SocketConfig, StateObserver, TestHooks, Timer, Timers, TraceId,
+    DEFAULT_MAX_UDP_PAYLOAD_SIZE, DEFAULT_POOL_CAPACITY,
 };
 
-use super::ConnectionState;
+// probing packets are padded to the smallest allowed max datagram size (RFC 9000 section 8.2.1)
+const MIN_PROBE_DATAGRAM_SIZE: usize = MIN_INITIAL_SIZE;
+
+// TODO: this should be the negotiated max_idle_timeout
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// how long a handshake gets by default before the connection fails, separate from the idle timeout
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
+#[allow(dead_code)]
 pub struct Connection {
-    state: ConnectionState,
-    // queue of incoming packets to be processed
-    recv_buf: Vec<Vec<u8>>,
-    // queue of outgoing packets to be sent
-    send_buf: Vec<Packet>,
-    socket: UdpSocket,
+    // only changed through `transition`, observers subscribe to it
+    state: watch::Sender<ConnectionState>,
+    // received datagrams waiting to be processed
+    recv_queue: RecvQueue,
+    // outgoing packets, per encryption level
+    send_queue: SendQueue,
+    // shared with `SharedConnection`, so a reader can wait on it without holding the connection
+    socket: Arc<UdpSocket>,
+    // woken on `rebind`, for anyone waiting on the old socket outside the connection
+    rebound: Arc<Notify>,
     peer_addr: SocketAddr,
     kill: Option<Sender<()>>,
+    side: Side,
+    // when set, every datagram sent or received is recorded for wireshark
+    pcap: Option<PcapWriter<BufWriter<File>>>,
+    // datagram buffers for the send and receive paths
+    pool: BufferPool,
+    io: BatchIo,
+    // the path currently being sent on
+    path: Path,
+    dst_cid: ConnectionId,
+    // the dst_cid of the very first Initial, which the trace id is derived from
+    original_dst_cid: ConnectionId,
+    // the src_cid of the Retry the handshake went through, if it went through one
+    retry_src_cid: Option<ConnectionId>,
+    // cids the peer issued with NEW_CONNECTION_ID
+    peer_cids: PeerCids,
+    // cids we've issued, that the peer can send to
+    local_cids: LocalCids,
+    // mints the cids in `local_cids`
+    cid_codec: Arc<dyn CidCodec>,
+    // what the stateless reset tokens for our cids are derived from.  without one they're random,
+    // and the connection can't be reset once its state is gone
+    reset_key: Option<StatelessResetKey>,
+    // shared by every packet number space, occasionally skipping one to catch optimistic ACKs
+    packet_numbers: PacketNumbers,
+    // the largest of our packets the peer has acknowledged, per space.  how short a packet number
+    // can be sent in
+    largest_acked: [Option<u64>; PacketSpace::ALL.len()],
+    // loss detection constants, and the rtt estimate they're applied to
+    recovery: RecoveryConfig,
+    rtt: RttEstimator,
+    // bandwidth samples from ACKs
+    delivery: DeliveryRateEstimator,
+    congestion: NewReno,
+    // what we've received and still need to acknowledge, per packet number space
+    acks: [AckTracker; PacketSpace::ALL.len()],
+    timers: Timers,
+    handshake_timeout: Duration,
+    // how close the packet protection keys are to their limits
+    key_usage: KeyUsage,
+    // whether `ConnectionEvent::KeyUpdateRequired` has gone out for the current keys
+    key_update_required: bool,
+    // which levels we have keys for, initial keys are derived from the first dst_cid so they're always there
+    installed_keys: [bool; EncryptionLevel::ALL.len()],
+    // which levels' keys are gone for good.  a late packet for one of those is dropped, there's
+    // nothing that'll ever open it
+    discarded_keys: [bool; EncryptionLevel::ALL.len()],
+    // packets waiting for keys we don't have yet
+    pending_packets: PendingPackets,
+    // CRYPTO data we're sending, per packet number space
+    crypto: [CryptoSendBuffer; 3],
+    crypto_recv: [CryptoRecvBuffer; 3],
+    // NEW_TOKEN, NEW_CONNECTION_ID and HANDSHAKE_DONE, sent until acknowledged
+    control: PendingControlFrames,
+    // on a server, what the NEW_TOKEN tokens sent once the handshake is done are minted with,
+    // and how many go out
+    new_token_key: Option<TokenKey>,
+    new_tokens: usize,
+    // how much we may send before the peer's address is validated
+    amplification: AmplificationLimit,
+    // the limits we give the peer and the ones it gives us, connection-wide and per stream
+    flow_control: FlowControl,
+    // the largest datagram we accept, which the peer is told in our transport parameters
+    max_udp_payload_size: usize,
+    // the largest datagram we send right now.  it starts small enough for any path and is only
+    // raised once the path is known to carry more
+    udp_payload_size: usize,
+    // from the peer's transport parameters
+    peer_max_udp_payload_size: usize,
+    // how long the peer says it may hold an ACK for, the most of its ack delay we believe
+    peer_max_ack_delay: Duration,
+    // all of them, once the handshake has delivered them
+    peer_transport_parameters: Option<TransportParameters>,
+    // on a client resuming a session, what its 0-rtt is held to
+    zero_rtt: Option<ZeroRttLimits>,
+    // who we think the peer is, for SNI and certificate verification.  None on the server side
+    server_name: Option<String>,
+    // waiting for the application to `poll_event`
+    events: VecDeque<ConnectionEvent>,
+    // set the first time anything starts closing the connection
+    close_reason: Option<CloseReason>,
+    // the application error code to close with once `go_away` has been called
+    going_away: Option<u64>,
+    // only ever set by tests
+    faults: Option<FaultInjector>,
+    // sees every datagram before it's written, and may send it some other way
+    pre_send: Option<Arc<PreSendHook>>,
+    // what faults duplicated or released behind a `write_datagram`, already counted as sent, for
+    // the next `write_datagram` or `flush` to write out
+    unwritten: VecDeque<Vec<u8>>,
+    // everything but the i/o and pending packet counters, which `stats` pulls from `io` /
+    // `recv_queue` / `pending_packets`
+    stats: ConnectionStats,
+    // dropped datagrams the endpoint counts in its `DropStats`, by `DropReason`, see `take_drops`
+    drops: [u64; DropReason::ALL.len()],
+    on_blocked: Option<Box<BlockedCallback>>,
+    on_congestion: Option<Box<CongestionCallback>>,
 }
 
-impl Connection {
-    pub async fn new(local_addr: SocketAddr, peer_addr: SocketAddr) -> QuicheResult<Self> {
-        let socket = UdpSocket::bind(local_addr).await?;
-        socket.connect(peer_addr).await?;
+impl Connection {
+    pub async fn new(local_addr: SocketAddr, peer_addr: SocketAddr) -> QuicheResult<Self> {
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(peer_addr).await?;
+
+        let mut io = BatchIo::default();
+        io.probe_gso(&socket);
+        let path = Path::validated(socket.local_addr()?, peer_addr);
+        let dst_cid = ConnectionId::random();
+
+        Ok(Self {
+            state: watch::Sender::new(ConnectionState::Idle),
+            recv_queue: RecvQueue::default(),
+            send_queue: SendQueue::new(),
+            socket: Arc::new(socket),
+            rebound: Arc::new(Notify::new()),
+            peer_addr,
+            kill: None,
+            side: Side::Client,
+            pcap: None,
+            pool: BufferPool::new(DEFAULT_MAX_UDP_PAYLOAD_SIZE + 1, DEFAULT_POOL_CAPACITY),
+            io,
+            path,
+            original_dst_cid: dst_cid.clone(),
+            retry_src_cid: None,
+            dst_cid,
+            peer_cids: PeerCids::new(),
+            local_cids: LocalCids::new(ConnectionId::random()),
+            cid_codec: Arc::new(RandomCidCodec),
+            reset_key: None,
+            packet_numbers: PacketNumbers::default(),
+            largest_acked: [None; PacketSpace::ALL.len()],
+            recovery: RecoveryConfig::default(),
+            rtt: RttEstimator::new(RecoveryConfig::default().initial_rtt),
+            delivery: DeliveryRateEstimator::new(),
+            congestion: NewReno::new(MIN_UDP_PAYLOAD_SIZE as usize),
+            acks: Default::default(),
+            timers: Timers::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            // TODO: the cipher should come from the negotiated TLS cipher suite
+            key_usage: KeyUsage::default(),
+            key_update_required: false,
+            installed_keys: [true, false, false, false],
+            discarded_keys: [false; EncryptionLevel::ALL.len()],
+            pending_packets: PendingPackets::default(),
+            crypto: Default::default(),
+            crypto_recv: Default::default(),
+            control: PendingControlFrames::new(),
+            new_token_key: None,
+            new_tokens: 0,
+            amplification: AmplificationLimit::validated(),
+            flow_control: FlowControl::new(FlowControlConfig::default(), 0, 0),
+            max_udp_payload_size: DEFAULT_MAX_UDP_PAYLOAD_SIZE,
+            udp_payload_size: MIN_UDP_PAYLOAD_SIZE as usize,
+            peer_max_udp_payload_size: MAX_UDP_PAYLOAD_SIZE as usize,
+            peer_max_ack_delay: Duration::from_millis(TransportParameters::default().max_ack_delay),
+            peer_transport_parameters: None,
+            zero_rtt: None,
+            server_name: None,
+            events: VecDeque::new(),
+            close_reason: None,
+            going_away: None,
+            faults: None,
+            pre_send: None,
+            unwritten: VecDeque::new(),
+            stats: ConnectionStats::default(),
+            drops: [0; DropReason::ALL.len()],
+            on_blocked: None,
+            on_congestion: None,
+        })
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    // lets the application await establishment / closure
+    pub fn subscribe(&self) -> StateObserver {
+        StateObserver(self.state.subscribe())
+    }
+
+    pub(crate) fn transition(&mut self, next: ConnectionState) -> QuicheResult<()> {
+        let mut result = Ok(());
+        self.state.send_if_modified(|state| {
+            result = state.transition(next);
+            result.is_ok()
+        });
+        if result.is_ok() && next == ConnectionState::Connected {
+            self.on_handshake_complete();
+        }
+        result
+    }
+
+    // a server has validated the client's address by now (RFC 9000 section 8.1), so it gets
+    // tokens to skip the Retry next time.  they go out like any control frame, again if lost
+    fn on_handshake_complete(&mut self) {
+        if self.side != Side::Server {
+            return;
+        }
+        let Some(key) = self.new_token_key.clone() else {
+            return;
+        };
+        let now = SystemTime::now();
+        for _ in 0..self.new_tokens {
+            self.send_new_token(key.mint_new_token(self.peer_addr, now));
+        }
+    }
+
+    // how long `open` waits for the handshake before failing the connection
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    // `initial` is what's sent before the path mtu is known, `max` is the upper bound on what's
+    // sent and received.  both are clamped to what a udp payload can be, and `initial` to `max`
+    pub fn set_max_udp_payload_size(&mut self, initial: usize, max: usize) {
+        let (min_size, max_size) = (MIN_UDP_PAYLOAD_SIZE as usize, MAX_UDP_PAYLOAD_SIZE as usize);
+        self.max_udp_payload_size = max.clamp(min_size, max_size);
+        self.udp_payload_size = initial.clamp(min_size, self.max_udp_payload_size);
+        // one byte over, so a datagram that got truncated to fit can be told apart from one that fit
+        self.pool.set_buf_size(self.max_udp_payload_size + 1);
+    }
+
+    // the path carries datagrams of `size` bytes, e.g. once a PMTUD probe of that size was acked
+    pub fn on_path_mtu(&mut self, size: usize) {
+        self.udp_payload_size = size.clamp(self.udp_payload_size, self.max_udp_payload_size);
+    }
+
+    // the largest datagram that can go out right now
+    pub fn max_datagram_size(&self) -> usize {
+        self.udp_payload_size.min(self.peer_max_udp_payload_size)
+    }
+
+    // what we tell the peer about ourselves in the handshake
+    pub fn transport_parameters(&self) -> TransportParameters {
+        let stream_max_data = self.flow_control.initial_stream_max_data();
+        let mut params = TransportParameters {
+            max_idle_timeout: IDLE_TIMEOUT.as_millis() as u64,
+            max_udp_payload_size: self.max_udp_payload_size as u64,
+            initial_max_data: self.flow_control.recv_window().max_data(),
+            initial_max_stream_data_bidi_local: stream_max_data,
+            initial_max_stream_data_bidi_remote: stream_max_data,
+            initial_max_stream_data_uni: stream_max_data,
+            initial_max_streams_bidi: self.flow_control.config().max_streams_bidi,
+            initial_max_streams_uni: self.flow_control.config().max_streams_uni,
+            ..Default::default()
+        };
+        if self.side == Side::Server {
+            params.original_destination_connection_id = Some(self.original_dst_cid.clone());
+            params.retry_source_connection_id = self.retry_src_cid.clone();
+        }
+        params
+    }
+
+    // the peer's transport parameters, once the handshake has authenticated them.  a client
+    // checks the server saw the same cids it did, so an attacker can't have swapped them with a
+    // Retry of its own (RFC 9000 section 7.3).  a server refuses the ones only it may send.
+    // either way a bad one closes with TRANSPORT_PARAMETER_ERROR
+    pub fn on_peer_transport_parameters(
+        &mut self,
+        params: &TransportParameters,
+    ) -> QuicheResult<()> {
+        let checked = match self.side {
+            Side::Client => {
+                params.check_from_server(&self.original_dst_cid, self.retry_src_cid.as_ref())
+            }
+            Side::Server => params.check_from_client(),
+        };
+        if let Err(err) = checked {
+            return self.fail_transport_parameters(err);
+        }
+        self.peer_max_udp_payload_size = params.max_udp_payload_size as usize;
+        self.peer_max_ack_delay = Duration::from_millis(params.max_ack_delay);
+        self.peer_transport_parameters = Some(params.clone());
+        self.flow_control.on_peer_transport_parameters(
+            params.initial_max_data,
+            params.initial_max_stream_data_bidi_remote,
+        );
+        // the server's own limits apply from here on
+        self.zero_rtt = None;
+        Ok(())
+    }
+
+    // `on_peer_transport_parameters` for them as they came in the TLS extension
+    pub fn on_encoded_peer_transport_parameters(&mut self, mut bytes: &[u8]) -> QuicheResult<()> {
+        match TransportParameters::decode(&mut bytes) {
+            Ok(params) => self.on_peer_transport_parameters(&params),
+            Err(err) => self.fail_transport_parameters(err),
+        }
+    }
+
+    // a client resuming the session `ticket` came from.  its 0-rtt is held to the limits the
+    // server had then, so `resume` has to come before `open`
+    pub fn resume(&mut self, ticket: &SessionTicket) -> QuicheResult<()> {
+        require(
+            self.side == Side::Client && self.state() == ConnectionState::Idle,
+            "Connection::resume: only a client that hasn't started can resume",
+        )?;
+        // TODO: the ticket goes in the ClientHello's pre_shared_key extension
+        self.zero_rtt = Some(ZeroRttLimits::new(ticket.params.clone()));
+        Ok(())
+    }
+
+    pub fn zero_rtt(&self) -> Option<&ZeroRttLimits> {
+        self.zero_rtt.as_ref()
+    }
+
+    // STREAM data on `stream_id` reaching `offset` is about to go out in 0-rtt.  refused without
+    // a resumed session, or past what the server allowed when it issued the ticket
+    pub fn on_zero_rtt_stream_data(&mut self, stream_id: u64, offset: u64) -> QuicheResult<()> {
+        let Some(zero_rtt) = self.zero_rtt.as_mut() else {
+            return Err(QuicheError(
+                "Connection::on_zero_rtt_stream_data: not resuming a session".to_string(),
+            ));
+        };
+        zero_rtt.on_stream_sent(stream_id, offset)
+    }
+
+    // the server turned our 0-rtt down, whatever was sent in it has to go again in 1-rtt
+    pub fn on_zero_rtt_rejected(&mut self) {
+        self.zero_rtt = None;
+        self.on_keys_discarded(EncryptionLevel::ZeroRtt);
+    }
+
+    // on a server, whether to take 0-rtt from a client that remembers `remembered` from the
+    // ticket.  if our limits have gone down since, the client may already have sent past them,
+    // so its 0-rtt is rejected and the 0-rtt keys discarded
+    pub fn accept_zero_rtt(&mut self, remembered: &TransportParameters) -> bool {
+        if self.side == Side::Server && self.transport_parameters().covers(remembered) {
+            return true;
+        }
+        self.on_keys_discarded(EncryptionLevel::ZeroRtt);
+        false
+    }
+
+    // on a client, a NewSessionTicket that's good for `lifetime`, kept with the server's
+    // transport parameters for resuming later.  None before they've arrived
+    pub fn session_ticket(
+        &self,
+        ticket: Vec<u8>,
+        lifetime: Duration,
+        now: Instant,
+    ) -> Option<SessionTicket> {
+        let params = self.peer_transport_parameters.as_ref()?;
+        (self.side == Side::Client).then(|| SessionTicket::new(ticket, params, lifetime, now))
+    }
+
+    // which parameter it was goes in the close reason and the stats
+    fn fail_transport_parameters(&mut self, err: TransportParameterError) -> QuicheResult<()> {
+        self.stats.transport_parameter_error = Some(err);
+        self.fail_with_reason(
+            ProtocolError::TransportParameterError,
+            err.to_string().as_bytes(),
+        )
+    }
+
+    pub fn key_usage(&self) -> &KeyUsage {
+        &self.key_usage
+    }
+
+    // a packet was protected with the current keys.  nearing the confidentiality limit raises
+    // `ConnectionEvent::KeyUpdateRequired` once, going past it closes the connection
+    pub fn on_packet_sealed(&mut self) -> QuicheResult<()> {
+        match self.key_usage.on_sealed() {
+            Ok(KeyUsageStatus::Ok) => Ok(()),
+            Ok(KeyUsageStatus::UpdateKeys) => {
+                if !self.key_update_required {
+                    self.key_update_required = true;
+                    self.events.push_back(ConnectionEvent::KeyUpdateRequired {
+                        sealed: self.key_usage.sealed(),
+                    });
+                }
+                Ok(())
+            }
+            Err(_) => self.fail(ProtocolError::AeadLimitReached),
+        }
+    }
+
+    // a received packet failed authentication, too many of those closes the connection
+    pub fn on_packet_open_failed(&mut self) -> QuicheResult<()> {
+        self.drops[DropReason::FailedIntegrity as usize] += 1;
+        match self.key_usage.on_open_failure() {
+            Ok(()) => Ok(()),
+            Err(_) => self.fail(ProtocolError::AeadLimitReached),
+        }
+    }
+
+    // a packet at `level` couldn't be unprotected.  without keys for it yet it's held on to until
+    // `on_keys_installed`, otherwise it counts as a failed authentication.  one for a level whose
+    // keys were discarded is just dropped
+    pub fn on_undecryptable_packet(
+        &mut self,
+        level: EncryptionLevel,
+        packet: Vec<u8>,
+    ) -> QuicheResult<()> {
+        if self.discarded_keys[level as usize] {
+            return Ok(());
+        }
+        if !self.installed_keys[level as usize] {
+            self.pending_packets.push(level, packet);
+            return Ok(());
+        }
+        self.stats.undecryptable_packets += 1;
+        self.on_packet_open_failed()
+    }
+
+    // keys for `level` are ready.  returns the packets that were waiting on them, to be processed again
+    pub fn on_keys_installed(&mut self, level: EncryptionLevel) -> Vec<Vec<u8>> {
+        self.installed_keys[level as usize] = true;
+        self.pending_packets.take(level)
+    }
+
+    // keys for `level` are gone, e.g. 0-rtt was rejected or the handshake is done with Initial /
+    // Handshake.  whatever was queued or buffered for that level goes with them
+    pub fn on_keys_discarded(&mut self, level: EncryptionLevel) {
+        self.installed_keys[level as usize] = false;
+        self.discarded_keys[level as usize] = true;
+        self.pending_packets.discard(level);
+        self.send_queue.discard(level);
+    }
+
+    pub fn on_key_update(&mut self) {
+        self.key_usage.on_key_update();
+        self.key_update_required = false;
+    }
+
+    // a 1-RTT packet opened, see `KeyPhases::open`.  when the peer updated its keys with it ours
+    // follow, the next packet we send has the new key phase too (RFC 9001 section 6.2)
+    pub fn on_key_phase_opened(&mut self, generation: KeyGeneration) {
+        if generation == KeyGeneration::Next {
+            self.stats.key_updates_received += 1;
+            self.on_key_update();
+        }
+    }
+
+    // a 1-RTT packet didn't open.  a failed trial counts against the integrity limit like any
+    // other packet that doesn't authenticate, going back to old keys closes the connection
+    pub fn on_key_phase_error(&mut self, err: &KeyPhaseError) -> QuicheResult<()> {
+        match err {
+            KeyPhaseError::Open { trial, .. } => {
+                if *trial {
+                    self.stats.key_phase_trial_failures += 1;
+                }
+                self.stats.undecryptable_packets += 1;
+                self.on_packet_open_failed()
+            }
+            KeyPhaseError::Rollback => self.fail(ProtocolError::KeyUpdateError),
+        }
+    }
+
+    // the name the client is connecting to.  it goes in the ClientHello's SNI and is what the
+    // server's certificate gets checked against, so it has to be set before `open`
+    pub fn set_server_name(&mut self, server_name: &str) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::set_server_name: the handshake has already started",
+        )?;
+        validate_server_name(server_name)?;
+        self.server_name = Some(server_name.to_ascii_lowercase());
+        Ok(())
+    }
+
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    pub fn poll_event(&mut self) -> Option<ConnectionEvent> {
+        self.events.pop_front()
+    }
+
+    // why the connection ended, or is ending.  None while it's still open
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    fn record_close(&mut self, initiator: CloseInitiator, cause: CloseCause, reason: &[u8]) {
+        self.close_reason.get_or_insert_with(|| CloseReason {
+            initiator,
+            cause,
+            reason: reason.to_vec(),
+        });
+    }
+
+    // the peer's CONNECTION_CLOSE.  nothing more gets sent, the connection drains for three PTOs
+    // (RFC 9000 section 10.2.2) so anything still in flight from the peer is absorbed
+    pub fn on_connection_close(&mut self, frame: &Frame, now: Instant) -> QuicheResult<()> {
+        let Frame::ConnectionClose {
+            error_code,
+            frame_type,
+            reason_phrase,
+            ..
+        } = frame
+        else {
+            return Err(QuicheError(
+                "Connection::on_connection_close: not a CONNECTION_CLOSE".to_string(),
+            ));
+        };
+        if !self.state().can_transition_to(ConnectionState::Draining) {
+            return Ok(());
+        }
+        let error_code = error_code.to_inner();
+        let cause = match frame_type {
+            // 0 is what's sent when the triggering frame isn't known
+            Some(frame_type) => CloseCause::Transport {
+                error_code,
+                frame_type: Some(*frame_type).filter(|&ty| ty != 0),
+            },
+            None => CloseCause::Application { error_code },
+        };
+        self.record_close(CloseInitiator::Peer, cause, reason_phrase);
+        self.transition(ConnectionState::Draining)?;
+        self.timers.stop(Timer::Idle);
+        self.timers.set(Timer::Draining, now + 3 * self.pto());
+        Ok(())
+    }
+
+    // drops / delays / duplicates / corrupts datagrams in both directions, for resilience tests
+    pub fn set_test_hooks(&mut self, hooks: TestHooks) {
+        self.faults = Some(FaultInjector::new(hooks));
+    }
+
+    // called with every outgoing datagram before it's written to the socket, one it forwards isn't
+    pub fn set_pre_send_hook(&mut self, hook: Option<Arc<PreSendHook>>) {
+        self.pre_send = hook;
+    }
+
+    pub fn set_pcap_writer(&mut self, pcap: PcapWriter<BufWriter<File>>) {
+        self.pcap = Some(pcap);
+    }
+
+    // max datagrams read / written per syscall
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.io.set_batch_size(batch_size);
+    }
+
+    pub fn io_stats(&self) -> IoStats {
+        IoStats {
+            datagrams_dropped: self.recv_queue.dropped(),
+            ..self.io.stats()
+        }
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            io: self.io_stats(),
+            pending_packets_dropped: self.pending_packets.dropped(),
+            oversized_packets_dropped: self.send_queue.dropped(),
+            ..self.stats.clone()
+        }
+    }
+
+    // called with each DATA_BLOCKED / STREAM_DATA_BLOCKED / STREAMS_BLOCKED the peer sends
+    pub fn set_blocked_callback(
+        &mut self,
+        callback: impl Fn(&BlockedEvent) + Send + Sync + 'static,
+    ) {
+        self.on_blocked = Some(Box::new(callback));
+    }
+
+    // records `frame` if it's one of the blocked frames, returns whether it was.
+    // they don't change any state, they're only a hint that our limits are too tight
+    pub fn on_blocked_frame(&mut self, frame: &Frame, now: Instant) -> bool {
+        let Some(blocked) = Blocked::from_frame(frame) else {
+            return false;
+        };
+        let event = BlockedEvent { at: now, blocked };
+        self.stats.record_blocked(event);
+        if let Some(callback) = self.on_blocked.as_ref() {
+            callback(&event);
+        }
+        true
+    }
+
+    pub fn acks(&self, space: PacketSpace) -> &AckTracker {
+        &self.acks[space as usize]
+    }
+
+    // the most ranges a generated ACK frame carries, in every packet number space
+    pub fn set_max_ack_ranges(&mut self, max_ranges: usize) {
+        for acks in self.acks.iter_mut() {
+            acks.set_max_ranges(max_ranges);
+        }
+    }
+
+    // returns false for a packet number we've already seen in `space`
+    pub fn on_packet_received(&mut self, space: PacketSpace, packet_number: u64) -> bool {
+        // only the peer could have removed Handshake protection, so its address is good
+        // (RFC 9000 section 8.1)
+        if space == PacketSpace::Handshake {
+            self.amplification.validate();
+        }
+        self.acks[space as usize].on_packet_received(packet_number)
+    }
+
+    // the peer acknowledged our packet `packet_number`, if it carried an ACK we stop repeating
+    // what that ACK covered, and any CRYPTO data in it is done with.  acknowledging a packet
+    // number we skipped, one that was never sent, fails the connection with PROTOCOL_VIOLATION
+    pub fn on_packet_acked(&mut self, space: PacketSpace, packet_number: u64) -> QuicheResult<()> {
+        if self.packet_numbers.is_skipped(packet_number) {
+            return self.fail(ProtocolError::ProtocolViolation);
+        }
+        self.acks[space as usize].on_packet_acked(packet_number);
+        self.crypto[space as usize].on_packet_acked(packet_number);
+        let largest_acked = &mut self.largest_acked[space as usize];
+        *largest_acked = Some(largest_acked.map_or(packet_number, |l| l.max(packet_number)));
+        if space == PacketSpace::ApplicationData {
+            self.control.on_packet_acked(packet_number);
+        }
+        Ok(())
+    }
+
+    // our packet `packet_number` was declared lost at `now`, its CRYPTO data and control frames
+    // go out again on the next flush.  only an ack-eliciting one counts as congestion
+    pub fn on_packet_lost(&mut self, space: PacketSpace, packet_number: u64, now: Instant) {
+        self.crypto[space as usize].on_packet_lost(packet_number);
+        if space == PacketSpace::ApplicationData {
+            self.control.on_packet_lost(packet_number);
+        }
+        if let Some(packet) = self.delivery.on_lost(packet_number) {
+            if self
+                .congestion
+                .on_lost(packet.size, packet.ack_eliciting, packet.sent_at, now)
+            {
+                self.on_congestion_event(CongestionCause::Loss, now);
+            }
+        }
+    }
+
+    // the ECN-CE count from the peer's latest ACK_ECN frame, received at `now`
+    pub fn on_ecn_ce(&mut self, ecn_ce_count: u64, now: Instant) {
+        if self.congestion.on_ecn_ce(ecn_ce_count, now) {
+            self.on_congestion_event(CongestionCause::EcnCe, now);
+        }
+    }
+
+    // called with every loss episode and ECN-CE mark that cuts the congestion window
+    pub fn set_congestion_callback(
+        &mut self,
+        callback: impl Fn(&CongestionEvent) + Send + Sync + 'static,
+    ) {
+        self.on_congestion = Some(Box::new(callback));
+    }
+
+    fn on_congestion_event(&mut self, cause: CongestionCause, now: Instant) {
+        let event = CongestionEvent {
+            at: now,
+            cause,
+            cwnd: self.congestion.cwnd(),
+            delivery_rate: self.delivery.rate(),
+        };
+        if let Some(callback) = self.on_congestion.as_ref() {
+            callback(&event);
+        }
+    }
+
+    pub fn congestion(&self) -> &NewReno {
+        &self.congestion
+    }
+
+    // `packet_number` went out in a datagram at `now`, taking `size` bytes of it.  only an ACK
+    // for an `ack_eliciting` packet is a trustworthy rtt sample, the peer may hold on to the
+    // ACK for anything else for as long as it likes
+    pub fn on_packet_sent(
+        &mut self,
+        packet_number: u64,
+        size: usize,
+        ack_eliciting: bool,
+        now: Instant,
+    ) {
+        self.delivery
+            .on_sent(packet_number, size, ack_eliciting, now);
+        self.congestion.on_sent(size, ack_eliciting);
+    }
+
+    // an ACK frame in `space` that acknowledged `acked`, received at `now`.  each packet is
+    // handled as by `on_packet_acked`, and it's an rtt sample if the largest of them is newly
+    // acknowledged and at least one newly acknowledged packet was ack-eliciting.  the peer's
+    // `ack_delay` only counts in the application data space, during the handshake it can't be
+    // trusted yet, and never for more than its max_ack_delay, so a peer can't talk our rtt
+    // estimate down by claiming it sat on ACKs (RFC 9002 section 5.3)
+    pub fn on_ack_received(
+        &mut self,
+        space: PacketSpace,
+        acked: &[u64],
+        ack_delay: Duration,
+        now: Instant,
+    ) -> QuicheResult<()> {
+        let largest = acked.iter().max().copied();
+        let mut largest_sent_at = None;
+        let mut ack_eliciting = false;
+        for &packet_number in acked {
+            self.on_packet_acked(space, packet_number)?;
+            let min_rtt = self.rtt.min_rtt();
+            if let Some(packet) = self.delivery.on_acked(packet_number, now, min_rtt) {
+                self.congestion
+                    .on_acked(packet.size, packet.ack_eliciting, packet.sent_at);
+                ack_eliciting |= packet.ack_eliciting;
+                if Some(packet.packet_number) == largest {
+                    largest_sent_at = Some(packet.sent_at);
+                }
+            }
+        }
+        if let (Some(sent_at), true) = (largest_sent_at, ack_eliciting) {
+            let ack_delay = match space {
+                PacketSpace::ApplicationData => ack_delay.min(self.peer_max_ack_delay),
+                _ => Duration::ZERO,
+            };
+            self.rtt
+                .update(now.saturating_duration_since(sent_at), ack_delay);
+        }
+        Ok(())
+    }
+
+    // the smoothed rtt, or the configured initial rtt until there's been a sample
+    pub fn rtt(&self) -> Duration {
+        self.rtt.smoothed_rtt()
+    }
+
+    // the lowest rtt seen, the closest to the path's propagation delay
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.rtt.min_rtt()
+    }
+
+    pub fn rtt_estimate(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    // the bandwidth the path is delivering at, in bytes per second, from the most recent ACK
+    pub fn delivery_rate(&self) -> Option<u64> {
+        self.delivery.rate()
+    }
+
+    // handshake data for the peer, e.g. the TLS ServerHello in Initial and the certificate chain in
+    // Handshake.  it's split into packets on `flush`, as the amplification limit allows
+    pub fn send_crypto(&mut self, space: PacketSpace, data: &[u8]) {
+        self.crypto[space as usize].write(data);
+    }
+
+    pub fn crypto(&self, space: PacketSpace) -> &CryptoSendBuffer {
+        &self.crypto[space as usize]
+    }
+
+    // how far out of order the peer's CRYPTO data may arrive in each space, see `CryptoRecvBuffer`
+    pub fn set_max_crypto_buffer(&mut self, max_buffered: usize) {
+        for buffer in self.crypto_recv.iter_mut() {
+            buffer.set_max_buffered(max_buffered);
+        }
+    }
+
+    // a CRYPTO frame from the peer in `space`.  more out of order data than we hold for it closes
+    // the connection with CRYPTO_BUFFER_EXCEEDED
+    pub fn on_crypto_frame(
+        &mut self,
+        space: PacketSpace,
+        offset: u64,
+        data: &[u8],
+    ) -> QuicheResult<()> {
+        match self.crypto_recv[space as usize].write(offset, data) {
+            Ok(()) => Ok(()),
+            Err(_) => self.fail(ProtocolError::CryptoBufferExceeded),
+        }
+    }
+
+    // the peer's CRYPTO data in `space` that's now in order, for TLS
+    pub fn read_crypto(&mut self, space: PacketSpace) -> Vec<u8> {
+        self.crypto_recv[space as usize].read()
+    }
+
+    // for a server taking on a connection from an address it hasn't validated: until the client
+    // sends a Handshake packet, no more than three times what it's sent us goes back
+    pub fn limit_amplification(&mut self) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::limit_amplification: connection already started",
+        )?;
+        self.amplification = AmplificationLimit::unvalidated();
+        Ok(())
+    }
+
+    pub fn amplification(&self) -> &AmplificationLimit {
+        &self.amplification
+    }
+
+    pub fn send_window(&self) -> &SendWindow {
+        self.flow_control.send_window()
+    }
+
+    pub fn flow_control(&self) -> &FlowControl {
+        &self.flow_control
+    }
+
+    // the receive windows we advertise, see `EndpointConfig::flow_control`.  they go out in the
+    // transport parameters, so it has to be before the connection starts
+    pub fn set_flow_control(&mut self, config: FlowControlConfig) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::set_flow_control: connection already started",
+        )?;
+        let send = self.flow_control.send_window().clone();
+        self.flow_control =
+            FlowControl::restore(config, RecvWindow::new(config.connection), send, 0);
+        Ok(())
+    }
+
+    // MAX_DATA, returns whether it raised the limit
+    pub fn on_max_data(&mut self, max_data: u64) -> bool {
+        self.flow_control.on_max_data(max_data)
+    }
+
+    // `len` new bytes of STREAM data went out, on any stream
+    pub fn on_stream_data_sent(&mut self, len: u64) -> QuicheResult<()> {
+        self.flow_control.on_sent(len)
+    }
+
+    // how many bytes could go out right now: the least of what the congestion window, the peer's
+    // MAX_DATA (or the remembered limit, in 0-rtt) and the amplification limit leave.  lets a
+    // writer size its batches up front instead of finding out from a short write.  the congestion
+    // and amplification budgets count whole datagrams, headers included, so this is an upper
+    // bound on the stream data that fits
+    pub fn send_capacity(&self) -> usize {
+        let credit = match &self.zero_rtt {
+            Some(zero_rtt) => zero_rtt.available(),
+            None => self.send_window().available(),
+        };
+        self.congestion
+            .available()
+            .min(credit.try_into().unwrap_or(usize::MAX))
+            .min(self.amplification.budget())
+    }
+
+    // how many received datagrams can wait for processing before new ones are shed
+    pub fn set_recv_queue_capacity(&mut self, capacity: usize) {
+        self.recv_queue.set_capacity(capacity, &mut self.pool);
+    }
+
+    pub fn configure_socket(&self, config: &SocketConfig) -> QuicheResult<()> {
+        Ok(config.apply(&self.socket)?)
+    }
+
+    pub(crate) fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    pub(crate) fn rebound(&self) -> Arc<Notify> {
+        self.rebound.clone()
+    }
+
+    pub async fn open(&mut self) -> QuicheResult<()> {
+        self.transition(ConnectionState::Handshaking)?;
+        let now = Instant::now();
+        self.timers.set(Timer::Idle, now + IDLE_TIMEOUT);
+        let handshake_deadline = now + self.handshake_timeout;
+        self.timers.set(Timer::Handshake, handshake_deadline);
+        // the first dst_cid a client uses is unpredictable, and gets replaced by the server's src_cid
+        self.dst_cid = ConnectionId::random();
+        self.original_dst_cid = self.dst_cid.clone();
+        self.send_client_hello(None).await?;
+
+        let mut response = self.recv_handshake(handshake_deadline).await?;
+        // a server that wants our address validated answers with a Retry first (RFC 9000 section
+        // 8.1.2).  the ClientHello goes again, with the Retry's token, to the cid it came from
+        if let Ok(Packet {
+            header: Header::Retry(retry),
+            ..
+        }) = Packet::decode(&mut response.clone())
+        {
+            if let LongHeaderExtension::Retry { retry_token, .. } = retry.extension() {
+                // TODO: check the integrity tag once there's AES-GCM to check it with
+                if !retry_token.is_empty() && self.on_retry(retry.src_cid().clone()) {
+                    self.pool.recycle(response);
+                    self.send_client_hello(Some(retry_token.clone())).await?;
+                    response = self.recv_handshake(handshake_deadline).await?;
+                }
+            }
+        }
+
+        let _server_hello = Packet::decode(&mut response)?;
+        self.pool.recycle(response);
+
+        Ok(())
+    }
+
+    async fn send_client_hello(&mut self, token: Option<Vec<u8>>) -> QuicheResult<()> {
+        // TODO: crypto_data should be the TLS ClientHello, with `server_name` as its SNI
+        let client_hello = Packet::create_client_hello(
+            self.dst_cid.clone(),
+            self.src_cid(),
+            token,
+            Frame::Crypto {
+                offset: VarInt::zero(),
+                crypto_length: VarInt::zero(),
+                crypto_data: SmallBytes::new(),
+            },
+            PacketNumber(VarInt(self.next_packet_number())),
+        );
+        let mut client_hello_bytes = self.pool.take();
+        client_hello.encode_into(&mut client_hello_bytes)?;
+        self.transmit(vec![client_hello_bytes]).await
+    }
+
+    // the next datagram from the server, failing the handshake if it doesn't come by `deadline`
+    async fn recv_handshake(&mut self, deadline: Instant) -> QuicheResult<Vec<u8>> {
+        let mut writer = self.pool.take_zeroed();
+        let recv = self
+            .io
+            .recv(&self.socket, std::slice::from_mut(&mut writer));
+        let Ok(result) = tokio::time::timeout_at(deadline.into(), recv).await else {
+            self.pool.recycle(writer);
+            // always an error
+            return self.fail_handshake().map(|_| Vec::new());
+        };
+        result?;
+        self.capture(false, &writer)?;
+        Ok(writer)
+    }
+
+    // the peer's NEW_CONNECTION_ID, the cid is kept for migrating to a new path.  any cids it
+    // retires get a RETIRE_CONNECTION_ID queued, and if that includes the one in use we move off it.
+    // one that breaks the rules (see `PeerCids::on_new_connection_id`) closes the connection
+    pub fn on_new_connection_id(
+        &mut self,
+        sequence_number: u64,
+        retire_prior_to: u64,
+        cid: ConnectionId,
+        reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
+    ) -> QuicheResult<()> {
+        match self.peer_cids.on_new_connection_id(
+            sequence_number,
+            retire_prior_to,
+            cid,
+            reset_token,
+        ) {
+            Ok(Some(next)) => self.dst_cid = next,
+            Ok(None) => {}
+            Err(error) => return self.fail(error),
+        }
+        let retirements = self.peer_cids.take_retirements();
+        if !retirements.is_empty() {
+            let packet = self.short_header_packet(retirements);
+            self.queue_packet(packet);
+        }
+        Ok(())
+    }
+
+    // stable for the life of the connection, whatever cids it moves through.  include it in your
+    // own logs to line them up with ours
+    pub fn trace_id(&self) -> TraceId {
+        TraceId::from_cid(&self.original_dst_cid)
+    }
+
+    // on a server, the dst_cid of the client's first Initial, which the trace id is derived from.
+    // a client picks its own in `open`
+    pub fn set_original_dst_cid(&mut self, cid: ConnectionId) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::set_original_dst_cid: connection already started",
+        )?;
+        self.original_dst_cid = cid;
+        Ok(())
+    }
+
+    // set by `Endpoint::insert_incoming` for a connection it accepted.  a server puts the cids
+    // from the client's Initial in its transport parameters instead of checking them
+    pub fn set_side(&mut self, side: Side) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::set_side: connection already started",
+        )?;
+        self.side = side;
+        Ok(())
+    }
+
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    // on a server that sent a Retry, the src_cid it carried, which is what the client's Initial
+    // with the token went to
+    pub fn set_retry_src_cid(&mut self, cid: ConnectionId) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Idle,
+            "Connection::set_retry_src_cid: connection already started",
+        )?;
+        self.retry_src_cid = Some(cid);
+        Ok(())
+    }
+
+    // a client got a Retry from `src_cid`, and sends to it from here on.  only the first one
+    // counts, and one that didn't pick a new cid isn't a Retry at all (RFC 9000 section
+    // 17.2.5.2).  returns whether it was taken
+    pub fn on_retry(&mut self, src_cid: ConnectionId) -> bool {
+        if self.side != Side::Client || self.retry_src_cid.is_some() || src_cid == self.dst_cid {
+            return false;
+        }
+        self.dst_cid = src_cid.clone();
+        self.retry_src_cid = Some(src_cid);
+        true
+    }
+
+    pub fn local_cids(&self) -> &LocalCids {
+        &self.local_cids
+    }
+
+    // the src_cid for long headers: the one from the handshake, or a throwaway once it's retired
+    fn src_cid(&self) -> ConnectionId {
+        self.local_cids
+            .initial()
+            .cloned()
+            .unwrap_or_else(ConnectionId::random)
+    }
+
+    // how our cids are minted from here on.  before the connection is opened the handshake cid is
+    // minted again with it too, since nothing has been sent on the old one
+    pub fn set_cid_codec(&mut self, codec: Arc<dyn CidCodec>) {
+        if self.state() == ConnectionState::Idle {
+            self.local_cids = LocalCids::new(codec.generate());
+        }
+        self.cid_codec = codec;
+    }
+
+    pub fn set_stateless_reset_key(&mut self, key: Option<StatelessResetKey>) {
+        self.reset_key = key;
+    }
+
+    // a new cid for the peer to send to, queued as NEW_CONNECTION_ID
+    pub fn issue_cid(&mut self) -> ConnectionId {
+        let cid = self.cid_codec.generate();
+        let token = match self.reset_key.as_ref() {
+            Some(key) => key.token(&cid.cid),
+            None => secure_bytes(),
+        };
+        if let Frame::NewConnectionId {
+            sequence_number, ..
+        } = self.local_cids.issue(cid.clone(), 0, token)
+        {
+            self.control
+                .push(ControlFrame::NewConnectionId(sequence_number.0));
+        }
+        cid
+    }
+
+    // how many NEW_TOKEN frames go out once the handshake is done, and the key their tokens are
+    // minted with.  servers only
+    pub fn set_new_tokens(&mut self, key: TokenKey, count: usize) {
+        self.new_token_key = Some(key);
+        self.new_tokens = count;
+    }
+
+    // a token the peer can present in a later connection's Initial to skip address validation,
+    // queued as NEW_TOKEN.  servers only
+    pub fn send_new_token(&mut self, token: Vec<u8>) {
+        self.control.push(ControlFrame::NewToken(token));
+    }
+
+    // tells the client the handshake is confirmed.  servers only, once the handshake is complete
+    pub fn send_handshake_done(&mut self) {
+        self.control.push(ControlFrame::HandshakeDone);
+    }
+
+    // the peer's RETIRE_CONNECTION_ID, which came in a packet sent to `packet_dst_cid`.
+    // returns the retired cid so it can stop being routed here
+    pub fn on_retire_connection_id(
+        &mut self,
+        sequence_number: u64,
+        packet_dst_cid: &ConnectionId,
+    ) -> QuicheResult<Option<ConnectionId>> {
+        self.local_cids
+            .on_retire_connection_id(sequence_number, packet_dst_cid)
+    }
+
+    // what's been dropped since the last call, by `DropReason`.  the endpoint collects these in `reap`
+    pub fn take_drops(&mut self) -> [u64; DropReason::ALL.len()] {
+        std::mem::take(&mut self.drops)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // only probing frames (PATH_CHALLENGE / PATH_RESPONSE / NEW_CONNECTION_ID / PADDING) may go
+    // out on a path that hasn't been validated yet
+    pub fn can_send_non_probing(&self) -> bool {
+        self.path.is_validated()
+    }
+
+    // moves the connection onto `socket`, e.g. after the client's local address changed.
+    // the new path gets a fresh cid so it can't be linked to the old one, and is probed with a
+    // PATH_CHALLENGE; non-probing traffic waits until the peer's PATH_RESPONSE comes back
+    pub async fn rebind(&mut self, socket: UdpSocket) -> QuicheResult<()> {
+        require(
+            !self.peer_cids.spare().is_empty(),
+            "Connection::rebind: no unused connection id to migrate with",
+        )?;
+        socket.connect(self.peer_addr).await?;
+        let (path, challenge) = Path::probe(socket.local_addr()?, self.peer_addr);
+        // only spent once the new socket is good, a failed rebind leaves it for the next try
+        let dst_cid = self.peer_cids.take_spare().unwrap();
+
+        self.io.probe_gso(&socket);
+        self.socket = Arc::new(socket);
+        self.rebound.notify_waiters();
+        self.path = path;
+        self.dst_cid = dst_cid;
+        // three PTOs (RFC 9000 section 8.2.4)
+        self.timers
+            .set(Timer::PathValidation, Instant::now() + 3 * self.pto());
+
+        let mut probe = self.short_header_packet(vec![challenge]);
+        let len = probe.encode()?.len();
+        probe.payload.extend(std::iter::repeat_n(
+            Frame::Padding,
+            MIN_PROBE_DATAGRAM_SIZE.saturating_sub(len),
+        ));
+
+        let mut probe_bytes = self.pool.take();
+        probe.encode_into(&mut probe_bytes)?;
+        self.transmit(vec![probe_bytes]).await
+    }
+
+    // returns whether `data` validated the current path
+    pub fn on_path_response(&mut self, data: [u8; 8]) -> bool {
+        let validated = self.path.on_path_response(data);
+        if validated {
+            self.timers.stop(Timer::PathValidation);
+        }
+        validated
+    }
+
+    // captures the connection so another process can carry on with it through `thaw`.
+    // queued and in-flight packets aren't part of it, loss recovery on both ends covers them.
+    // refused mid-migration, since an outstanding PATH_CHALLENGE can't be answered elsewhere
+    #[cfg(feature = "dangerous-snapshot")]
+    pub fn freeze(&self) -> QuicheResult<ConnectionSnapshot> {
+        require(
+            self.path.is_validated(),
+            "Connection::freeze: path validation in progress",
+        )?;
+        Ok(ConnectionSnapshot {
+            state: self.state(),
+            peer_addr: self.peer_addr,
+            side: self.side,
+            original_dst_cid: self.original_dst_cid.clone(),
+            dst_cid: self.dst_cid.clone(),
+            dst_cid_sequence: self.peer_cids.active(),
+            retire_prior_to: self.peer_cids.retire_prior_to(),
+            spare_dst_cids: self.peer_cids.spare(),
+            local_cids: self.local_cids.issued(),
+            local_retire_prior_to: self.local_cids.retire_prior_to(),
+            next_local_sequence: self.local_cids.next_sequence_number(),
+            next_packet_number: self.packet_numbers.peek(),
+            idle_remaining: self
+                .timers
+                .get(Timer::Idle)
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            installed_keys: self.installed_keys,
+            discarded_keys: self.discarded_keys,
+            peer_transport_parameters: self.peer_transport_parameters.clone(),
+            max_data: self.send_window().max_data(),
+            data_sent: self.send_window().sent(),
+            recv_max_data: self.flow_control.recv_window().max_data(),
+            data_received: self.flow_control.recv_window().received(),
+        })
+    }
+
+    // resumes a frozen connection on `socket`, which gets connected to the snapshot's peer.  what
+    // the endpoint decides for every connection (the cid codec, the reset key, ...) isn't in the
+    // snapshot, `Endpoint::insert` applies it again
+    #[cfg(feature = "dangerous-snapshot")]
+    pub async fn thaw(snapshot: ConnectionSnapshot, socket: UdpSocket) -> QuicheResult<Self> {
+        socket.connect(snapshot.peer_addr).await?;
+        let mut io = BatchIo::default();
+        io.probe_gso(&socket);
+        let path = Path::validated(socket.local_addr()?, snapshot.peer_addr);
+
+        let mut timers = Timers::new();
+        if let Some(remaining) = snapshot.idle_remaining {
+            timers.set(Timer::Idle, Instant::now() + remaining);
+        }
+        let peer_params = snapshot
+            .peer_transport_parameters
+            .clone()
+            .unwrap_or_default();
+
+        Ok(Self {
+            state: watch::Sender::new(snapshot.state),
+            recv_queue: RecvQueue::default(),
+            send_queue: SendQueue::new(),
+            socket: Arc::new(socket),
+            rebound: Arc::new(Notify::new()),
+            peer_addr: snapshot.peer_addr,
+            kill: None,
+            side: snapshot.side,
+            pcap: None,
+            pool: BufferPool::new(DEFAULT_MAX_UDP_PAYLOAD_SIZE + 1, DEFAULT_POOL_CAPACITY),
+            io,
+            path,
+            original_dst_cid: snapshot.original_dst_cid,
+            // only needed during the handshake, which is over by the time a connection is frozen
+            retry_src_cid: None,
+            dst_cid: snapshot.dst_cid,
+            peer_cids: PeerCids::restore(
+                snapshot.dst_cid_sequence,
+                snapshot.retire_prior_to,
+                snapshot.spare_dst_cids,
+            ),
+            local_cids: LocalCids::restore(
+                snapshot.local_cids,
+                snapshot.local_retire_prior_to,
+                snapshot.next_local_sequence,
+            ),
+            cid_codec: Arc::new(RandomCidCodec),
+            reset_key: None,
+            packet_numbers: PacketNumbers::new(snapshot.next_packet_number),
+            largest_acked: [None; PacketSpace::ALL.len()],
+            recovery: RecoveryConfig::default(),
+            rtt: RttEstimator::new(RecoveryConfig::default().initial_rtt),
+            delivery: DeliveryRateEstimator::new(),
+            congestion: NewReno::new(MIN_UDP_PAYLOAD_SIZE as usize),
+            acks: Default::default(),
+            timers,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            // TODO: the cipher should come from the negotiated TLS cipher suite
+            key_usage: KeyUsage::default(),
+            key_update_required: false,
+            installed_keys: snapshot.installed_keys,
+            discarded_keys: snapshot.discarded_keys,
+            pending_packets: PendingPackets::default(),
+            crypto: Default::default(),
+            crypto_recv: Default::default(),
+            control: PendingControlFrames::new(),
+            new_token_key: None,
+            new_tokens: 0,
+            amplification: AmplificationLimit::validated(),
+            // the windows' sizes are the endpoint's to set, only where they'd got to is kept
+            flow_control: FlowControl::restore(
+                FlowControlConfig::default(),
+                RecvWindow::restore(
+                    FlowControlConfig::default().connection,
+                    snapshot.recv_max_data,
+                    snapshot.data_received,
+                ),
+                SendWindow::restore(snapshot.max_data, snapshot.data_sent),
+                peer_params.initial_max_stream_data_bidi_remote,
+            ),
+            max_udp_payload_size: DEFAULT_MAX_UDP_PAYLOAD_SIZE,
+            udp_payload_size: MIN_UDP_PAYLOAD_SIZE as usize,
+            peer_max_udp_payload_size: peer_params.max_udp_payload_size as usize,
+            peer_max_ack_delay: Duration::from_millis(peer_params.max_ack_delay),
+            peer_transport_parameters: snapshot.peer_transport_parameters,
+            zero_rtt: None,
+            server_name: None,
+            events: VecDeque::new(),
+            close_reason: None,
+            going_away: None,
+            faults: None,
+            pre_send: None,
+            unwritten: VecDeque::new(),
+            stats: ConnectionStats::default(),
+            drops: [0; DropReason::ALL.len()],
+            on_blocked: None,
+            on_congestion: None,
+        })
+    }
+
+    // replaces the loss detection constants.  the rtt estimate starts over from the new initial
+    // rtt if there's no sample yet
+    pub fn set_recovery_config(&mut self, recovery: RecoveryConfig) -> QuicheResult<()> {
+        recovery.validate()?;
+        if !self.rtt.has_sample() {
+            self.rtt = RttEstimator::new(recovery.initial_rtt);
+        }
+        self.recovery = recovery;
+        Ok(())
+    }
+
+    pub fn recovery_config(&self) -> &RecoveryConfig {
+        &self.recovery
+    }
+
+    // how often a packet number is skipped, see `PacketNumbers`.  0, never, by default
+    pub fn set_packet_number_skip_probability(
+        &mut self,
+        skip_probability: f64,
+    ) -> QuicheResult<()> {
+        self.packet_numbers.set_skip_probability(skip_probability)
+    }
+
+    // the probe timeout, without the peer's max_ack_delay.  until there's an rtt sample this is
+    // about three times the initial rtt, a second with the RFC defaults
+    fn pto(&self) -> Duration {
+        self.recovery.pto(&self.rtt)
+    }
+
+    // when the driver should next call `on_timeout`
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.timers.next_timeout()
+    }
+
+    // handles the earliest timer that's expired by `now`, returns which one it was.
+    // call it until it returns None
+    pub fn on_timeout(&mut self, now: Instant) -> QuicheResult<Option<Timer>> {
+        let Some(timer) = self.timers.pop_expired(now) else {
+            return Ok(None);
+        };
+        match timer {
+            // the idle timeout closes the connection silently (RFC 9000 section 10.1)
+            Timer::Idle if !self.state().is_terminal() => {
+                self.record_close(CloseInitiator::Local, CloseCause::IdleTimeout, &[]);
+                self.transition(ConnectionState::Closed)?;
+            }
+            Timer::Handshake if self.state() == ConnectionState::Handshaking => {
+                // surfaced through the state and `poll_event`, the timer firing isn't an error in itself
+                let _ = self.fail_handshake();
+            }
+            Timer::Draining
+                if matches!(
+                    self.state(),
+                    ConnectionState::Closing | ConnectionState::Draining
+                ) =>
+            {
+                self.transition(ConnectionState::Closed)?;
+            }
+            Timer::GoAway if self.state() == ConnectionState::Connected => {
+                if let Some(app_error_code) = self.going_away {
+                    self.queue_close(app_error_code, &[], now)?;
+                    self.events
+                        .push_back(ConnectionEvent::GoneAway { timed_out: true });
+                }
+            }
+            // the peer never answered the PATH_CHALLENGE.  there's no old path kept around to
+            // fall back to, so the best that can be done is to stop waiting
+            Timer::PathValidation => {}
+            // TODO: loss detection, PTO, ack delay and pacing have nothing to drive yet
+            _ => {}
+        }
+        Ok(Some(timer))
+    }
+
+    // the handshake ran out of time.  nothing is sent, as far as we know the peer never heard of
+    // us, so like the idle timeout it just closes
+    fn fail_handshake(&mut self) -> QuicheResult<()> {
+        metrics::handshake(false);
+        self.timers.stop(Timer::Handshake);
+        self.timers.stop(Timer::Idle);
+        self.record_close(CloseInitiator::Local, CloseCause::HandshakeTimeout, &[]);
+        self.transition(ConnectionState::Closed)?;
+        self.events.push_back(ConnectionEvent::HandshakeTimedOut {
+            after: self.handshake_timeout,
+        });
+        Err(QuicheError(format!(
+            "Connection::open [{}]: handshake timed out after {:?}",
+            self.trace_id(),
+            self.handshake_timeout
+        )))
+    }
+
+    // closes the connection over a transport error.  CONNECTION_CLOSE is queued for the next
+    // `flush`, but there's no closing period, the connection is done as far as we're concerned
+    fn fail(&mut self, error: ProtocolError) -> QuicheResult<()> {
+        self.fail_with_reason(error, &[])
+    }
+
+    fn fail_with_reason(&mut self, error: ProtocolError, reason: &[u8]) -> QuicheResult<()> {
+        let code = error.code();
+        let reason = &reason[..reason.len().min(MAX_REASON_PHRASE_LEN)];
+        self.record_close(
+            CloseInitiator::Local,
+            CloseCause::Transport {
+                error_code: code,
+                frame_type: None,
+            },
+            reason,
+        );
+        if self.state() == ConnectionState::Connected {
+            let close = Frame::ConnectionClose {
+                error_code: VarInt::new_u64(code)?,
+                frame_type: Some(0),
+                reason_phrase_length: VarInt::new_u32(reason.len() as u32),
+                reason_phrase: SmallBytes::from_slice(reason),
+            };
+            let packet = self.short_header_packet(vec![close]);
+            self.queue_packet(packet);
+        }
+        for timer in Timer::ALL {
+            self.timers.stop(timer);
+        }
+        self.transition(ConnectionState::Failed(code))?;
+        Err(error.into())
+    }
+
+    fn next_packet_number(&mut self) -> u64 {
+        self.packet_numbers.allocate()
+    }
 
-        Ok(Self {
-            state: ConnectionState::Closed,
-            recv_buf: Vec::new(),
-            send_buf: Vec::new(),
-            socket,
-            peer_addr,
-            kill: None,
-        })
+    // a 1-rtt packet to the current dst_cid
+    fn short_header_packet(&mut self, payload: Vec<Frame>) -> Packet {
+        let packet_number = self.next_packet_number();
+        self.numbered_packet(PacketSpace::ApplicationData, packet_number, payload)
     }
 
-    pub async fn open(&mut self) -> QuicheResult<()> {
-        self.state = ConnectionState::Handshake;
-        let client_hello = Packet::create_client_hello(todo!(), todo!(), todo!(), todo!());
-        self.socket.send(client_hello.encode()?.as_slice()).await?;
+    // a packet in `space` to the current dst_cid, for when the packet number is already taken
+    fn numbered_packet(
+        &self,
+        space: PacketSpace,
+        packet_number: u64,
+        payload: Vec<Frame>,
+    ) -> Packet {
+        let packet_number_len =
+            PnLen::for_packet(packet_number, self.largest_acked[space as usize]);
+        let type_specific_bits = packet_number_len.long_header_bits();
+        let truncated = packet_number_len.truncate(packet_number);
+        // long headers carry the same low bytes, as a number
+        let packet_number_field = PacketNumber(VarInt(
+            truncated
+                .iter()
+                .fold(0, |number, byte| number << 8 | *byte as u64),
+        ));
+        let mut packet = match space {
+            PacketSpace::Initial => Packet::initial(
+                MINI_QUICHE_VERSION,
+                self.dst_cid.clone(),
+                self.src_cid(),
+                type_specific_bits,
+                VarInt::zero(),
+                Vec::new(),
+                VarInt::zero(),
+                packet_number_field,
+                payload,
+            ),
+            PacketSpace::Handshake => Packet::long_header(
+                LongPacketType::handshake(),
+                type_specific_bits,
+                MINI_QUICHE_VERSION,
+                self.dst_cid.clone(),
+                self.src_cid(),
+                LongHeaderExtension::Handshake {
+                    length: VarInt::zero(),
+                    packet_number: packet_number_field,
+                },
+                payload,
+            ),
+            PacketSpace::ApplicationData => Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                packet_number_len,
+                self.dst_cid.clone(),
+                truncated,
+                payload,
+            ),
+        };
+        packet.update_length();
+        packet
+    }
+
+    // the next packet of CRYPTO data in `space`, at most `max_size` bytes encoded
+    fn crypto_packet(
+        &mut self,
+        space: PacketSpace,
+        max_size: usize,
+    ) -> QuicheResult<Option<Packet>> {
+        if !self.crypto[space as usize].has_pending() {
+            return Ok(None);
+        }
+        let packet_number = self.packet_numbers.peek();
+        let mut packet = self.numbered_packet(space, packet_number, Vec::new());
+        // the Length field of an empty packet is 1 byte, and can grow to 4
+        let overhead = packet.encode()?.len() + 3;
+        let Some(frame) = max_size
+            .checked_sub(overhead)
+            .and_then(|room| self.crypto[space as usize].next_frame(packet_number, room))
+        else {
+            return Ok(None);
+        };
+        self.next_packet_number();
+        packet.payload.push(frame);
+        packet.update_length();
+        Ok(Some(packet))
+    }
 
-        let mut writer: Vec<u8> = vec![0; 1_024];
-        let bytes_recv = self.socket.recv(writer.as_mut_slice()).await?;
-        writer.truncate(bytes_recv);
+    // the frame for `control` as things stand now, None if it no longer needs sending
+    fn control_frame(&self, control: &ControlFrame) -> Option<Frame> {
+        match control {
+            ControlFrame::NewToken(token) => Some(Frame::NewToken {
+                token_length: VarInt(token.len() as u64),
+                token: SmallBytes::from_slice(token),
+            }),
+            ControlFrame::NewConnectionId(sequence_number) => {
+                self.local_cids.new_connection_id(*sequence_number)
+            }
+            ControlFrame::HandshakeDone => Some(Frame::HandshakeDone),
+        }
+    }
 
-        let server_hello = Packet::decode(&mut writer)?;
+    // puts pending control frames in a 1-rtt packet, as many as fit in a datagram.  the rest wait
+    // for the next flush
+    fn queue_control(&mut self) -> QuicheResult<()> {
+        if !self.control.has_pending() {
+            return Ok(());
+        }
+        let packet_number = self.packet_numbers.peek();
+        let mut packet =
+            self.numbered_packet(PacketSpace::ApplicationData, packet_number, Vec::new());
+        let mut room = self
+            .max_datagram_size()
+            .saturating_sub(packet.encode()?.len());
+        let mut sent = Vec::new();
+        while let Some(control) = self.control.peek() {
+            let Some(frame) = self.control_frame(control) else {
+                self.control.pop();
+                continue;
+            };
+            let size = frame.encode().len();
+            if size > room {
+                break;
+            }
+            room -= size;
+            packet.payload.push(frame);
+            sent.extend(self.control.pop());
+        }
+        if packet.payload.is_empty() {
+            return Ok(());
+        }
+        self.next_packet_number();
+        for control in sent {
+            self.control.on_sent(packet_number, control);
+        }
+        packet.update_length();
+        self.queue_packet(packet);
+        Ok(())
+    }
 
+    // cuts pending CRYPTO data into packets, no more than `budget` bytes of them.  every packet is
+    // as big as a datagram may be, so a long certificate chain takes as few datagrams as possible
+    // and never relies on ip fragmentation.  whatever's left waits for more budget, which for a
+    // server is usually the client's ACKs raising the amplification limit
+    fn queue_crypto(&mut self, mut budget: usize) -> QuicheResult<()> {
+        for space in PacketSpace::ALL {
+            while let Some(packet) =
+                self.crypto_packet(space, self.max_datagram_size().min(budget))?
+            {
+                budget -= packet.encode()?.len();
+                self.queue_packet(packet);
+            }
+        }
         Ok(())
     }
 
+    #[allow(clippy::never_loop)]
     pub async fn _f(&mut self) -> QuicheResult<()> {
         let (unsub_tx, mut unsub_rx) = tokio::sync::mpsc::channel::<()>(1);
         self.kill = Some(unsub_tx);
-        self.state = ConnectionState::Connected;
+        self.transition(ConnectionState::Connected)?;
+        self.timers.stop(Timer::Handshake);
+        metrics::handshake(true);
 
         tokio::spawn({
             async move {
@@ -76,30 +1650,384 @@ impl Connection {
         Ok(())
     }
 
-    pub async fn close(&mut self) -> QuicheResult<()> {
-        match self.state {
+    // closes the connection on the application's behalf with a CONNECTION_CLOSE (0x1d) carrying
+    // `app_error_code` and `reason`.  the connection then sits in the closing state for
+    // three PTOs (RFC 9000 section 10.2) before it's closed for good, see `on_timeout`
+    pub async fn close(&mut self, app_error_code: u64, reason: &[u8]) -> QuicheResult<()> {
+        if self.queue_close(app_error_code, reason, Instant::now())? {
+            self.flush(usize::MAX).await?;
+        }
+        Ok(())
+    }
+
+    // `close` without the flush, the CONNECTION_CLOSE goes out with whatever's sent next.
+    // returns whether one was queued, not when there's nobody to tell or it's already closing
+    fn queue_close(
+        &mut self,
+        app_error_code: u64,
+        reason: &[u8],
+        now: Instant,
+    ) -> QuicheResult<bool> {
+        if !self.state().is_terminal()
+            && !matches!(
+                self.state(),
+                ConnectionState::Closing | ConnectionState::Draining
+            )
+        {
+            let reason = &reason[..reason.len().min(MAX_REASON_PHRASE_LEN)];
+            self.record_close(
+                CloseInitiator::Local,
+                CloseCause::Application {
+                    error_code: app_error_code,
+                },
+                reason,
+            );
+        }
+        let packets = match self.state() {
+            // nothing has been sent, so there's nobody to tell
+            ConnectionState::Idle => {
+                self.transition(ConnectionState::Closed)?;
+                return Ok(false);
+            }
+            // the application's code and reason could leak application state before the handshake
+            // has authenticated the peer, so it goes out as a transport close with
+            // APPLICATION_ERROR instead (RFC 9000 section 10.2.3).  with Handshake keys the peer
+            // might not have yet, it goes in both an Initial and a Handshake packet, coalesced into
+            // one datagram.  once Initial keys are gone, only the latter
+            ConnectionState::Handshaking => {
+                let close = Frame::ConnectionClose {
+                    error_code: VarInt::new_u64(ProtocolError::ApplicationError.code())?,
+                    frame_type: Some(0),
+                    reason_phrase_length: VarInt::zero(),
+                    reason_phrase: SmallBytes::new(),
+                };
+                let handshake = self.installed_keys[EncryptionLevel::Handshake as usize];
+                let initial = !handshake || !self.discarded_keys[EncryptionLevel::Initial as usize];
+                let mut packets = Vec::new();
+                for (space, send) in [
+                    (PacketSpace::Initial, initial),
+                    (PacketSpace::Handshake, handshake),
+                ] {
+                    if send {
+                        let packet_number = self.next_packet_number();
+                        packets.push(self.numbered_packet(
+                            space,
+                            packet_number,
+                            vec![close.clone()],
+                        ));
+                    }
+                }
+                packets
+            }
             ConnectionState::Connected => {
-                self.state = ConnectionState::Closing;
-                self.kill.take().unwrap().send(()).await?;
-                self.state = ConnectionState::Closed;
-                Ok(())
+                let reason_phrase =
+                    SmallBytes::from_slice(&reason[..reason.len().min(MAX_REASON_PHRASE_LEN)]);
+                let close = Frame::ConnectionClose {
+                    error_code: VarInt::new_u64(app_error_code)?,
+                    frame_type: None,
+                    reason_phrase_length: VarInt::new_u32(reason_phrase.len() as u32),
+                    reason_phrase,
+                };
+                vec![self.short_header_packet(vec![close])]
             }
-            ConnectionState::Handshake => {
-                // special kill here...
-                unimplemented!()
+            // already closing, or closed
+            _ => return Ok(false),
+        };
+
+        self.transition(ConnectionState::Closing)?;
+        // a thawed connection has no driver task to stop, and one that's already stopped is fine
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.try_send(());
+        }
+        self.timers.stop(Timer::Idle);
+        self.timers.stop(Timer::GoAway);
+        self.timers.set(Timer::Draining, now + 3 * self.pto());
+
+        for packet in packets {
+            self.queue_packet(packet);
+        }
+        Ok(true)
+    }
+
+    // a graceful close in two steps.  from here on `can_open_stream` is false and the peer is told
+    // as `notice` says, then the connection closes with `app_error_code` once the application
+    // calls `finish_go_away` or, at the latest, after `grace`.  progress comes out of
+    // `poll_event` as `GoingAway` then `GoneAway`.  calling it again changes nothing
+    pub fn go_away(
+        &mut self,
+        notice: GoAwayNotice,
+        grace: Duration,
+        app_error_code: u64,
+        now: Instant,
+    ) -> QuicheResult<()> {
+        require(
+            self.state() == ConnectionState::Connected,
+            "Connection::go_away: connection isn't connected",
+        )?;
+        if self.going_away.is_some() {
+            return Ok(());
+        }
+        if let GoAwayNotice::ControlStream { stream, message } = notice {
+            let mut stream = stream.lock();
+            // all of it or none, half a notice is worse than none
+            require(
+                stream.writable() >= message.len(),
+                "Connection::go_away: no room on the control stream for the notice",
+            )?;
+            stream.write(&message)?;
+            stream.finish();
+        }
+        let deadline = now + grace;
+        self.going_away = Some(app_error_code);
+        self.timers.set(Timer::GoAway, deadline);
+        self.events
+            .push_back(ConnectionEvent::GoingAway { deadline });
+        Ok(())
+    }
+
+    pub fn is_going_away(&self) -> bool {
+        self.going_away.is_some()
+    }
+
+    // whether the application should open another stream, false once it's going away
+    pub fn can_open_stream(&self) -> bool {
+        self.state() == ConnectionState::Connected && self.going_away.is_none()
+    }
+
+    // the application is done with its streams, the go away closes the connection now instead of
+    // waiting for the deadline
+    pub async fn finish_go_away(&mut self) -> QuicheResult<()> {
+        let Some(app_error_code) = self.going_away else {
+            return Err(QuicheError(
+                "Connection::finish_go_away: not going away".to_string(),
+            ));
+        };
+        if self.queue_close(app_error_code, &[], Instant::now())? {
+            self.events
+                .push_back(ConnectionEvent::GoneAway { timed_out: false });
+            self.flush(usize::MAX).await?;
+        }
+        Ok(())
+    }
+
+    fn capture(&mut self, sent: bool, datagram: &[u8]) -> QuicheResult<()> {
+        if let Some(pcap) = self.pcap.as_mut() {
+            let local_addr = self.socket.local_addr()?;
+            match sent {
+                true => pcap.record(local_addr, self.peer_addr, datagram)?,
+                false => pcap.record(self.peer_addr, local_addr, datagram)?,
             }
-            _ => Ok(()),
+            pcap.flush()?;
         }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    async fn recv(&mut self) -> QuicheResult<()> {
-        unimplemented!()
+    // reads whatever datagrams are ready (waiting for at least one) into the receive queue.
+    // returns how many were queued, not counting any shed because the queue was full
+    pub async fn recv(&mut self) -> QuicheResult<usize> {
+        let mut bufs = (0..self.io.batch_size())
+            .map(|_| self.pool.take_zeroed())
+            .collect::<Vec<_>>();
+        let metas = self.io.recv(&self.socket, &mut bufs).await?;
+        for buf in bufs.drain(metas.len()..) {
+            self.pool.recycle(buf);
+        }
+        metrics::datagrams_received(
+            bufs.iter()
+                .zip(metas.iter())
+                .map(|(buf, meta)| segments(buf, meta.segment_size).count())
+                .sum(),
+            bufs.iter().map(Vec::len).sum(),
+        );
+        // bigger than we said we'd accept (RFC 9000 section 18.2)
+        let bufs = bufs
+            .into_iter()
+            .zip(metas)
+            .filter_map(|(buf, meta)| {
+                if meta.segment_size <= self.max_udp_payload_size {
+                    return Some(buf);
+                }
+                self.stats.oversized_datagrams += 1;
+                metrics::datagrams_dropped(1);
+                self.pool.recycle(buf);
+                None
+            })
+            .collect::<Vec<_>>();
+        let bufs = match self.faults.as_mut() {
+            Some(faults) => faults.apply(Direction::Incoming, bufs),
+            None => bufs,
+        };
+        self.amplification
+            .on_received(bufs.iter().map(Vec::len).sum());
+
+        let mut queued = 0;
+        let mut shed = 0;
+        for buf in bufs {
+            self.capture(false, &buf)?;
+            if self.recv_queue.push(buf, &mut self.pool) {
+                queued += 1;
+            } else {
+                shed += 1;
+            }
+        }
+        metrics::datagrams_dropped(shed);
+        // receiving anything from the peer restarts the idle timer
+        if self.timers.is_armed(Timer::Idle) {
+            self.timers.set(Timer::Idle, Instant::now() + IDLE_TIMEOUT);
+        }
+        Ok(queued)
+    }
+
+    // the oldest received datagram, hand it back with `recycle` once it's been processed
+    pub fn next_datagram(&mut self) -> Option<Vec<u8>> {
+        self.recv_queue.pop()
+    }
+
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        self.pool.recycle(buf);
+    }
+
+    pub fn queue_packet(&mut self, packet: Packet) {
+        self.send_queue.push(packet);
+    }
+
+    // sends queued packets, coalesced into as few datagrams as possible, without going over `budget` bytes.
+    // returns how many bytes went out
+    pub async fn flush(&mut self, budget: usize) -> QuicheResult<usize> {
+        let limited = self.amplification.budget() < budget;
+        let budget = budget.min(self.amplification.budget());
+        self.queue_crypto(budget)?;
+        self.queue_control()?;
+        let datagrams = self.send_queue.drain(self.max_datagram_size(), budget)?;
+        let sent = datagrams.iter().map(Vec::len).sum();
+        self.transmit(datagrams).await?;
+        // an answer the amplification limit held back counts as a drop of what asked for it
+        if limited && (!self.send_queue.is_empty() || self.crypto.iter().any(|c| c.has_pending())) {
+            self.drops[DropReason::AmplificationLimited as usize] += 1;
+        }
+        Ok(sent)
+    }
+
+    // the send fast path for 1-RTT packets: writes the oldest one queued into `out`, sealed, and
+    // returns its length for the caller's own socket call.  0 if there isn't one, or if faults or
+    // the pre-send hook took it.  it goes through the same bookkeeping as `flush` and is counted
+    // as sent here, and a packet that can't be written (too big for `out`, the datagram size or
+    // what the amplification limit leaves) stays queued
+    pub fn write_datagram(&mut self, out: &mut [u8]) -> QuicheResult<usize> {
+        if let Some(datagram) = self.unwritten.front() {
+            let len = datagram.len();
+            if len > out.len() {
+                return Err(QuicheError(format!(
+                    "Connection::write_datagram: {} byte datagram doesn't fit in {} bytes",
+                    len,
+                    out.len()
+                )));
+            }
+            out[..len].copy_from_slice(datagram);
+            if let Some(datagram) = self.unwritten.pop_front() {
+                self.pool.recycle(datagram);
+            }
+            return Ok(len);
+        }
+        let Some(packet) = self.send_queue.pop(EncryptionLevel::OneRtt) else {
+            return Ok(0);
+        };
+        let limit = out
+            .len()
+            .min(self.max_datagram_size())
+            .min(self.amplification.budget());
+        let written = match &packet.header {
+            Header::Short(header) => PnLen::from_len(header.packet_number_len()).and_then(|len| {
+                let packet_number = len.expand(
+                    header.packet_number(),
+                    self.largest_acked[PacketSpace::ApplicationData as usize],
+                );
+                write_short_packet(header, &packet.payload, &PlaintextSealer, &mut out[..limit])
+                    .map(|written| (packet_number, written))
+            }),
+            _ => Err(QuicheError(
+                "Connection::write_datagram: 1-RTT packet without a short header".to_string(),
+            )),
+        };
+        let (packet_number, len) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                self.send_queue.push_front(packet);
+                return Err(e);
+            }
+        };
+        let ack_eliciting = packet.payload.iter().any(Frame::is_ack_eliciting);
+        self.on_packet_sent(packet_number, len, ack_eliciting, Instant::now());
+
+        let mut datagram = self.pool.take();
+        datagram.extend_from_slice(&out[..len]);
+        let (datagrams, forwarded) = self.before_send(vec![datagram]);
+        self.after_send(&datagrams)?;
+        self.after_send(&forwarded)?;
+        forwarded
+            .into_iter()
+            .for_each(|datagram| self.pool.recycle(datagram));
+        let mut datagrams = datagrams.into_iter();
+        let len = match datagrams.next() {
+            Some(datagram) => {
+                // a corrupted copy is the same length, so it still fits
+                out[..datagram.len()].copy_from_slice(&datagram);
+                let len = datagram.len();
+                self.pool.recycle(datagram);
+                len
+            }
+            None => 0,
+        };
+        self.unwritten.extend(datagrams);
+        Ok(len)
+    }
+
+    // writes `datagrams` to the socket (through any injected faults), then hands the buffers back to the pool
+    async fn transmit(&mut self, datagrams: Vec<Vec<u8>>) -> QuicheResult<()> {
+        let (datagrams, forwarded) = self.before_send(datagrams);
+        // whatever `write_datagram` left behind was already counted, it just goes out first
+        let unwritten = self.unwritten.drain(..).collect::<Vec<_>>();
+        let sent = unwritten.len();
+        let mut datagrams = unwritten.into_iter().chain(datagrams).collect::<Vec<_>>();
+        self.io.send(&self.socket, &datagrams).await?;
+        datagrams.extend(forwarded);
+        self.after_send(&datagrams[sent..])?;
+        for datagram in datagrams {
+            self.pool.recycle(datagram);
+        }
+        Ok(())
+    }
+
+    // runs outgoing datagrams through any injected faults, then the pre-send hook.  returns the ones
+    // left to write, and the ones the hook forwarded: those went out some other way, but were still sent
+    fn before_send(&mut self, datagrams: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let datagrams = match self.faults.as_mut() {
+            Some(faults) => faults.apply(Direction::Outgoing, datagrams),
+            None => datagrams,
+        };
+        match self.pre_send.as_ref() {
+            Some(hook) => datagrams
+                .into_iter()
+                .partition(|datagram| hook(datagram, self.peer_addr) == Forwarding::Continue),
+            None => (datagrams, Vec::new()),
+        }
+    }
+
+    // what every datagram that went out counts towards, however it was written
+    fn after_send(&mut self, datagrams: &[Vec<u8>]) -> QuicheResult<()> {
+        let bytes = datagrams.iter().map(Vec::len).sum();
+        self.amplification.on_sent(bytes);
+        metrics::datagrams_sent(datagrams.len(), bytes);
+        for datagram in datagrams {
+            self.capture(true, datagram)?;
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
     async fn send(&mut self) -> QuicheResult<()> {
-        unimplemented!()
+        self.flush(self.congestion.available()).await?;
+        Ok(())
     }
 
     #[allow(dead_code)]
@@ -113,9 +2041,1163 @@ impl Connection {
     }
 }
 
+// a dns name (RFC 1035 section 2.3.1, without the trailing dot) or an ip address literal.
+// the latter can't go in SNI but certificates can still be issued for them
+fn validate_server_name(server_name: &str) -> QuicheResult<()> {
+    if server_name.parse::<std::net::IpAddr>().is_ok() {
+        return Ok(());
+    }
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+    };
+    if server_name.len() > 253 || !server_name.split('.').all(valid_label) {
+        return Err(QuicheError(format!(
+            "Connection::set_server_name: {:?} isn't a valid server name",
+            server_name
+        )));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::connection::Fault;
+    use crate::macros::FrameType;
+    use crate::packet::transport_parameters::ParameterViolation;
+    use crate::stream::{SendStream, SharedSendStream};
+
+    #[tokio::test]
+    async fn test_rtt_and_delivery_rate() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(conn.rtt(), RecoveryConfig::default().initial_rtt);
+        assert_eq!((conn.min_rtt(), conn.delivery_rate()), (None, None));
+
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        for packet_number in 0..4 {
+            conn.on_packet_sent(packet_number, 1_000, true, ms(packet_number));
+        }
+        // the handshake ACK's delay is ignored
+        conn.on_ack_received(
+            PacketSpace::Handshake,
+            &[0, 1],
+            Duration::from_millis(10),
+            ms(41),
+        )
+        .unwrap();
+        assert_eq!(conn.min_rtt(), Some(Duration::from_millis(40)));
+        assert_eq!(conn.rtt(), Duration::from_millis(40));
+        assert!(conn.delivery_rate().is_some());
+
+        // no sample when the largest was acked already
+        conn.on_ack_received(PacketSpace::ApplicationData, &[1], Duration::ZERO, ms(90))
+            .unwrap();
+        assert_eq!(conn.rtt_estimate().latest_rtt(), Duration::from_millis(40));
+        // 60ms, 20ms of which the peer held the ACK for
+        conn.on_ack_received(
+            PacketSpace::ApplicationData,
+            &[2, 3],
+            Duration::from_millis(20),
+            ms(63),
+        )
+        .unwrap();
+        assert_eq!(conn.rtt_estimate().latest_rtt(), Duration::from_millis(60));
+        assert_eq!(conn.rtt(), Duration::from_millis(40));
+        assert_eq!(conn.min_rtt(), Some(Duration::from_millis(40)));
+    }
+
+    #[tokio::test]
+    async fn test_rtt_sample_filtering() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let ack = |conn: &mut Connection, packet_number, ack_delay, at| {
+            conn.on_ack_received(
+                PacketSpace::ApplicationData,
+                &[packet_number],
+                Duration::from_millis(ack_delay),
+                ms(at),
+            )
+            .unwrap()
+        };
+        conn.on_packet_sent(0, 1_000, true, ms(0));
+        ack(&mut conn, 0, 0, 40);
+        assert_eq!(conn.rtt(), Duration::from_millis(40));
+
+        // an ACK of nothing but an ACK isn't a sample, however late it comes
+        conn.on_packet_sent(1, 50, false, ms(50));
+        ack(&mut conn, 1, 0, 150);
+        assert_eq!(conn.rtt_estimate().latest_rtt(), Duration::from_millis(40));
+
+        // 100ms, of which the peer claims 60 was it holding the ACK.  only its max_ack_delay, 25ms
+        // by default, is believed
+        conn.on_packet_sent(2, 1_000, true, ms(200));
+        ack(&mut conn, 2, 60, 300);
+        assert_eq!(conn.rtt(), Duration::from_micros(44_375));
+    }
+
+    #[tokio::test]
+    async fn test_congestion_callback() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        conn.set_congestion_callback({
+            let events = events.clone();
+            move |event| events.lock().unwrap().push(*event)
+        });
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let initial_cwnd = conn.congestion().cwnd();
+
+        for packet_number in 0..4 {
+            conn.on_packet_sent(packet_number, 1_000, true, ms(0));
+        }
+        conn.on_ack_received(PacketSpace::ApplicationData, &[0], Duration::ZERO, ms(40))
+            .unwrap();
+        // two losses from the same flight are one episode
+        conn.on_packet_lost(PacketSpace::ApplicationData, 1, ms(50));
+        conn.on_packet_lost(PacketSpace::ApplicationData, 2, ms(50));
+        conn.on_packet_sent(4, 1_000, true, ms(60));
+        conn.on_ack_received(PacketSpace::ApplicationData, &[4], Duration::ZERO, ms(100))
+            .unwrap();
+        conn.on_ecn_ce(3, ms(100));
+        conn.on_ecn_ce(3, ms(110));
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            events.iter().map(|event| event.cause).collect::<Vec<_>>(),
+            vec![CongestionCause::Loss, CongestionCause::EcnCe]
+        );
+        assert_eq!(events[0].cwnd, (initial_cwnd + 1_000) / 2);
+        assert_eq!(events[1].cwnd, conn.congestion().cwnd());
+        assert!(events[1].delivery_rate.is_some());
+        assert_eq!(conn.congestion().bytes_in_flight(), 1_000);
+
+        // an ACK-only packet takes no room in the window and its loss starts no episode
+        let cwnd = conn.congestion().cwnd();
+        conn.on_packet_sent(5, 50, false, ms(500));
+        assert_eq!(conn.congestion().bytes_in_flight(), 1_000);
+        conn.on_packet_lost(PacketSpace::ApplicationData, 5, ms(600));
+        assert_eq!(conn.congestion().bytes_in_flight(), 1_000);
+        assert_eq!(conn.congestion().cwnd(), cwnd);
+    }
+
+    #[tokio::test]
+    async fn test_optimistic_ack() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(conn.set_packet_number_skip_probability(-0.1).is_err());
+        conn.set_packet_number_skip_probability(0.5).unwrap();
+
+        let sent = (0..16)
+            .map(|_| conn.next_packet_number())
+            .collect::<Vec<_>>();
+        conn.on_ack_received(PacketSpace::Initial, &sent, Duration::ZERO, Instant::now())
+            .unwrap();
+        // a peer that acks everything up to the largest, without having seen all of it
+        let largest = *sent.last().unwrap();
+        let optimistic = (0..=largest).collect::<Vec<_>>();
+        assert!(optimistic.len() > sent.len());
+        assert!(conn
+            .on_ack_received(
+                PacketSpace::Initial,
+                &optimistic,
+                Duration::ZERO,
+                Instant::now()
+            )
+            .is_err());
+        assert_eq!(
+            conn.state(),
+            ConnectionState::Failed(ProtocolError::ProtocolViolation.code())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rebind() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let old_local_addr = conn.path().local_addr;
+
+        let new_socket = || UdpSocket::bind("127.0.0.1:0");
+        // can't migrate without a cid the peer hasn't seen yet
+        assert!(conn.rebind(new_socket().await.unwrap()).await.is_err());
+
+        let cid = ConnectionId::new(8, vec![7; 8]);
+        conn.on_new_connection_id(1, 0, cid.clone(), [1; 16])
+            .unwrap();
+        // an ipv6 socket can't reach the ipv4 peer, and the cid is still there after
+        let v6 = UdpSocket::bind("[::1]:0").await.unwrap();
+        assert!(conn.rebind(v6).await.is_err());
+        assert_eq!(conn.peer_cids.spare().len(), 1);
+        conn.rebind(new_socket().await.unwrap()).await.unwrap();
+        assert_ne!(conn.path().local_addr, old_local_addr);
+        assert!(!conn.can_send_non_probing());
+        assert!(conn.next_timeout().is_some());
+
+        let mut buf = vec![0; 1_500];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, conn.path().local_addr);
+        assert!(len >= MIN_PROBE_DATAGRAM_SIZE);
+        buf.truncate(len);
+        // short header: first byte, cid length, cid
+        assert_eq!(buf[1] as usize, cid.cid.len());
+        assert_eq!(&buf[2..2 + cid.cid.len()], cid.cid.as_slice());
+
+        let probe = Packet::decode(&mut buf).unwrap();
+        let Frame::PathChallenge(challenge) = probe.payload[0] else {
+            panic!("expected a PATH_CHALLENGE, got {:?}", probe.payload[0]);
+        };
+        assert!(conn.on_path_response(challenge));
+        assert!(conn.can_send_non_probing());
+        assert_eq!(conn.next_timeout(), None);
+        assert_eq!(conn.on_timeout(Instant::now()).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_connection_id_limit() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        conn.on_new_connection_id(1, 0, ConnectionId::new(8, vec![1; 8]), [1; 16])
+            .unwrap();
+        // a third active cid is over the limit we advertised
+        assert!(conn
+            .on_new_connection_id(2, 0, ConnectionId::new(8, vec![2; 8]), [2; 16])
+            .is_err());
+        let code = ProtocolError::ConnectionIdLimitError.code();
+        assert_eq!(conn.state(), ConnectionState::Failed(code));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_frames() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        conn.set_blocked_callback({
+            let seen = seen.clone();
+            move |event| seen.lock().unwrap().push(event.blocked)
+        });
+
+        let now = Instant::now();
+        assert!(!conn.on_blocked_frame(&Frame::Ping, now));
+        assert!(conn.on_blocked_frame(&Frame::DataBlocked(VarInt::new_u32(1_000)), now));
+        assert!(conn.on_blocked_frame(
+            &Frame::StreamDataBlocked {
+                stream_id: VarInt::new_u32(4),
+                stream_data_limit: VarInt::new_u32(500),
+            },
+            now
+        ));
+
+        let stats = conn.stats();
+        assert_eq!((stats.data_blocked, stats.stream_data_blocked), (1, 1));
+        assert_eq!(stats.blocked_events[0].at, now);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                Blocked::Data { limit: 1_000 },
+                Blocked::StreamData {
+                    stream_id: 4,
+                    limit: 500
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_udp_payload_size() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        peer.connect(conn.path().local_addr).await.unwrap();
+        conn.set_max_udp_payload_size(1_000, 1_300);
+        assert_eq!(conn.max_datagram_size(), 1_200);
+        assert_eq!(conn.transport_parameters().max_udp_payload_size, 1_300);
+
+        // PMTUD can raise it up to our limit, and the peer's limit caps it in turn
+        conn.on_path_mtu(9_000);
+        assert_eq!(conn.max_datagram_size(), 1_300);
+        conn.on_peer_transport_parameters(&TransportParameters {
+            max_udp_payload_size: 1_250,
+            original_destination_connection_id: Some(conn.original_dst_cid.clone()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(conn.max_datagram_size(), 1_250);
+
+        let mut packet = conn.short_header_packet(vec![Frame::Ping]);
+        packet
+            .payload
+            .extend(std::iter::repeat_n(Frame::Padding, 1_260));
+        conn.queue_packet(packet);
+        // too big for the peer, so it never goes out
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+        assert_eq!(conn.stats().oversized_packets_dropped, 1);
+
+        for len in [1_400, 1_301, 1_300] {
+            peer.send(&vec![0; len]).await.unwrap();
+            // each recv only picks up what's already arrived, so wait for all three
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut queued = 0;
+        while queued + (conn.stats().oversized_datagrams as usize) < 3 {
+            queued += conn.recv().await.unwrap();
+        }
+        assert_eq!(queued, 1);
+        assert_eq!(conn.stats().oversized_datagrams, 2);
+        assert_eq!(conn.next_datagram().unwrap().len(), 1_300);
+    }
+
+    #[tokio::test]
+    async fn test_retry_cids() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let original = ConnectionId::new(8, vec![1; 8]);
+        let retry = ConnectionId::new(8, vec![2; 8]);
+
+        // the server hands back what the client's Initials went to
+        let mut server = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        server.set_side(Side::Server).unwrap();
+        server.set_original_dst_cid(original.clone()).unwrap();
+        server.set_retry_src_cid(retry.clone()).unwrap();
+        let params = server.transport_parameters();
+        assert_eq!(
+            params.original_destination_connection_id,
+            Some(original.clone())
+        );
+        assert_eq!(params.retry_source_connection_id, Some(retry.clone()));
+
+        let client = || async {
+            let mut client = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+                .await
+                .unwrap();
+            client.set_original_dst_cid(original.clone()).unwrap();
+            client.dst_cid = original.clone();
+            client.transition(ConnectionState::Handshaking).unwrap();
+            client
+        };
+        assert_eq!(
+            client()
+                .await
+                .transport_parameters()
+                .original_destination_connection_id,
+            None
+        );
+
+        // only the first Retry counts, and not one that kept the same cid
+        let mut conn = client().await;
+        assert!(!conn.on_retry(original.clone()));
+        assert!(conn.on_retry(retry.clone()));
+        assert!(!conn.on_retry(ConnectionId::new(8, vec![3; 8])));
+        assert_eq!(conn.dst_cid, retry);
+        conn.on_peer_transport_parameters(&params).unwrap();
+
+        // a Retry the server never sent, or one it sent that we never saw
+        let mut conn = client().await;
+        assert!(conn.on_peer_transport_parameters(&params).is_err());
+        assert_eq!(
+            conn.state(),
+            ConnectionState::Failed(ProtocolError::TransportParameterError.code())
+        );
+        assert_eq!(
+            conn.close_reason().unwrap().reason_str(),
+            "mismatched retry_source_connection_id (0x10)"
+        );
+        assert_eq!(
+            conn.stats().transport_parameter_error.unwrap().violation,
+            ParameterViolation::Mismatch
+        );
+        let mut conn = client().await;
+        conn.on_retry(ConnectionId::new(8, vec![3; 8]));
+        assert!(conn.on_peer_transport_parameters(&params).is_err());
+
+        // a server that doesn't say which cid it saw
+        let mut conn = client().await;
+        conn.on_retry(retry.clone());
+        let missing = TransportParameters {
+            original_destination_connection_id: None,
+            ..params.clone()
+        };
+        assert!(conn.on_peer_transport_parameters(&missing).is_err());
+
+        // and a client sending what only a server may
+        let mut server = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        server.set_side(Side::Server).unwrap();
+        server.transition(ConnectionState::Handshaking).unwrap();
+        let encoded = params.encode().unwrap();
+        assert!(server
+            .on_encoded_peer_transport_parameters(&encoded)
+            .is_err());
+        assert_eq!(
+            server.close_reason().unwrap().reason_str(),
+            "client sent original_destination_connection_id (0x0)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_capacity() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.set_side(Side::Server).unwrap();
+        conn.limit_amplification().unwrap();
+        let cwnd = conn.congestion().available();
+
+        // nothing before the peer's transport parameters
+        assert_eq!(conn.send_capacity(), 0);
+        let params = TransportParameters {
+            initial_max_data: 100_000,
+            ..Default::default()
+        };
+        conn.on_peer_transport_parameters(&params).unwrap();
+
+        // three times what's been received
+        conn.amplification.on_received(1_200);
+        assert_eq!(conn.send_capacity(), 3_600);
+
+        // then the congestion window
+        conn.on_packet_received(PacketSpace::Handshake, 0);
+        assert_eq!(conn.send_capacity(), cwnd);
+
+        // then MAX_DATA
+        conn.on_stream_data_sent(99_000).unwrap();
+        assert_eq!(conn.send_capacity(), 1_000.min(cwnd));
+        conn.on_stream_data_sent(1_000).unwrap();
+        assert_eq!(conn.send_capacity(), 0);
+        assert!(conn.on_stream_data_sent(1).is_err());
+        assert!(conn.on_max_data(200_000));
+        assert_eq!(conn.send_capacity(), cwnd);
+    }
+
+    #[tokio::test]
+    async fn test_zero_rtt_limits() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let now = Instant::now();
+        let server_params = TransportParameters {
+            initial_max_data: 1_000,
+            initial_max_stream_data_bidi_remote: 1_000,
+            initial_max_streams_bidi: 1,
+            original_destination_connection_id: Some(ConnectionId::new(8, vec![1; 8])),
+            ..Default::default()
+        };
+
+        // the first connection hands out a ticket, kept with the server's parameters
+        let mut first = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        first
+            .set_original_dst_cid(ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+        assert!(first
+            .session_ticket(vec![7], Duration::from_secs(60), now)
+            .is_none());
+        first.on_peer_transport_parameters(&server_params).unwrap();
+        let ticket = first
+            .session_ticket(vec![7], Duration::from_secs(60), now)
+            .unwrap();
+
+        // without it there's no 0-rtt at all, with it the remembered limits apply
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        assert!(conn.on_zero_rtt_stream_data(0, 1).is_err());
+        conn.resume(&ticket).unwrap();
+        conn.on_zero_rtt_stream_data(0, 1_000).unwrap();
+        assert!(conn.on_zero_rtt_stream_data(0, 1_001).is_err());
+        assert!(conn.on_zero_rtt_stream_data(4, 1).is_err());
+        conn.on_zero_rtt_rejected();
+        assert!(conn.zero_rtt().is_none());
+
+        // a server whose limits went down since the ticket turns 0-rtt away
+        let mut server = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        server.set_side(Side::Server).unwrap();
+        server
+            .set_flow_control(FlowControlConfig {
+                max_streams_bidi: 0,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(server.resume(&ticket).is_err());
+        assert!(!server.accept_zero_rtt(&ticket.params));
+        assert!(server.accept_zero_rtt(&TransportParameters::default().remembered()));
+    }
+
+    #[tokio::test]
+    async fn test_write_datagram() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut out = vec![0; 1_500];
+        assert_eq!(conn.write_datagram(&mut out).unwrap(), 0);
+
+        let packet = conn.short_header_packet(vec![Frame::Ping, Frame::Padding]);
+        let encoded = packet.encode().unwrap();
+        conn.queue_packet(packet);
+        // doesn't fit, so it's still there for the next try
+        assert!(conn.write_datagram(&mut out[..4]).is_err());
+        let len = conn.write_datagram(&mut out).unwrap();
+        assert_eq!(out[..len], encoded);
+        assert_eq!(conn.write_datagram(&mut out).unwrap(), 0);
+        // counted like anything `flush` sends
+        assert_eq!(conn.congestion().bytes_in_flight(), len);
+
+        // faults apply, and a duplicate comes out of the next call
+        conn.set_test_hooks(TestHooks::new(|_, _, _| Fault::Duplicate));
+        let packet = conn.short_header_packet(vec![Frame::Ping]);
+        let encoded = packet.encode().unwrap();
+        conn.queue_packet(packet);
+        let len = conn.write_datagram(&mut out).unwrap();
+        assert_eq!(out[..len], encoded);
+        out.fill(0);
+        let len = conn.write_datagram(&mut out).unwrap();
+        assert_eq!(out[..len], encoded);
+        assert_eq!(conn.write_datagram(&mut out).unwrap(), 0);
+
+        // and so does the pre-send hook, a forwarded packet isn't for the caller to write
+        conn.set_test_hooks(TestHooks::new(|_, _, _| Fault::Pass));
+        conn.set_pre_send_hook(Some(Arc::new(|_, _| Forwarding::Forwarded)));
+        let packet = conn.short_header_packet(vec![Frame::Ping]);
+        conn.queue_packet(packet);
+        let in_flight = conn.congestion().bytes_in_flight();
+        assert_eq!(conn.write_datagram(&mut out).unwrap(), 0);
+        assert!(conn.congestion().bytes_in_flight() > in_flight);
+        assert_eq!(conn.send_queue.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_control_frame_retransmission() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let received = || async {
+            let mut buf = vec![0; 1_500];
+            let len = peer.recv(&mut buf).await.unwrap();
+            buf.truncate(len);
+            Packet::decode(&mut buf).unwrap().payload
+        };
+
+        let first = conn.issue_cid();
+        let second = conn.issue_cid();
+        conn.send_handshake_done();
+        conn.flush(usize::MAX).await.unwrap();
+        let frames = received().await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2], Frame::HandshakeDone);
+
+        // the packet was lost, and the first cid retired in the meantime, so it isn't sent again
+        let lost = conn.packet_numbers.peek() - 1;
+        conn.on_packet_lost(PacketSpace::ApplicationData, lost, Instant::now());
+        let initial = conn.local_cids().initial().unwrap().clone();
+        assert_eq!(
+            conn.on_retire_connection_id(1, &initial).unwrap(),
+            Some(first)
+        );
+        conn.flush(usize::MAX).await.unwrap();
+        let frames = received().await;
+        assert!(matches!(
+            &frames[..],
+            [Frame::NewConnectionId { sequence_number, connection_id, .. }, Frame::HandshakeDone]
+                if sequence_number.0 == 2 && *connection_id == second
+        ));
+
+        // once acked they're done with
+        let retransmission = conn.packet_numbers.peek() - 1;
+        conn.on_packet_acked(PacketSpace::ApplicationData, retransmission)
+            .unwrap();
+        conn.on_packet_lost(PacketSpace::ApplicationData, retransmission, Instant::now());
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_new_tokens() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = peer.local_addr().unwrap();
+        let key = TokenKey::new([5; 16]);
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        conn.set_side(Side::Server).unwrap();
+        conn.set_new_tokens(key.clone(), 2);
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        conn.flush(usize::MAX).await.unwrap();
+
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        let frames = Packet::decode(&mut buf).unwrap().payload;
+        assert_eq!(frames.len(), 2);
+        for frame in frames {
+            let Frame::NewToken { token, .. } = frame else {
+                panic!("expected NEW_TOKEN, got {:?}", frame);
+            };
+            assert!(key.validate_new_token(
+                &token,
+                addr,
+                SystemTime::now(),
+                Duration::from_secs(60)
+            ));
+        }
+
+        // a client never sends them
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), addr)
+            .await
+            .unwrap();
+        conn.set_new_tokens(key, 2);
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_server_first_flight() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        peer.connect(conn.path().local_addr).await.unwrap();
+        conn.limit_amplification().unwrap();
+        // a small ServerHello, then a certificate chain that needs a few datagrams
+        conn.send_crypto(PacketSpace::Initial, &[1; 100]);
+        conn.send_crypto(PacketSpace::Handshake, &[2; 4_000]);
+        // nothing has come from the client yet, so nothing can go back
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+
+        // the client's Initial buys us three times its size
+        peer.send(&[0; 1_200]).await.unwrap();
+        conn.recv().await.unwrap();
+        let sent = conn.flush(usize::MAX).await.unwrap();
+        assert!(sent > 3_000 && sent <= 3_600);
+        let mut buf = [0; 2_000];
+        let mut received = 0;
+        while received < sent {
+            let len = peer.recv(&mut buf).await.unwrap();
+            // nothing that'd need fragmenting
+            assert!(len <= conn.max_datagram_size());
+            received += len;
+        }
+        assert!(!conn.crypto(PacketSpace::Initial).has_pending());
+        assert!(conn.crypto(PacketSpace::Handshake).has_pending());
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+
+        // a Handshake packet from the client validates its address, and the rest of the chain goes
+        conn.on_packet_received(PacketSpace::Handshake, 0);
+        assert!(conn.amplification().is_validated());
+        assert!(conn.flush(usize::MAX).await.unwrap() > 0);
+        assert!(!conn.crypto(PacketSpace::Handshake).has_pending());
+
+        // the ServerHello was lost, it goes again; once it's acked there's nothing left of it
+        conn.on_packet_lost(PacketSpace::Initial, 0, Instant::now());
+        assert!(conn.crypto(PacketSpace::Initial).has_pending());
+        conn.flush(usize::MAX).await.unwrap();
+        let retransmission = conn.packet_numbers.peek() - 1;
+        conn.on_packet_acked(PacketSpace::Initial, retransmission)
+            .unwrap();
+        assert!(conn.crypto(PacketSpace::Initial).is_complete());
+    }
+
+    #[tokio::test]
+    async fn test_key_phase() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        conn.on_packet_sealed().unwrap();
+
+        // the peer updating its keys resets ours
+        conn.on_key_phase_opened(KeyGeneration::Current);
+        assert_eq!(conn.key_usage().sealed(), 1);
+        conn.on_key_phase_opened(KeyGeneration::Next);
+        assert_eq!(conn.key_usage().sealed(), 0);
+        assert_eq!(conn.stats().key_updates_received, 1);
+
+        let trial = KeyPhaseError::Open {
+            err: QuicheError("bad tag".to_string()),
+            trial: true,
+        };
+        conn.on_key_phase_error(&trial).unwrap();
+        assert_eq!(conn.stats().key_phase_trial_failures, 1);
+        assert_eq!(conn.key_usage().open_failures(), 1);
+        assert_eq!(conn.state(), ConnectionState::Connected);
+
+        assert!(conn.on_key_phase_error(&KeyPhaseError::Rollback).is_err());
+        assert_eq!(
+            conn.state(),
+            ConnectionState::Failed(ProtocolError::KeyUpdateError.code())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_crypto_buffer_exceeded() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.set_max_crypto_buffer(1000);
+
+        conn.on_crypto_frame(PacketSpace::Handshake, 500, &[1; 500])
+            .unwrap();
+        conn.on_crypto_frame(PacketSpace::Handshake, 0, &[0; 500])
+            .unwrap();
+        assert_eq!(conn.read_crypto(PacketSpace::Handshake).len(), 1000);
+        // each space has its own buffer
+        assert!(conn.read_crypto(PacketSpace::Initial).is_empty());
+
+        assert!(conn
+            .on_crypto_frame(PacketSpace::Handshake, 1999, &[0; 2])
+            .is_err());
+        assert_eq!(
+            conn.state(),
+            ConnectionState::Failed(ProtocolError::CryptoBufferExceeded.code())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aead_limits() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+
+        let limit = conn.key_usage().cipher().confidentiality_limit();
+        for _ in 0..limit - limit / 8 {
+            conn.on_packet_sealed().unwrap();
+        }
+        let sealed = conn.key_usage().sealed();
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::KeyUpdateRequired { sealed })
+        );
+        // only once per key
+        conn.on_packet_sealed().unwrap();
+        assert_eq!(conn.poll_event(), None);
+
+        conn.on_key_update();
+        assert_eq!(conn.key_usage().sealed(), 0);
+        for _ in 0..limit {
+            conn.on_packet_sealed().unwrap();
+        }
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::KeyUpdateRequired {
+                sealed: limit - limit / 8
+            })
+        );
+        // never updated, so the keys are used up
+        assert!(conn.on_packet_sealed().is_err());
+        let code = ProtocolError::AeadLimitReached.code();
+        assert_eq!(conn.state(), ConnectionState::Failed(code));
+
+        conn.flush(usize::MAX).await.unwrap();
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        let packet = Packet::decode(&mut buf).unwrap();
+        assert!(matches!(
+            packet.payload[0],
+            Frame::ConnectionClose { error_code, frame_type: Some(_), .. } if error_code.to_inner() == code
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_undecryptable_packets() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        // handshake packets ahead of the handshake keys wait for them, up to a point
+        for i in 0..20 {
+            conn.on_undecryptable_packet(EncryptionLevel::Handshake, vec![i])
+                .unwrap();
+        }
+        // 0-rtt and 1-rtt racing the handshake get their own room
+        for i in [99, 100] {
+            conn.on_undecryptable_packet(EncryptionLevel::OneRtt, vec![i])
+                .unwrap();
+        }
+        conn.on_undecryptable_packet(EncryptionLevel::ZeroRtt, vec![50])
+            .unwrap();
+        assert_eq!(conn.stats().pending_packets_dropped, 4);
+        assert_eq!(conn.stats().undecryptable_packets, 0);
+
+        let retry = conn.on_keys_installed(EncryptionLevel::Handshake);
+        assert_eq!(retry, (0..16).map(|i| vec![i]).collect::<Vec<_>>());
+        // replayed in the order they came in
+        assert_eq!(
+            conn.on_keys_installed(EncryptionLevel::OneRtt),
+            vec![vec![99], vec![100]]
+        );
+        // rejected 0-rtt takes whatever was waiting on it along
+        conn.on_keys_discarded(EncryptionLevel::ZeroRtt);
+        // and 0-rtt arriving after that has nothing to wait for
+        conn.on_undecryptable_packet(EncryptionLevel::ZeroRtt, vec![51])
+            .unwrap();
+        assert!(conn.on_keys_installed(EncryptionLevel::ZeroRtt).is_empty());
+        assert_eq!(conn.stats().undecryptable_packets, 0);
+        assert!(conn.pending_packets.is_empty());
+
+        // with the keys in place a failure is just a failure
+        conn.on_undecryptable_packet(EncryptionLevel::Handshake, vec![0])
+            .unwrap();
+        assert_eq!(conn.stats().undecryptable_packets, 1);
+        assert_eq!(conn.key_usage().open_failures(), 1);
+
+        // once the handshake is done with its keys, a retransmission of the peer's that turns up
+        // late isn't kept around waiting for them to come back
+        conn.on_keys_discarded(EncryptionLevel::Handshake);
+        conn.on_undecryptable_packet(EncryptionLevel::Handshake, vec![1])
+            .unwrap();
+        assert!(conn.pending_packets.is_empty());
+        assert_eq!(conn.stats().undecryptable_packets, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        // a peer that never answers
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.set_handshake_timeout(Duration::from_millis(50));
+        let mut observer = conn.subscribe();
+
+        let error = conn.open().await.unwrap_err();
+        // the id comes from the dst_cid `open` picked, and it's in the error for correlating
+        let trace_id = conn.trace_id();
+        assert_eq!(trace_id, TraceId::from_cid(&conn.original_dst_cid));
+        assert!(error.0.contains(trace_id.as_str()));
+        assert!(conn
+            .set_original_dst_cid(ConnectionId::new(1, vec![1]))
+            .is_err());
+        assert_eq!(conn.state(), ConnectionState::Closed);
+        assert_eq!(
+            conn.close_reason().map(|reason| reason.cause),
+            Some(CloseCause::HandshakeTimeout)
+        );
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::HandshakeTimedOut {
+                after: Duration::from_millis(50)
+            })
+        );
+        assert_eq!(conn.poll_event(), None);
+        assert!(observer.established().await.is_err());
+        // nothing left to wait for, the idle timer doesn't fire later on top of it
+        assert_eq!(conn.next_timeout(), None);
+    }
+
+    #[tokio::test]
+    async fn test_open_after_retry() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0; 1_500];
+            let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+            let first = buf[..len].to_vec();
+            let retry = crate::endpoint::Endpoint::new()
+                .retry(from, &first)
+                .unwrap();
+            peer.send_to(&retry, from).await.unwrap();
+            // the client comes back with the token, echo it so `open` has something to read
+            let len = peer.recv(&mut buf).await.unwrap();
+            let second = buf[..len].to_vec();
+            peer.send_to(&second, from).await.unwrap();
+            (first, retry, second)
+        });
+        conn.open().await.unwrap();
+        let (first, retry, second) = server.await.unwrap();
+
+        let retry_src_cid = match Packet::decode(&mut retry.clone()).unwrap().header {
+            Header::Retry(header) => header.src_cid().clone(),
+            _ => panic!("not a retry"),
+        };
+        let first = Header::peek_initial(&first).unwrap();
+        let second = Header::peek_initial(&second).unwrap();
+        assert!(first.token.is_empty());
+        assert!(!second.token.is_empty());
+        // the second Initial goes to the cid the Retry picked, and the first stays the original
+        assert_eq!(second.dst_cid, retry_src_cid.cid.as_slice());
+        assert_eq!(first.dst_cid, conn.original_dst_cid.cid.as_slice());
+        assert_eq!(conn.retry_src_cid, Some(retry_src_cid));
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new_conn =
+            || Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap());
+        let recv = || async {
+            let mut buf = vec![0; 1_500];
+            let len = peer.recv(&mut buf).await.unwrap();
+            buf.truncate(len);
+            Packet::decode(&mut buf).unwrap()
+        };
+
+        // mid-handshake the application's code and reason are swapped out
+        let mut conn = new_conn().await.unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.close(0x42, b"bye").await.unwrap();
+        assert_eq!(conn.state(), ConnectionState::Closing);
+        let packet = recv().await;
+        assert!(matches!(packet.header, Header::Initial(_)));
+        assert_eq!(
+            packet.payload,
+            vec![Frame::ConnectionClose {
+                error_code: VarInt(0x0c),
+                frame_type: Some(0),
+                reason_phrase_length: VarInt::zero(),
+                reason_phrase: SmallBytes::new(),
+            }]
+        );
+
+        // with Handshake keys it goes in both, coalesced, and only in the Handshake packet once
+        // Initial keys are gone
+        let received = || async {
+            let mut buf = vec![0; 1_500];
+            let len = peer.recv(&mut buf).await.unwrap();
+            buf.truncate(len);
+            Packet::decode_coalesced(&mut buf)
+                .unwrap()
+                .into_iter()
+                .map(|packet| (packet.encryption_level(), packet.frames()[0].clone()))
+                .collect::<Vec<_>>()
+        };
+        let mut conn = new_conn().await.unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.on_keys_installed(EncryptionLevel::Handshake);
+        conn.close(0x42, b"bye").await.unwrap();
+        let close = packet.payload[0].clone();
+        assert_eq!(
+            received().await,
+            vec![
+                (Some(EncryptionLevel::Initial), close.clone()),
+                (Some(EncryptionLevel::Handshake), close.clone())
+            ]
+        );
+        let mut conn = new_conn().await.unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.on_keys_installed(EncryptionLevel::Handshake);
+        conn.on_keys_discarded(EncryptionLevel::Initial);
+        conn.close(0x42, b"bye").await.unwrap();
+        assert_eq!(
+            received().await,
+            vec![(Some(EncryptionLevel::Handshake), close)]
+        );
+
+        let mut conn = new_conn().await.unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        // an application code that happens to overlap a transport one still goes out as 0x1d
+        conn.close(0x05, b"done").await.unwrap();
+        let packet = recv().await;
+        assert!(packet.payload[0].ty() == FrameType::CONNECTION_CLOSE_APPLICATION);
+        assert_eq!(
+            packet.payload[0],
+            Frame::ConnectionClose {
+                error_code: VarInt(0x05),
+                frame_type: None,
+                reason_phrase_length: VarInt::new_u32(4),
+                reason_phrase: SmallBytes::from_slice(b"done"),
+            }
+        );
+
+        // closing twice does nothing, and the closing period ends in closed
+        conn.close(0x06, b"").await.unwrap();
+        let deadline = conn.next_timeout().unwrap();
+        assert_eq!(conn.on_timeout(deadline).unwrap(), Some(Timer::Draining));
+        assert_eq!(conn.state(), ConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_go_away() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new_conn = || async {
+            let mut conn =
+                Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+                    .await
+                    .unwrap();
+            conn.transition(ConnectionState::Handshaking).unwrap();
+            conn.transition(ConnectionState::Connected).unwrap();
+            conn
+        };
+        let now = Instant::now();
+        let grace = Duration::from_secs(5);
+
+        // the notice goes on the application's control stream, which is then finished
+        let mut conn = new_conn().await;
+        assert!(conn.can_open_stream());
+        let control = SharedSendStream::new(SendStream::new(1_000));
+        let notice = GoAwayNotice::ControlStream {
+            stream: control.clone(),
+            message: b"goaway".to_vec(),
+        };
+        conn.go_away(notice, grace, 0x10, now).unwrap();
+        assert!(conn.is_going_away() && !conn.can_open_stream());
+        assert_eq!(control.lock().buffered(), 6);
+        assert!(control.lock().write(b"more").is_err());
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::GoingAway {
+                deadline: now + grace
+            })
+        );
+        // a second call doesn't move the deadline
+        conn.go_away(GoAwayNotice::CloseAtDeadline, grace, 0x11, now + grace)
+            .unwrap();
+        assert_eq!(conn.poll_event(), None);
+
+        // the application finishing early closes with its code
+        conn.finish_go_away().await.unwrap();
+        assert_eq!(conn.state(), ConnectionState::Closing);
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::GoneAway { timed_out: false })
+        );
+        assert_eq!(
+            conn.close_reason().unwrap().cause,
+            CloseCause::Application { error_code: 0x10 }
+        );
+
+        // otherwise the deadline does
+        let mut conn = new_conn().await;
+        conn.go_away(GoAwayNotice::CloseAtDeadline, grace, 0x20, now)
+            .unwrap();
+        conn.poll_event();
+        assert_eq!(conn.on_timeout(now + grace).unwrap(), Some(Timer::GoAway));
+        assert_eq!(conn.state(), ConnectionState::Closing);
+        assert_eq!(
+            conn.poll_event(),
+            Some(ConnectionEvent::GoneAway { timed_out: true })
+        );
+        assert!(conn.finish_go_away().await.is_ok());
+        assert_eq!(conn.poll_event(), None);
+
+        // a control stream without room for the notice
+        let mut conn = new_conn().await;
+        let control = SharedSendStream::new(SendStream::new(2));
+        let notice = GoAwayNotice::ControlStream {
+            stream: control.clone(),
+            message: b"goaway".to_vec(),
+        };
+        assert!(conn.go_away(notice, grace, 0x30, now).is_err());
+        assert!(conn.can_open_stream());
+        assert_eq!(control.lock().buffered(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_reason() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let new_conn = || async {
+            let mut conn =
+                Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+                    .await
+                    .unwrap();
+            conn.transition(ConnectionState::Handshaking).unwrap();
+            conn.transition(ConnectionState::Connected).unwrap();
+            conn
+        };
+
+        let mut conn = new_conn().await;
+        assert_eq!(conn.close_reason(), None);
+        conn.close(0x05, b"done").await.unwrap();
+        // the first cause sticks
+        conn.close(0x06, b"").await.unwrap();
+        let reason = conn.close_reason().unwrap();
+        assert_eq!(reason.initiator, CloseInitiator::Local);
+        assert_eq!(reason.cause, CloseCause::Application { error_code: 0x05 });
+        assert_eq!(reason.reason_str(), "done");
+
+        let mut conn = new_conn().await;
+        let now = Instant::now();
+        assert!(conn.on_connection_close(&Frame::Ping, now).is_err());
+        let close = Frame::ConnectionClose {
+            error_code: VarInt(ProtocolError::FlowControlError.code()),
+            frame_type: Some(0x08),
+            reason_phrase_length: VarInt::new_u32(8),
+            reason_phrase: SmallBytes::from_slice(b"too much"),
+        };
+        conn.on_connection_close(&close, now).unwrap();
+        assert_eq!(conn.state(), ConnectionState::Draining);
+        assert_eq!(
+            conn.close_reason(),
+            Some(&CloseReason {
+                initiator: CloseInitiator::Peer,
+                cause: CloseCause::Transport {
+                    error_code: ProtocolError::FlowControlError.code(),
+                    frame_type: Some(0x08),
+                },
+                reason: b"too much".to_vec(),
+            })
+        );
+        // draining, there's nothing left to close
+        conn.close(0x01, b"").await.unwrap();
+        assert_eq!(conn.close_reason().unwrap().initiator, CloseInitiator::Peer);
+        let deadline = conn.next_timeout().unwrap();
+        assert_eq!(conn.on_timeout(deadline).unwrap(), Some(Timer::Draining));
+        assert_eq!(conn.state(), ConnectionState::Closed);
+
+        // a transport error of our own
+        let mut conn = new_conn().await;
+        conn.set_packet_number_skip_probability(0.5).unwrap();
+        let skipped = loop {
+            let packet_number = conn.next_packet_number();
+            if conn.packet_numbers.is_skipped(packet_number + 1) {
+                break packet_number + 1;
+            }
+        };
+        assert!(conn
+            .on_packet_acked(PacketSpace::ApplicationData, skipped)
+            .is_err());
+        assert_eq!(
+            conn.close_reason().unwrap().cause,
+            CloseCause::Transport {
+                error_code: ProtocolError::ProtocolViolation.code(),
+                frame_type: None,
+            }
+        );
+    }
 
     #[tokio::test]
     async fn test_handshake() {
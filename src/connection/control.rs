@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, VecDeque};
+
+// a control frame that has to reach the peer.  only what identifies it is kept, the frame itself
+// is built when it goes out, so a retransmission carries the values as they are by then (RFC 9000
+// section 13.3) rather than what was in the packet that was lost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlFrame {
+    NewToken(Vec<u8>),
+    // by sequence number, not sent again once the cid is retired
+    NewConnectionId(u64),
+    HandshakeDone,
+}
+
+// control frames waiting to be sent, and the ones in flight by packet number so a loss can put
+// them back in line.  stream data and CRYPTO data have their own retransmission, this is
+// everything else that has to arrive eventually
+#[derive(Debug, Clone, Default)]
+pub struct PendingControlFrames {
+    pending: VecDeque<ControlFrame>,
+    in_flight: BTreeMap<u64, Vec<ControlFrame>>,
+}
+
+impl PendingControlFrames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // queues `frame`, unless the same frame is queued already
+    pub fn push(&mut self, frame: ControlFrame) {
+        if !self.pending.contains(&frame) {
+            self.pending.push_back(frame);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // the next frame to send, oldest first
+    pub fn peek(&self) -> Option<&ControlFrame> {
+        self.pending.front()
+    }
+
+    pub fn pop(&mut self) -> Option<ControlFrame> {
+        self.pending.pop_front()
+    }
+
+    // `frame` went out in packet `packet_number`
+    pub fn on_sent(&mut self, packet_number: u64, frame: ControlFrame) {
+        self.in_flight.entry(packet_number).or_default().push(frame);
+    }
+
+    pub fn on_packet_acked(&mut self, packet_number: u64) {
+        self.in_flight.remove(&packet_number);
+    }
+
+    // whatever `packet_number` carried goes back in line
+    pub fn on_packet_lost(&mut self, packet_number: u64) {
+        for frame in self.in_flight.remove(&packet_number).unwrap_or_default() {
+            self.push(frame);
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_control_frame_retransmission() {
+        let mut frames = PendingControlFrames::new();
+        frames.push(ControlFrame::HandshakeDone);
+        frames.push(ControlFrame::NewConnectionId(1));
+        frames.push(ControlFrame::HandshakeDone);
+
+        for packet_number in 0..2 {
+            let frame = frames.pop().unwrap();
+            frames.on_sent(packet_number, frame);
+        }
+        assert!(!frames.has_pending());
+        assert_eq!(frames.in_flight(), 2);
+
+        frames.on_packet_acked(0);
+        frames.on_packet_lost(1);
+        // acked or lost twice changes nothing
+        frames.on_packet_lost(0);
+        frames.on_packet_lost(1);
+        assert_eq!(frames.pop(), Some(ControlFrame::NewConnectionId(1)));
+        assert_eq!(frames.pop(), None);
+        assert_eq!(frames.in_flight(), 0);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+struct SentPacket {
+    sent_at: Instant,
+    size: usize,
+    ack_eliciting: bool,
+    // the connection's delivery state when this was sent, what its sample is measured from
+    delivered: u64,
+    delivered_at: Instant,
+    first_sent_at: Instant,
+}
+
+// what the peer acknowledged of a packet, for the rtt estimate and congestion control.  a lost
+// packet is handed back the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckedPacket {
+    pub packet_number: u64,
+    pub sent_at: Instant,
+    pub size: usize,
+    pub ack_eliciting: bool,
+}
+
+// estimates the bandwidth the path delivers at from how quickly ACKs come back for what was sent
+// (draft-cheng-iccrg-delivery-rate-estimation).  each ACK gives a sample: the bytes acknowledged
+// since the packet was sent, over the longer of how long sending them took and how long their
+// ACKs took, so neither a burst of sends nor a burst of ACKs inflates it
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryRateEstimator {
+    // in flight, by packet number
+    sent: BTreeMap<u64, SentPacket>,
+    // bytes acknowledged so far
+    delivered: u64,
+    delivered_at: Option<Instant>,
+    // when the packet whose ACK produced the last sample was sent
+    first_sent_at: Option<Instant>,
+    // bytes per second, from the most recent sample
+    rate: Option<u64>,
+}
+
+impl DeliveryRateEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_sent(&mut self, packet_number: u64, size: usize, ack_eliciting: bool, now: Instant) {
+        // sending from idle starts the clocks over, the idle time isn't the path's fault
+        if self.sent.is_empty() {
+            self.first_sent_at = Some(now);
+            self.delivered_at = Some(now);
+        }
+        self.sent.insert(
+            packet_number,
+            SentPacket {
+                sent_at: now,
+                size,
+                ack_eliciting,
+                delivered: self.delivered,
+                delivered_at: self.delivered_at.unwrap_or(now),
+                first_sent_at: self.first_sent_at.unwrap_or(now),
+            },
+        );
+    }
+
+    // `packet_number` was acknowledged at `now`.  None if it isn't in flight (acked already,
+    // declared lost, or never recorded).  samples over less than `min_rtt` are too short to
+    // trust and are left out of the rate
+    pub fn on_acked(
+        &mut self,
+        packet_number: u64,
+        now: Instant,
+        min_rtt: Option<Duration>,
+    ) -> Option<AckedPacket> {
+        let packet = self.sent.remove(&packet_number)?;
+        self.delivered += packet.size as u64;
+        self.delivered_at = Some(now);
+        self.first_sent_at = Some(packet.sent_at);
+
+        let send_elapsed = packet.sent_at - packet.first_sent_at;
+        let ack_elapsed = now - packet.delivered_at;
+        let interval = send_elapsed.max(ack_elapsed);
+        if !interval.is_zero() && min_rtt.is_none_or(|min_rtt| interval >= min_rtt) {
+            let delivered = self.delivered - packet.delivered;
+            self.rate = Some((delivered as f64 / interval.as_secs_f64()) as u64);
+        }
+        Some(AckedPacket {
+            packet_number,
+            sent_at: packet.sent_at,
+            size: packet.size,
+            ack_eliciting: packet.ack_eliciting,
+        })
+    }
+
+    // None if `packet_number` isn't in flight
+    pub fn on_lost(&mut self, packet_number: u64) -> Option<AckedPacket> {
+        let packet = self.sent.remove(&packet_number)?;
+        Some(AckedPacket {
+            packet_number,
+            sent_at: packet.sent_at,
+            size: packet.size,
+            ack_eliciting: packet.ack_eliciting,
+        })
+    }
+
+    // bytes per second, None until there's been a sample
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.sent.values().map(|packet| packet.size).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delivery_rate() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut estimator = DeliveryRateEstimator::new();
+
+        // a 1000 byte packet every millisecond, each acked 50ms after it was sent
+        for t in 0..200 {
+            if t >= 50 {
+                let acked = estimator.on_acked(t - 50, ms(t), None).unwrap();
+                assert_eq!(acked.sent_at, ms(t - 50));
+            }
+            estimator.on_sent(t, 1_000, true, ms(t));
+            if t == 50 {
+                // the first ACK covers one packet over the whole 50ms
+                assert_eq!(estimator.rate(), Some(20_000));
+            }
+        }
+        // once ACKs are flowing it's 1000 bytes a millisecond, the rate they were sent at
+        assert_eq!(estimator.rate(), Some(1_000_000));
+        assert_eq!(estimator.delivered(), 150_000);
+        assert_eq!(estimator.in_flight(), 50_000);
+        for t in 200..250 {
+            estimator.on_acked(t - 50, ms(t), None);
+        }
+
+        // nothing is sampled twice, or from a lost packet
+        assert!(estimator.on_acked(3, ms(250), None).is_none());
+        estimator.on_sent(200, 1_000, true, ms(300));
+        estimator.on_lost(200);
+        assert!(estimator.on_acked(200, ms(350), None).is_none());
+        assert_eq!(estimator.in_flight(), 0);
+
+        // an ACK that comes back implausibly fast doesn't move the estimate
+        estimator.on_sent(201, 1_000, true, ms(400));
+        estimator.on_acked(201, ms(410), Some(Duration::from_millis(50)));
+        assert_eq!(estimator.rate(), Some(1_000_000));
+    }
+}
@@ -0,0 +1,137 @@
+use std::{fmt, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Outgoing,
+    Incoming,
+}
+
+// what happens to a datagram on its way through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Pass,
+    Drop,
+    // held back until `n` more datagrams have gone the same direction, i.e. reordered
+    Delay(usize),
+    Duplicate,
+    // flips every bit of the byte at this index (wrapping around the datagram's length)
+    Corrupt(usize),
+}
+
+// decides the fate of each datagram, given its direction and its index in that direction (0, 1, 2..).
+// the index is what makes it deterministic, e.g. "drop the third outgoing datagram"
+pub type FaultHook = dyn Fn(Direction, u64, &[u8]) -> Fault + Send + Sync;
+
+// fault injection for resilience tests, so loss / reordering / duplication / corruption can be
+// exercised without a network shaper.  never set outside of tests
+#[derive(Clone)]
+pub struct TestHooks {
+    hook: Arc<FaultHook>,
+}
+
+impl TestHooks {
+    pub fn new(hook: impl Fn(Direction, u64, &[u8]) -> Fault + Send + Sync + 'static) -> Self {
+        Self {
+            hook: Arc::new(hook),
+        }
+    }
+}
+
+impl fmt::Debug for TestHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestHooks").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Lane {
+    seen: u64,
+    // delayed datagrams with how many more have to pass before they're released
+    held: Vec<(usize, Vec<u8>)>,
+}
+
+// applies `TestHooks` to a connection's traffic, keeping the per-direction counters and held datagrams
+#[derive(Debug)]
+pub struct FaultInjector {
+    hooks: TestHooks,
+    outgoing: Lane,
+    incoming: Lane,
+}
+
+impl FaultInjector {
+    pub fn new(hooks: TestHooks) -> Self {
+        Self {
+            hooks,
+            outgoing: Lane::default(),
+            incoming: Lane::default(),
+        }
+    }
+
+    // what actually makes it through, in order
+    pub fn apply(&mut self, direction: Direction, datagrams: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let lane = match direction {
+            Direction::Outgoing => &mut self.outgoing,
+            Direction::Incoming => &mut self.incoming,
+        };
+
+        let mut out = Vec::with_capacity(datagrams.len());
+        for mut datagram in datagrams {
+            let fault = (self.hooks.hook)(direction, lane.seen, &datagram);
+            lane.seen += 1;
+
+            // every datagram that goes by (delayed or not) counts down the ones being held
+            let (due, held) = std::mem::take(&mut lane.held)
+                .into_iter()
+                .map(|(n, held)| (n.saturating_sub(1), held))
+                .partition::<Vec<_>, _>(|(n, _)| *n == 0);
+            lane.held = held;
+
+            match fault {
+                Fault::Pass => out.push(datagram),
+                Fault::Drop => {}
+                Fault::Delay(0) => out.push(datagram),
+                Fault::Delay(n) => lane.held.push((n, datagram)),
+                Fault::Duplicate => {
+                    out.push(datagram.clone());
+                    out.push(datagram);
+                }
+                Fault::Corrupt(index) => {
+                    if !datagram.is_empty() {
+                        let index = index % datagram.len();
+                        datagram[index] = !datagram[index];
+                    }
+                    out.push(datagram);
+                }
+            }
+            out.extend(due.into_iter().map(|(_, held)| held));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fault_injector() {
+        let hooks = TestHooks::new(|direction, i, _| match (direction, i) {
+            (Direction::Outgoing, 0) => Fault::Delay(2),
+            (Direction::Outgoing, 1) => Fault::Drop,
+            (Direction::Outgoing, 3) => Fault::Duplicate,
+            (Direction::Incoming, 0) => Fault::Corrupt(4),
+            _ => Fault::Pass,
+        });
+        let mut injector = FaultInjector::new(hooks);
+
+        let sent = injector.apply(Direction::Outgoing, vec![vec![0], vec![1], vec![2]]);
+        // 0 is held for two datagrams (1 was dropped but still counts), 2 overtakes it
+        assert_eq!(sent, vec![vec![2], vec![0]]);
+        let sent = injector.apply(Direction::Outgoing, vec![vec![3], vec![4]]);
+        assert_eq!(sent, vec![vec![3], vec![3], vec![4]]);
+
+        // the directions are counted separately
+        let recv = injector.apply(Direction::Incoming, vec![vec![0x0F, 0xFF], vec![7]]);
+        assert_eq!(recv, vec![vec![0xF0, 0xFF], vec![7]]);
+    }
+}
@@ -1,4 +1,46 @@
+pub mod ack;
+pub mod amplification;
+pub mod batch;
+pub mod buffer_pool;
+pub mod cids;
+pub mod congestion;
 pub mod connection;
+pub mod control;
+pub mod delivery;
+pub mod faults;
+pub mod packet_number;
+pub mod path;
+pub mod recovery;
+pub mod recv_queue;
+pub mod send_queue;
+pub mod shared;
+#[cfg(feature = "dangerous-snapshot")]
+pub mod snapshot;
+pub mod socket;
+pub mod stats;
+pub mod timers;
 pub mod types;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
 
+pub use ack::*;
+pub use amplification::*;
+pub use batch::*;
+pub use buffer_pool::*;
+pub use cids::*;
+pub use congestion::*;
+pub use control::*;
+pub use delivery::*;
+pub use faults::*;
+pub use packet_number::*;
+pub use path::*;
+pub use recovery::*;
+pub use recv_queue::*;
+pub use send_queue::*;
+pub use shared::*;
+#[cfg(feature = "dangerous-snapshot")]
+pub use snapshot::*;
+pub use socket::*;
+pub use stats::*;
+pub use timers::*;
 pub use types::*;
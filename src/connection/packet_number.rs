@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    result::{require, QuicheResult},
+    secure_u64,
+};
+
+// how many skipped packet numbers are remembered.  an ACK for one older than that is unlikely,
+// and not catching it only means not catching that one attempt
+pub const MAX_SKIPPED_PACKET_NUMBERS: usize = 32;
+
+// hands out packet numbers.  with a skip probability set, a number is now and then left out so
+// a peer acknowledging packets it never received, to get us to send faster, gives itself away by
+// acknowledging one that was never sent (RFC 9000 section 21.4)
+#[derive(Debug, Clone, Default)]
+pub struct PacketNumbers {
+    next: u64,
+    skip_probability: f64,
+    skipped: BTreeSet<u64>,
+}
+
+impl PacketNumbers {
+    pub fn new(next: u64) -> Self {
+        Self {
+            next,
+            ..Self::default()
+        }
+    }
+
+    // 0 never skips, which is the default.  something like 1 in 256 is plenty to catch a peer
+    // that's acknowledging optimistically, without wasting packet number space.  anything over
+    // 0.5 is an error, it'd waste more numbers than it sends
+    pub fn set_skip_probability(&mut self, skip_probability: f64) -> QuicheResult<()> {
+        require(
+            (0.0..=0.5).contains(&skip_probability),
+            "PacketNumbers::set_skip_probability: must be between 0 and 0.5",
+        )?;
+        self.skip_probability = skip_probability;
+        Ok(())
+    }
+
+    // the number the next packet gets, without taking it
+    pub fn peek(&self) -> u64 {
+        self.next
+    }
+
+    pub fn allocate(&mut self) -> u64 {
+        let packet_number = self.next;
+        self.next += 1;
+        // decided now rather than on the next call, so `peek` is always right
+        if self.should_skip() {
+            self.skipped.insert(self.next);
+            if self.skipped.len() > MAX_SKIPPED_PACKET_NUMBERS {
+                self.skipped.pop_first();
+            }
+            self.next += 1;
+        }
+        packet_number
+    }
+
+    // whether `packet_number` was skipped, never sent, so an ACK for it is a PROTOCOL_VIOLATION
+    pub fn is_skipped(&self, packet_number: u64) -> bool {
+        self.skipped.contains(&packet_number)
+    }
+
+    fn should_skip(&self) -> bool {
+        if self.skip_probability <= 0.0 {
+            return false;
+        }
+        // from the OS, a peer that could predict the skipped numbers could ack around them
+        let sample = secure_u64() >> 11;
+        (sample as f64) < self.skip_probability * (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_number_skipping() {
+        let mut packet_numbers = PacketNumbers::new(5);
+        assert!((0..100).map(|_| packet_numbers.allocate()).eq(5..105));
+        assert!(packet_numbers.set_skip_probability(1.5).is_err());
+        // not clamped, refused
+        assert!(packet_numbers.set_skip_probability(0.75).is_err());
+        assert!(packet_numbers.set_skip_probability(-0.1).is_err());
+
+        packet_numbers.set_skip_probability(0.25).unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..1_000 {
+            let peeked = packet_numbers.peek();
+            let packet_number = packet_numbers.allocate();
+            assert_eq!(peeked, packet_number);
+            sent.push(packet_number);
+        }
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]));
+        // every gap is a skipped number, and acknowledging one is caught
+        let gaps = sent
+            .windows(2)
+            .filter(|pair| pair[1] - pair[0] > 1)
+            .map(|pair| pair[0] + 1)
+            .collect::<Vec<_>>();
+        assert!(gaps.len() > 100);
+        // the last allocation may have skipped the number after it too, which isn't a gap yet
+        // but pushes the oldest one out
+        for gap in gaps.iter().rev().take(MAX_SKIPPED_PACKET_NUMBERS - 1) {
+            assert!(packet_numbers.is_skipped(*gap));
+        }
+        assert!(!sent.iter().any(|&sent| packet_numbers.is_skipped(sent)));
+    }
+}
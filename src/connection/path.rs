@@ -0,0 +1,88 @@
+use std::{net::SocketAddr, time::Instant};
+
+use crate::{packet::frame::Frame, secure_bytes};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathState {
+    // a PATH_CHALLENGE carrying `challenge` is out, and we're waiting for the matching PATH_RESPONSE
+    Validating {
+        challenge: [u8; 8],
+        sent_at: Instant,
+    },
+    Validated,
+}
+
+// a local / peer address pair, and whether the peer has proven it can be reached over it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path {
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    pub state: PathState,
+}
+
+impl Path {
+    // the path a connection was opened on, which the handshake validates
+    pub fn validated(local_addr: SocketAddr, peer_addr: SocketAddr) -> Self {
+        Self {
+            local_addr,
+            peer_addr,
+            state: PathState::Validated,
+        }
+    }
+
+    // a new path, along with the PATH_CHALLENGE to send on it.  the data comes from the OS, an
+    // off-path attacker that could guess it could answer for an address it can't receive at
+    pub fn probe(local_addr: SocketAddr, peer_addr: SocketAddr) -> (Self, Frame) {
+        let challenge = secure_bytes();
+        let path = Self {
+            local_addr,
+            peer_addr,
+            state: PathState::Validating {
+                challenge,
+                sent_at: Instant::now(),
+            },
+        };
+        (path, Frame::PathChallenge(challenge))
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.state == PathState::Validated
+    }
+
+    // returns whether `data` answered the outstanding challenge
+    pub fn on_path_response(&mut self, data: [u8; 8]) -> bool {
+        match self.state {
+            PathState::Validating { challenge, .. } if challenge == data => {
+                self.state = PathState::Validated;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_validation() {
+        let local_addr = "127.0.0.1:4433".parse().unwrap();
+        let peer_addr = "127.0.0.1:4434".parse().unwrap();
+        let (mut path, frame) = Path::probe(local_addr, peer_addr);
+        assert!(!path.is_validated());
+
+        let Frame::PathChallenge(challenge) = frame else {
+            panic!("expected a PATH_CHALLENGE, got {:?}", frame);
+        };
+        let mut wrong = challenge;
+        wrong[0] ^= 0xFF;
+        assert!(!path.on_path_response(wrong));
+        assert!(!path.is_validated());
+
+        assert!(path.on_path_response(challenge));
+        assert!(path.is_validated());
+        // a late duplicate doesn't do anything
+        assert!(!path.on_path_response(challenge));
+    }
+}
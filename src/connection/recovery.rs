@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant};
+
+use crate::result::{require, QuicheResult};
+
+// the RFC 9002 loss detection constants, as defaults that can be overridden for links that are
+// nothing like the internet the RFC was tuned for: a satellite hop wants a much larger initial
+// rtt, a datacenter a much smaller one and a finer granularity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryConfig {
+    // the rtt assumed until there's a sample (kInitialRtt, RFC 9002 section 6.2.2)
+    pub initial_rtt: Duration,
+    // how many packets sent after one have to be acknowledged before it's declared lost
+    // (kPacketThreshold, section 6.1.1)
+    pub packet_threshold: u64,
+    // how many rtts a packet gets before it's declared lost, once something sent after it
+    // has been acknowledged (kTimeThreshold, section 6.1.2)
+    pub time_threshold: f64,
+    // the timer granularity, the smallest a loss delay or rtt variance term ever gets
+    // (kGranularity, section 6.1.2)
+    pub granularity: Duration,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        Self {
+            initial_rtt: Duration::from_millis(333),
+            packet_threshold: 3,
+            time_threshold: 9.0 / 8.0,
+            granularity: Duration::from_millis(1),
+        }
+    }
+}
+
+impl RecoveryConfig {
+    // the RFC says the thresholds shouldn't go below its defaults (section 6.1), since that only
+    // trades spurious retransmissions for nothing.  we allow it, but not nonsense
+    pub fn validate(&self) -> QuicheResult<()> {
+        require(
+            !self.initial_rtt.is_zero(),
+            "RecoveryConfig: initial_rtt must be non-zero",
+        )?;
+        require(
+            self.packet_threshold >= 1,
+            "RecoveryConfig: packet_threshold must be at least 1",
+        )?;
+        require(
+            self.time_threshold.is_finite() && self.time_threshold >= 1.0,
+            "RecoveryConfig: time_threshold must be at least 1",
+        )?;
+        require(
+            !self.granularity.is_zero(),
+            "RecoveryConfig: granularity must be non-zero",
+        )
+    }
+
+    // how long after it was sent a packet is declared lost, once a later one has been acknowledged
+    pub fn loss_delay(&self, rtt: &RttEstimator) -> Duration {
+        rtt.latest_rtt()
+            .max(rtt.smoothed_rtt())
+            .mul_f64(self.time_threshold)
+            .max(self.granularity)
+    }
+
+    // whether `packet_number`, sent at `sent_at`, is lost now that `largest_acked` has been
+    // acknowledged (section 6.1)
+    pub fn is_lost(
+        &self,
+        rtt: &RttEstimator,
+        packet_number: u64,
+        sent_at: Instant,
+        largest_acked: u64,
+        now: Instant,
+    ) -> bool {
+        if packet_number >= largest_acked {
+            return false;
+        }
+        largest_acked - packet_number >= self.packet_threshold
+            || now.saturating_duration_since(sent_at) >= self.loss_delay(rtt)
+    }
+
+    // the probe timeout, not counting the peer's max_ack_delay (section 6.2.1), which only
+    // applies in the application data space
+    pub fn pto(&self, rtt: &RttEstimator) -> Duration {
+        rtt.smoothed_rtt() + (4 * rtt.rttvar()).max(self.granularity)
+    }
+}
+
+// the rtt estimate (RFC 9002 section 5)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttEstimator {
+    latest_rtt: Duration,
+    smoothed_rtt: Duration,
+    rttvar: Duration,
+    // None until the first sample
+    min_rtt: Option<Duration>,
+}
+
+impl RttEstimator {
+    pub fn new(initial_rtt: Duration) -> Self {
+        Self {
+            latest_rtt: initial_rtt,
+            smoothed_rtt: initial_rtt,
+            rttvar: initial_rtt / 2,
+            min_rtt: None,
+        }
+    }
+
+    pub fn latest_rtt(&self) -> Duration {
+        self.latest_rtt
+    }
+
+    pub fn smoothed_rtt(&self) -> Duration {
+        self.smoothed_rtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+
+    pub fn has_sample(&self) -> bool {
+        self.min_rtt.is_some()
+    }
+
+    // a new sample from an ACK that newly acknowledged its largest packet.  `ack_delay` is what
+    // the peer says it held the ACK for, already capped at its max_ack_delay once the handshake is
+    // confirmed (section 5.3)
+    pub fn update(&mut self, latest_rtt: Duration, ack_delay: Duration) {
+        self.latest_rtt = latest_rtt;
+        let Some(min_rtt) = self.min_rtt else {
+            self.min_rtt = Some(latest_rtt);
+            self.smoothed_rtt = latest_rtt;
+            self.rttvar = latest_rtt / 2;
+            return;
+        };
+        let min_rtt = min_rtt.min(latest_rtt);
+        self.min_rtt = Some(min_rtt);
+
+        // the ack delay is only taken off if that doesn't go below min_rtt
+        let adjusted_rtt = match latest_rtt >= min_rtt + ack_delay {
+            true => latest_rtt - ack_delay,
+            false => latest_rtt,
+        };
+        let deviation = self.smoothed_rtt.abs_diff(adjusted_rtt);
+        self.rttvar = (3 * self.rttvar + deviation) / 4;
+        self.smoothed_rtt = (7 * self.smoothed_rtt + adjusted_rtt) / 8;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_recovery() {
+        let config = RecoveryConfig::default();
+        config.validate().unwrap();
+        let mut rtt = RttEstimator::new(config.initial_rtt);
+        // before any sample, the PTO comes out at about a second
+        assert_eq!(config.pto(&rtt), ms(999));
+
+        rtt.update(ms(100), ms(0));
+        assert_eq!(rtt.min_rtt(), Some(ms(100)));
+        assert_eq!((rtt.smoothed_rtt(), rtt.rttvar()), (ms(100), ms(50)));
+        // 20ms of it was the peer sitting on the ACK
+        rtt.update(ms(140), ms(20));
+        assert_eq!(rtt.smoothed_rtt(), ms(102_500) / 1_000);
+        assert_eq!(rtt.rttvar(), ms(42_500) / 1_000);
+        // but never below min_rtt
+        rtt.update(ms(101), ms(20));
+        assert_eq!(rtt.min_rtt(), Some(ms(100)));
+        assert_eq!(rtt.latest_rtt(), ms(101));
+
+        let sent_at = Instant::now();
+        let delay = config.loss_delay(&rtt);
+        assert_eq!(delay, rtt.smoothed_rtt().mul_f64(1.125));
+        assert!(!config.is_lost(&rtt, 8, sent_at, 10, sent_at));
+        assert!(config.is_lost(&rtt, 7, sent_at, 10, sent_at));
+        assert!(config.is_lost(&rtt, 8, sent_at, 10, sent_at + delay));
+        assert!(!config.is_lost(&rtt, 10, sent_at, 10, sent_at + delay));
+
+        // a datacenter tuning
+        let tuned = RecoveryConfig {
+            initial_rtt: ms(1),
+            packet_threshold: 5,
+            granularity: Duration::from_micros(100),
+            ..config
+        };
+        tuned.validate().unwrap();
+        assert!(!tuned.is_lost(&rtt, 7, sent_at, 10, sent_at));
+        assert_eq!(
+            tuned.pto(&RttEstimator::new(tuned.initial_rtt)),
+            Duration::from_micros(3_000)
+        );
+        assert!(RecoveryConfig {
+            time_threshold: 0.5,
+            ..config
+        }
+        .validate()
+        .is_err());
+    }
+}
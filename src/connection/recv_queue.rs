@@ -0,0 +1,112 @@
+use std::collections::VecDeque;
+
+use super::BufferPool;
+
+// how many received datagrams can wait for processing before new ones are dropped
+pub const DEFAULT_RECV_QUEUE_CAPACITY: usize = 256;
+
+// received datagrams waiting to be processed.  it's bounded, so when the application falls behind
+// new datagrams are dropped (and counted) instead of the queue growing forever.  dropping is fine,
+// the peer's loss recovery treats it like any other loss
+#[derive(Debug)]
+pub struct RecvQueue {
+    datagrams: VecDeque<Vec<u8>>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl RecvQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            datagrams: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // shrinking below the current length sheds the newest datagrams
+    pub fn set_capacity(&mut self, capacity: usize, pool: &mut BufferPool) {
+        self.capacity = capacity;
+        while self.datagrams.len() > capacity {
+            if let Some(datagram) = self.datagrams.pop_back() {
+                self.dropped += 1;
+                pool.recycle(datagram);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.datagrams.len() >= self.capacity
+    }
+
+    // datagrams shed because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    // returns false if the queue was full, in which case the buffer goes straight back to `pool`
+    pub fn push(&mut self, datagram: Vec<u8>, pool: &mut BufferPool) -> bool {
+        if self.is_full() {
+            self.dropped += 1;
+            pool.recycle(datagram);
+            return false;
+        }
+        self.datagrams.push_back(datagram);
+        true
+    }
+
+    // hand the buffer back to the pool once it's been processed
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.datagrams.pop_front()
+    }
+}
+
+impl Default for RecvQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_RECV_QUEUE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recv_queue_sheds_when_full() {
+        let mut pool = BufferPool::new(1_200, 8);
+        let mut queue = RecvQueue::new(3);
+
+        for i in 0..5u8 {
+            let mut datagram = pool.take();
+            datagram.push(i);
+            assert_eq!(queue.push(datagram, &mut pool), i < 3);
+        }
+        assert!(queue.is_full());
+        assert_eq!(queue.dropped(), 2);
+        // the shed buffer went back to the pool (and got reused for the next one)
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.allocations(), 4);
+
+        assert_eq!(queue.pop(), Some(vec![0]));
+        let mut datagram = pool.take();
+        datagram.push(5);
+        assert!(queue.push(datagram, &mut pool));
+
+        queue.set_capacity(1, &mut pool);
+        assert_eq!(queue.dropped(), 4);
+        assert_eq!(queue.pop(), Some(vec![1]));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+
+use crate::{
+    packet::{packet::Packet, EncryptionLevel},
+    result::QuicheResult,
+};
+
+// outgoing packets, queued per encryption level.  draining packs them into datagrams in the order
+// RFC 9000 section 12.2 wants (initial, 0-rtt, handshake, 1-rtt), coalescing as many as fit
+#[derive(Debug, Default)]
+pub struct SendQueue {
+    levels: [VecDeque<Packet>; 4],
+    // retry / version negotiation packets, which always go out on their own
+    unprotected: VecDeque<Packet>,
+    // packets dropped by `drain` for being too big for any datagram
+    dropped: u64,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, packet: Packet) {
+        match packet.encryption_level() {
+            Some(level) => self.levels[level as usize].push_back(packet),
+            None => self.unprotected.push_back(packet),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.unprotected.len() + self.levels.iter().map(VecDeque::len).sum::<usize>()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn pending(&self, level: EncryptionLevel) -> usize {
+        self.levels[level as usize].len()
+    }
+
+    // the oldest packet queued at `level`, for a caller encoding it itself
+    pub fn pop(&mut self, level: EncryptionLevel) -> Option<Packet> {
+        self.levels[level as usize].pop_front()
+    }
+
+    // puts a packet that was popped but couldn't be sent back where it was
+    pub fn push_front(&mut self, packet: Packet) {
+        match packet.encryption_level() {
+            Some(level) => self.levels[level as usize].push_front(packet),
+            None => self.unprotected.push_front(packet),
+        }
+    }
+
+    // drops everything queued at `level`, e.g. once its keys are discarded
+    pub fn discard(&mut self, level: EncryptionLevel) {
+        self.levels[level as usize].clear();
+    }
+
+    // encodes queued packets into datagrams of at most `max_datagram_size` bytes, stopping before
+    // the total goes over `budget` (whatever the congestion controller / pacer allows right now).
+    // anything that didn't fit stays queued for the next call.  a packet that's bigger than
+    // `max_datagram_size` on its own is dropped and counted in `dropped`, the peer can't be sent
+    // it, and draining carries on past it
+    pub fn drain(&mut self, max_datagram_size: usize, budget: usize) -> QuicheResult<Vec<Vec<u8>>> {
+        let mut datagrams = Vec::new();
+        let mut total = 0;
+
+        while let Some(packet) = self.unprotected.front() {
+            let encoded = packet.encode()?;
+            if encoded.len() > max_datagram_size {
+                self.unprotected.pop_front();
+                self.dropped += 1;
+                continue;
+            }
+            if total + encoded.len() > budget {
+                return Ok(datagrams);
+            }
+            total += encoded.len();
+            datagrams.push(encoded);
+            self.unprotected.pop_front();
+        }
+
+        let mut datagram: Vec<u8> = Vec::new();
+        for level in EncryptionLevel::ALL {
+            while let Some(packet) = self.levels[level as usize].front() {
+                let encoded = packet.encode()?;
+                if encoded.len() > max_datagram_size {
+                    self.levels[level as usize].pop_front();
+                    self.dropped += 1;
+                    continue;
+                }
+                if !datagram.is_empty() && datagram.len() + encoded.len() > max_datagram_size {
+                    datagrams.push(std::mem::take(&mut datagram));
+                }
+                if total + encoded.len() > budget {
+                    if !datagram.is_empty() {
+                        datagrams.push(datagram);
+                    }
+                    return Ok(datagrams);
+                }
+                total += encoded.len();
+                datagram.extend(encoded);
+                self.levels[level as usize].pop_front();
+
+                // short headers have no length field, so nothing can be coalesced after one
+                if level == EncryptionLevel::OneRtt {
+                    datagrams.push(std::mem::take(&mut datagram));
+                }
+            }
+        }
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+        Ok(datagrams)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::{
+            frame::Frame, header::LongHeaderExtension, ConnectionId, FourBits, LongPacketType,
+            PacketNumber, PnLen, SingleBit, TwoBits,
+        },
+        BitsExt, VarInt, MINI_QUICHE_VERSION,
+    };
+
+    fn cid() -> ConnectionId {
+        ConnectionId::new(8, vec![1; 8])
+    }
+
+    fn initial(padding: usize) -> Packet {
+        let mut payload = vec![Frame::Ping];
+        payload.extend(std::iter::repeat_n(Frame::Padding, padding));
+        Packet::initial(
+            MINI_QUICHE_VERSION,
+            cid(),
+            cid(),
+            FourBits::zero(),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::new_u32(padding as u32 + 2),
+            PacketNumber(VarInt::zero()),
+            payload,
+        )
+    }
+
+    fn handshake(padding: usize) -> Packet {
+        let mut payload = vec![Frame::Ping];
+        payload.extend(std::iter::repeat_n(Frame::Padding, padding));
+        Packet::long_header(
+            LongPacketType::handshake(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            cid(),
+            cid(),
+            LongHeaderExtension::Handshake {
+                length: VarInt::new_u32(padding as u32 + 2),
+                packet_number: PacketNumber(VarInt::zero()),
+            },
+            payload,
+        )
+    }
+
+    fn one_rtt(padding: usize) -> Packet {
+        let mut payload = vec![Frame::Ping];
+        payload.extend(std::iter::repeat_n(Frame::Padding, padding));
+        Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid(),
+            vec![0],
+            payload,
+        )
+    }
+
+    #[test]
+    fn test_send_queue_coalesces_by_level() {
+        let mut queue = SendQueue::new();
+        // pushed out of order on purpose
+        queue.push(one_rtt(10));
+        queue.push(one_rtt(10));
+        queue.push(handshake(10));
+        queue.push(initial(10));
+        assert_eq!(queue.pending(EncryptionLevel::OneRtt), 2);
+
+        let sizes =
+            [initial(10), handshake(10), one_rtt(10)].map(|packet| packet.encode().unwrap().len());
+        let datagrams = queue.drain(1_200, usize::MAX).unwrap();
+        assert!(queue.is_empty());
+        // initial + handshake + the first 1-rtt packet share a datagram, the second 1-rtt can't follow a short header
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].len(), sizes.iter().sum::<usize>());
+        assert_eq!(datagrams[0][..sizes[0]], initial(10).encode().unwrap());
+        assert_eq!(datagrams[1].len(), sizes[2]);
+    }
+
+    #[test]
+    fn test_send_queue_respects_limits() {
+        let mut queue = SendQueue::new();
+        queue.push(initial(600));
+        queue.push(handshake(600));
+        queue.push(handshake(10));
+
+        let sizes = [initial(600), handshake(600), handshake(10)]
+            .map(|packet| packet.encode().unwrap().len());
+
+        // the two big packets don't fit in one datagram
+        let budget = sizes[0] + sizes[1];
+        let datagrams = queue.drain(1_200, budget).unwrap();
+        assert_eq!(
+            datagrams.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![sizes[0], sizes[1]]
+        );
+        // out of budget, the small handshake packet waits
+        assert_eq!(queue.pending(EncryptionLevel::Handshake), 1);
+
+        let datagrams = queue.drain(1_200, usize::MAX).unwrap();
+        assert_eq!(datagrams, vec![handshake(10).encode().unwrap()]);
+
+        queue.push(handshake(10));
+        queue.discard(EncryptionLevel::Handshake);
+        assert!(queue.is_empty());
+
+        // too big to ever go out, so it's dropped rather than left at the front of the queue, and
+        // the packets either side of it still go out
+        queue.push(initial(10));
+        queue.push(initial(1_300));
+        queue.push(handshake(10));
+        let datagrams = queue.drain(1_200, usize::MAX).unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(queue.dropped(), 1);
+        let mut expected = initial(10).encode().unwrap();
+        expected.extend(handshake(10).encode().unwrap());
+        assert_eq!(datagrams, vec![expected]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::result::QuicheResult;
+
+use super::{connection::Connection, ConnectionState, StateObserver};
+
+// a cloneable handle to a connection, so one task can be reading while others write, close, or
+// check stats.  everything goes through `lock`, and none of the methods here hold the lock
+// while waiting on the network
+#[derive(Clone)]
+pub struct SharedConnection {
+    inner: Arc<Mutex<Connection>>,
+    // kept outside the lock so the state can be read without waiting for whoever holds it
+    observer: StateObserver,
+}
+
+impl SharedConnection {
+    pub fn new(connection: Connection) -> Self {
+        let observer = connection.subscribe();
+        Self {
+            inner: Arc::new(Mutex::new(connection)),
+            observer,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.observer.state()
+    }
+
+    pub fn subscribe(&self) -> StateObserver {
+        self.observer.clone()
+    }
+
+    // exclusive access to the connection, for anything there isn't a method for here
+    pub async fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.inner.lock().await
+    }
+
+    // like `Connection::recv`, but the wait for the socket to become readable happens without
+    // the lock, so other handles aren't held up until a datagram arrives.  a `rebind` while
+    // waiting wakes it up to wait on the new socket instead
+    pub async fn recv(&self) -> QuicheResult<usize> {
+        loop {
+            let connection = self.inner.lock().await;
+            let socket = connection.socket();
+            let rebound = connection.rebound();
+            // registered before the lock goes, so a rebind right after can't be missed
+            let notified = rebound.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            drop(connection);
+
+            tokio::select! {
+                readable = socket.readable() => readable?,
+                _ = notified => continue,
+            }
+            let mut connection = self.inner.lock().await;
+            // another handle may have read it in the meantime, or the socket changed since
+            if Arc::ptr_eq(&socket, &connection.socket())
+                && connection.socket().try_peek_sender().is_ok()
+            {
+                return connection.recv().await;
+            }
+        }
+    }
+
+    pub async fn close(&self, app_error_code: u64, reason: &[u8]) -> QuicheResult<()> {
+        self.inner.lock().await.close(app_error_code, reason).await
+    }
+
+    pub async fn flush(&self, budget: usize) -> QuicheResult<usize> {
+        self.inner.lock().await.flush(budget).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::ConnectionId;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shared_connection() {
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let connection =
+            Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+                .await
+                .unwrap();
+        let local_addr = connection.path().local_addr;
+        let shared = SharedConnection::new(connection);
+
+        // one task waits on the socket...
+        let reader = tokio::spawn({
+            let shared = shared.clone();
+            async move { shared.recv().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // ...which doesn't stop another from getting at the connection
+        let stats = tokio::time::timeout(Duration::from_secs(1), async {
+            shared.lock().await.stats()
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.io.datagrams_recv, 0);
+
+        peer.send_to(&[0; 32], local_addr).await.unwrap();
+        let queued = tokio::time::timeout(Duration::from_secs(5), reader)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(queued, 1);
+        assert_eq!(shared.lock().await.next_datagram().unwrap().len(), 32);
+
+        let observer = shared.subscribe();
+        shared.close(0, b"").await.unwrap();
+        assert_eq!(shared.state(), ConnectionState::Closed);
+        assert_eq!(observer.state(), ConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_recv_across_rebind() {
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut connection =
+            Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+                .await
+                .unwrap();
+        connection
+            .on_new_connection_id(1, 0, ConnectionId::new(8, vec![7; 8]), [1; 16])
+            .unwrap();
+        let shared = SharedConnection::new(connection);
+
+        // parked on the socket that's about to be replaced
+        let reader = tokio::spawn({
+            let shared = shared.clone();
+            async move { shared.recv().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        shared.lock().await.rebind(socket).await.unwrap();
+        let local_addr = shared.lock().await.path().local_addr;
+        peer.send_to(&[0; 32], local_addr).await.unwrap();
+
+        let queued = tokio::time::timeout(Duration::from_secs(5), reader)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(queued, 1);
+    }
+}
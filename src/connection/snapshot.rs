@@ -0,0 +1,375 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    consts::STATELESS_RESET_TOKEN_LEN,
+    packet::{
+        transport_parameters::TransportParameters,
+        types::{ConnectionId, EncryptionLevel},
+    },
+    result::{require, QuicheError, QuicheResult},
+};
+
+use super::{ConnectionState, LocalCid, PeerCid, Side};
+
+// bumped whenever the encoding changes, a snapshot from another version is refused rather than misread
+const SNAPSHOT_VERSION: u8 = 6;
+
+const LEVELS: usize = EncryptionLevel::ALL.len();
+
+// everything needed to pick a connection back up in another process, see `Connection::freeze`.
+// this is enough to impersonate the connection, so it should be handled like key material
+// (never logged, never written anywhere the peer or another tenant can read it)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSnapshot {
+    pub state: ConnectionState,
+    pub peer_addr: SocketAddr,
+    pub side: Side,
+    // what the trace id comes from, so it survives the move
+    pub original_dst_cid: ConnectionId,
+    pub dst_cid: ConnectionId,
+    pub dst_cid_sequence: u64,
+    pub retire_prior_to: u64,
+    pub spare_dst_cids: Vec<PeerCid>,
+    // the cids we've issued and not had retired, which the peer can still send to
+    pub local_cids: Vec<LocalCid>,
+    pub local_retire_prior_to: u64,
+    pub next_local_sequence: u64,
+    pub next_packet_number: u64,
+    // how long the idle timer had left, if it was armed
+    pub idle_remaining: Option<Duration>,
+    // which encryption levels have keys, and which have had theirs discarded.  every packet is
+    // sealed with `PlaintextSealer` until there's a crypto dependency, so there's no key
+    // material to carry yet
+    pub installed_keys: [bool; LEVELS],
+    pub discarded_keys: [bool; LEVELS],
+    // None if they never arrived, which a frozen connection past its handshake can't be
+    pub peer_transport_parameters: Option<TransportParameters>,
+    // the connection-level send window: the peer's MAX_DATA, and how much of it we've used
+    pub max_data: u64,
+    pub data_sent: u64,
+    // and the receive window: the MAX_DATA we'd given the peer, and how much of it it's used
+    pub recv_max_data: u64,
+    pub data_received: u64,
+}
+
+impl ConnectionSnapshot {
+    pub fn encode(&self) -> QuicheResult<Vec<u8>> {
+        let mut buf = vec![SNAPSHOT_VERSION];
+        encode_state(&mut buf, self.state);
+        encode_addr(&mut buf, self.peer_addr);
+        buf.push(match self.side {
+            Side::Client => 0,
+            Side::Server => 1,
+        });
+        encode_cid(&mut buf, &self.original_dst_cid);
+        encode_cid(&mut buf, &self.dst_cid);
+        buf.extend(self.dst_cid_sequence.to_be_bytes());
+        buf.extend(self.retire_prior_to.to_be_bytes());
+        buf.extend((self.spare_dst_cids.len() as u64).to_be_bytes());
+        for peer_cid in self.spare_dst_cids.iter() {
+            buf.extend(peer_cid.sequence_number.to_be_bytes());
+            encode_cid(&mut buf, &peer_cid.cid);
+            buf.extend(peer_cid.reset_token);
+        }
+        buf.extend((self.local_cids.len() as u64).to_be_bytes());
+        for local_cid in self.local_cids.iter() {
+            buf.extend(local_cid.sequence_number.to_be_bytes());
+            encode_cid(&mut buf, &local_cid.cid);
+            match local_cid.reset_token {
+                Some(token) => {
+                    buf.push(1);
+                    buf.extend(token);
+                }
+                None => buf.push(0),
+            }
+        }
+        buf.extend(self.local_retire_prior_to.to_be_bytes());
+        buf.extend(self.next_local_sequence.to_be_bytes());
+        buf.extend(self.next_packet_number.to_be_bytes());
+        match self.idle_remaining {
+            Some(remaining) => {
+                buf.push(1);
+                buf.extend((remaining.as_millis() as u64).to_be_bytes());
+            }
+            None => buf.push(0),
+        }
+        buf.push(encode_levels(&self.installed_keys));
+        buf.push(encode_levels(&self.discarded_keys));
+        match self.peer_transport_parameters.as_ref() {
+            Some(params) => {
+                let params = params.encode()?;
+                buf.push(1);
+                buf.extend((params.len() as u64).to_be_bytes());
+                buf.extend(params);
+            }
+            None => buf.push(0),
+        }
+        buf.extend(self.max_data.to_be_bytes());
+        buf.extend(self.data_sent.to_be_bytes());
+        buf.extend(self.recv_max_data.to_be_bytes());
+        buf.extend(self.data_received.to_be_bytes());
+        Ok(buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> QuicheResult<Self> {
+        let mut buf = bytes;
+        let version = take(&mut buf, 1)?[0];
+        require(
+            version == SNAPSHOT_VERSION,
+            "ConnectionSnapshot::decode: unsupported snapshot version",
+        )?;
+
+        let state = decode_state(&mut buf)?;
+        let peer_addr = decode_addr(&mut buf)?;
+        let side = match take(&mut buf, 1)?[0] {
+            0 => Side::Client,
+            1 => Side::Server,
+            side => {
+                return Err(QuicheError(format!(
+                    "ConnectionSnapshot::decode: unknown side {}",
+                    side
+                )))
+            }
+        };
+        let original_dst_cid = decode_cid(&mut buf)?;
+        let dst_cid = decode_cid(&mut buf)?;
+        let dst_cid_sequence = take_u64(&mut buf)?;
+        let retire_prior_to = take_u64(&mut buf)?;
+        let spare_dst_cids = (0..take_u64(&mut buf)?)
+            .map(|_| {
+                Ok(PeerCid {
+                    sequence_number: take_u64(&mut buf)?,
+                    cid: decode_cid(&mut buf)?,
+                    reset_token: take(&mut buf, STATELESS_RESET_TOKEN_LEN)?
+                        .try_into()
+                        .unwrap(),
+                })
+            })
+            .collect::<QuicheResult<Vec<_>>>()?;
+        let local_cids = (0..take_u64(&mut buf)?)
+            .map(|_| {
+                Ok(LocalCid {
+                    sequence_number: take_u64(&mut buf)?,
+                    cid: decode_cid(&mut buf)?,
+                    reset_token: match take(&mut buf, 1)?[0] {
+                        0 => None,
+                        _ => Some(
+                            take(&mut buf, STATELESS_RESET_TOKEN_LEN)?
+                                .try_into()
+                                .unwrap(),
+                        ),
+                    },
+                })
+            })
+            .collect::<QuicheResult<Vec<_>>>()?;
+        let local_retire_prior_to = take_u64(&mut buf)?;
+        let next_local_sequence = take_u64(&mut buf)?;
+        let next_packet_number = take_u64(&mut buf)?;
+        let idle_remaining = match take(&mut buf, 1)?[0] {
+            0 => None,
+            _ => Some(Duration::from_millis(take_u64(&mut buf)?)),
+        };
+        let installed_keys = decode_levels(take(&mut buf, 1)?[0]);
+        let discarded_keys = decode_levels(take(&mut buf, 1)?[0]);
+        let peer_transport_parameters = match take(&mut buf, 1)?[0] {
+            0 => None,
+            _ => {
+                let len = take_u64(&mut buf)? as usize;
+                let mut params = take(&mut buf, len)?;
+                Some(TransportParameters::decode(&mut params)?)
+            }
+        };
+        let max_data = take_u64(&mut buf)?;
+        let data_sent = take_u64(&mut buf)?;
+        let recv_max_data = take_u64(&mut buf)?;
+        let data_received = take_u64(&mut buf)?;
+        require(
+            buf.is_empty(),
+            "ConnectionSnapshot::decode: trailing bytes after snapshot",
+        )?;
+
+        Ok(Self {
+            state,
+            peer_addr,
+            side,
+            original_dst_cid,
+            dst_cid,
+            dst_cid_sequence,
+            retire_prior_to,
+            spare_dst_cids,
+            local_cids,
+            local_retire_prior_to,
+            next_local_sequence,
+            next_packet_number,
+            idle_remaining,
+            installed_keys,
+            discarded_keys,
+            peer_transport_parameters,
+            max_data,
+            data_sent,
+            recv_max_data,
+            data_received,
+        })
+    }
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> QuicheResult<&'a [u8]> {
+    require(
+        buf.len() >= len,
+        "ConnectionSnapshot::decode: truncated snapshot",
+    )?;
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+fn take_u64(buf: &mut &[u8]) -> QuicheResult<u64> {
+    let bytes = take(buf, 8)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn encode_state(buf: &mut Vec<u8>, state: ConnectionState) {
+    let tag = match state {
+        ConnectionState::Idle => 0,
+        ConnectionState::Handshaking => 1,
+        ConnectionState::Connected => 2,
+        ConnectionState::Closing => 3,
+        ConnectionState::Draining => 4,
+        ConnectionState::Closed => 5,
+        ConnectionState::Failed(_) => 6,
+    };
+    buf.push(tag);
+    if let ConnectionState::Failed(code) = state {
+        buf.extend(code.to_be_bytes());
+    }
+}
+
+fn decode_state(buf: &mut &[u8]) -> QuicheResult<ConnectionState> {
+    Ok(match take(buf, 1)?[0] {
+        0 => ConnectionState::Idle,
+        1 => ConnectionState::Handshaking,
+        2 => ConnectionState::Connected,
+        3 => ConnectionState::Closing,
+        4 => ConnectionState::Draining,
+        5 => ConnectionState::Closed,
+        6 => ConnectionState::Failed(take_u64(buf)?),
+        tag => {
+            return Err(QuicheError(format!(
+                "ConnectionSnapshot::decode: unknown connection state {}",
+                tag
+            )))
+        }
+    })
+}
+
+// one bit per encryption level, in `EncryptionLevel::ALL` order
+fn encode_levels(levels: &[bool; LEVELS]) -> u8 {
+    levels
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, set)| bits | ((*set as u8) << i))
+}
+
+fn decode_levels(bits: u8) -> [bool; LEVELS] {
+    std::array::from_fn(|i| bits & (1 << i) != 0)
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(4);
+            buf.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(6);
+            buf.extend(ip.octets());
+        }
+    }
+    buf.extend(addr.port().to_be_bytes());
+}
+
+fn decode_addr(buf: &mut &[u8]) -> QuicheResult<SocketAddr> {
+    let ip = match take(buf, 1)?[0] {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(take(buf, 4)?).unwrap())),
+        6 => IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(take(buf, 16)?).unwrap(),
+        )),
+        family => {
+            return Err(QuicheError(format!(
+                "ConnectionSnapshot::decode: unknown address family {}",
+                family
+            )))
+        }
+    };
+    let port = u16::from_be_bytes(take(buf, 2)?.try_into().unwrap());
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn encode_cid(buf: &mut Vec<u8>, cid: &ConnectionId) {
+    buf.push(cid.cid_len);
+    buf.extend(cid.cid.iter());
+}
+
+fn decode_cid(buf: &mut &[u8]) -> QuicheResult<ConnectionId> {
+    let cid_len = take(buf, 1)?[0];
+    let cid = take(buf, cid_len as usize)?.to_vec();
+    Ok(ConnectionId::new(cid_len, cid))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connection::connection::Connection;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_freeze_thaw() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut conn = Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        conn.set_side(Side::Server).unwrap();
+        conn.on_new_connection_id(1, 0, ConnectionId::new(4, vec![9; 4]), [9; 16])
+            .unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.on_peer_transport_parameters(&TransportParameters {
+            initial_max_data: 10_000,
+            max_ack_delay: 10,
+            ..Default::default()
+        })
+        .unwrap();
+        conn.on_keys_installed(EncryptionLevel::Handshake);
+        conn.on_keys_installed(EncryptionLevel::OneRtt);
+        conn.on_keys_discarded(EncryptionLevel::Initial);
+        conn.transition(ConnectionState::Connected).unwrap();
+        let issued = conn.issue_cid();
+        conn.on_stream_data_sent(1_000).unwrap();
+
+        let snapshot = conn.freeze().unwrap();
+        let bytes = snapshot.encode().unwrap();
+        assert_eq!(ConnectionSnapshot::decode(&bytes).unwrap(), snapshot);
+        assert!(ConnectionSnapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        // picked up on a fresh socket, as another worker would
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut thawed = Connection::thaw(ConnectionSnapshot::decode(&bytes).unwrap(), socket)
+            .await
+            .unwrap();
+        assert_eq!(thawed.state(), ConnectionState::Connected);
+        assert_eq!(thawed.path().peer_addr, peer.local_addr().unwrap());
+        assert_eq!(thawed.freeze().unwrap(), snapshot);
+        // it can carry on sending where the other left off, to cids the peer already has
+        assert_eq!(thawed.send_window().max_data(), 10_000);
+        assert_eq!(thawed.send_window().sent(), 1_000);
+        assert!(thawed.send_capacity() > 0);
+        assert_eq!(thawed.local_cids().get(1), Some(&issued));
+        assert_eq!(thawed.local_cids().initial(), conn.local_cids().initial());
+        assert_eq!(thawed.local_cids().next_sequence_number(), 2);
+        assert_eq!(thawed.trace_id(), conn.trace_id());
+        thawed.close(0, b"").await.unwrap();
+        assert_eq!(thawed.state(), ConnectionState::Closing);
+    }
+}
@@ -0,0 +1,318 @@
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+// the two low bits of the ip tos / traffic class byte (RFC 3168)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EcnCodepoint {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl EcnCodepoint {
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & 0b11 {
+            0b00 => Self::NotEct,
+            0b01 => Self::Ect1,
+            0b10 => Self::Ect0,
+            _ => Self::Ce,
+        }
+    }
+
+    pub fn bits(&self) -> u8 {
+        match self {
+            Self::NotEct => 0b00,
+            Self::Ect1 => 0b01,
+            Self::Ect0 => 0b10,
+            Self::Ce => 0b11,
+        }
+    }
+}
+
+// the socket options a quic stack cares about, which the tokio socket doesn't expose
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketConfig {
+    // QUIC packets MUST NOT be fragmented (RFC 9000 section 14), and mtu probes rely on it
+    pub dont_fragment: bool,
+    // marks outgoing datagrams ECT(0) and reports the codepoint of incoming ones in `RecvMeta`
+    pub ecn: bool,
+    // the upper 6 bits of the tos / traffic class byte
+    pub dscp: u8,
+    // SO_RCVBUF / SO_SNDBUF, left at the os default when unset
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            dont_fragment: true,
+            ecn: false,
+            dscp: 0,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    // a dscp past 6 bits would spill into the ecn bits, so it's refused rather than truncated
+    pub fn tos(&self) -> io::Result<u8> {
+        if self.dscp > 0x3f {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("dscp {:#x} doesn't fit in 6 bits", self.dscp),
+            ));
+        }
+        let ecn = match self.ecn {
+            true => EcnCodepoint::Ect0,
+            false => EcnCodepoint::NotEct,
+        };
+        Ok((self.dscp << 2) | ecn.bits())
+    }
+
+    pub fn apply(&self, socket: &UdpSocket) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::fd::AsRawFd;
+
+            linux::apply(self, socket.as_raw_fd(), socket.local_addr()?.is_ipv6())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = socket;
+            self.tos()?;
+            match *self == Self::default() {
+                true => Ok(()),
+                false => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "socket options are only supported on linux",
+                )),
+            }
+        }
+    }
+}
+
+// a socket bound to `addr` with SO_REUSEPORT set, so several can share the port and the kernel
+// spreads incoming datagrams between them by 4-tuple.  every socket on the port has to be bound
+// this way, by the same user
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<UdpSocket> {
+    #[cfg(target_os = "linux")]
+    {
+        UdpSocket::from_std(linux::bind_reuseport(addr)?)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = addr;
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is only supported on linux",
+        ))
+    }
+}
+
+// (SO_RCVBUF, SO_SNDBUF) as reported by the os, which may round or double what was asked for
+#[cfg(target_os = "linux")]
+pub fn buffer_sizes(socket: &UdpSocket) -> io::Result<(usize, usize)> {
+    use std::os::fd::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    Ok((
+        linux::getsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF)? as usize,
+        linux::getsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF)? as usize,
+    ))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        io,
+        mem::size_of,
+        net::SocketAddr,
+        os::fd::{FromRawFd, RawFd},
+    };
+
+    use super::SocketConfig;
+
+    pub(super) fn setsockopt(
+        fd: RawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        // SAFETY: value is valid for the duration of the call
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const _ as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn getsockopt(
+        fd: RawFd,
+        level: libc::c_int,
+        name: libc::c_int,
+    ) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for the duration of the call
+        let result = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(value),
+        }
+    }
+
+    pub(super) fn bind_reuseport(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        // SAFETY: no pointers involved, the fd is checked before it's used
+        let fd = unsafe {
+            libc::socket(
+                domain,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd is a socket we just opened and nothing else owns, it's closed on drop from
+        // here on, errors included
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+        setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+        // SAFETY: both are plain old data, and the one that matches `domain` is what's passed
+        let mut v4: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        let mut v6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        let (sockaddr, len) = match addr {
+            SocketAddr::V4(addr) => {
+                v4.sin_family = libc::AF_INET as libc::sa_family_t;
+                v4.sin_port = addr.port().to_be();
+                v4.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                (
+                    &v4 as *const _ as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in>(),
+                )
+            }
+            SocketAddr::V6(addr) => {
+                v6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                v6.sin6_port = addr.port().to_be();
+                v6.sin6_addr.s6_addr = addr.ip().octets();
+                v6.sin6_flowinfo = addr.flowinfo();
+                v6.sin6_scope_id = addr.scope_id();
+                (
+                    &v6 as *const _ as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_in6>(),
+                )
+            }
+        };
+        // SAFETY: sockaddr points at a sockaddr of len bytes that outlives the call
+        match unsafe { libc::bind(fd, sockaddr, len as libc::socklen_t) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(socket),
+        }
+    }
+
+    pub(super) fn apply(config: &SocketConfig, fd: RawFd, ipv6: bool) -> io::Result<()> {
+        // PROBE sets DF without letting the kernel's cached path mtu cap what we send
+        let pmtudisc = match config.dont_fragment {
+            true => libc::IP_PMTUDISC_PROBE,
+            false => libc::IP_PMTUDISC_DONT,
+        };
+        let tos = config.tos()? as libc::c_int;
+        let recv_tos = config.ecn as libc::c_int;
+
+        if ipv6 {
+            let pmtudisc6 = match config.dont_fragment {
+                true => libc::IPV6_PMTUDISC_PROBE,
+                false => libc::IPV6_PMTUDISC_DONT,
+            };
+            setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, pmtudisc6)?;
+            setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+            setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, recv_tos)?;
+        }
+        // a dual stack ipv6 socket still sends ipv4 (mapped) datagrams, so these are set either
+        // way.  they fail on ipv6-only sockets, which is fine
+        let ipv4 = [
+            (libc::IP_MTU_DISCOVER, pmtudisc),
+            (libc::IP_TOS, tos),
+            (libc::IP_RECVTOS, recv_tos),
+        ]
+        .into_iter()
+        .try_for_each(|(name, value)| setsockopt(fd, libc::IPPROTO_IP, name, value));
+        if !ipv6 {
+            ipv4?;
+        }
+
+        if let Some(size) = config.recv_buffer_size {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+        }
+        if let Some(size) = config.send_buffer_size {
+            setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::connection::BatchIo;
+
+    #[tokio::test]
+    async fn test_socket_config() {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.connect(b.local_addr().unwrap()).await.unwrap();
+        b.connect(a.local_addr().unwrap()).await.unwrap();
+
+        let config = SocketConfig {
+            ecn: true,
+            dscp: 0b101110,
+            recv_buffer_size: Some(1 << 20),
+            send_buffer_size: Some(1 << 20),
+            ..Default::default()
+        };
+        assert_eq!(config.tos().unwrap(), 0b1011_1010);
+        let too_big = SocketConfig {
+            dscp: 0x40,
+            ..config.clone()
+        };
+        assert!(too_big.tos().is_err());
+        assert!(too_big.apply(&a).is_err());
+        config.apply(&a).unwrap();
+        config.apply(&b).unwrap();
+
+        // linux doubles the requested size for bookkeeping, and caps it at rmem_max / wmem_max
+        let (recv, send) = buffer_sizes(&a).unwrap();
+        assert!(recv > 0 && send > 0);
+
+        let mut io = BatchIo::default();
+        io.send(&a, &[vec![1, 2, 3]]).await.unwrap();
+        let mut bufs = vec![vec![0; 1_500]];
+        let metas = io.recv(&b, &mut bufs).await.unwrap();
+        assert_eq!(bufs[0], vec![1, 2, 3]);
+        assert_eq!(metas[0].ecn, Some(EcnCodepoint::Ect0));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{collections::VecDeque, time::Instant};
+
+use crate::packet::{
+    frame::{Frame, StreamType},
+    transport_parameters::TransportParameterError,
+};
+
+use super::IoStats;
+
+// how many blocked events are kept, the oldest go first.  a peer can send as many as it likes
+pub const MAX_BLOCKED_EVENTS: usize = 64;
+
+// the peer told us it wanted to send but ran into one of our limits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blocked {
+    // DATA_BLOCKED, the connection-wide limit
+    Data { limit: u64 },
+    // STREAM_DATA_BLOCKED
+    StreamData { stream_id: u64, limit: u64 },
+    // STREAMS_BLOCKED, out of streams of this type
+    Streams { stream_type: StreamType, limit: u64 },
+}
+
+impl Blocked {
+    pub fn from_frame(frame: &Frame) -> Option<Self> {
+        match frame {
+            Frame::DataBlocked(limit) => Some(Blocked::Data {
+                limit: limit.to_inner(),
+            }),
+            Frame::StreamDataBlocked {
+                stream_id,
+                stream_data_limit,
+            } => Some(Blocked::StreamData {
+                stream_id: stream_id.to_inner(),
+                limit: stream_data_limit.to_inner(),
+            }),
+            Frame::StreamsBlocked {
+                stream_type,
+                max_streams,
+            } => Some(Blocked::Streams {
+                stream_type: *stream_type,
+                limit: max_streams.to_inner(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockedEvent {
+    pub at: Instant,
+    pub blocked: Blocked,
+}
+
+// called with every blocked event as it's recorded, e.g. to feed flow control tuning
+pub type BlockedCallback = dyn Fn(&BlockedEvent) + Send + Sync;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectionStats {
+    pub io: IoStats,
+    pub data_blocked: u64,
+    pub stream_data_blocked: u64,
+    pub streams_blocked: u64,
+    // received datagrams dropped for being over our max_udp_payload_size
+    pub oversized_datagrams: u64,
+    // packets that failed authentication with keys we had, be it corruption, key phase skew or an attack
+    pub undecryptable_packets: u64,
+    // 1-RTT packets whose key phase bit said key update, but that didn't open with the next keys
+    pub key_phase_trial_failures: u64,
+    // key updates the peer started
+    pub key_updates_received: u64,
+    // packets that arrived before their keys and didn't fit in the buffer
+    pub pending_packets_dropped: u64,
+    // queued packets too big for a datagram, dropped instead of sent
+    pub oversized_packets_dropped: u64,
+    // which of the peer's transport parameters the connection was closed over, if it was
+    pub transport_parameter_error: Option<TransportParameterError>,
+    // the most recent `MAX_BLOCKED_EVENTS`, oldest first
+    pub blocked_events: VecDeque<BlockedEvent>,
+}
+
+impl ConnectionStats {
+    pub fn record_blocked(&mut self, event: BlockedEvent) {
+        match event.blocked {
+            Blocked::Data { .. } => self.data_blocked += 1,
+            Blocked::StreamData { .. } => self.stream_data_blocked += 1,
+            Blocked::Streams { .. } => self.streams_blocked += 1,
+        }
+        if self.blocked_events.len() == MAX_BLOCKED_EVENTS {
+            self.blocked_events.pop_front();
+        }
+        self.blocked_events.push_back(event);
+    }
+}
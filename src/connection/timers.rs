@@ -0,0 +1,109 @@
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    LossDetection,
+    Pto,
+    Idle,
+    AckDelay,
+    PathValidation,
+    // when the pacer next lets a packet out
+    Pacing,
+    // the end of the closing / draining period, after which the connection is closed for good
+    Draining,
+    // how long the handshake gets before the connection gives up on it
+    Handshake,
+    // how long a connection going away waits for its streams before closing anyway
+    GoAway,
+}
+
+impl Timer {
+    pub const ALL: [Timer; 9] = [
+        Timer::LossDetection,
+        Timer::Pto,
+        Timer::Idle,
+        Timer::AckDelay,
+        Timer::PathValidation,
+        Timer::Pacing,
+        Timer::Draining,
+        Timer::Handshake,
+        Timer::GoAway,
+    ];
+}
+
+// every timer a connection runs, in one place so the driver only has to sleep until `next_timeout`
+#[derive(Debug, Default, Clone)]
+pub struct Timers {
+    deadlines: [Option<Instant>; Timer::ALL.len()],
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // (re)arms `timer`, replacing whatever deadline it had
+    pub fn set(&mut self, timer: Timer, deadline: Instant) {
+        self.deadlines[timer as usize] = Some(deadline);
+    }
+
+    pub fn stop(&mut self, timer: Timer) {
+        self.deadlines[timer as usize] = None;
+    }
+
+    pub fn get(&self, timer: Timer) -> Option<Instant> {
+        self.deadlines[timer as usize]
+    }
+
+    pub fn is_armed(&self, timer: Timer) -> bool {
+        self.get(timer).is_some()
+    }
+
+    // the earliest deadline across all timers
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.deadlines.iter().flatten().min().copied()
+    }
+
+    // disarms and returns the earliest timer that has expired by `now`
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Timer> {
+        let timer = Timer::ALL
+            .into_iter()
+            .filter(|timer| self.get(*timer).is_some_and(|deadline| deadline <= now))
+            .min_by_key(|timer| self.get(*timer))?;
+        self.stop(timer);
+        Some(timer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timers() {
+        let now = Instant::now();
+        let ms = |ms| now + Duration::from_millis(ms);
+
+        let mut timers = Timers::new();
+        assert_eq!(timers.next_timeout(), None);
+
+        timers.set(Timer::Idle, ms(30_000));
+        timers.set(Timer::AckDelay, ms(25));
+        timers.set(Timer::Pto, ms(10));
+        timers.set(Timer::Pacing, ms(1));
+        timers.stop(Timer::Pacing);
+        assert_eq!(timers.next_timeout(), Some(ms(10)));
+
+        assert_eq!(timers.pop_expired(ms(5)), None);
+        // earliest first, and each one only fires once
+        assert_eq!(timers.pop_expired(ms(30)), Some(Timer::Pto));
+        assert_eq!(timers.pop_expired(ms(30)), Some(Timer::AckDelay));
+        assert_eq!(timers.pop_expired(ms(30)), None);
+
+        // re-arming replaces the old deadline
+        timers.set(Timer::Idle, ms(60_000));
+        assert_eq!(timers.next_timeout(), Some(ms(60_000)));
+        assert!(timers.is_armed(Timer::Idle));
+    }
+}
@@ -1,7 +1,251 @@
-#[derive(PartialEq)]
-pub(crate) enum ConnectionState {
-    Handshake,
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use crate::{
+    packet::types::ConnectionId,
+    result::{QuicheError, QuicheResult},
+    stream::SharedSendStream,
+};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ConnectionState {
+    // created, nothing sent or received yet
+    Idle,
+    Handshaking,
     Connected,
+    // we sent CONNECTION_CLOSE and are waiting out the closing period
     Closing,
+    // the peer sent CONNECTION_CLOSE, nothing more gets sent
+    Draining,
     Closed,
+    // closed because of an error, with its code
+    Failed(u64),
+}
+
+impl ConnectionState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Closed | Self::Failed(_))
+    }
+
+    // the only moves a connection can make.  anything can fail, and nothing leaves a terminal state
+    pub fn can_transition_to(&self, next: ConnectionState) -> bool {
+        use ConnectionState::*;
+
+        match (self, next) {
+            (from, _) if from.is_terminal() => false,
+            (_, Failed(_)) => true,
+            (Idle, Handshaking | Closed) => true,
+            (Handshaking, Connected | Closing | Draining | Closed) => true,
+            // closed straight from connected covers the idle timeout, which is silent
+            (Connected, Closing | Draining | Closed) => true,
+            (Closing, Draining | Closed) => true,
+            (Draining, Closed) => true,
+            _ => false,
+        }
+    }
+
+    pub fn transition(&mut self, next: ConnectionState) -> QuicheResult<()> {
+        if !self.can_transition_to(next) {
+            return Err(QuicheError(format!(
+                "ConnectionState::transition: illegal transition from {:?} to {:?}",
+                self, next
+            )));
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+// which end of the connection we are
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+// things that happened to a connection that its state alone doesn't explain
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ConnectionEvent {
+    // the handshake didn't finish in time, the peer is unreachable or dropping our Initials
+    HandshakeTimedOut { after: Duration },
+    // the packet protection keys are close to their confidentiality limit and should be updated
+    // before the connection has to be closed over it
+    KeyUpdateRequired { sealed: u64 },
+    // `go_away` was called: no new streams from here on, and the connection closes by `deadline`
+    GoingAway { deadline: Instant },
+    // the go away is over and the connection is closing, either the application finished with
+    // its streams or `timed_out` and they were cut off
+    GoneAway { timed_out: bool },
+}
+
+// how the peer hears a connection is going away.  QUIC itself has no GOAWAY, HTTP/3 sends its
+// own on a control stream, other applications can do the same or just close at the deadline
+#[derive(Debug, Clone)]
+pub enum GoAwayNotice {
+    // `message` is written to `stream`, a control stream the application already has open, and
+    // the stream is finished.  what the message means is up to the application
+    ControlStream {
+        stream: SharedSendStream,
+        message: Vec<u8>,
+    },
+    // nothing is said ahead of the CONNECTION_CLOSE at the deadline
+    CloseAtDeadline,
+}
+
+// which end closed the connection
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CloseInitiator {
+    Local,
+    Peer,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CloseCause {
+    // a CONNECTION_CLOSE (0x1c), and the type of the frame that caused it if the closing end
+    // said
+    Transport {
+        error_code: u64,
+        frame_type: Option<u8>,
+    },
+    // a CONNECTION_CLOSE (0x1d), with the application's own code
+    Application {
+        error_code: u64,
+    },
+    // nothing was heard from the peer for too long, no CONNECTION_CLOSE either way
+    IdleTimeout,
+    // the handshake didn't finish within the handshake timeout, nothing was sent
+    HandshakeTimeout,
+}
+
+// why a connection ended, the first cause wins.  a connection the application closed during
+// the handshake is recorded with its code and reason, though what went out on the wire was a
+// transport APPLICATION_ERROR
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CloseReason {
+    pub initiator: CloseInitiator,
+    pub cause: CloseCause,
+    // peer controlled when it's the peer's, and not necessarily utf-8, see `reason_str`
+    pub reason: Vec<u8>,
+}
+
+impl CloseReason {
+    pub fn reason_str(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.reason)
+    }
+}
+
+// a stable id for a connection, for correlating logs, captures and metrics across cid changes.
+// it's the original destination cid in hex, the same thing qlog groups a connection's traces by
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
+pub struct TraceId(String);
+
+impl TraceId {
+    pub fn from_cid(original_dst_cid: &ConnectionId) -> Self {
+        Self(
+            original_dst_cid
+                .cid
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        )
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// a read-only view of a connection's state that can wait for it to change
+#[derive(Debug, Clone)]
+pub struct StateObserver(pub(crate) watch::Receiver<ConnectionState>);
+
+impl StateObserver {
+    pub fn state(&self) -> ConnectionState {
+        *self.0.borrow()
+    }
+
+    // waits for the state to change, returns None once the connection has gone away
+    pub async fn changed(&mut self) -> Option<ConnectionState> {
+        self.0.changed().await.ok()?;
+        Some(*self.0.borrow_and_update())
+    }
+
+    // resolves once the connection is established, or errors if it ends before getting there
+    pub async fn established(&mut self) -> QuicheResult<()> {
+        let state = self
+            .0
+            .wait_for(|state| *state == ConnectionState::Connected || state.is_terminal())
+            .await
+            .map_err(|_| QuicheError("StateObserver::established: connection dropped".into()))?;
+        match *state {
+            ConnectionState::Connected => Ok(()),
+            state => Err(QuicheError(format!(
+                "StateObserver::established: connection ended in {:?}",
+                state
+            ))),
+        }
+    }
+
+    // resolves with the terminal state once the connection has closed or failed
+    pub async fn closed(&mut self) -> ConnectionState {
+        match self.0.wait_for(ConnectionState::is_terminal).await {
+            Ok(state) => *state,
+            // dropped without ever closing properly
+            Err(_) => ConnectionState::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ConnectionState::*;
+
+    #[test]
+    fn test_transitions() {
+        let mut state = Idle;
+        assert!(state.transition(Connected).is_err());
+        assert_eq!(state, Idle);
+
+        for next in [Handshaking, Connected, Closing, Draining, Closed] {
+            state.transition(next).unwrap();
+        }
+        assert!(state.transition(Failed(0x1)).is_err());
+
+        let mut state = Connected;
+        assert!(state.transition(Handshaking).is_err());
+        state.transition(Failed(0xA)).unwrap();
+        assert!(state.is_terminal());
+        assert!(state.transition(Closed).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        let (tx, rx) = watch::channel(Idle);
+        let mut observer = StateObserver(rx);
+
+        let established = tokio::spawn({
+            let mut observer = observer.clone();
+            async move { observer.established().await }
+        });
+        tx.send_replace(Handshaking);
+        tx.send_replace(Connected);
+        established.await.unwrap().unwrap();
+
+        tx.send_replace(Failed(0x2));
+        assert_eq!(observer.closed().await, Failed(0x2));
+        assert!(observer.established().await.is_err());
+        drop(tx);
+        assert_eq!(observer.changed().await, None);
+    }
 }
@@ -0,0 +1,207 @@
+use std::{collections::VecDeque, io, net::UdpSocket, os::fd::AsRawFd};
+
+use io_uring::{opcode, squeue::Entry, types, IoUring};
+
+use super::IoStats;
+
+// set in the user_data of writes, reads carry just their slot
+const SEND: u64 = 1 << 63;
+
+// udp i/o over io_uring with registered buffers, an alternative to the tokio socket for servers
+// pushing a lot of traffic.  every call blocks, so this wants a thread of its own.
+// half the registered slots always have a read posted, the other half are used for writes
+pub struct UringIo {
+    // dropped first, so the ring is torn down before the buffers it reads into
+    ring: IoUring,
+    socket: UdpSocket,
+    bufs: Vec<Box<[u8]>>,
+    recv_slots: usize,
+    // reads that have completed but haven't been handed out yet, (slot, len)
+    ready: VecDeque<(usize, usize)>,
+    stats: IoStats,
+}
+
+impl UringIo {
+    // `socket` should already be connected to the peer
+    pub fn new(socket: UdpSocket, slots: usize, buf_size: usize) -> io::Result<Self> {
+        let slots = slots.max(1);
+        let ring = IoUring::new((slots * 2).next_power_of_two() as u32)?;
+        let bufs = (0..slots * 2)
+            .map(|_| vec![0; buf_size].into_boxed_slice())
+            .collect::<Vec<_>>();
+        let iovecs = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: the boxed buffers never move or get reallocated, and live as long as the ring
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        let mut uring = Self {
+            ring,
+            socket,
+            bufs,
+            recv_slots: slots,
+            ready: VecDeque::new(),
+            stats: IoStats::default(),
+        };
+        for slot in 0..slots {
+            uring.post_recv(slot)?;
+        }
+        uring.ring.submit()?;
+        Ok(uring)
+    }
+
+    pub fn stats(&self) -> IoStats {
+        self.stats
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn send(&mut self, datagrams: &[Vec<u8>]) -> io::Result<()> {
+        let send_slots = self.bufs.len() - self.recv_slots;
+        for batch in datagrams.chunks(send_slots) {
+            for (i, datagram) in batch.iter().enumerate() {
+                let slot = self.recv_slots + i;
+                let buf = self.bufs[slot].get_mut(..datagram.len()).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "datagram is bigger than the registered buffers",
+                    )
+                })?;
+                buf.copy_from_slice(datagram);
+                let entry = opcode::WriteFixed::new(
+                    types::Fd(self.socket.as_raw_fd()),
+                    buf.as_ptr(),
+                    datagram.len() as u32,
+                    slot as u16,
+                )
+                .build()
+                .user_data(SEND | slot as u64);
+                self.push(entry)?;
+            }
+
+            let mut pending = batch.len();
+            while pending > 0 {
+                self.stats.send_syscalls += 1;
+                self.ring.submit_and_wait(1)?;
+                pending -= self.reap()?;
+            }
+            self.stats.datagrams_sent += batch.len() as u64;
+        }
+        Ok(())
+    }
+
+    // blocks until at least one datagram is available, then copies up to `bufs.len()` of them out
+    pub fn recv(&mut self, bufs: &mut [Vec<u8>]) -> io::Result<usize> {
+        while self.ready.is_empty() {
+            self.stats.recv_syscalls += 1;
+            self.ring.submit_and_wait(1)?;
+            self.reap()?;
+        }
+
+        let mut received = 0;
+        while received < bufs.len() {
+            let Some((slot, len)) = self.ready.pop_front() else {
+                break;
+            };
+            bufs[received].clear();
+            bufs[received].extend_from_slice(&self.bufs[slot][..len]);
+            self.post_recv(slot)?;
+            received += 1;
+        }
+        self.ring.submit()?;
+        self.stats.datagrams_recv += received as u64;
+        Ok(received)
+    }
+
+    fn post_recv(&mut self, slot: usize) -> io::Result<()> {
+        let buf = &mut self.bufs[slot];
+        let entry = opcode::ReadFixed::new(
+            types::Fd(self.socket.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as u32,
+            slot as u16,
+        )
+        .build()
+        .user_data(slot as u64);
+        self.push(entry)
+    }
+
+    fn push(&mut self, entry: Entry) -> io::Result<()> {
+        // SAFETY: entries only point into registered buffers, which aren't touched until they complete
+        unsafe { self.ring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))
+    }
+
+    // drains the completion queue, stashing finished reads.  returns how many writes finished
+    fn reap(&mut self) -> io::Result<usize> {
+        let completions = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect::<Vec<_>>();
+
+        let mut sent = 0;
+        let mut error = None;
+        for (user_data, result) in completions {
+            if result < 0 {
+                error.get_or_insert(io::Error::from_raw_os_error(-result));
+            }
+            match user_data & SEND {
+                0 if result < 0 => self.post_recv(user_data as usize)?,
+                0 => self.ready.push_back((user_data as usize, result as usize)),
+                _ => sent += 1,
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(sent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uring_io() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+
+        // io_uring is often disabled in containers
+        let Ok(mut uring) = UringIo::new(a, 4, 1_500) else {
+            return;
+        };
+
+        let datagrams = (0..10u8)
+            .map(|i| vec![i; 100 + i as usize])
+            .collect::<Vec<_>>();
+        uring.send(&datagrams).unwrap();
+        assert_eq!(uring.stats().datagrams_sent, 10);
+        let mut buf = [0; 1_500];
+        for datagram in datagrams.iter() {
+            let len = b.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], datagram.as_slice());
+        }
+
+        for datagram in datagrams.iter() {
+            b.send(datagram).unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < datagrams.len() {
+            let mut bufs = vec![Vec::new(); 3];
+            let n = uring.recv(&mut bufs).unwrap();
+            received.extend(bufs.into_iter().take(n));
+        }
+        assert_eq!(received, datagrams);
+        assert!(uring.send(&[vec![0; 1_501]]).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+// the numbers RFC 9000 fixes, in one place so the codecs and anything built on the crate agree on
+// them
+
+// a client's Initial datagrams are padded to at least this, and it's the smallest max datagram
+// size a peer may advertise (RFC 9000 section 14.1)
+pub const MIN_INITIAL_SIZE: usize = 1_200;
+
+// the largest a udp payload can be, and max_udp_payload_size when the peer doesn't send one
+pub const MAX_UDP_PAYLOAD_SIZE: usize = 65_527;
+
+// the longest cid version 1 allows (RFC 9000 section 17.2)
+pub const MAX_CID_LEN: usize = 20;
+
+// how long the cids we mint are, when the codec doesn't say otherwise.  a client's first dst cid
+// has to be at least 8 bytes (RFC 9000 section 7.2)
+pub const LOCAL_CID_LEN: usize = 8;
+
+// 2^62 - 1, the largest value a varint can hold (RFC 9000 section 16)
+pub const MAX_VARINT: u64 = (1 << 62) - 1;
+
+// RFC 9000 section 10.3
+pub const STATELESS_RESET_TOKEN_LEN: usize = 16;
+
+// the AES-128-GCM tag a Retry ends with (RFC 9001 section 5.8)
+pub const RETRY_INTEGRITY_TAG_LEN: usize = 16;
+
+// what the transport parameters default to when the peer leaves them out (RFC 9000 section 18.2)
+pub const DEFAULT_ACK_DELAY_EXPONENT: u64 = 3;
+// in milliseconds
+pub const DEFAULT_MAX_ACK_DELAY: u64 = 25;
+pub const DEFAULT_ACTIVE_CONNECTION_ID_LIMIT: u64 = 2;
+
+// RFC 9000 and RFC 9369
+pub const QUIC_V1: u32 = 0x0000_0001;
+pub const QUIC_V2: u32 = 0x6b33_43cf;
+// our own, laid out as v1
+pub const MINI_QUICHE_VERSION: u32 = 0b0000_0010;
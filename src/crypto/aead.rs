@@ -0,0 +1,131 @@
+use crate::{packet::error::ProtocolError, result::QuicheResult};
+
+// start asking for a key update once this fraction of the confidentiality limit is left, so
+// there's time for it to complete before the limit is hit
+const KEY_UPDATE_HEADROOM_DIVISOR: u64 = 8;
+
+// the packet protection ciphers QUIC v1 allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    #[default]
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    // how many packets one key may protect (RFC 9001 section 6.6)
+    pub fn confidentiality_limit(&self) -> u64 {
+        match self {
+            Cipher::Aes128Gcm | Cipher::Aes256Gcm => 1 << 23,
+            // larger than the number of packets that can ever be sent
+            Cipher::ChaCha20Poly1305 => u64::MAX,
+        }
+    }
+
+    // how many packets may fail authentication over the life of the connection
+    pub fn integrity_limit(&self) -> u64 {
+        match self {
+            Cipher::Aes128Gcm | Cipher::Aes256Gcm => 1 << 52,
+            Cipher::ChaCha20Poly1305 => 1 << 36,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsageStatus {
+    Ok,
+    // getting close to the confidentiality limit, the keys should be updated
+    UpdateKeys,
+}
+
+// counts how hard the packet protection keys are being used, against the cipher's limits
+#[derive(Debug, Clone, Default)]
+pub struct KeyUsage {
+    cipher: Cipher,
+    // packets protected with the current key
+    sealed: u64,
+    // packets that failed authentication, with any key
+    open_failures: u64,
+}
+
+impl KeyUsage {
+    pub fn new(cipher: Cipher) -> Self {
+        Self {
+            cipher,
+            ..Self::default()
+        }
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn sealed(&self) -> u64 {
+        self.sealed
+    }
+
+    pub fn open_failures(&self) -> u64 {
+        self.open_failures
+    }
+
+    // a packet was protected with the current key.  past the confidentiality limit nothing more
+    // may be sent with it, which is AEAD_LIMIT_REACHED if the keys weren't updated in time
+    pub fn on_sealed(&mut self) -> QuicheResult<KeyUsageStatus> {
+        let limit = self.cipher.confidentiality_limit();
+        if self.sealed >= limit {
+            return Err(ProtocolError::AeadLimitReached.into());
+        }
+        self.sealed += 1;
+        if self.sealed >= limit - limit / KEY_UPDATE_HEADROOM_DIVISOR {
+            return Ok(KeyUsageStatus::UpdateKeys);
+        }
+        Ok(KeyUsageStatus::Ok)
+    }
+
+    // a packet failed authentication.  the count carries across key updates
+    pub fn on_open_failure(&mut self) -> QuicheResult<()> {
+        self.open_failures += 1;
+        if self.open_failures > self.cipher.integrity_limit() {
+            return Err(ProtocolError::AeadLimitReached.into());
+        }
+        Ok(())
+    }
+
+    // the keys were updated, the new ones start with a clean slate
+    pub fn on_key_update(&mut self) {
+        self.sealed = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_confidentiality_limit() {
+        let mut usage = KeyUsage::new(Cipher::Aes128Gcm);
+        let limit = Cipher::Aes128Gcm.confidentiality_limit();
+        usage.sealed = limit - limit / KEY_UPDATE_HEADROOM_DIVISOR - 2;
+        assert_eq!(usage.on_sealed().unwrap(), KeyUsageStatus::Ok);
+        assert_eq!(usage.on_sealed().unwrap(), KeyUsageStatus::UpdateKeys);
+
+        usage.sealed = limit - 1;
+        assert_eq!(usage.on_sealed().unwrap(), KeyUsageStatus::UpdateKeys);
+        assert!(usage.on_sealed().is_err());
+
+        usage.on_key_update();
+        assert_eq!(usage.on_sealed().unwrap(), KeyUsageStatus::Ok);
+    }
+
+    #[test]
+    fn test_integrity_limit() {
+        let mut usage = KeyUsage::new(Cipher::ChaCha20Poly1305);
+        usage.open_failures = Cipher::ChaCha20Poly1305.integrity_limit() - 1;
+        usage.on_open_failure().unwrap();
+        // key updates don't reset it
+        usage.on_key_update();
+        assert!(usage.on_open_failure().is_err());
+        assert_eq!(usage.sealed(), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use std::io::Read;
+
+use crate::result::{require, QuicheError, QuicheResult};
+
+// the most a TLS handshake message can hold, a 24 bit length
+pub const MAX_CERTIFICATE_MESSAGE_LEN: usize = (1 << 24) - 1;
+
+// certificate compression algorithms (RFC 8879 section 3).  zstd has a code point but isn't
+// implemented, it's here so a peer offering it can be parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CertificateCompressionAlgorithm {
+    Zlib,
+    Brotli,
+    Zstd,
+}
+
+impl CertificateCompressionAlgorithm {
+    pub const ALL: [CertificateCompressionAlgorithm; 3] = [
+        CertificateCompressionAlgorithm::Zlib,
+        CertificateCompressionAlgorithm::Brotli,
+        CertificateCompressionAlgorithm::Zstd,
+    ];
+
+    pub fn code(&self) -> u16 {
+        match self {
+            CertificateCompressionAlgorithm::Zlib => 1,
+            CertificateCompressionAlgorithm::Brotli => 2,
+            CertificateCompressionAlgorithm::Zstd => 3,
+        }
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.code() == code)
+    }
+
+    // whether this build can compress and decompress with it, which is down to cargo features
+    pub fn is_supported(&self) -> bool {
+        match self {
+            CertificateCompressionAlgorithm::Zlib => cfg!(feature = "cert-compression-zlib"),
+            CertificateCompressionAlgorithm::Brotli => cfg!(feature = "cert-compression-brotli"),
+            CertificateCompressionAlgorithm::Zstd => false,
+        }
+    }
+
+    // everything this build supports, in the order we'd rather use them
+    pub fn supported() -> Vec<Self> {
+        [
+            CertificateCompressionAlgorithm::Brotli,
+            CertificateCompressionAlgorithm::Zlib,
+        ]
+        .into_iter()
+        .filter(Self::is_supported)
+        .collect()
+    }
+
+    // the first of `ours` the peer offered in its compress_certificate extension
+    pub fn negotiate(ours: &[Self], peers: &[Self]) -> Option<Self> {
+        ours.iter()
+            .find(|algorithm| algorithm.is_supported() && peers.contains(algorithm))
+            .copied()
+    }
+}
+
+// the CompressedCertificate handshake message (RFC 8879 section 4), which replaces Certificate.
+// shrinking the chain is what keeps a server's first flight inside the amplification limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressedCertificate {
+    pub algorithm: CertificateCompressionAlgorithm,
+    pub uncompressed_length: usize,
+    pub compressed_certificate_message: Vec<u8>,
+}
+
+impl CompressedCertificate {
+    // compresses an encoded Certificate message
+    pub fn compress(
+        algorithm: CertificateCompressionAlgorithm,
+        certificate_message: &[u8],
+    ) -> QuicheResult<Self> {
+        require(
+            !certificate_message.is_empty()
+                && certificate_message.len() <= MAX_CERTIFICATE_MESSAGE_LEN,
+            "CompressedCertificate::compress: certificate message is empty or too long",
+        )?;
+        let compressed_certificate_message = compress(algorithm, certificate_message)?;
+        Ok(Self {
+            algorithm,
+            uncompressed_length: certificate_message.len(),
+            compressed_certificate_message,
+        })
+    }
+
+    // the Certificate message back.  anything that doesn't decompress to exactly
+    // `uncompressed_length` bytes is a bad_certificate alert, and decompression stops there so a
+    // small message can't be made to expand without bound
+    pub fn decompress(&self) -> QuicheResult<Vec<u8>> {
+        let certificate_message = decompress(
+            self.algorithm,
+            &self.compressed_certificate_message,
+            self.uncompressed_length,
+        )?;
+        require(
+            certificate_message.len() == self.uncompressed_length,
+            "CompressedCertificate::decompress: bad_certificate, wrong uncompressed length",
+        )?;
+        Ok(certificate_message)
+    }
+
+    // algorithm (2 bytes), uncompressed_length (3), then the compressed message with a 3 byte length
+    pub fn encode(&self) -> Vec<u8> {
+        let len = self.compressed_certificate_message.len();
+        let mut buf = Vec::with_capacity(8 + len);
+        buf.extend(self.algorithm.code().to_be_bytes());
+        buf.extend(&(self.uncompressed_length as u32).to_be_bytes()[1..]);
+        buf.extend(&(len as u32).to_be_bytes()[1..]);
+        buf.extend(&self.compressed_certificate_message);
+        buf
+    }
+
+    pub fn decode(bytes: &[u8]) -> QuicheResult<Self> {
+        require(bytes.len() >= 8, "CompressedCertificate::decode: too short")?;
+        let code = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let algorithm = CertificateCompressionAlgorithm::from_code(code).ok_or_else(|| {
+            QuicheError(format!(
+                "CompressedCertificate::decode: unknown algorithm {}",
+                code
+            ))
+        })?;
+        let uncompressed_length = u24(&bytes[2..5]);
+        let len = u24(&bytes[5..8]);
+        require(
+            uncompressed_length > 0 && len > 0 && bytes.len() == 8 + len,
+            "CompressedCertificate::decode: bad lengths",
+        )?;
+        Ok(Self {
+            algorithm,
+            uncompressed_length,
+            compressed_certificate_message: bytes[8..].to_vec(),
+        })
+    }
+}
+
+fn u24(bytes: &[u8]) -> usize {
+    u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize
+}
+
+fn unsupported(algorithm: CertificateCompressionAlgorithm) -> QuicheError {
+    QuicheError(format!(
+        "certificate compression: {:?} isn't enabled in this build",
+        algorithm
+    ))
+}
+
+#[cfg_attr(
+    not(any(feature = "cert-compression-zlib", feature = "cert-compression-brotli")),
+    allow(unused_variables)
+)]
+fn compress(algorithm: CertificateCompressionAlgorithm, data: &[u8]) -> QuicheResult<Vec<u8>> {
+    match algorithm {
+        #[cfg(feature = "cert-compression-zlib")]
+        CertificateCompressionAlgorithm::Zlib => {
+            use std::io::Write;
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(feature = "cert-compression-brotli")]
+        CertificateCompressionAlgorithm::Brotli => {
+            use std::io::Write;
+            let mut compressed = Vec::new();
+            {
+                // quality 11, 22 bit window: the chain is compressed once and sent many times
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(compressed)
+        }
+        algorithm => Err(unsupported(algorithm)),
+    }
+}
+
+fn decompress(
+    algorithm: CertificateCompressionAlgorithm,
+    data: &[u8],
+    limit: usize,
+) -> QuicheResult<Vec<u8>> {
+    // one byte past the limit is enough to know it's wrong
+    let mut decompressed = Vec::new();
+    decoder(algorithm, data)?
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg_attr(
+    not(any(feature = "cert-compression-zlib", feature = "cert-compression-brotli")),
+    allow(unused_variables)
+)]
+fn decoder(
+    algorithm: CertificateCompressionAlgorithm,
+    data: &[u8],
+) -> QuicheResult<Box<dyn Read + '_>> {
+    match algorithm {
+        #[cfg(feature = "cert-compression-zlib")]
+        CertificateCompressionAlgorithm::Zlib => Ok(Box::new(flate2::read::ZlibDecoder::new(data))),
+        #[cfg(feature = "cert-compression-brotli")]
+        CertificateCompressionAlgorithm::Brotli => {
+            Ok(Box::new(brotli::Decompressor::new(data, 4096)))
+        }
+        algorithm => Err(unsupported(algorithm)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use CertificateCompressionAlgorithm::*;
+
+    // something shaped like a chain: a lot of repeated DER structure
+    fn certificate_message() -> Vec<u8> {
+        (0..4_000).map(|i| (i % 97) as u8).collect()
+    }
+
+    #[test]
+    fn test_certificate_compression() {
+        assert_eq!(CertificateCompressionAlgorithm::from_code(2), Some(Brotli));
+        assert_eq!(CertificateCompressionAlgorithm::from_code(4), None);
+        assert!(!Zstd.is_supported());
+        assert!(CompressedCertificate::compress(Zstd, &certificate_message()).is_err());
+        assert_eq!(
+            CertificateCompressionAlgorithm::negotiate(&[Zstd], &[Zstd]),
+            None
+        );
+
+        let message = CompressedCertificate {
+            algorithm: Zlib,
+            uncompressed_length: 300,
+            compressed_certificate_message: vec![1, 2, 3],
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded[..8], [0, 1, 0, 1, 44, 0, 0, 3]);
+        assert_eq!(CompressedCertificate::decode(&encoded).unwrap(), message);
+        assert!(CompressedCertificate::decode(&encoded[..10]).is_err());
+        assert!(CompressedCertificate::decode(&[0, 9, 0, 0, 1, 0, 0, 1, 0]).is_err());
+
+        for algorithm in CertificateCompressionAlgorithm::supported() {
+            let original = certificate_message();
+            let compressed = CompressedCertificate::compress(algorithm, &original).unwrap();
+            assert!(compressed.compressed_certificate_message.len() < original.len() / 4);
+            let decoded = CompressedCertificate::decode(&compressed.encode()).unwrap();
+            assert_eq!(decoded.decompress().unwrap(), original);
+
+            // lying about the length either way is bad_certificate
+            for uncompressed_length in [original.len() - 1, original.len() + 1] {
+                let wrong = CompressedCertificate {
+                    uncompressed_length,
+                    ..compressed.clone()
+                };
+                assert!(wrong.decompress().is_err());
+            }
+            assert_eq!(
+                CertificateCompressionAlgorithm::negotiate(&[Zstd, algorithm], &[algorithm, Zlib]),
+                Some(algorithm)
+            );
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    packet::{error::ProtocolError, frame::Frame},
+    result::QuicheResult,
+    SmallBytes, VarInt,
+};
+
+// how far past what TLS has read the peer's CRYPTO data may run, in each space.  RFC 9000 section
+// 7.5 asks for at least 4096 bytes, this leaves room for a long certificate chain arriving out
+// of order
+pub const DEFAULT_MAX_CRYPTO_BUFFER: usize = 64 * 1024;
+
+// the CRYPTO data we're sending in one packet number space.  a server's first flight (its
+// certificate chain especially) is usually too big for one datagram, so it's cut into frames that
+// each fit the room a packet has left, and anything in a lost packet is sent again before new data
+#[derive(Debug, Clone, Default)]
+pub struct CryptoSendBuffer {
+    // everything written so far, from offset 0
+    data: Vec<u8>,
+    // where data that's never been sent starts
+    next_offset: u64,
+    // offset -> end of ranges that were lost and need sending again
+    lost: BTreeMap<u64, u64>,
+    // packet number -> the (offset, end) it carried
+    in_flight: BTreeMap<u64, (u64, u64)>,
+}
+
+impl CryptoSendBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // whether there's anything left to put in a packet, be it new or lost
+    pub fn has_pending(&self) -> bool {
+        !self.lost.is_empty() || self.next_offset < self.data.len() as u64
+    }
+
+    // bytes that haven't been sent, or were lost and haven't been sent again
+    pub fn pending(&self) -> u64 {
+        let lost = self
+            .lost
+            .iter()
+            .map(|(offset, end)| end - offset)
+            .sum::<u64>();
+        lost + self.data.len() as u64 - self.next_offset
+    }
+
+    // every byte has been acknowledged
+    pub fn is_complete(&self) -> bool {
+        !self.has_pending() && self.in_flight.is_empty()
+    }
+
+    // the next CRYPTO frame for packet `packet_number`, no bigger than `max_size` encoded.  lost
+    // data goes first, oldest first.  None if there's nothing to send or no room for a byte of it
+    pub fn next_frame(&mut self, packet_number: u64, max_size: usize) -> Option<Frame> {
+        let (offset, end) = match self.lost.first_key_value() {
+            Some((&offset, &end)) => (offset, end),
+            None if self.next_offset < self.data.len() as u64 => {
+                (self.next_offset, self.data.len() as u64)
+            }
+            None => return None,
+        };
+
+        // type + offset + length, assuming the length takes as many bytes as the largest it could be
+        let overhead = 1 + VarInt(offset).size() + VarInt(max_size as u64).size();
+        let len = (end - offset).min(max_size.checked_sub(overhead)? as u64);
+        if len == 0 {
+            return None;
+        }
+        let sent_end = offset + len;
+        if self.lost.remove(&offset).is_some() {
+            if sent_end < end {
+                self.lost.insert(sent_end, end);
+            }
+        } else {
+            self.next_offset = sent_end;
+        }
+        self.in_flight.insert(packet_number, (offset, sent_end));
+
+        Some(Frame::Crypto {
+            offset: VarInt(offset),
+            crypto_length: VarInt(len),
+            crypto_data: SmallBytes::from_slice(&self.data[offset as usize..sent_end as usize]),
+        })
+    }
+
+    pub fn on_packet_acked(&mut self, packet_number: u64) {
+        self.in_flight.remove(&packet_number);
+    }
+
+    // whatever `packet_number` carried goes back in line, ahead of new data
+    pub fn on_packet_lost(&mut self, packet_number: u64) {
+        if let Some((offset, end)) = self.in_flight.remove(&packet_number) {
+            self.lost.insert(offset, end);
+        }
+    }
+}
+
+// the CRYPTO data we've received in one packet number space, put back in order for TLS.  data
+// that arrives ahead of a gap is held until the gap fills, up to `max_buffered` bytes past what's
+// been read.  a peer that goes further is a CRYPTO_BUFFER_EXCEEDED (RFC 9000 section 7.5)
+#[derive(Debug, Clone)]
+pub struct CryptoRecvBuffer {
+    // everything before this has been read
+    read_offset: u64,
+    // offset -> data at or past `read_offset`, never overlapping
+    pending: BTreeMap<u64, Vec<u8>>,
+    max_buffered: usize,
+}
+
+impl Default for CryptoRecvBuffer {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CRYPTO_BUFFER)
+    }
+}
+
+impl CryptoRecvBuffer {
+    pub fn new(max_buffered: usize) -> Self {
+        Self {
+            read_offset: 0,
+            pending: BTreeMap::new(),
+            max_buffered,
+        }
+    }
+
+    pub fn set_max_buffered(&mut self, max_buffered: usize) {
+        self.max_buffered = max_buffered;
+    }
+
+    pub fn max_buffered(&self) -> usize {
+        self.max_buffered
+    }
+
+    // bytes held waiting for a gap before them to fill
+    pub fn buffered(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    // a CRYPTO frame's data.  whatever was already received, or read, is ignored
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> QuicheResult<()> {
+        let end = offset + data.len() as u64;
+        if end <= self.read_offset {
+            return Ok(());
+        }
+        if end - self.read_offset > self.max_buffered as u64 {
+            return Err(ProtocolError::CryptoBufferExceeded.into());
+        }
+
+        // only the gaps between what's already held get filled
+        let mut at = offset.max(self.read_offset);
+        let mut gaps = Vec::new();
+        for (&start, held) in self.pending.range(..end) {
+            let held_end = start + held.len() as u64;
+            if held_end <= at {
+                continue;
+            }
+            if start > at {
+                gaps.push((at, start));
+            }
+            at = held_end;
+        }
+        if at < end {
+            gaps.push((at, end));
+        }
+        for (start, gap_end) in gaps {
+            let from = (start - offset) as usize;
+            let to = (gap_end - offset) as usize;
+            self.pending.insert(start, data[from..to].to_vec());
+        }
+        Ok(())
+    }
+
+    // everything that's now in order, empty if the next byte hasn't arrived
+    pub fn read(&mut self) -> Vec<u8> {
+        let mut data = Vec::new();
+        while let Some(held) = self.pending.remove(&self.read_offset) {
+            self.read_offset += held.len() as u64;
+            data.extend(held);
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(frame: Frame) -> (u64, u64) {
+        match frame {
+            Frame::Crypto {
+                offset,
+                crypto_length,
+                ..
+            } => (offset.0, offset.0 + crypto_length.0),
+            frame => panic!("expected a CRYPTO frame, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn test_crypto_send_buffer() {
+        let mut buffer = CryptoSendBuffer::new();
+        // a 3000 byte certificate chain doesn't fit in one 1200 byte packet
+        let chain = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        buffer.write(&chain);
+
+        let frame = buffer.next_frame(0, 1200).unwrap();
+        assert!(frame.encode().len() <= 1200);
+        assert_eq!(range(frame.clone()), (0, 1196));
+        let Frame::Crypto { crypto_data, .. } = frame else {
+            unreachable!()
+        };
+        assert_eq!(crypto_data.as_ref(), &chain[..1196]);
+
+        assert_eq!(range(buffer.next_frame(1, 1200).unwrap()), (1196, 2391));
+        buffer.on_packet_acked(0);
+        // packet 1 was lost, it's sent again (in smaller pieces) before the rest of the chain
+        buffer.on_packet_lost(1);
+        assert_eq!(buffer.pending(), 3000 - 1196);
+        assert_eq!(range(buffer.next_frame(2, 600).unwrap()), (1196, 1791));
+        assert_eq!(range(buffer.next_frame(3, 1200).unwrap()), (1791, 2391));
+        assert_eq!(range(buffer.next_frame(4, 1200).unwrap()), (2391, 3000));
+        assert!(buffer.next_frame(5, 1200).is_none());
+        // no room for even one byte
+        buffer.write(&[0]);
+        assert!(buffer.next_frame(5, 4).is_none());
+        assert!(buffer.next_frame(5, 5).is_some());
+
+        for packet_number in 2..=5 {
+            assert!(!buffer.is_complete());
+            buffer.on_packet_acked(packet_number);
+        }
+        assert!(buffer.is_complete());
+    }
+
+    #[test]
+    fn test_crypto_recv_buffer() {
+        let mut buffer = CryptoRecvBuffer::new(100);
+        let data = (0..100).collect::<Vec<u8>>();
+
+        // out of order, with a retransmission overlapping both sides of what's held
+        buffer.write(40, &data[40..60]).unwrap();
+        assert!(buffer.read().is_empty());
+        buffer.write(30, &data[30..70]).unwrap();
+        assert_eq!(buffer.buffered(), 40);
+        buffer.write(0, &data[..30]).unwrap();
+        assert_eq!(buffer.read(), &data[..70]);
+        assert_eq!(buffer.buffered(), 0);
+
+        // already read, or a duplicate
+        buffer.write(10, &data[10..20]).unwrap();
+        buffer.write(60, &data[60..75]).unwrap();
+        assert_eq!(buffer.read(), &data[70..75]);
+
+        // no further than 100 bytes past what's been read
+        buffer.write(170, &[0; 5]).unwrap();
+        assert!(buffer.write(170, &[0; 6]).is_err());
+    }
+}
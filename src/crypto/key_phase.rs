@@ -0,0 +1,259 @@
+use std::mem;
+
+use crate::{
+    packet::{error::ProtocolError, one_rtt::PacketOpener},
+    result::QuicheError,
+};
+
+// which keys opened a 1-RTT packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyGeneration {
+    Current,
+    // the ones before the last key update, for packets that were reordered across it
+    Previous,
+    // the next ones: the peer updated its keys with this packet, and they're the current ones now
+    Next,
+}
+
+#[derive(Debug)]
+pub enum KeyPhaseError {
+    // the keys the key phase bit pointed at didn't open the packet.  `trial` if those were the
+    // next generation's, i.e. it would have been a key update
+    Open { err: QuicheError, trial: bool },
+    // the old keys opened a packet numbered after the key update.  the peer went back to keys it
+    // had moved on from, a KEY_UPDATE_ERROR (RFC 9001 section 6.4)
+    Rollback,
+}
+
+impl KeyPhaseError {
+    // the header couldn't be read, before any keys were tried
+    pub fn header(err: QuicheError) -> Self {
+        KeyPhaseError::Open { err, trial: false }
+    }
+}
+
+impl From<KeyPhaseError> for QuicheError {
+    fn from(err: KeyPhaseError) -> Self {
+        match err {
+            KeyPhaseError::Open { err, .. } => err,
+            KeyPhaseError::Rollback => ProtocolError::KeyUpdateError.into(),
+        }
+    }
+}
+
+// the receive side of key updates (RFC 9001 section 6.3).  a packet whose key phase bit matches
+// ours is opened with the current keys.  one where it differs is from before the last update if
+// it's numbered before the first packet after it, otherwise it's the peer starting the next
+// one and gets a trial with the next keys.  a failed trial is only a forged or corrupt packet,
+// the phase doesn't change until one opens
+#[derive(Debug)]
+pub struct KeyPhases<O> {
+    current: O,
+    next: O,
+    // dropped some time after an update, see `discard_previous`
+    previous: Option<O>,
+    key_phase: bool,
+    // the lowest packet number opened with `current`
+    first_in_phase: Option<u64>,
+    updates: u64,
+    trial_failures: u64,
+}
+
+impl<O: PacketOpener> KeyPhases<O> {
+    // the 1-RTT keys the handshake ended with, key phase 0
+    pub fn new(opener: O) -> Result<Self, QuicheError> {
+        Ok(Self {
+            next: opener.next_keys()?,
+            current: opener,
+            previous: None,
+            key_phase: false,
+            first_in_phase: None,
+            updates: 0,
+            trial_failures: 0,
+        })
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.key_phase
+    }
+
+    // key updates the peer has made
+    pub fn updates(&self) -> u64 {
+        self.updates
+    }
+
+    // packets that looked like a key update but didn't open with the next keys
+    pub fn trial_failures(&self) -> u64 {
+        self.trial_failures
+    }
+
+    // header protection keys don't change with the key phase
+    pub fn header_keys(&self) -> &O {
+        &self.current
+    }
+
+    // once there's been time for the stragglers to arrive, three PTOs say (RFC 9001 section 6.5).
+    // after this a packet with the old key phase can only be another update
+    pub fn discard_previous(&mut self) {
+        self.previous = None;
+    }
+
+    // opens `payload` in place with the keys `key_phase` points at.  `packet_number` is the full
+    // one, not what was on the wire
+    pub fn open(
+        &mut self,
+        key_phase: bool,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> Result<(usize, KeyGeneration), KeyPhaseError> {
+        let open_error = |err| KeyPhaseError::Open { err, trial: false };
+        if key_phase == self.key_phase {
+            let len = self
+                .current
+                .open_in_place(packet_number, header, payload)
+                .map_err(open_error)?;
+            self.first_in_phase = Some(
+                self.first_in_phase
+                    .map_or(packet_number, |first| first.min(packet_number)),
+            );
+            return Ok((len, KeyGeneration::Current));
+        }
+        if let (Some(previous), Some(first)) = (&self.previous, self.first_in_phase) {
+            if packet_number < first {
+                let len = previous
+                    .open_in_place(packet_number, header, payload)
+                    .map_err(open_error)?;
+                return Ok((len, KeyGeneration::Previous));
+            }
+        }
+
+        // opening is in place, so if the trial fails the old keys need the ciphertext back
+        let ciphertext = self.previous.is_some().then(|| payload.to_vec());
+        match self.next.open_in_place(packet_number, header, payload) {
+            Ok(len) => {
+                let after_next = self
+                    .next
+                    .next_keys()
+                    .map_err(|err| KeyPhaseError::Open { err, trial: true })?;
+                let next = mem::replace(&mut self.next, after_next);
+                self.previous = Some(mem::replace(&mut self.current, next));
+                self.key_phase = !self.key_phase;
+                self.first_in_phase = Some(packet_number);
+                self.updates += 1;
+                Ok((len, KeyGeneration::Next))
+            }
+            Err(err) => {
+                self.trial_failures += 1;
+                if let (Some(previous), Some(ciphertext)) = (&self.previous, ciphertext) {
+                    payload.copy_from_slice(&ciphertext);
+                    if previous
+                        .open_in_place(packet_number, header, payload)
+                        .is_ok()
+                    {
+                        return Err(KeyPhaseError::Rollback);
+                    }
+                }
+                Err(KeyPhaseError::Open { err, trial: true })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::result::QuicheResult;
+
+    // keys are a generation number.  a packet's first byte is the generation it was sealed with,
+    // which only the same generation's keys accept
+    #[derive(Debug)]
+    struct Generation(u8);
+
+    impl PacketOpener for Generation {
+        fn header_mask(&self, _datagram: &[u8], _pn_offset: usize) -> QuicheResult<[u8; 5]> {
+            Ok([0; 5])
+        }
+
+        fn open_in_place(
+            &self,
+            _packet_number: u64,
+            _header: &[u8],
+            payload: &mut [u8],
+        ) -> QuicheResult<usize> {
+            let sealed_with = payload[0];
+            // scribbles over the payload either way, like an AEAD would
+            payload[0] = 0xff;
+            match sealed_with == self.0 {
+                true => Ok(payload.len()),
+                false => Err(QuicheError("bad tag".to_string())),
+            }
+        }
+
+        fn next_keys(&self) -> QuicheResult<Self> {
+            Ok(Generation(self.0 + 1))
+        }
+    }
+
+    fn open(
+        keys: &mut KeyPhases<Generation>,
+        key_phase: bool,
+        packet_number: u64,
+        generation: u8,
+    ) -> Result<KeyGeneration, KeyPhaseError> {
+        let mut payload = [generation, 0, 0];
+        keys.open(key_phase, packet_number, &[], &mut payload)
+            .map(|(_, opened)| opened)
+    }
+
+    fn opened(
+        keys: &mut KeyPhases<Generation>,
+        key_phase: bool,
+        packet_number: u64,
+        generation: u8,
+    ) -> KeyGeneration {
+        open(keys, key_phase, packet_number, generation).unwrap()
+    }
+
+    #[test]
+    fn test_key_phases() {
+        let mut keys = KeyPhases::new(Generation(0)).unwrap();
+        assert_eq!(opened(&mut keys, false, 0, 0), KeyGeneration::Current);
+        assert_eq!(opened(&mut keys, false, 1, 0), KeyGeneration::Current);
+
+        // a flipped bit that doesn't open with the next keys changes nothing
+        assert!(matches!(
+            open(&mut keys, true, 2, 0),
+            Err(KeyPhaseError::Open { trial: true, .. })
+        ));
+        assert_eq!(keys.trial_failures(), 1);
+        assert!(!keys.key_phase());
+
+        // the peer updates
+        assert_eq!(opened(&mut keys, true, 5, 1), KeyGeneration::Next);
+        assert!(keys.key_phase());
+        assert_eq!(keys.updates(), 1);
+        assert_eq!(opened(&mut keys, true, 6, 1), KeyGeneration::Current);
+        // one from before the update, reordered
+        assert_eq!(opened(&mut keys, false, 4, 0), KeyGeneration::Previous);
+        // the old keys after the update is a rollback
+        assert!(matches!(
+            open(&mut keys, false, 7, 0),
+            Err(KeyPhaseError::Rollback)
+        ));
+        assert_eq!(keys.trial_failures(), 2);
+
+        // and the next update after that
+        assert_eq!(opened(&mut keys, false, 8, 2), KeyGeneration::Next);
+        assert!(!keys.key_phase());
+        keys.discard_previous();
+        assert!(matches!(
+            open(&mut keys, true, 3, 1),
+            Err(KeyPhaseError::Open { trial: true, .. })
+        ));
+        assert_eq!(
+            QuicheError::from(KeyPhaseError::Rollback).0,
+            QuicheError::from(ProtocolError::KeyUpdateError).0
+        );
+    }
+}
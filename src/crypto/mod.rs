@@ -0,0 +1,13 @@
+pub mod aead;
+pub mod cert_compression;
+pub mod flight;
+pub mod key_phase;
+pub mod pending;
+pub mod session;
+
+pub use aead::*;
+pub use cert_compression::*;
+pub use flight::*;
+pub use key_phase::*;
+pub use pending::*;
+pub use session::*;
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use crate::packet::EncryptionLevel;
+
+// how many packets can wait for keys we don't have yet, per encryption level (RFC 9001 section 5.7
+// says to buffer "a limited number"), anything past that is dropped like it was lost
+pub const DEFAULT_MAX_PENDING_PACKETS: usize = 16;
+
+// packets that arrived before the keys to remove their protection, e.g. Handshake packets
+// coalesced behind the server's Initial, or the client's 0-rtt / the server's 1-rtt racing the
+// handshake flight.  they're retried in arrival order once the keys are installed.  each level
+// gets its own share, so a burst of early 1-rtt packets can't crowd out the Handshake packets
+// that are needed to get the 1-rtt keys in the first place
+#[derive(Debug)]
+pub struct PendingPackets {
+    packets: VecDeque<(EncryptionLevel, Vec<u8>)>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl PendingPackets {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    // packets turned away because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn pending(&self, level: EncryptionLevel) -> usize {
+        self.packets
+            .iter()
+            .filter(|(packet_level, _)| *packet_level == level)
+            .count()
+    }
+
+    // returns false if there was no room for it
+    pub fn push(&mut self, level: EncryptionLevel, packet: Vec<u8>) -> bool {
+        if self.pending(level) >= self.capacity {
+            self.dropped += 1;
+            return false;
+        }
+        self.packets.push_back((level, packet));
+        true
+    }
+
+    // everything waiting on `level`'s keys, in the order it arrived
+    pub fn take(&mut self, level: EncryptionLevel) -> Vec<Vec<u8>> {
+        let (ready, waiting) = std::mem::take(&mut self.packets)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|(packet_level, _)| *packet_level == level);
+        self.packets = waiting;
+        ready.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    // drops everything waiting on `level`, e.g. once the server has rejected 0-rtt.
+    // returns how many packets went
+    pub fn discard(&mut self, level: EncryptionLevel) -> usize {
+        self.take(level).len()
+    }
+}
+
+impl Default for PendingPackets {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_PENDING_PACKETS)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use EncryptionLevel::*;
+
+    #[test]
+    fn test_pending_packets() {
+        let mut pending = PendingPackets::new(2);
+        for (level, byte) in [(OneRtt, 0), (Handshake, 1), (OneRtt, 2), (OneRtt, 3)] {
+            pending.push(level, vec![byte]);
+        }
+        // the third 1-rtt packet didn't fit, but the handshake one still did
+        assert_eq!(pending.dropped(), 1);
+        assert!(pending.push(Handshake, vec![4]));
+        assert!(!pending.push(Handshake, vec![5]));
+
+        assert_eq!(pending.take(Handshake), vec![vec![1], vec![4]]);
+        pending.push(ZeroRtt, vec![6]);
+        assert_eq!(pending.discard(ZeroRtt), 1);
+        assert_eq!(pending.take(OneRtt), vec![vec![0], vec![2]]);
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    packet::{error::ProtocolError, transport_parameters::TransportParameters},
+    result::{QuicheError, QuicheResult},
+    stream::SendWindow,
+};
+
+pub const DEFAULT_SESSION_CACHE_CAPACITY: usize = 256;
+
+// a ticket from the server's NewSessionTicket, and the transport parameters of the connection it
+// came on.  0-rtt on the resumed connection is held to those (RFC 9000 section 7.4.1), since the
+// server's new ones aren't known until the handshake is done
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTicket {
+    // opaque to the client, it goes back in the ClientHello
+    pub ticket: Vec<u8>,
+    // only the ones a client remembers, see `TransportParameters::remembered`
+    pub params: TransportParameters,
+    pub expires: Instant,
+}
+
+impl SessionTicket {
+    pub fn new(
+        ticket: Vec<u8>,
+        params: &TransportParameters,
+        lifetime: Duration,
+        now: Instant,
+    ) -> Self {
+        Self {
+            ticket,
+            params: params.remembered(),
+            expires: now + lifetime,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires
+    }
+}
+
+// a client's tickets, one per server name.  a ticket is taken out to be used, so it's never used
+// twice and a passive observer can't link the connections (RFC 8446 appendix C.4).  when it's
+// full the ticket closest to expiring makes room
+#[derive(Debug)]
+pub struct SessionCache {
+    tickets: HashMap<String, SessionTicket>,
+    capacity: usize,
+}
+
+impl Default for SessionCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_CACHE_CAPACITY)
+    }
+}
+
+impl SessionCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tickets: HashMap::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tickets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tickets.is_empty()
+    }
+
+    // replaces any ticket already there for `server_name`, the newest one is the one to use
+    pub fn insert(&mut self, server_name: &str, ticket: SessionTicket) {
+        if self.capacity == 0 {
+            return;
+        }
+        // names are case-insensitive, so a differently cased one replaces rather than evicts
+        let server_name = server_name.to_ascii_lowercase();
+        if !self.tickets.contains_key(&server_name) && self.tickets.len() >= self.capacity {
+            let oldest = self
+                .tickets
+                .iter()
+                .min_by_key(|(_, ticket)| ticket.expires)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.tickets.remove(&oldest);
+            }
+        }
+        self.tickets.insert(server_name, ticket);
+    }
+
+    // the ticket for `server_name`, if there's one that hasn't expired
+    pub fn take(&mut self, server_name: &str, now: Instant) -> Option<SessionTicket> {
+        self.tickets
+            .remove(&server_name.to_ascii_lowercase())
+            .filter(|ticket| !ticket.is_expired(now))
+    }
+}
+
+// what a resumed client has left to send in 0-rtt.  the defaults would allow nothing at all, so
+// it's the remembered limits that apply until the server's own transport parameters arrive
+#[derive(Debug, Clone)]
+pub struct ZeroRttLimits {
+    params: TransportParameters,
+    data: SendWindow,
+    streams: HashMap<u64, SendWindow>,
+}
+
+impl ZeroRttLimits {
+    pub fn new(params: TransportParameters) -> Self {
+        Self {
+            data: SendWindow::new(params.initial_max_data),
+            params,
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn params(&self) -> &TransportParameters {
+        &self.params
+    }
+
+    // how much more can go out in 0-rtt across all streams
+    pub fn available(&self) -> u64 {
+        self.data.available()
+    }
+
+    // 0-rtt STREAM data on `stream_id` reaching `offset` is about to go out.  anything past the
+    // remembered stream count, stream limit or connection limit is refused, and nothing is
+    // charged for it
+    pub fn on_stream_sent(&mut self, stream_id: u64, offset: u64) -> QuicheResult<()> {
+        // a client can only send on streams it opened, which have the low bit clear
+        let bidi = stream_id & 0b10 == 0;
+        let (max_streams, max_stream_data) = match bidi {
+            true => (
+                self.params.initial_max_streams_bidi,
+                self.params.initial_max_stream_data_bidi_remote,
+            ),
+            false => (
+                self.params.initial_max_streams_uni,
+                self.params.initial_max_stream_data_uni,
+            ),
+        };
+        if stream_id & 1 != 0 || stream_id >> 2 >= max_streams {
+            return Err(QuicheError(format!(
+                "ZeroRttLimits::on_stream_sent: stream {} is over the remembered stream limit",
+                stream_id
+            )));
+        }
+        let stream = self
+            .streams
+            .entry(stream_id)
+            .or_insert_with(|| SendWindow::new(max_stream_data));
+        let added = offset.saturating_sub(stream.sent());
+        if offset > stream.max_data() || added > self.data.available() {
+            return Err(ProtocolError::FlowControlError.into());
+        }
+        stream.on_sent(offset)?;
+        self.data.on_sent(self.data.sent() + added)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_cache() {
+        let now = Instant::now();
+        let params = TransportParameters {
+            initial_max_data: 1_000,
+            max_idle_timeout: 30_000,
+            ..Default::default()
+        };
+        let ticket = SessionTicket::new(vec![1], &params, Duration::from_secs(60), now);
+        // only what 0-rtt is held to is kept
+        assert_eq!(ticket.params.initial_max_data, 1_000);
+        assert_eq!(ticket.params.max_idle_timeout, 0);
+
+        let mut cache = SessionCache::new(2);
+        cache.insert("Example.com", ticket.clone());
+        assert_eq!(cache.take("example.com", now), Some(ticket.clone()));
+        // used once
+        assert_eq!(cache.take("example.com", now), None);
+
+        cache.insert("a.example", ticket.clone());
+        assert_eq!(cache.take("a.example", now + Duration::from_secs(61)), None);
+
+        let soon = SessionTicket::new(vec![2], &params, Duration::from_secs(1), now);
+        cache.insert("a.example", soon);
+        cache.insert("b.example", ticket.clone());
+        cache.insert("c.example", ticket.clone());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.take("a.example", now), None);
+
+        // a full cache replacing a name in different case doesn't evict anything else
+        let newer = SessionTicket::new(vec![3], &params, Duration::from_secs(60), now);
+        cache.insert("B.Example", newer.clone());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.take("b.example", now), Some(newer));
+        assert_eq!(cache.take("c.example", now), Some(ticket));
+    }
+
+    #[test]
+    fn test_zero_rtt_limits() {
+        let mut limits = ZeroRttLimits::new(TransportParameters {
+            initial_max_data: 1_000,
+            initial_max_stream_data_bidi_remote: 600,
+            initial_max_streams_bidi: 2,
+            ..Default::default()
+        });
+        limits.on_stream_sent(0, 500).unwrap();
+        // a retransmission below what's been sent isn't charged again
+        limits.on_stream_sent(0, 300).unwrap();
+        assert_eq!(limits.available(), 500);
+        // over the stream's limit
+        assert!(limits.on_stream_sent(0, 601).is_err());
+        // over the connection's
+        assert!(limits.on_stream_sent(4, 501).is_err());
+        limits.on_stream_sent(4, 500).unwrap();
+        assert_eq!(limits.available(), 0);
+
+        // a third bidi stream, a uni stream when none were allowed, and a server's stream
+        assert!(limits.on_stream_sent(8, 0).is_err());
+        assert!(limits.on_stream_sent(2, 0).is_err());
+        assert!(limits.on_stream_sent(1, 0).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+#[allow(deprecated)]
+use std::{
+    fmt::{self, Debug},
+    hash::{Hasher, SipHasher},
+    sync::Arc,
+};
+
+use crate::{
+    consts, fill_secure,
+    packet::types::ConnectionId,
+    result::{require, QuicheResult},
+};
+
+// how local cids are minted and read back.  a load balancer in front of several servers can route
+// on what the codec puts in the cid, instead of keeping per-connection state (the QUIC-LB draft)
+pub trait CidCodec: Debug + Send + Sync {
+    // a fresh cid for the peer to send to
+    fn generate(&self) -> ConnectionId;
+
+    // the server id encoded in one of our cids, or None if the codec doesn't encode one / the cid
+    // isn't one of ours
+    fn server_id(&self, cid: &[u8]) -> Option<Vec<u8>>;
+
+    // how long every cid `generate` mints is, None if it varies
+    fn cid_len(&self) -> Option<usize> {
+        None
+    }
+
+    // whether `cid` could have come from `generate`.  a codec that can't tell says yes, so only
+    // one that can prove a cid is forged (e.g. `AuthenticatedCidCodec`) ever turns one away
+    fn is_ours(&self, _cid: &[u8]) -> bool {
+        true
+    }
+}
+
+// random cids with nothing routable in them, what every connection used before codecs existed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomCidCodec;
+
+impl CidCodec for RandomCidCodec {
+    fn generate(&self) -> ConnectionId {
+        ConnectionId::random()
+    }
+
+    fn server_id(&self, _cid: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn cid_len(&self) -> Option<usize> {
+        Some(consts::LOCAL_CID_LEN)
+    }
+}
+
+// the QUIC-LB plaintext algorithm: a first octet of 3 config rotation bits and the self-encoded
+// length (cid_len - 1), then the server id in the clear, then a random nonce
+#[derive(Debug, Clone)]
+pub struct PlaintextCidCodec {
+    config_rotation: u8,
+    server_id: Vec<u8>,
+    nonce_len: usize,
+}
+
+impl PlaintextCidCodec {
+    // the draft's nonce is at least 4 bytes, and the whole cid can't be over 20
+    pub const MIN_NONCE_LEN: usize = 4;
+    pub const MAX_CID_LEN: usize = consts::MAX_CID_LEN;
+
+    // `config_rotation` is 0..=6, 7 being reserved for cids a load balancer can't route
+    pub fn new(config_rotation: u8, server_id: Vec<u8>, nonce_len: usize) -> QuicheResult<Self> {
+        require(
+            config_rotation < 7,
+            "PlaintextCidCodec::new: config rotation must be under 7",
+        )?;
+        require(
+            !server_id.is_empty() && nonce_len >= Self::MIN_NONCE_LEN,
+            "PlaintextCidCodec::new: need a server id and at least a 4 byte nonce",
+        )?;
+        require(
+            1 + server_id.len() + nonce_len <= Self::MAX_CID_LEN,
+            "PlaintextCidCodec::new: cid would be longer than 20 bytes",
+        )?;
+        Ok(Self {
+            config_rotation,
+            server_id,
+            nonce_len,
+        })
+    }
+
+    fn cid_len(&self) -> usize {
+        1 + self.server_id.len() + self.nonce_len
+    }
+}
+
+impl CidCodec for PlaintextCidCodec {
+    fn cid_len(&self) -> Option<usize> {
+        Some(self.cid_len())
+    }
+
+    fn generate(&self) -> ConnectionId {
+        let cid_len = self.cid_len();
+        let mut cid = Vec::with_capacity(cid_len);
+        cid.push(self.config_rotation << 5 | (cid_len - 1) as u8);
+        cid.extend(&self.server_id);
+        // from the OS, so cids can't be predicted and a connection can't be followed across them
+        cid.resize(cid_len, 0);
+        fill_secure(&mut cid[cid_len - self.nonce_len..]);
+        ConnectionId::new(cid_len as u8, cid)
+    }
+
+    fn server_id(&self, cid: &[u8]) -> Option<Vec<u8>> {
+        let first = *cid.first()?;
+        if first >> 5 != self.config_rotation || cid.len() < self.cid_len() {
+            return None;
+        }
+        Some(cid[1..1 + self.server_id.len()].to_vec())
+    }
+}
+
+// another codec's cids with a truncated MAC over them on the end, so a cid can be checked without
+// any per-connection state.  every server that shares the key can check every other one's cids,
+// and nobody without it can mint one that passes
+// TODO: this should be an HMAC like the reset tokens, siphash is what std has
+#[derive(Clone)]
+pub struct AuthenticatedCidCodec {
+    inner: Arc<dyn CidCodec>,
+    key: [u8; 16],
+    tag_len: usize,
+}
+
+impl AuthenticatedCidCodec {
+    pub const DEFAULT_TAG_LEN: usize = 4;
+
+    // `inner` has to mint cids of one length, that with the tag fits in 20 bytes
+    pub fn new(inner: Arc<dyn CidCodec>, key: [u8; 16], tag_len: usize) -> QuicheResult<Self> {
+        require(
+            (1..=8).contains(&tag_len),
+            "AuthenticatedCidCodec::new: the tag is 1 to 8 bytes",
+        )?;
+        require(
+            inner
+                .cid_len()
+                .is_some_and(|len| len + tag_len <= consts::MAX_CID_LEN),
+            "AuthenticatedCidCodec::new: inner codec must mint fixed length cids with room for the tag",
+        )?;
+        Ok(Self {
+            inner,
+            key,
+            tag_len,
+        })
+    }
+
+    #[allow(deprecated)]
+    fn tag(&self, routed: &[u8]) -> Vec<u8> {
+        let k0 = u64::from_le_bytes(self.key[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(self.key[8..].try_into().unwrap());
+        let mut hasher = SipHasher::new_with_keys(k0, k1);
+        hasher.write(routed);
+        hasher.finish().to_le_bytes()[..self.tag_len].to_vec()
+    }
+
+    // the inner codec's part of an authentic cid
+    fn routed<'a>(&self, cid: &'a [u8]) -> Option<&'a [u8]> {
+        let len = self.cid_len()?;
+        if cid.len() != len {
+            return None;
+        }
+        let (routed, tag) = cid.split_at(len - self.tag_len);
+        (self.tag(routed) == tag).then_some(routed)
+    }
+}
+
+impl CidCodec for AuthenticatedCidCodec {
+    fn generate(&self) -> ConnectionId {
+        let mut cid = self.inner.generate().cid;
+        cid.extend(self.tag(&cid));
+        ConnectionId::new(cid.len() as u8, cid)
+    }
+
+    fn server_id(&self, cid: &[u8]) -> Option<Vec<u8>> {
+        self.inner.server_id(self.routed(cid)?)
+    }
+
+    fn cid_len(&self) -> Option<usize> {
+        Some(self.inner.cid_len()? + self.tag_len)
+    }
+
+    fn is_ours(&self, cid: &[u8]) -> bool {
+        self.routed(cid)
+            .is_some_and(|routed| self.inner.is_ours(routed))
+    }
+}
+
+impl Debug for AuthenticatedCidCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthenticatedCidCodec")
+            .field("inner", &self.inner)
+            .field("tag_len", &self.tag_len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plaintext_cid_codec() {
+        let codec = PlaintextCidCodec::new(2, vec![0xAA, 0xBB], 6).unwrap();
+        let cid = codec.generate();
+        assert_eq!(cid.cid_len, 9);
+        assert_eq!(cid.cid[0], 2 << 5 | 8);
+        assert_eq!(codec.server_id(&cid.cid), Some(vec![0xAA, 0xBB]));
+
+        // another config rotation, or too short to be one of ours
+        let other = PlaintextCidCodec::new(3, vec![0xAA, 0xBB], 6).unwrap();
+        assert_eq!(other.server_id(&cid.cid), None);
+        assert_eq!(codec.server_id(&cid.cid[..4]), None);
+        assert_eq!(codec.server_id(&[]), None);
+
+        assert!(PlaintextCidCodec::new(7, vec![1], 4).is_err());
+        assert!(PlaintextCidCodec::new(0, vec![1], 3).is_err());
+        assert!(PlaintextCidCodec::new(0, vec![1; 16], 4).is_err());
+        assert_eq!(RandomCidCodec.server_id(&cid.cid), None);
+        assert!(RandomCidCodec.is_ours(&cid.cid));
+        // long enough for a client's first dst cid, and not the same twice
+        let random = RandomCidCodec.generate();
+        assert_eq!(random.cid.len(), consts::LOCAL_CID_LEN);
+        assert_eq!(RandomCidCodec.cid_len(), Some(consts::LOCAL_CID_LEN));
+        assert_ne!(random, RandomCidCodec.generate());
+    }
+
+    #[test]
+    fn test_authenticated_cid_codec() {
+        let plaintext = Arc::new(PlaintextCidCodec::new(2, vec![0xAA, 0xBB], 6).unwrap());
+        let codec = AuthenticatedCidCodec::new(plaintext.clone(), [5; 16], 4).unwrap();
+        let cid = codec.generate();
+        assert_eq!(cid.cid_len, 13);
+        assert_eq!(codec.cid_len(), Some(13));
+        assert!(codec.is_ours(&cid.cid));
+        assert_eq!(codec.server_id(&cid.cid), Some(vec![0xAA, 0xBB]));
+
+        // another server with the same key can check it, one with another key can't
+        let peer = AuthenticatedCidCodec::new(plaintext.clone(), [5; 16], 4).unwrap();
+        assert!(peer.is_ours(&cid.cid));
+        let stranger = AuthenticatedCidCodec::new(plaintext.clone(), [6; 16], 4).unwrap();
+        assert!(!stranger.is_ours(&cid.cid));
+        assert_eq!(stranger.server_id(&cid.cid), None);
+
+        // any change to the routing bits, or a cid of another length, fails the tag
+        let mut forged = cid.cid.clone();
+        forged[1] ^= 1;
+        assert!(!codec.is_ours(&forged));
+        assert!(!codec.is_ours(&plaintext.generate().cid));
+        assert!(!codec.is_ours(&[]));
+
+        let random = AuthenticatedCidCodec::new(Arc::new(RandomCidCodec), [5; 16], 4).unwrap();
+        assert!(random.is_ours(&random.generate().cid));
+        assert!(AuthenticatedCidCodec::new(plaintext.clone(), [5; 16], 9).is_err());
+        let long = Arc::new(PlaintextCidCodec::new(0, vec![1; 8], 10).unwrap());
+        assert!(AuthenticatedCidCodec::new(long, [5; 16], 4).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::{
+    connection::{
+        connection::DEFAULT_HANDSHAKE_TIMEOUT, RecoveryConfig, TestHooks, DEFAULT_MAX_ACK_RANGES,
+        DEFAULT_MAX_UDP_PAYLOAD_SIZE,
+    },
+    crypto::DEFAULT_MAX_CRYPTO_BUFFER,
+    packet::{transport_parameters::MIN_UDP_PAYLOAD_SIZE, version::Version},
+    stream::FlowControlConfig,
+};
+
+use super::{
+    CidCodec, DropThreshold, Forwarding, RandomCidCodec, RoutingHooks, StatelessResetKey, TokenKey,
+};
+
+#[derive(Debug, Clone)]
+pub struct EndpointConfig {
+    // the versions we accept connections on, most preferred first.  a client on any other is
+    // answered with a Version Negotiation listing these.  every version we can speak by default
+    pub supported_versions: Vec<Version>,
+    // receive window sizes, and how far autotuning may move them.  every connection advertises
+    // them in its transport parameters
+    pub flow_control: FlowControlConfig,
+    // the most ranges an ACK frame carries, the oldest are left out past this
+    pub max_ack_ranges: usize,
+    // how long a connection's handshake gets before it fails
+    pub handshake_timeout: Duration,
+    // initial rtt and loss detection thresholds, the RFC 9002 values by default
+    pub recovery: RecoveryConfig,
+    // the chance each packet number is skipped, to catch a peer acknowledging packets it never
+    // got (RFC 9000 section 21.4).  0 by default, and at most 0.5
+    pub packet_number_skip_probability: f64,
+    // the datagram size used until the path mtu is known
+    pub initial_max_udp_payload_size: usize,
+    // the largest datagram sent or accepted, advertised in the transport parameters
+    pub max_udp_payload_size: usize,
+    // how far ahead of a gap the peer's CRYPTO data may arrive in each packet number space before
+    // the connection closes with CRYPTO_BUFFER_EXCEEDED.  64 KB by default
+    pub max_crypto_buffer: usize,
+    // mints every local cid and reads the server id back out of them, random cids by default
+    pub cid_codec: Arc<dyn CidCodec>,
+    // stateless reset tokens are derived from this, and packets for cids we don't know are
+    // answered with a stateless reset.  None means a random key for each endpoint, whose tokens
+    // don't survive a restart, and unknown cids are dropped
+    pub stateless_reset_key: Option<StatelessResetKey>,
+    // what's asked of clients before the endpoint takes them on
+    pub server: ServerConfig,
+    // calls its hook as a source address keeps getting datagrams dropped, see `DropStats`
+    pub drop_threshold: Option<DropThreshold>,
+    // fault injection applied to every connection the endpoint takes on.  tests only
+    pub test_hooks: Option<TestHooks>,
+    // for moving datagrams between endpoints sharing a port, see `on_unroutable` / `on_pre_send`
+    pub routing: RoutingHooks,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            supported_versions: Version::ALL.to_vec(),
+            flow_control: FlowControlConfig::default(),
+            max_ack_ranges: DEFAULT_MAX_ACK_RANGES,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            recovery: RecoveryConfig::default(),
+            packet_number_skip_probability: 0.0,
+            initial_max_udp_payload_size: MIN_UDP_PAYLOAD_SIZE as usize,
+            max_udp_payload_size: DEFAULT_MAX_UDP_PAYLOAD_SIZE,
+            max_crypto_buffer: DEFAULT_MAX_CRYPTO_BUFFER,
+            cid_codec: Arc::new(RandomCidCodec),
+            stateless_reset_key: None,
+            server: ServerConfig::default(),
+            drop_threshold: None,
+            test_hooks: None,
+            routing: RoutingHooks::default(),
+        }
+    }
+}
+
+impl EndpointConfig {
+    // `hook` sees every datagram that doesn't route to one of our connections before anything is
+    // made of it, e.g. a short header whose cid carries another worker's server id.  if it
+    // forwards the datagram, `route_datagram` returns `Incoming::Forwarded`
+    pub fn on_unroutable(
+        mut self,
+        hook: impl Fn(&[u8], SocketAddr) -> Forwarding + Send + Sync + 'static,
+    ) -> Self {
+        self.routing.on_unroutable = Some(Arc::new(hook));
+        self
+    }
+
+    // `hook` sees every datagram our connections send, after any injected faults.  one it
+    // forwards isn't written to the connection's socket, but still counts as sent
+    pub fn on_pre_send(
+        mut self,
+        hook: impl Fn(&[u8], SocketAddr) -> Forwarding + Send + Sync + 'static,
+    ) -> Self {
+        self.routing.on_pre_send = Some(Arc::new(hook));
+        self
+    }
+}
+
+// when a client has to prove it owns its address with a Retry round trip before the server
+// commits any state to it (RFC 9000 section 8.1.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AddressValidation {
+    // take every Initial on as it comes, the amplification limit is the only protection
+    #[default]
+    Never,
+    // Retry every Initial that doesn't carry a valid token
+    Always,
+    // Retry once this many handshakes are in progress, e.g. while under a flood of Initials
+    // from spoofed addresses
+    UnderLoad(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub address_validation: AddressValidation,
+    // what Retry and NEW_TOKEN tokens are minted with.  by default a fresh key from the OS for
+    // each config, set it to share one between the servers behind a load balancer
+    pub token_key: TokenKey,
+    // how long a Retry token is good for.  it only has to last one round trip
+    pub retry_token_lifetime: Duration,
+    // how many NEW_TOKEN frames a client gets once its handshake is done, 0 sends none.  a client
+    // that comes back with one skips the Retry round trip
+    pub new_tokens: usize,
+    // how long a NEW_TOKEN token is good for.  it's meant for a later connection, so it's long
+    pub new_token_lifetime: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address_validation: AddressValidation::Never,
+            token_key: TokenKey::random(),
+            retry_token_lifetime: Duration::from_secs(10),
+            new_tokens: 1,
+            new_token_lifetime: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::metrics;
+
+// how many addresses `DropStats` tracks at once for the per-ip threshold.  past this the address
+// with the fewest drops makes room for the new one, so a scan from many addresses can't grow the
+// map without bound, or wipe out the count of one that's close to its threshold
+pub const MAX_TRACKED_ADDRS: usize = 4_096;
+
+// why the endpoint dropped a datagram without handing it to a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    // a short header packet for a cid that doesn't route anywhere
+    UnknownCid,
+    // an Initial in a datagram under 1200 bytes (RFC 9000 section 14.1)
+    UndersizedInitial,
+    // the packet didn't authenticate
+    FailedIntegrity,
+    // it would have needed an answer bigger than the amplification limit allows
+    AmplificationLimited,
+    // too short or garbled to even find a cid in
+    Malformed,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 5] = [
+        DropReason::UnknownCid,
+        DropReason::UndersizedInitial,
+        DropReason::FailedIntegrity,
+        DropReason::AmplificationLimited,
+        DropReason::Malformed,
+    ];
+}
+
+// called with the address and how many of its datagrams have been dropped, once each time an
+// address reaches a multiple of the threshold.  e.g. to add it to a deny list
+pub type DropThresholdHook = dyn Fn(IpAddr, u64) + Send + Sync;
+
+#[derive(Clone)]
+pub struct DropThreshold {
+    pub threshold: u64,
+    pub hook: Arc<DropThresholdHook>,
+}
+
+impl DropThreshold {
+    pub fn new(threshold: u64, hook: impl Fn(IpAddr, u64) + Send + Sync + 'static) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            hook: Arc::new(hook),
+        }
+    }
+}
+
+impl fmt::Debug for DropThreshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DropThreshold")
+            .field("threshold", &self.threshold)
+            .finish_non_exhaustive()
+    }
+}
+
+// datagrams the endpoint dropped, by reason.  a climbing unknown cid or undersized Initial count
+// usually means someone is scanning, or trying to use us as a reflector
+#[derive(Debug, Clone, Default)]
+pub struct DropStats {
+    counts: [u64; DropReason::ALL.len()],
+    // drops per source address, only kept while there's a threshold to check them against
+    by_addr: HashMap<IpAddr, u64>,
+    // the same, ordered by count so the address with the fewest is the first to go
+    by_count: BTreeSet<(u64, IpAddr)>,
+}
+
+impl DropStats {
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // drops from `addr` since it started being tracked
+    pub fn from_addr(&self, addr: IpAddr) -> u64 {
+        self.by_addr.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        from: SocketAddr,
+        reason: DropReason,
+        threshold: Option<&DropThreshold>,
+    ) {
+        self.counts[reason as usize] += 1;
+        metrics::datagrams_dropped(1);
+
+        let Some(threshold) = threshold else {
+            return;
+        };
+        if self.by_addr.len() >= MAX_TRACKED_ADDRS && !self.by_addr.contains_key(&from.ip()) {
+            if let Some((_, fewest)) = self.by_count.pop_first() {
+                self.by_addr.remove(&fewest);
+            }
+        }
+        let count = self.by_addr.entry(from.ip()).or_default();
+        self.by_count.remove(&(*count, from.ip()));
+        *count += 1;
+        self.by_count.insert((*count, from.ip()));
+        if count.is_multiple_of(threshold.threshold) {
+            (threshold.hook)(from.ip(), *count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_drop_stats() {
+        let tripped = Arc::new(Mutex::new(Vec::new()));
+        let threshold = DropThreshold::new(3, {
+            let tripped = tripped.clone();
+            move |addr, count| tripped.lock().unwrap().push((addr, count))
+        });
+        let scanner: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let other: SocketAddr = "192.0.2.2:4433".parse().unwrap();
+
+        let mut stats = DropStats::default();
+        // nothing is tracked per address without a threshold
+        stats.record(scanner, DropReason::Malformed, None);
+        assert_eq!(stats.from_addr(scanner.ip()), 0);
+
+        for port in 0..7 {
+            let from = SocketAddr::new(scanner.ip(), port);
+            stats.record(from, DropReason::UnknownCid, Some(&threshold));
+        }
+        stats.record(other, DropReason::UndersizedInitial, Some(&threshold));
+
+        assert_eq!(stats.get(DropReason::UnknownCid), 7);
+        assert_eq!(stats.get(DropReason::FailedIntegrity), 0);
+        assert_eq!(stats.total(), 9);
+        assert_eq!(stats.from_addr(scanner.ip()), 7);
+        // the hook fires at every multiple of the threshold, not on every drop past it
+        assert_eq!(
+            *tripped.lock().unwrap(),
+            vec![(scanner.ip(), 3), (scanner.ip(), 6)]
+        );
+
+        // a flood from fresh addresses pushes out the least dropped ones, not the scanner's count
+        for i in 0..MAX_TRACKED_ADDRS as u32 {
+            let from = SocketAddr::new(IpAddr::from((0x0a00_0000 + i).to_be_bytes()), 4433);
+            stats.record(from, DropReason::UnknownCid, Some(&threshold));
+        }
+        assert_eq!(stats.by_addr.len(), MAX_TRACKED_ADDRS);
+        assert_eq!(stats.by_count.len(), MAX_TRACKED_ADDRS);
+        assert_eq!(stats.from_addr(scanner.ip()), 7);
+        stats.record(scanner, DropReason::UnknownCid, Some(&threshold));
+        stats.record(scanner, DropReason::UnknownCid, Some(&threshold));
+        assert_eq!(tripped.lock().unwrap().last(), Some(&(scanner.ip(), 9)));
+    }
+}
@@ -0,0 +1,1784 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::task::JoinSet;
+
+use super::{
+    AddressValidation, DropReason, DropStats, EndpointConfig, Forwarding, StatelessResetKey,
+};
+use crate::{
+    bits::BitsExt,
+    connection::{connection::Connection, ConnectionState, Side},
+    consts::MIN_INITIAL_SIZE,
+    metrics,
+    packet::{
+        header::{Header, LongHeader, LongHeaderExtension},
+        types::{ConnectionId, FourBits, LongPacketType},
+        version::Version,
+    },
+    result::{require, QuicheError, QuicheResult},
+    MINI_QUICHE_VERSION,
+};
+
+// how long a closed connection's cids keep routing to it, so late packets from the peer are
+// absorbed instead of looking like a new connection.  three times the initial PTO
+// (RFC 9000 section 10.2), until there's an rtt estimate to go off
+pub const DRAIN_PERIOD: Duration = Duration::from_secs(3);
+
+// how long the IPv4 attempt waits for the IPv6 one before racing it (RFC 8305 section 5)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+pub type ConnectionHandle = u64;
+
+// what `Endpoint::route_datagram` made of a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incoming {
+    Connection(ConnectionHandle),
+    // an Initial for a cid we don't know, i.e. a client starting a new connection.  its address
+    // is validated if it carried a Retry token we minted
+    NewConnection { address_validated: bool },
+    // an Initial that has to prove its address first, answer it with `Endpoint::retry`
+    Retry,
+    // a client on a version we don't speak, answer it with `Endpoint::version_negotiation`
+    VersionNegotiation,
+    // taken by the `on_unroutable` hook, nothing more to do with it here
+    Forwarded,
+    // already counted in `Endpoint::drops`
+    Dropped(DropReason),
+}
+
+// what `Endpoint::graceful_shutdown` had to do
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    // connections still open at the deadline, closed then
+    pub closed: usize,
+    // connections that errored along the way, by handle.  each is only reported once
+    pub errors: Vec<(ConnectionHandle, QuicheError)>,
+}
+
+impl ShutdownReport {
+    fn failed(&self, handle: ConnectionHandle) -> bool {
+        self.errors.iter().any(|(failed, _)| *failed == handle)
+    }
+}
+
+struct Entry {
+    connection: Connection,
+    // every cid we've issued for this connection, all of which route to it
+    cids: Vec<ConnectionId>,
+    // set the first time `reap` sees the connection closing, it's dropped once this passes
+    drain_deadline: Option<Instant>,
+}
+
+// owns every connection and routes incoming datagrams to them by destination cid
+#[derive(Default)]
+pub struct Endpoint {
+    connections: HashMap<ConnectionHandle, Entry>,
+    routes: HashMap<Vec<u8>, ConnectionHandle>,
+    // server connections still in their handshake, by the client's address and the dst cid of
+    // its first Initial.  a client keeps sending Initials there until it hears back, so a
+    // duplicated or reordered one finds the connection it started instead of making another
+    initials: HashMap<(SocketAddr, Vec<u8>), ConnectionHandle>,
+    next_handle: ConnectionHandle,
+    config: EndpointConfig,
+    // set by `begin_shutdown`, no new connections are taken on after that
+    shutdown_deadline: Option<Instant>,
+    drops: DropStats,
+    // the configured reset key, or a random one for this endpoint
+    reset_key: StatelessResetKey,
+}
+
+impl Endpoint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: EndpointConfig) -> Self {
+        Self {
+            reset_key: config.stateless_reset_key.clone().unwrap_or_default(),
+            config,
+            ..Self::default()
+        }
+    }
+
+    pub fn config(&self) -> &EndpointConfig {
+        &self.config
+    }
+
+    pub fn insert(
+        &mut self,
+        mut connection: Connection,
+        cid: ConnectionId,
+    ) -> QuicheResult<ConnectionHandle> {
+        require(
+            !self.is_shutting_down(),
+            "Endpoint::insert: endpoint is shutting down",
+        )?;
+        configure(&self.config, &self.reset_key, &mut connection)?;
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        // configuring an Idle connection mints its handshake cid again with the endpoint's codec,
+        // and that's the src cid it sends from, so it has to route here too.  so does every cid a
+        // thawed connection had already issued
+        let mut cids = vec![cid];
+        for local in connection.local_cids().issued() {
+            if !cids.contains(&local.cid) {
+                cids.push(local.cid);
+            }
+        }
+        for cid in cids.iter() {
+            self.routes.insert(cid.cid.clone(), handle);
+        }
+        self.connections.insert(
+            handle,
+            Entry {
+                connection,
+                cids,
+                drain_deadline: None,
+            },
+        );
+        self.publish_connections();
+        Ok(handle)
+    }
+
+    // connects to `addr`, which is expected to be `server_name`.  the name is what goes in SNI and
+    // what the server's certificate is verified against, the address is only where to send
+    pub async fn connect(
+        &mut self,
+        server_name: &str,
+        addr: SocketAddr,
+    ) -> QuicheResult<ConnectionHandle> {
+        require(
+            !self.is_shutting_down(),
+            "Endpoint::connect: endpoint is shutting down",
+        )?;
+        let connection = self.attempt(server_name, addr).await?;
+        self.insert_client(connection)
+    }
+
+    // resolves `hostname` and connects to what it resolves to, see `connect_addrs`
+    pub async fn connect_dual_stack(
+        &mut self,
+        hostname: &str,
+        port: u16,
+    ) -> QuicheResult<ConnectionHandle> {
+        require(
+            !self.is_shutting_down(),
+            "Endpoint::connect_dual_stack: endpoint is shutting down",
+        )?;
+        let addrs = tokio::net::lookup_host((hostname, port))
+            .await?
+            .collect::<Vec<_>>();
+        self.connect_addrs(hostname, &addrs).await
+    }
+
+    // connects to `server_name` over the first IPv6 and the first IPv4 address in `addrs` at
+    // once, the IPv4 attempt starting `CONNECTION_ATTEMPT_DELAY` later, or as soon as the IPv6
+    // one fails.  whichever handshake completes first is kept, so a broken IPv6 path costs a short
+    // delay instead of a handshake timeout
+    pub async fn connect_addrs(
+        &mut self,
+        server_name: &str,
+        addrs: &[SocketAddr],
+    ) -> QuicheResult<ConnectionHandle> {
+        require(
+            !self.is_shutting_down(),
+            "Endpoint::connect_addrs: endpoint is shutting down",
+        )?;
+        let v6 = addrs.iter().find(|addr| addr.is_ipv6()).copied();
+        let v4 = addrs.iter().find(|addr| addr.is_ipv4()).copied();
+
+        let connection = match (v6, v4) {
+            (Some(v6), Some(v4)) => {
+                let v6 = self.attempt(server_name, v6);
+                let v4 = self.attempt(server_name, v4);
+                tokio::pin!(v6, v4);
+                let head_start = tokio::select! {
+                    result = &mut v6 => Some(result),
+                    _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => None,
+                };
+                match head_start {
+                    Some(Ok(connection)) => connection,
+                    Some(Err(_)) => v4.await?,
+                    // the loser is dropped along with its socket
+                    None => tokio::select! {
+                        result = &mut v6 => match result {
+                            Ok(connection) => connection,
+                            Err(_) => v4.await?,
+                        },
+                        result = &mut v4 => match result {
+                            Ok(connection) => connection,
+                            Err(_) => v6.await?,
+                        },
+                    },
+                }
+            }
+            (Some(addr), None) | (None, Some(addr)) => self.attempt(server_name, addr).await?,
+            (None, None) => {
+                return Err(QuicheError(format!(
+                    "Endpoint::connect_addrs: no addresses for {}",
+                    server_name
+                )))
+            }
+        };
+        self.insert_client(connection)
+    }
+
+    // one connection attempt to `server_name` at `peer_addr`
+    fn attempt(
+        &self,
+        server_name: &str,
+        peer_addr: SocketAddr,
+    ) -> impl std::future::Future<Output = QuicheResult<Connection>> {
+        let config = self.config.clone();
+        let reset_key = self.reset_key.clone();
+        let server_name = server_name.to_string();
+        async move {
+            let local_addr = match peer_addr {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            };
+            let mut connection = Connection::new(local_addr, peer_addr).await?;
+            configure(&config, &reset_key, &mut connection)?;
+            connection.set_server_name(&server_name)?;
+            connection.open().await?;
+            Ok(connection)
+        }
+    }
+
+    // a server connection for the client at `from` that sent `initial`.  it's routed by `cid`
+    // like any other, and until its handshake completes, Initials from the same client to the
+    // same dst cid route to it too.  the connection echoes the cids the client used in its
+    // transport parameters: the Initial's dst cid, or if it came back from a Retry, the cid
+    // its token carries and the Retry's own.  `address_validated` is what `route_datagram` said
+    // in `Incoming::NewConnection`: a client that didn't come back with a token of ours hasn't
+    // proven its address, so the connection sends it no more than three times what it's received
+    // until it does (RFC 9000 section 8.1)
+    pub fn insert_incoming(
+        &mut self,
+        mut connection: Connection,
+        cid: ConnectionId,
+        from: SocketAddr,
+        initial: &[u8],
+        address_validated: bool,
+    ) -> QuicheResult<ConnectionHandle> {
+        let initial_dst_cid = Header::peek_initial(initial)
+            .map(|initial| ConnectionId::new(initial.dst_cid.len() as u8, initial.dst_cid.to_vec()))
+            .ok_or_else(|| QuicheError("Endpoint::insert_incoming: not an Initial".to_string()))?;
+        connection.set_side(Side::Server)?;
+        match self.validate_token(from, initial) {
+            Some(original_dst_cid) => {
+                connection.set_original_dst_cid(original_dst_cid)?;
+                connection.set_retry_src_cid(initial_dst_cid.clone())?;
+            }
+            None => connection.set_original_dst_cid(initial_dst_cid.clone())?,
+        }
+        if !address_validated {
+            connection.limit_amplification()?;
+        }
+        let handle = self.insert(connection, cid)?;
+        self.initials.insert((from, initial_dst_cid.cid), handle);
+        Ok(handle)
+    }
+
+    // the connection an Initial from `from` to `dst_cid` belongs to, if it's one still
+    // handshaking.  entries for connections that are past it are cleaned up on the way
+    fn route_initial(&mut self, from: SocketAddr, dst_cid: &[u8]) -> Option<ConnectionHandle> {
+        let key = (from, dst_cid.to_vec());
+        let handle = *self.initials.get(&key)?;
+        if !self
+            .get(handle)
+            .is_some_and(|connection| is_handshaking(connection.state()))
+        {
+            self.initials.remove(&key);
+            return None;
+        }
+        Some(handle)
+    }
+
+    // a client connection that's been opened, routed by the cid it gave the server
+    fn insert_client(&mut self, connection: Connection) -> QuicheResult<ConnectionHandle> {
+        let cid = connection.local_cids().initial().cloned().ok_or_else(|| {
+            QuicheError("Endpoint::insert_client: connection has no cid".to_string())
+        })?;
+        self.insert(connection, cid)
+    }
+
+    // another cid for `handle`, e.g. one we just issued with NEW_CONNECTION_ID
+    pub fn add_cid(&mut self, handle: ConnectionHandle, cid: ConnectionId) -> QuicheResult<()> {
+        let entry = self.connections.get_mut(&handle).ok_or_else(|| {
+            QuicheError(format!(
+                "Endpoint::add_cid: no connection with handle {}",
+                handle
+            ))
+        })?;
+        self.routes.insert(cid.cid.clone(), handle);
+        entry.cids.push(cid);
+        Ok(())
+    }
+
+    // a new cid for `handle`, minted by the endpoint's codec and queued as NEW_CONNECTION_ID.
+    // it routes to the connection straight away
+    pub fn issue_cid(&mut self, handle: ConnectionHandle) -> QuicheResult<ConnectionId> {
+        let connection = self.get_mut(handle).ok_or_else(|| {
+            QuicheError(format!(
+                "Endpoint::issue_cid: no connection with handle {}",
+                handle
+            ))
+        })?;
+        let cid = connection.issue_cid();
+        self.add_cid(handle, cid.clone())?;
+        Ok(cid)
+    }
+
+    // the server id in a destination cid, per the endpoint's codec.  a cid minted by another
+    // server behind the same load balancer decodes to that server's id
+    pub fn server_id(&self, dst_cid: &[u8]) -> Option<Vec<u8>> {
+        self.config.cid_codec.server_id(dst_cid)
+    }
+
+    // whether the endpoint's codec could have minted `dst_cid`, see `CidCodec::is_ours`
+    pub fn is_ours(&self, dst_cid: &[u8]) -> bool {
+        self.config.cid_codec.is_ours(dst_cid)
+    }
+
+    // which connection a datagram with this destination cid belongs to
+    pub fn route(&self, dst_cid: &[u8]) -> Option<ConnectionHandle> {
+        self.routes.get(dst_cid).copied()
+    }
+
+    // where a datagram from `from` goes.  whatever the endpoint can tell is bad on its own is
+    // counted and dropped here; a connection that drops something for a reason only it can tell
+    // (integrity, amplification) reports it with `on_dropped`.  an unknown cid can still be
+    // answered with `stateless_reset`
+    pub fn route_datagram(&mut self, from: SocketAddr, datagram: &[u8]) -> Incoming {
+        let Some(dst_cid) = Header::peek_dst_cid(datagram) else {
+            self.on_dropped(from, DropReason::Malformed);
+            return Incoming::Dropped(DropReason::Malformed);
+        };
+        if let Some(handle) = self.route(dst_cid) {
+            return Incoming::Connection(handle);
+        }
+        // a short header cid the codec can tell we never minted isn't worth forwarding or
+        // counting against anyone, it's turned away before either
+        if datagram[0] & 0b10_000000 == 0 && !self.is_ours(dst_cid) {
+            return Incoming::Dropped(DropReason::UnknownCid);
+        }
+        if self.config.routing.unroutable(datagram, from) == Forwarding::Forwarded {
+            return Incoming::Forwarded;
+        }
+
+        // long header, version 0 is a Version Negotiation and never answered.  only a datagram big
+        // enough to start a connection is, so it can't be used to amplify (RFC 9000 section 6.1)
+        let version = datagram
+            .get(1..5)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()));
+        if datagram[0] & 0b10_000000 != 0
+            && version.is_some_and(|v| v != 0 && self.supported_version(v).is_none())
+            && datagram.len() >= MIN_INITIAL_SIZE
+        {
+            return Incoming::VersionNegotiation;
+        }
+        let reason = if Header::peek_initial(datagram).is_some() {
+            if let Some(handle) = self.route_initial(from, dst_cid) {
+                return Incoming::Connection(handle);
+            }
+            if datagram.len() >= MIN_INITIAL_SIZE {
+                let address_validated = self.validates_address(from, datagram);
+                if !address_validated && self.requires_retry() {
+                    return Incoming::Retry;
+                }
+                return Incoming::NewConnection { address_validated };
+            }
+            DropReason::UndersizedInitial
+        } else {
+            DropReason::UnknownCid
+        };
+        self.on_dropped(from, reason);
+        Incoming::Dropped(reason)
+    }
+
+    // whether a new client has to go through a Retry before we take it on
+    fn requires_retry(&self) -> bool {
+        match self.config.server.address_validation {
+            AddressValidation::Never => false,
+            AddressValidation::Always => true,
+            AddressValidation::UnderLoad(threshold) => self.handshakes() >= threshold,
+        }
+    }
+
+    // connections that haven't finished their handshake
+    pub fn handshakes(&self) -> usize {
+        self.connections
+            .values()
+            .filter(|entry| is_handshaking(entry.connection.state()))
+            .count()
+    }
+
+    // the original dst cid from the Retry token in an Initial from `from`, if it carries one we
+    // minted that's still good.  it goes in the server's transport parameters
+    pub fn validate_token(&self, from: SocketAddr, datagram: &[u8]) -> Option<ConnectionId> {
+        let initial = Header::peek_initial(datagram)?;
+        if initial.token.is_empty() {
+            return None;
+        }
+        let server = &self.config.server;
+        let cid = server.token_key.validate_retry_token(
+            initial.token,
+            from,
+            SystemTime::now(),
+            server.retry_token_lifetime,
+        )?;
+        Some(ConnectionId::new(cid.len() as u8, cid))
+    }
+
+    // whether an Initial from `from` carries a token we minted for it, from a Retry or NEW_TOKEN
+    fn validates_address(&self, from: SocketAddr, datagram: &[u8]) -> bool {
+        if self.validate_token(from, datagram).is_some() {
+            return true;
+        }
+        let server = &self.config.server;
+        Header::peek_initial(datagram).is_some_and(|initial| {
+            server.token_key.validate_new_token(
+                initial.token,
+                from,
+                SystemTime::now(),
+                server.new_token_lifetime,
+            )
+        })
+    }
+
+    // a Retry in answer to an Initial from `from`, carrying a token for it to come back with.
+    // the client's next Initial goes to the cid in it, which doesn't route anywhere either, so
+    // it's told apart by the token alone
+    pub fn retry(&self, from: SocketAddr, datagram: &[u8]) -> QuicheResult<Vec<u8>> {
+        let initial = Header::peek_initial(datagram)
+            .ok_or_else(|| QuicheError("Endpoint::retry: not an Initial".to_string()))?;
+        let token =
+            self.config
+                .server
+                .token_key
+                .mint_retry_token(from, initial.dst_cid, SystemTime::now());
+        Header::Retry(LongHeader::new(
+            LongPacketType::retry(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            ConnectionId::new(initial.src_cid.len() as u8, initial.src_cid.to_vec()),
+            self.config.cid_codec.generate(),
+            LongHeaderExtension::Retry {
+                retry_token: token,
+                // TODO: the integrity tag is AES-128-GCM over the pseudo-packet (RFC 9001 section
+                // 5.8), zeroed until there's a crypto dependency
+                retry_integrity_tag: [0; 16],
+            },
+        ))
+        .encode()
+    }
+
+    // a Version Negotiation in answer to a long header packet on a version we don't speak, the
+    // client's cids echoed back swapped.  read by hand rather than with `Header::peek`, which
+    // won't read a reserved version, and a client greasing its own is one to answer
+    pub fn version_negotiation(&self, datagram: &[u8]) -> QuicheResult<Vec<u8>> {
+        let malformed =
+            || QuicheError("Endpoint::version_negotiation: not a long header".to_string());
+        require(
+            datagram
+                .first()
+                .is_some_and(|first| first & 0b10_000000 != 0),
+            "Endpoint::version_negotiation: not a long header",
+        )?;
+        let dst_cid = Header::peek_dst_cid(datagram).ok_or_else(malformed)?;
+        let src_at = 6 + dst_cid.len();
+        let src_len = *datagram.get(src_at).ok_or_else(malformed)? as usize;
+        let src_cid = datagram
+            .get(src_at + 1..src_at + 1 + src_len)
+            .ok_or_else(malformed)?;
+        Header::VersionNegotiate(LongHeader::version_negotiate(
+            ConnectionId::new(src_len as u8, src_cid.to_vec()),
+            ConnectionId::new(dst_cid.len() as u8, dst_cid.to_vec()),
+            Version::advertised(&self.config.supported_versions),
+        ))
+        .encode()
+    }
+
+    // `version` if it's one of `EndpointConfig::supported_versions`
+    fn supported_version(&self, version: u32) -> Option<Version> {
+        Version::from_u32(version).filter(|v| self.config.supported_versions.contains(v))
+    }
+
+    pub fn on_dropped(&mut self, from: SocketAddr, reason: DropReason) {
+        self.drops
+            .record(from, reason, self.config.drop_threshold.as_ref());
+    }
+
+    pub fn drops(&self) -> &DropStats {
+        &self.drops
+    }
+
+    // what to send back for a datagram that didn't route anywhere, if anything.  a short header
+    // packet for a cid we don't know is most likely for a connection we've lost the state of, so
+    // with a reset key configured the peer gets a stateless reset and can stop waiting on it.
+    // long headers are left to the handshake, and anything too small to answer with something
+    // smaller is dropped so two endpoints can't bounce resets off each other.  neither is a cid
+    // the codec can tell we never minted, there's no connection of ours to reset
+    pub fn stateless_reset(&self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let key = self.config.stateless_reset_key.as_ref()?;
+        if datagram.first()? & 0b10_000000 != 0 {
+            return None;
+        }
+        let dst_cid = Header::peek_dst_cid(datagram)?;
+        if self.route(dst_cid).is_some() || !self.is_ours(dst_cid) {
+            return None;
+        }
+        key.stateless_reset(dst_cid, datagram.len())
+    }
+
+    pub fn get(&self, handle: ConnectionHandle) -> Option<&Connection> {
+        self.connections.get(&handle).map(|entry| &entry.connection)
+    }
+
+    pub fn get_mut(&mut self, handle: ConnectionHandle) -> Option<&mut Connection> {
+        self.connections
+            .get_mut(&handle)
+            .map(|entry| &mut entry.connection)
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    // connections that haven't started closing
+    pub fn active_connections(&self) -> usize {
+        self.connections
+            .values()
+            .filter(|entry| !is_closing(entry.connection.state()))
+            .count()
+    }
+
+    // connections that are closing / draining / closed but still hold their cids
+    pub fn draining_connections(&self) -> usize {
+        self.len() - self.active_connections()
+    }
+
+    // when the next closing connection is due to be reaped
+    pub fn next_reap(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .filter_map(|entry| entry.drain_deadline)
+            .min()
+    }
+
+    // drops every connection whose draining period has run out, along with all of its cids.
+    // connections that closed or idled out since the last call start their draining period now.
+    // returns how many were dropped; call it periodically, or at `next_reap`.  it's also where what
+    // the connections dropped themselves (see `Connection::take_drops`) makes it into `drops`
+    pub fn reap(&mut self, now: Instant) -> usize {
+        let mut reaped = Vec::new();
+        for (handle, entry) in self.connections.iter_mut() {
+            let drops = entry.connection.take_drops();
+            for reason in DropReason::ALL {
+                for _ in 0..drops[reason as usize] {
+                    self.drops.record(
+                        entry.connection.path().peer_addr,
+                        reason,
+                        self.config.drop_threshold.as_ref(),
+                    );
+                }
+            }
+            if !is_closing(entry.connection.state()) {
+                continue;
+            }
+            let deadline = *entry.drain_deadline.get_or_insert(now + DRAIN_PERIOD);
+            if deadline <= now {
+                reaped.push(*handle);
+            }
+        }
+
+        self.initials.retain(|_, handle| !reaped.contains(handle));
+        for handle in reaped.iter() {
+            if let Some(entry) = self.connections.remove(handle) {
+                for cid in entry.cids.iter() {
+                    // a cid can only have been re-pointed at another connection by a caller mistake,
+                    // but don't yank it out from under that one
+                    if self.routes.get(&cid.cid) == Some(handle) {
+                        self.routes.remove(&cid.cid);
+                    }
+                }
+            }
+        }
+        self.publish_connections();
+        reaped.len()
+    }
+
+    // stops taking on new connections, existing ones carry on until `deadline`.  for an
+    // application that keeps driving its connections itself and calls `graceful_shutdown` once
+    // they're done or the deadline is up
+    pub fn begin_shutdown(&mut self, deadline: Instant) {
+        // a second call can bring the deadline forward, never push it back
+        let deadline = self.shutdown_deadline.map_or(deadline, |d| d.min(deadline));
+        self.shutdown_deadline = Some(deadline);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_deadline.is_some()
+    }
+
+    pub fn shutdown_deadline(&self) -> Option<Instant> {
+        self.shutdown_deadline
+    }
+
+    // drains the endpoint for a rolling deploy: no new connections, and the existing ones get
+    // until `deadline` to finish.  in the meantime their timers are driven, what comes in is
+    // received and handed to `process` (which handles it as the application would anywhere else,
+    // see `Connection::next_datagram`), and what they have queued is flushed.  whatever is still
+    // open after that is closed, with an application CONNECTION_CLOSE carrying `close`'s code and
+    // reason if there is one, silently otherwise.  a connection that errors doesn't stop the
+    // others, its error is in the report and it's closed with the rest
+    pub async fn graceful_shutdown<F>(
+        &mut self,
+        deadline: Instant,
+        close: Option<(u64, &[u8])>,
+        mut process: F,
+    ) -> ShutdownReport
+    where
+        F: FnMut(ConnectionHandle, &mut Connection) -> QuicheResult<()>,
+    {
+        self.begin_shutdown(deadline);
+        let deadline = self.shutdown_deadline.unwrap_or(deadline);
+        let mut report = ShutdownReport::default();
+        loop {
+            let now = Instant::now();
+            for (handle, entry) in self.connections.iter_mut() {
+                if is_closing(entry.connection.state()) || report.failed(*handle) {
+                    continue;
+                }
+                if let Err(e) = drive(*handle, &mut entry.connection, now, &mut process).await {
+                    report.errors.push((*handle, e));
+                }
+            }
+            if self.active_connections() == 0 || now >= deadline {
+                break;
+            }
+            let wake = self
+                .connections
+                .values()
+                .filter_map(|entry| entry.connection.next_timeout())
+                .fold(deadline, Instant::min);
+            // woken by whichever comes first, a timer or a datagram for any connection still open
+            let mut readable = JoinSet::new();
+            for entry in self.connections.values() {
+                if !is_closing(entry.connection.state()) {
+                    let socket = entry.connection.socket();
+                    readable.spawn(async move { socket.readable().await });
+                }
+            }
+            tokio::select! {
+                _ = readable.join_next(), if !readable.is_empty() => {}
+                _ = tokio::time::sleep_until(wake.into()) => {}
+            }
+        }
+
+        for (handle, entry) in self.connections.iter_mut() {
+            if is_closing(entry.connection.state()) {
+                continue;
+            }
+            let closed = match close {
+                Some((app_error_code, reason)) => {
+                    entry.connection.close(app_error_code, reason).await
+                }
+                None => entry.connection.transition(ConnectionState::Closed),
+            };
+            match closed {
+                Ok(()) => report.closed += 1,
+                Err(e) => report.errors.push((*handle, e)),
+            }
+        }
+        self.publish_connections();
+        report
+    }
+
+    fn publish_connections(&self) {
+        metrics::connections(self.active_connections(), self.draining_connections());
+    }
+}
+
+// one round of `Endpoint::graceful_shutdown` for a connection that's still open: its timers, then
+// anything that's come in, then anything it has to send
+async fn drive<F>(
+    handle: ConnectionHandle,
+    connection: &mut Connection,
+    now: Instant,
+    process: &mut F,
+) -> QuicheResult<()>
+where
+    F: FnMut(ConnectionHandle, &mut Connection) -> QuicheResult<()>,
+{
+    while connection.on_timeout(now)?.is_some() {}
+    // only when there's something there, so one quiet connection doesn't hold up the rest
+    if connection.socket().try_peek_sender().is_ok() {
+        connection.recv().await?;
+        process(handle, connection)?;
+    }
+    if !is_closing(connection.state()) {
+        connection
+            .flush(connection.congestion().available())
+            .await?;
+    }
+    Ok(())
+}
+
+// applies the endpoint-wide settings to a connection it's taking on
+fn configure(
+    config: &EndpointConfig,
+    reset_key: &StatelessResetKey,
+    connection: &mut Connection,
+) -> QuicheResult<()> {
+    if let Some(hooks) = config.test_hooks.clone() {
+        connection.set_test_hooks(hooks);
+    }
+    connection.set_cid_codec(config.cid_codec.clone());
+    connection.set_stateless_reset_key(Some(reset_key.clone()));
+    connection.set_pre_send_hook(config.routing.on_pre_send.clone());
+    connection.set_max_ack_ranges(config.max_ack_ranges);
+    connection.set_handshake_timeout(config.handshake_timeout);
+    connection.set_recovery_config(config.recovery)?;
+    connection.set_packet_number_skip_probability(config.packet_number_skip_probability)?;
+    connection.set_max_udp_payload_size(
+        config.initial_max_udp_payload_size,
+        config.max_udp_payload_size,
+    );
+    connection.set_max_crypto_buffer(config.max_crypto_buffer);
+    if connection.state() == ConnectionState::Idle {
+        connection.set_flow_control(config.flow_control)?;
+    }
+    if connection.side() == Side::Server {
+        connection.set_new_tokens(config.server.token_key.clone(), config.server.new_tokens);
+    }
+    Ok(())
+}
+
+fn is_handshaking(state: ConnectionState) -> bool {
+    matches!(state, ConnectionState::Idle | ConnectionState::Handshaking)
+}
+
+fn is_closing(state: ConnectionState) -> bool {
+    matches!(state, ConnectionState::Closing | ConnectionState::Draining) || state.is_terminal()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        connection::{Direction, Fault, RecoveryConfig, TestHooks},
+        endpoint::{
+            AuthenticatedCidCodec, CidCodec, DropThreshold, PlaintextCidCodec, ServerConfig,
+            MIN_STATELESS_RESET_SIZE,
+        },
+        packet::{
+            frame::Frame, header::PacketType, packet::Packet, PacketNumber, PacketSpace, PnLen,
+            SingleBit, TwoBits,
+        },
+        stream::{FlowControlConfig, WindowConfig},
+        VarInt,
+    };
+    use std::{
+        net::SocketAddrV6,
+        sync::{Arc, Mutex},
+    };
+    use tokio::net::UdpSocket;
+
+    async fn connection(peer: &UdpSocket) -> Connection {
+        Connection::new("127.0.0.1:0".parse().unwrap(), peer.local_addr().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reap() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cid = |byte| ConnectionId::new(8, vec![byte; 8]);
+
+        let mut endpoint = Endpoint::new();
+        let open = endpoint.insert(connection(&peer).await, cid(1)).unwrap();
+        let closed = endpoint.insert(connection(&peer).await, cid(2)).unwrap();
+        endpoint.add_cid(closed, cid(3)).unwrap();
+        assert!(endpoint.add_cid(99, cid(4)).is_err());
+        assert_eq!(endpoint.route(&[3; 8]), Some(closed));
+
+        let conn = endpoint.get_mut(closed).unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Closed).unwrap();
+
+        let now = Instant::now();
+        // the first pass only starts the draining period
+        assert_eq!(endpoint.reap(now), 0);
+        assert_eq!(endpoint.active_connections(), 1);
+        assert_eq!(endpoint.draining_connections(), 1);
+        assert_eq!(endpoint.next_reap(), Some(now + DRAIN_PERIOD));
+        assert_eq!(endpoint.route(&[2; 8]), Some(closed));
+
+        assert_eq!(endpoint.reap(now + DRAIN_PERIOD), 1);
+        assert_eq!(endpoint.len(), 1);
+        assert_eq!(endpoint.draining_connections(), 0);
+        assert_eq!(endpoint.route(&[2; 8]), None);
+        assert_eq!(endpoint.route(&[3; 8]), None);
+        assert_eq!(endpoint.route(&[1; 8]), Some(open));
+        assert_eq!(endpoint.next_reap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_test_hooks() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let hooks = TestHooks::new(|direction, i, _| match (direction, i) {
+            (Direction::Outgoing, 0) => Fault::Drop,
+            _ => Fault::Pass,
+        });
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            test_hooks: Some(hooks),
+            ..Default::default()
+        });
+        let handle = endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+
+        let ping = |number| {
+            Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                ConnectionId::new(8, vec![2; 8]),
+                vec![number],
+                vec![Frame::Ping],
+            )
+        };
+        let conn = endpoint.get_mut(handle).unwrap();
+        conn.queue_packet(ping(0));
+        conn.queue_packet(ping(1));
+        conn.flush(usize::MAX).await.unwrap();
+
+        // the first datagram never made it
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        assert_eq!(buf, ping(1).encode().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connect_addrs() {
+        // an IPv4-only server that answers the first datagram it gets by echoing it
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let v4 = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1_500];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            handshake_timeout: Duration::from_secs(2),
+            ..Default::default()
+        });
+        // a link-local address on an interface that doesn't exist can't even be connected to, so
+        // that attempt fails straight away and the IPv4 one doesn't wait out its delay
+        let v6 = SocketAddr::V6(SocketAddrV6::new(
+            Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
+            443,
+            0,
+            u32::MAX,
+        ));
+        let start = Instant::now();
+        let handle = endpoint
+            .connect_addrs("example.com", &[v6, v4])
+            .await
+            .unwrap();
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+        let conn = endpoint.get(handle).unwrap();
+        assert_eq!(conn.path().peer_addr, v4);
+        assert_eq!(conn.server_name(), Some("example.com"));
+        let cid = conn.local_cids().initial().unwrap().clone();
+        assert_eq!(endpoint.route(&cid.cid), Some(handle));
+
+        assert!(endpoint.connect_addrs("example.com", &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 1_500];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let mut endpoint = Endpoint::new();
+        // nothing is sent for a name that could never be verified
+        assert!(endpoint.connect("bad_name.example", addr).await.is_err());
+        assert!(endpoint.connect("-example.com", addr).await.is_err());
+        assert_eq!(endpoint.len(), 0);
+
+        let handle = endpoint.connect("Example.COM", addr).await.unwrap();
+        let conn = endpoint.get(handle).unwrap();
+        assert_eq!(conn.server_name(), Some("example.com"));
+        assert_eq!(conn.path().peer_addr, addr);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cid = |byte| ConnectionId::new(8, vec![byte; 8]);
+        let mut endpoint = Endpoint::new();
+        let open = endpoint.insert(connection(&peer).await, cid(1)).unwrap();
+        let closed = endpoint.insert(connection(&peer).await, cid(2)).unwrap();
+        endpoint
+            .get_mut(open)
+            .unwrap()
+            .transition(ConnectionState::Handshaking)
+            .unwrap();
+        endpoint
+            .get_mut(closed)
+            .unwrap()
+            .close(0, b"")
+            .await
+            .unwrap();
+
+        // nothing left to wait for
+        let mut idle = Endpoint::new();
+        let report = idle
+            .graceful_shutdown(
+                Instant::now() + Duration::from_secs(60),
+                None,
+                |_, _| Ok(()),
+            )
+            .await;
+        assert_eq!(report.closed, 0);
+        assert!(idle.insert(connection(&peer).await, cid(3)).is_err());
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        endpoint.begin_shutdown(deadline + Duration::from_secs(60));
+        assert!(endpoint
+            .connect("example.com", peer.local_addr().unwrap())
+            .await
+            .is_err());
+        // the open connection never finishes, so it's closed once the deadline is up
+        let report = endpoint
+            .graceful_shutdown(deadline, Some((7, b"going away")), |_, _| Ok(()))
+            .await;
+        assert_eq!(report.closed, 1);
+        assert!(report.errors.is_empty());
+        assert!(Instant::now() >= deadline);
+        assert_eq!(endpoint.shutdown_deadline(), Some(deadline));
+        assert_eq!(endpoint.active_connections(), 0);
+        assert_eq!(
+            endpoint.get(open).unwrap().state(),
+            ConnectionState::Closing
+        );
+
+        // and the peer was told: still handshaking, so it's an Initial
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        assert!(Packet::decode(&mut buf)
+            .unwrap()
+            .payload
+            .iter()
+            .any(|frame| matches!(frame, Frame::ConnectionClose { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_drives_connections() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cid = |byte| ConnectionId::new(8, vec![byte; 8]);
+        let mut endpoint = Endpoint::new();
+        let finishing = endpoint.insert(connection(&peer).await, cid(1)).unwrap();
+        let failing = endpoint.insert(connection(&peer).await, cid(2)).unwrap();
+        let slow = endpoint.insert(connection(&peer).await, cid(3)).unwrap();
+        for handle in [finishing, failing, slow] {
+            let conn = endpoint.get_mut(handle).unwrap();
+            conn.transition(ConnectionState::Handshaking).unwrap();
+            conn.transition(ConnectionState::Connected).unwrap();
+        }
+        // and one with something left to send, which goes out during the drain
+        let slow_addr = endpoint.get(slow).unwrap().path().local_addr;
+        endpoint
+            .get_mut(slow)
+            .unwrap()
+            .send_crypto(PacketSpace::Handshake, &[1; 100]);
+
+        // what the peer sends during the drain is received and processed: the last of one
+        // connection's data, after which it's done, and something the other chokes on
+        for handle in [finishing, failing] {
+            let addr = endpoint.get(handle).unwrap().path().local_addr;
+            peer.send_to(&[handle as u8; 32], addr).await.unwrap();
+        }
+        let start = Instant::now();
+        let report = endpoint
+            .graceful_shutdown(start + Duration::from_millis(100), None, |handle, conn| {
+                let datagram = conn.next_datagram().unwrap();
+                assert_eq!(datagram[0], handle as u8);
+                conn.recycle(datagram);
+                require(handle != failing, "can't make sense of it")?;
+                conn.transition(ConnectionState::Closed)
+            })
+            .await;
+        // the failure was reported without holding up the rest, and it was closed anyway
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, failing);
+        assert_eq!(report.closed, 2);
+        for handle in [finishing, failing, slow] {
+            assert_eq!(
+                endpoint.get(handle).unwrap().state(),
+                ConnectionState::Closed
+            );
+        }
+        let mut buf = vec![0; 1_500];
+        let (_, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, slow_addr);
+    }
+
+    #[tokio::test]
+    async fn test_stateless_reset() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let key = StatelessResetKey::new([9; 16]);
+        let one_rtt = |cid: &ConnectionId| {
+            let mut packet = Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                cid.clone(),
+                vec![0],
+                vec![Frame::Ping],
+            );
+            packet
+                .payload
+                .extend(std::iter::repeat_n(Frame::Padding, 40));
+            packet.encode().unwrap()
+        };
+        let known = ConnectionId::new(8, vec![1; 8]);
+        let unknown = ConnectionId::new(8, vec![2; 8]);
+
+        // without a key, nothing is ever sent back
+        let mut endpoint = Endpoint::new();
+        assert_eq!(endpoint.stateless_reset(&one_rtt(&unknown)), None);
+
+        endpoint = Endpoint::with_config(EndpointConfig {
+            stateless_reset_key: Some(key.clone()),
+            ..Default::default()
+        });
+        let handle = endpoint
+            .insert(connection(&peer).await, known.clone())
+            .unwrap();
+        assert_eq!(endpoint.stateless_reset(&one_rtt(&known)), None);
+
+        let reset = endpoint.stateless_reset(&one_rtt(&unknown)).unwrap();
+        assert!(reset.len() < one_rtt(&unknown).len());
+        assert_eq!(reset[reset.len() - 16..], key.token(&unknown.cid));
+        // too small to answer with anything smaller
+        let mut runt = one_rtt(&unknown);
+        runt.truncate(MIN_STATELESS_RESET_SIZE);
+        assert_eq!(endpoint.stateless_reset(&runt), None);
+        // long headers aren't reset
+        let mut long = one_rtt(&unknown);
+        long[0] |= 0b10_000000;
+        assert_eq!(endpoint.stateless_reset(&long), None);
+
+        // the tokens handed out for the endpoint's cids are the ones it would reset with
+        let cid = endpoint.issue_cid(handle).unwrap();
+        endpoint
+            .get_mut(handle)
+            .unwrap()
+            .flush(usize::MAX)
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        let token = Packet::decode(&mut buf)
+            .unwrap()
+            .payload
+            .into_iter()
+            .find_map(|frame| match frame {
+                Frame::NewConnectionId {
+                    stateless_reset_token,
+                    ..
+                } => Some(stateless_reset_token),
+                _ => None,
+            });
+        assert_eq!(token, Some(key.token(&cid.cid)));
+    }
+
+    #[tokio::test]
+    async fn test_route_datagram() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = peer.local_addr().unwrap();
+        let tripped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            drop_threshold: Some(DropThreshold::new(3, {
+                let tripped = tripped.clone();
+                move |addr, count| tripped.lock().unwrap().push((addr, count))
+            })),
+            ..Default::default()
+        });
+        let known = ConnectionId::new(8, vec![1; 8]);
+        let unknown = ConnectionId::new(8, vec![2; 8]);
+        let handle = endpoint
+            .insert(connection(&peer).await, known.clone())
+            .unwrap();
+
+        let one_rtt = |cid: &ConnectionId| {
+            Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                cid.clone(),
+                vec![0],
+                vec![Frame::Ping],
+            )
+            .encode()
+            .unwrap()
+        };
+        let initial = Packet::initial(
+            MINI_QUICHE_VERSION,
+            unknown.clone(),
+            known.clone(),
+            FourBits::zero(),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::zero(),
+            PacketNumber(VarInt::zero()),
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap();
+        let mut padded = initial.clone();
+        padded.resize(MIN_INITIAL_SIZE, 0);
+
+        assert_eq!(
+            endpoint.route_datagram(from, &one_rtt(&known)),
+            Incoming::Connection(handle)
+        );
+        assert_eq!(
+            endpoint.route_datagram(from, &padded),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+        assert_eq!(
+            endpoint.route_datagram(from, &initial),
+            Incoming::Dropped(DropReason::UndersizedInitial)
+        );
+        assert_eq!(
+            endpoint.route_datagram(from, &one_rtt(&unknown)),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+        assert_eq!(
+            endpoint.route_datagram(from, &[0]),
+            Incoming::Dropped(DropReason::Malformed)
+        );
+        endpoint.on_dropped(from, DropReason::FailedIntegrity);
+
+        let drops = endpoint.drops();
+        for reason in [
+            DropReason::UndersizedInitial,
+            DropReason::UnknownCid,
+            DropReason::Malformed,
+            DropReason::FailedIntegrity,
+        ] {
+            assert_eq!(drops.get(reason), 1);
+        }
+        assert_eq!(drops.get(DropReason::AmplificationLimited), 0);
+        assert_eq!(drops.from_addr(from.ip()), 4);
+        assert_eq!(*tripped.lock().unwrap(), vec![(from.ip(), 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_address_validation() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = peer.local_addr().unwrap();
+        let client = ConnectionId::new(4, vec![3; 4]);
+        let initial = |dst_cid: &ConnectionId, token: Vec<u8>| {
+            let mut initial = Packet::initial(
+                MINI_QUICHE_VERSION,
+                dst_cid.clone(),
+                client.clone(),
+                FourBits::zero(),
+                VarInt(token.len() as u64),
+                token,
+                VarInt::zero(),
+                PacketNumber(VarInt::zero()),
+                vec![Frame::Ping],
+            )
+            .encode()
+            .unwrap();
+            initial.resize(MIN_INITIAL_SIZE, 0);
+            initial
+        };
+        let original = ConnectionId::new(8, vec![2; 8]);
+
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            server: ServerConfig {
+                address_validation: AddressValidation::Always,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let first = initial(&original, Vec::new());
+        assert_eq!(endpoint.route_datagram(from, &first), Incoming::Retry);
+
+        // the Retry goes back to the client's cid, from a new one of ours, with a token after them
+        let retry = endpoint.retry(from, &first).unwrap();
+        assert_eq!(Header::peek_dst_cid(&retry), Some(&client.cid[..]));
+        let src_len = retry[6 + client.cid.len()] as usize;
+        let src_at = 7 + client.cid.len();
+        let retry_cid = ConnectionId::new(src_len as u8, retry[src_at..src_at + src_len].to_vec());
+        let token = retry[src_at + src_len..retry.len() - 16].to_vec();
+
+        let second = initial(&retry_cid, token.clone());
+        assert_eq!(
+            endpoint.route_datagram(from, &second),
+            Incoming::NewConnection {
+                address_validated: true
+            }
+        );
+        assert_eq!(
+            endpoint.validate_token(from, &second),
+            Some(original.clone())
+        );
+        // the connection it starts echoes the cid from before the Retry, and the Retry's
+        let handle = endpoint
+            .insert_incoming(
+                connection(&peer).await,
+                ConnectionId::new(8, vec![5; 8]),
+                from,
+                &second,
+                true,
+            )
+            .unwrap();
+        let params = endpoint.get(handle).unwrap().transport_parameters();
+        assert_eq!(
+            params.original_destination_connection_id,
+            Some(original.clone())
+        );
+        assert_eq!(params.retry_source_connection_id, Some(retry_cid.clone()));
+        assert!(endpoint.get(handle).unwrap().amplification().is_validated());
+        // from anywhere else it's no good
+        let spoofed: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        assert_eq!(endpoint.route_datagram(spoofed, &second), Incoming::Retry);
+
+        // a token from an earlier connection's NEW_TOKEN skips the Retry too
+        let new_token = endpoint
+            .config()
+            .server
+            .token_key
+            .mint_new_token(from, SystemTime::now());
+        let resumed = initial(&original, new_token);
+        assert_eq!(
+            endpoint.route_datagram(from, &resumed),
+            Incoming::NewConnection {
+                address_validated: true
+            }
+        );
+        assert_eq!(endpoint.validate_token(from, &resumed), None);
+        assert_eq!(endpoint.route_datagram(spoofed, &resumed), Incoming::Retry);
+
+        // under load, only once there are enough handshakes going
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            server: ServerConfig {
+                address_validation: AddressValidation::UnderLoad(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(
+            endpoint.route_datagram(from, &first),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+        endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+        assert_eq!(endpoint.handshakes(), 1);
+        assert_eq!(endpoint.route_datagram(from, &first), Incoming::Retry);
+    }
+
+    #[tokio::test]
+    async fn test_version_negotiation() {
+        let from: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let mut endpoint = Endpoint::new();
+        let initial = |version| {
+            let mut initial = Packet::initial(
+                version,
+                ConnectionId::new(8, vec![2; 8]),
+                ConnectionId::new(4, vec![3; 4]),
+                FourBits::zero(),
+                VarInt::zero(),
+                Vec::new(),
+                VarInt::zero(),
+                PacketNumber(VarInt::zero()),
+                vec![Frame::Ping],
+            )
+            .encode()
+            .unwrap();
+            initial.resize(MIN_INITIAL_SIZE, 0);
+            initial
+        };
+
+        // a client greasing its version is answered like any other we don't speak
+        for version in [Version::greased(), 0xff00_001d] {
+            let datagram = initial(version);
+            assert_eq!(
+                endpoint.route_datagram(from, &datagram),
+                Incoming::VersionNegotiation
+            );
+            // too small to have started a connection
+            assert_eq!(
+                endpoint.route_datagram(from, &datagram[..100]),
+                Incoming::Dropped(DropReason::UndersizedInitial)
+            );
+
+            let answer = endpoint.version_negotiation(&datagram).unwrap();
+            let info = Header::peek(&answer, 0).unwrap();
+            assert_eq!(info.packet_type, PacketType::VersionNegotiation);
+            assert_eq!(info.dst_cid, ConnectionId::new(4, vec![3; 4]));
+            assert_eq!(info.src_cid, Some(ConnectionId::new(8, vec![2; 8])));
+            let versions = answer[7 + 4 + 8..]
+                .chunks(4)
+                .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            assert_eq!(versions.len(), Version::ALL.len() + 1);
+            assert!(versions.contains(&MINI_QUICHE_VERSION));
+            assert!(versions.iter().any(|&v| Version::is_reserved(v)));
+            assert_eq!(
+                Version::select(&[Version::MiniQuiche], &versions),
+                Some(Version::MiniQuiche)
+            );
+        }
+        assert_eq!(
+            endpoint.route_datagram(from, &initial(MINI_QUICHE_VERSION)),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+
+        // what's turned away and what's advertised come from the same list
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            supported_versions: vec![Version::MiniQuiche],
+            ..Default::default()
+        });
+        let datagram = initial(Version::V1.to_u32());
+        assert_eq!(
+            endpoint.route_datagram(from, &datagram),
+            Incoming::VersionNegotiation
+        );
+        let answer = endpoint.version_negotiation(&datagram).unwrap();
+        let versions = answer[7 + 4 + 8..]
+            .chunks(4)
+            .map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+            .filter(|&v| !Version::is_reserved(v))
+            .collect::<Vec<_>>();
+        assert_eq!(versions, vec![MINI_QUICHE_VERSION]);
+        assert_eq!(
+            endpoint.route_datagram(from, &initial(MINI_QUICHE_VERSION)),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_initials() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = peer.local_addr().unwrap();
+        let original = ConnectionId::new(8, vec![2; 8]);
+        let mut initial = Packet::initial(
+            MINI_QUICHE_VERSION,
+            original.clone(),
+            ConnectionId::new(4, vec![3; 4]),
+            FourBits::zero(),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::zero(),
+            PacketNumber(VarInt::zero()),
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap();
+        initial.resize(MIN_INITIAL_SIZE, 0);
+
+        let mut endpoint = Endpoint::new();
+        assert_eq!(
+            endpoint.route_datagram(from, &initial),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+        let handle = endpoint
+            .insert_incoming(
+                connection(&peer).await,
+                ConnectionId::new(8, vec![1; 8]),
+                from,
+                &initial,
+                false,
+            )
+            .unwrap();
+        // which it echoes in its transport parameters, no Retry in between
+        let params = endpoint.get(handle).unwrap().transport_parameters();
+        assert_eq!(
+            params.original_destination_connection_id,
+            Some(original.clone())
+        );
+        assert_eq!(params.retry_source_connection_id, None);
+        assert!(!endpoint.get(handle).unwrap().amplification().is_validated());
+        // the same Initial again goes to the connection it started, from another client it's new
+        assert_eq!(
+            endpoint.route_datagram(from, &initial),
+            Incoming::Connection(handle)
+        );
+        let other: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        assert_eq!(
+            endpoint.route_datagram(other, &initial),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+
+        // once the handshake is done the original dst cid means nothing anymore
+        let conn = endpoint.get_mut(handle).unwrap();
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.transition(ConnectionState::Connected).unwrap();
+        assert_eq!(
+            endpoint.route_datagram(from, &initial),
+            Incoming::NewConnection {
+                address_validated: false
+            }
+        );
+        assert!(endpoint.initials.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_amplification_limit() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from = peer.local_addr().unwrap();
+        let mut initial = Packet::initial(
+            MINI_QUICHE_VERSION,
+            ConnectionId::new(8, vec![2; 8]),
+            ConnectionId::new(4, vec![3; 4]),
+            FourBits::zero(),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::zero(),
+            PacketNumber(VarInt::zero()),
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap();
+        initial.resize(MIN_INITIAL_SIZE, 0);
+
+        // no token, so the server connection answers with no more than three times the Initial
+        let mut endpoint = Endpoint::new();
+        let Incoming::NewConnection { address_validated } = endpoint.route_datagram(from, &initial)
+        else {
+            panic!("expected a new connection");
+        };
+        assert!(!address_validated);
+        let handle = endpoint
+            .insert_incoming(
+                connection(&peer).await,
+                ConnectionId::new(8, vec![1; 8]),
+                from,
+                &initial,
+                address_validated,
+            )
+            .unwrap();
+        let conn = endpoint.get_mut(handle).unwrap();
+        peer.connect(conn.path().local_addr).await.unwrap();
+        conn.send_crypto(PacketSpace::Initial, &[1; 100]);
+        conn.send_crypto(PacketSpace::Handshake, &[2; 10_000]);
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+
+        peer.send(&initial).await.unwrap();
+        conn.recv().await.unwrap();
+        let sent = conn.flush(usize::MAX).await.unwrap();
+        assert!(sent > 0 && sent <= 3 * initial.len());
+        assert!(conn.crypto(PacketSpace::Handshake).has_pending());
+        assert_eq!(conn.flush(usize::MAX).await.unwrap(), 0);
+
+        // every flush that held something back counts, as does a packet that didn't authenticate,
+        // once the endpoint collects them
+        conn.on_packet_open_failed().unwrap();
+        assert_eq!(endpoint.drops().total(), 0);
+        endpoint.reap(Instant::now());
+        assert_eq!(endpoint.drops().get(DropReason::AmplificationLimited), 3);
+        assert_eq!(endpoint.drops().get(DropReason::FailedIntegrity), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flow_control_config() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let window = |initial| WindowConfig {
+            initial,
+            min: 1_000,
+            max: 1_000_000,
+        };
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            flow_control: FlowControlConfig {
+                stream: window(20_000),
+                connection: window(50_000),
+                max_streams_bidi: 10,
+                max_streams_uni: 3,
+            },
+            ..Default::default()
+        });
+        let handle = endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+        let params = endpoint.get(handle).unwrap().transport_parameters();
+        assert_eq!(params.initial_max_data, 50_000);
+        assert_eq!(params.initial_max_stream_data_bidi_local, 20_000);
+        assert_eq!(params.initial_max_stream_data_uni, 20_000);
+        assert_eq!(
+            (
+                params.initial_max_streams_bidi,
+                params.initial_max_streams_uni
+            ),
+            (10, 3)
+        );
+
+        // and it's what the peer ends up allowed to send
+        let mut other = connection(&peer).await;
+        other.set_side(Side::Server).unwrap();
+        other
+            .on_encoded_peer_transport_parameters(&params.encode().unwrap())
+            .unwrap();
+        assert_eq!(other.send_window().max_data(), 50_000);
+    }
+
+    #[tokio::test]
+    async fn test_recovery_config() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // a datacenter link: the closing period is three PTOs of a 10ms rtt, not three seconds
+        let recovery = RecoveryConfig {
+            initial_rtt: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            recovery,
+            ..Default::default()
+        });
+        let handle = endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+        let conn = endpoint.get_mut(handle).unwrap();
+        assert_eq!(conn.recovery_config(), &recovery);
+        conn.transition(ConnectionState::Handshaking).unwrap();
+        conn.close(0, b"").await.unwrap();
+        let closing = conn.next_timeout().unwrap() - Instant::now();
+        assert!(closing <= Duration::from_millis(90), "{:?}", closing);
+
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            recovery: RecoveryConfig {
+                packet_threshold: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert!(endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![2; 8]))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cid_codec() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let codec = PlaintextCidCodec::new(1, vec![0x42], 8).unwrap();
+        let mut endpoint = Endpoint::with_config(EndpointConfig {
+            cid_codec: Arc::new(codec.clone()),
+            ..Default::default()
+        });
+
+        let handle = endpoint
+            .insert(connection(&peer).await, ConnectionId::new(8, vec![1; 8]))
+            .unwrap();
+        // the handshake cid is minted again by the codec, since nothing was sent on it yet
+        let initial = endpoint
+            .get(handle)
+            .unwrap()
+            .local_cids()
+            .initial()
+            .cloned();
+        let initial = initial.unwrap();
+        assert_eq!(endpoint.server_id(&initial.cid), Some(vec![0x42]));
+        // and it's what routes to the connection, along with the cid it was inserted under
+        assert_eq!(endpoint.route(&initial.cid), Some(handle));
+        assert_eq!(endpoint.route(&[1; 8]), Some(handle));
+
+        let cid = endpoint.issue_cid(handle).unwrap();
+        assert_eq!(cid.cid_len, 10);
+        assert_eq!(endpoint.route(&cid.cid), Some(handle));
+        assert_eq!(endpoint.server_id(&cid.cid), Some(vec![0x42]));
+        assert!(endpoint.issue_cid(99).is_err());
+
+        // another server behind the same load balancer
+        let other = PlaintextCidCodec::new(1, vec![0x43], 8).unwrap().generate();
+        assert_eq!(endpoint.route(&other.cid), None);
+        assert_eq!(endpoint.server_id(&other.cid), Some(vec![0x43]));
+
+        // with authenticated cids, ones we never minted get no stateless reset
+        let codec = AuthenticatedCidCodec::new(Arc::new(codec), [7; 16], 4).unwrap();
+        let unroutable = Arc::new(Mutex::new(0));
+        let mut endpoint = Endpoint::with_config(
+            EndpointConfig {
+                cid_codec: Arc::new(codec.clone()),
+                stateless_reset_key: Some(StatelessResetKey::new([9; 16])),
+                ..Default::default()
+            }
+            .on_unroutable({
+                let unroutable = unroutable.clone();
+                move |_, _| {
+                    *unroutable.lock().unwrap() += 1;
+                    Forwarding::Continue
+                }
+            }),
+        );
+        let one_rtt = |cid: &ConnectionId| {
+            let mut packet = Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                cid.clone(),
+                vec![0],
+                vec![Frame::Ping],
+            );
+            packet
+                .payload
+                .extend(std::iter::repeat_n(Frame::Padding, 40));
+            packet.encode().unwrap()
+        };
+        let lost = codec.generate();
+        assert!(endpoint.is_ours(&lost.cid));
+        assert!(endpoint.stateless_reset(&one_rtt(&lost)).is_some());
+        assert!(!endpoint.is_ours(&other.cid));
+        assert_eq!(endpoint.stateless_reset(&one_rtt(&other)), None);
+
+        // and are dropped before the hook or the drop stats ever see them
+        let from: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        assert_eq!(
+            endpoint.route_datagram(from, &one_rtt(&other)),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+        assert_eq!(*unroutable.lock().unwrap(), 0);
+        assert_eq!(endpoint.drops().total(), 0);
+        assert_eq!(
+            endpoint.route_datagram(from, &one_rtt(&lost)),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+        assert_eq!(*unroutable.lock().unwrap(), 1);
+        assert_eq!(endpoint.drops().total(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_routing_hooks() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let from: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let ours = PlaintextCidCodec::new(0, vec![0x42], 8).unwrap();
+        let theirs = PlaintextCidCodec::new(0, vec![0x43], 8).unwrap();
+        let forwarded = Arc::new(Mutex::new(Vec::new()));
+        let sent = Arc::new(Mutex::new(0));
+
+        // a worker that hands anything with another server id to its owner, and sends every
+        // other datagram it writes itself
+        let mut endpoint = Endpoint::with_config(
+            EndpointConfig {
+                cid_codec: Arc::new(ours.clone()),
+                ..Default::default()
+            }
+            .on_unroutable({
+                let forwarded = forwarded.clone();
+                let ours = ours.clone();
+                move |datagram, src| match Header::peek_dst_cid(datagram)
+                    .and_then(|cid| ours.server_id(cid))
+                {
+                    Some(server_id) if server_id != [0x42] => {
+                        forwarded.lock().unwrap().push((datagram.to_vec(), src));
+                        Forwarding::Forwarded
+                    }
+                    _ => Forwarding::Continue,
+                }
+            })
+            .on_pre_send({
+                let sent = sent.clone();
+                move |_, _| {
+                    let mut sent = sent.lock().unwrap();
+                    *sent += 1;
+                    match *sent % 2 {
+                        1 => Forwarding::Forwarded,
+                        _ => Forwarding::Continue,
+                    }
+                }
+            }),
+        );
+
+        let short = |cid: ConnectionId| {
+            Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                cid,
+                vec![0],
+                vec![Frame::Ping],
+            )
+            .encode()
+            .unwrap()
+        };
+        let datagram = short(theirs.generate());
+        assert_eq!(
+            endpoint.route_datagram(from, &datagram),
+            Incoming::Forwarded
+        );
+        assert_eq!(*forwarded.lock().unwrap(), vec![(datagram, from)]);
+        assert_eq!(endpoint.drops().total(), 0);
+        // one of ours we've lost track of is still ours to drop
+        assert_eq!(
+            endpoint.route_datagram(from, &short(ours.generate())),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+        assert_eq!(forwarded.lock().unwrap().len(), 1);
+
+        let handle = endpoint
+            .insert(connection(&peer).await, ours.generate())
+            .unwrap();
+        let conn = endpoint.get_mut(handle).unwrap();
+        let ping = |number| {
+            Packet::short_header(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                PnLen::from_len(1).unwrap(),
+                ConnectionId::new(8, vec![2; 8]),
+                vec![number],
+                vec![Frame::Ping],
+            )
+        };
+        for number in 0..2 {
+            conn.queue_packet(ping(number));
+            conn.flush(usize::MAX).await.unwrap();
+        }
+        assert_eq!(*sent.lock().unwrap(), 2);
+
+        // the first went out through the hook, only the second through the socket
+        let mut buf = vec![0; 1_500];
+        let len = peer.recv(&mut buf).await.unwrap();
+        buf.truncate(len);
+        assert_eq!(buf, ping(1).encode().unwrap());
+    }
+}
@@ -0,0 +1,43 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+// what a routing hook did with a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forwarding {
+    // nothing, the endpoint / connection carries on with it as usual
+    Continue,
+    // the hook took it, e.g. passed it to the worker that owns its cid, and it goes no further
+    Forwarded,
+}
+
+// called with every datagram whose dst cid doesn't route to one of our connections, and where
+// it came from, before the endpoint decides what to make of it
+pub type UnroutableHook = dyn Fn(&[u8], SocketAddr) -> Forwarding + Send + Sync;
+
+// called with every datagram a connection is about to write to its socket, and where it's going
+pub type PreSendHook = dyn Fn(&[u8], SocketAddr) -> Forwarding + Send + Sync;
+
+// where a deployment running several endpoints on one port (SO_REUSEPORT, one per process) steps
+// in to move datagrams between them, by whatever it reads out of the cids.  neither is set by
+// default
+#[derive(Clone, Default)]
+pub struct RoutingHooks {
+    pub on_unroutable: Option<Arc<UnroutableHook>>,
+    pub on_pre_send: Option<Arc<PreSendHook>>,
+}
+
+impl RoutingHooks {
+    pub fn unroutable(&self, datagram: &[u8], src: SocketAddr) -> Forwarding {
+        self.on_unroutable
+            .as_ref()
+            .map_or(Forwarding::Continue, |hook| hook(datagram, src))
+    }
+}
+
+impl fmt::Debug for RoutingHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoutingHooks")
+            .field("on_unroutable", &self.on_unroutable.is_some())
+            .field("on_pre_send", &self.on_pre_send.is_some())
+            .finish()
+    }
+}
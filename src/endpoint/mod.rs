@@ -0,0 +1,17 @@
+pub mod cid_codec;
+pub mod config;
+pub mod drops;
+pub mod endpoint;
+pub mod hooks;
+pub mod shards;
+pub mod stateless_reset;
+pub mod token;
+
+pub use cid_codec::*;
+pub use config::*;
+pub use drops::*;
+pub use endpoint::*;
+pub use hooks::*;
+pub use shards::*;
+pub use stateless_reset::*;
+pub use token::*;
@@ -0,0 +1,215 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender},
+};
+
+use super::{CidCodec, Endpoint, EndpointConfig, Forwarding};
+use crate::{
+    connection::bind_reuseport,
+    packet::header::Header,
+    result::{require, QuicheError, QuicheResult},
+};
+
+// how many handed off datagrams can wait on a worker before more are dropped on the worker they
+// landed on instead
+pub const HANDOFF_QUEUE_LEN: usize = 1_024;
+
+// a datagram that landed on the wrong worker, on its way to the one whose server id its dst cid
+// carries.  after a client migrates the kernel hashes its new 4-tuple to any worker, so this is
+// how its packets find their connection again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handoff {
+    pub datagram: Vec<u8>,
+    pub from: SocketAddr,
+}
+
+// one of several endpoints sharing a port with SO_REUSEPORT, each meant to be driven by its own
+// task or thread.  it reads datagrams off its own socket and any handed over by the others
+pub struct Worker {
+    pub endpoint: Endpoint,
+    pub socket: Arc<UdpSocket>,
+    server_id: Vec<u8>,
+    handoffs: Receiver<Handoff>,
+}
+
+impl Worker {
+    // the server id this worker's cids carry
+    pub fn server_id(&self) -> &[u8] {
+        &self.server_id
+    }
+
+    // the next datagram for this worker and where it came from, truncated to `buf` like a socket
+    // read would be
+    pub async fn recv(&mut self, buf: &mut [u8]) -> QuicheResult<(usize, SocketAddr)> {
+        tokio::select! {
+            result = self.socket.recv_from(buf) => Ok(result?),
+            Some(handoff) = self.handoffs.recv() => {
+                let len = handoff.datagram.len().min(buf.len());
+                buf[..len].copy_from_slice(&handoff.datagram[..len]);
+                Ok((len, handoff.from))
+            }
+        }
+    }
+}
+
+// an endpoint per codec, all bound to `addr` with SO_REUSEPORT.  the codecs have to encode each
+// worker's own server id in a way every one of them can read back, e.g. `PlaintextCidCodec`s
+// with the same config rotation and server id length.  a datagram for a cid that doesn't route
+// locally but carries another worker's server id is handed to that worker; anything else goes
+// on to `config`'s own `on_unroutable`, if it has one.  port 0 is resolved by the first bind, so
+// every worker ends up on the same port
+pub async fn bind_workers(
+    addr: SocketAddr,
+    config: EndpointConfig,
+    codecs: Vec<Arc<dyn CidCodec>>,
+) -> QuicheResult<Vec<Worker>> {
+    require(!codecs.is_empty(), "bind_workers: need at least one codec")?;
+    let mut server_ids = Vec::with_capacity(codecs.len());
+    for codec in codecs.iter() {
+        let server_id = codec.server_id(&codec.generate().cid).ok_or_else(|| {
+            QuicheError("bind_workers: codec doesn't encode a server id".to_string())
+        })?;
+        require(
+            !server_ids.contains(&server_id),
+            "bind_workers: two workers with the same server id",
+        )?;
+        server_ids.push(server_id);
+    }
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = codecs
+        .iter()
+        .map(|_| mpsc::channel(HANDOFF_QUEUE_LEN))
+        .unzip();
+    let senders: Arc<HashMap<Vec<u8>, Sender<Handoff>>> =
+        Arc::new(server_ids.iter().cloned().zip(senders).collect());
+
+    let mut addr = addr;
+    let mut workers = Vec::with_capacity(codecs.len());
+    for ((codec, server_id), handoffs) in codecs.into_iter().zip(server_ids).zip(receivers) {
+        let socket = bind_reuseport(addr)?;
+        addr = socket.local_addr()?;
+
+        let mut worker_config = config.clone();
+        worker_config.cid_codec = codec.clone();
+        worker_config.routing.on_unroutable = Some(Arc::new({
+            let senders = senders.clone();
+            let own = server_id.clone();
+            let next = config.routing.clone();
+            move |datagram: &[u8], from: SocketAddr| {
+                let owner = Header::peek_dst_cid(datagram)
+                    .and_then(|cid| codec.server_id(cid))
+                    .filter(|owner| *owner != own)
+                    .and_then(|owner| senders.get(&owner));
+                let Some(owner) = owner else {
+                    return next.unroutable(datagram, from);
+                };
+                let handoff = Handoff {
+                    datagram: datagram.to_vec(),
+                    from,
+                };
+                match owner.try_send(handoff) {
+                    Ok(()) => Forwarding::Forwarded,
+                    // the owner is backed up or gone, it's ours to drop
+                    Err(TrySendError::Full(_) | TrySendError::Closed(_)) => Forwarding::Continue,
+                }
+            }
+        }));
+        workers.push(Worker {
+            endpoint: Endpoint::with_config(worker_config),
+            socket: Arc::new(socket),
+            server_id,
+            handoffs,
+        });
+    }
+    Ok(workers)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use crate::{
+        bits::BitsExt,
+        endpoint::{DropReason, Incoming, PlaintextCidCodec},
+        packet::{frame::Frame, packet::Packet, types::ConnectionId, PnLen, SingleBit, TwoBits},
+    };
+
+    fn short(cid: ConnectionId) -> Vec<u8> {
+        Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid,
+            vec![0],
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bind_workers() {
+        let codec = |server_id| PlaintextCidCodec::new(0, vec![server_id], 8).unwrap();
+        let codecs: Vec<Arc<dyn CidCodec>> = vec![Arc::new(codec(1)), Arc::new(codec(2))];
+        let mut workers = bind_workers(
+            "127.0.0.1:0".parse().unwrap(),
+            EndpointConfig::default(),
+            codecs,
+        )
+        .await
+        .unwrap();
+        let port = workers[0].socket.local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(workers[1].socket.local_addr().unwrap().port(), port);
+        assert_eq!(workers[1].server_id(), [2]);
+
+        // a migrated client's packet for worker 1 lands on worker 2
+        let from: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let datagram = short(codec(1).generate());
+        assert_eq!(
+            workers[1].endpoint.route_datagram(from, &datagram),
+            Incoming::Forwarded
+        );
+        let mut buf = vec![0; 1_500];
+        let (len, handed_from) = workers[0].recv(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], handed_from), (&datagram[..], from));
+
+        // worker 1 has no such connection, and won't pass it back.  nor does anyone hand over a
+        // cid no worker owns
+        assert_eq!(
+            workers[0].endpoint.route_datagram(from, &datagram),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+        let stranger = PlaintextCidCodec::new(0, vec![3], 8).unwrap().generate();
+        assert_eq!(
+            workers[1].endpoint.route_datagram(from, &short(stranger)),
+            Incoming::Dropped(DropReason::UnknownCid)
+        );
+
+        // and datagrams off the socket still come through
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(("127.0.0.1", port)).await.unwrap();
+        client.send(b"hello").await.unwrap();
+        let mut other = vec![0; 1_500];
+        let (worker, second) = workers.split_at_mut(1);
+        let recv = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            tokio::select! {
+                result = worker[0].recv(&mut buf) => result.unwrap().0,
+                result = second[0].recv(&mut other) => result.unwrap().0,
+            }
+        });
+        let len = recv.await.unwrap();
+        assert!(buf[..len] == *b"hello" || other[..len] == *b"hello");
+
+        let same: Vec<Arc<dyn CidCodec>> = vec![Arc::new(codec(1)), Arc::new(codec(1))];
+        assert!(bind_workers(
+            "127.0.0.1:0".parse().unwrap(),
+            EndpointConfig::default(),
+            same
+        )
+        .await
+        .is_err());
+    }
+}
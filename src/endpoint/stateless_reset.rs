@@ -0,0 +1,113 @@
+#[allow(deprecated)]
+use std::hash::{Hasher, SipHasher};
+
+use crate::{consts::STATELESS_RESET_TOKEN_LEN, fill_secure, secure_bytes};
+
+// a stateless reset is at least 5 unpredictable bytes and the token (RFC 9000 section 10.3)
+pub const MIN_STATELESS_RESET_SIZE: usize = 5 + STATELESS_RESET_TOKEN_LEN;
+
+// no bigger than the smallest short header packets, so a reset can't be told apart by its size
+pub const MAX_STATELESS_RESET_SIZE: usize = 43;
+
+// the static key reset tokens are derived from, so that a server that's lost a connection's state
+// (e.g. after a restart) can still produce the token it gave out for a cid (RFC 9000 section
+// 10.3.2).  every server behind the same load balancer should share it
+#[derive(Clone)]
+pub struct StatelessResetKey([u8; 16]);
+
+impl StatelessResetKey {
+    pub fn new(key: [u8; 16]) -> Self {
+        Self(key)
+    }
+
+    // a fresh key from the OS's generator, the tokens derived from it don't survive a restart
+    pub fn random() -> Self {
+        Self(secure_bytes())
+    }
+
+    // TODO: this should be an HMAC, siphash is what std has until there's a crypto dependency
+    #[allow(deprecated)]
+    pub fn token(&self, cid: &[u8]) -> [u8; STATELESS_RESET_TOKEN_LEN] {
+        let k0 = u64::from_le_bytes(self.0[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(self.0[8..].try_into().unwrap());
+        let mut token = [0; STATELESS_RESET_TOKEN_LEN];
+        for (half, chunk) in token.chunks_mut(8).enumerate() {
+            let mut hasher = SipHasher::new_with_keys(k0, k1);
+            hasher.write_u8(half as u8);
+            hasher.write(cid);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        token
+    }
+
+    // a stateless reset in answer to a datagram of `trigger_len` bytes.  it's always shorter than
+    // what triggered it, so two endpoints can't keep resetting each other forever, which means
+    // there's no answer at all to anything too small to be shorter than
+    pub fn stateless_reset(&self, cid: &[u8], trigger_len: usize) -> Option<Vec<u8>> {
+        if trigger_len <= MIN_STATELESS_RESET_SIZE {
+            return None;
+        }
+        let len = (trigger_len - 1).min(MAX_STATELESS_RESET_SIZE);
+        let mut reset = vec![0; len - STATELESS_RESET_TOKEN_LEN];
+        fill_secure(&mut reset);
+        // it has to pass for a short header packet: header form cleared, fixed bit set
+        reset[0] = (reset[0] & 0b00_111111) | 0b01_000000;
+        reset.extend(self.token(cid));
+        Some(reset)
+    }
+}
+
+impl Default for StatelessResetKey {
+    fn default() -> Self {
+        Self::random()
+    }
+}
+
+// the key itself stays out of logs
+impl std::fmt::Debug for StatelessResetKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StatelessResetKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stateless_reset() {
+        let key = StatelessResetKey::new([7; 16]);
+        // the same cid always gets the same token, and only under the same key
+        assert_eq!(key.token(&[1; 8]), key.token(&[1; 8]));
+        assert_ne!(key.token(&[1; 8]), key.token(&[2; 8]));
+        assert_ne!(
+            key.token(&[1; 8]),
+            StatelessResetKey::new([8; 16]).token(&[1; 8])
+        );
+
+        assert!(key
+            .stateless_reset(&[1; 8], MIN_STATELESS_RESET_SIZE)
+            .is_none());
+        let reset = key
+            .stateless_reset(&[1; 8], MIN_STATELESS_RESET_SIZE + 1)
+            .unwrap();
+        assert_eq!(reset.len(), MIN_STATELESS_RESET_SIZE);
+        assert_eq!(reset[0] & 0b11_000000, 0b01_000000);
+        assert_eq!(reset[reset.len() - 16..], key.token(&[1; 8]));
+        assert_eq!(
+            key.stateless_reset(&[1; 8], 1_200).unwrap().len(),
+            MAX_STATELESS_RESET_SIZE
+        );
+        // the filler is never the same twice
+        assert_ne!(
+            key.stateless_reset(&[1; 8], 1_200),
+            key.stateless_reset(&[1; 8], 1_200)
+        );
+
+        // two random keys, say two processes' default keys, never agree
+        assert_ne!(
+            StatelessResetKey::random().token(&[1; 8]),
+            StatelessResetKey::random().token(&[1; 8])
+        );
+    }
+}
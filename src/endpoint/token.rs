@@ -0,0 +1,221 @@
+#[allow(deprecated)]
+use std::hash::{Hasher, SipHasher};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::secure_bytes;
+
+// a token is its kind, when it was issued (8 bytes), for a Retry token the original dst cid's
+// length and the cid, then a tag this long
+const TAG_LEN: usize = 16;
+
+// the first byte, so a NEW_TOKEN token can't be passed off as a Retry one or the other way
+// around (RFC 9000 section 8.1.3).  they're good for very different lengths of time
+const RETRY_TOKEN: u8 = 0;
+const NEW_TOKEN: u8 = 1;
+
+// random bytes from the OS after a NEW_TOKEN token's issue time, so no two are the same and a
+// client using one can't be linked to the connection it came on
+const NONCE_LEN: usize = 8;
+
+// mints and checks the address validation tokens a server hands out in Retry packets and NEW_TOKEN
+// frames, so a client that comes back with one has proven it can receive at its address (RFC
+// 9000 section 8.1).  a token only checks out for the address it was minted for, and only for as
+// long as it's fresh.  every server behind the same load balancer should share the key
+#[derive(Clone)]
+pub struct TokenKey([u8; 16]);
+
+impl TokenKey {
+    pub fn new(key: [u8; 16]) -> Self {
+        Self(key)
+    }
+
+    // a fresh key from the OS's generator, tokens minted with it don't survive a restart
+    pub fn random() -> Self {
+        Self(secure_bytes())
+    }
+
+    // a token for a client at `addr` whose first Initial went to `original_dst_cid`.  the cid
+    // comes back out of the token, since the server has to echo it in its transport parameters
+    pub fn mint_retry_token(
+        &self,
+        addr: SocketAddr,
+        original_dst_cid: &[u8],
+        now: SystemTime,
+    ) -> Vec<u8> {
+        let mut token = issued(RETRY_TOKEN, now);
+        token.push(original_dst_cid.len() as u8);
+        token.extend_from_slice(original_dst_cid);
+        let tag = self.tag(addr.ip(), &token);
+        token.extend(tag);
+        token
+    }
+
+    // a token for a client at `addr` to skip the Retry round trip on a later connection.  sent in
+    // NEW_TOKEN once the handshake has validated the address
+    pub fn mint_new_token(&self, addr: SocketAddr, now: SystemTime) -> Vec<u8> {
+        let mut token = issued(NEW_TOKEN, now);
+        token.extend(secure_bytes::<NONCE_LEN>());
+        let tag = self.tag(addr.ip(), &token);
+        token.extend(tag);
+        token
+    }
+
+    // the original dst cid from a token minted for `addr` no more than `lifetime` ago, None if
+    // it's expired, was minted for another address, or wasn't minted by us at all
+    pub fn validate_retry_token(
+        &self,
+        token: &[u8],
+        addr: SocketAddr,
+        now: SystemTime,
+        lifetime: Duration,
+    ) -> Option<Vec<u8>> {
+        let body = self.check(token, RETRY_TOKEN, addr, now, lifetime)?;
+        let cid_len = *body.first()? as usize;
+        let original_dst_cid = body.get(1..1 + cid_len)?;
+        Some(original_dst_cid.to_vec())
+    }
+
+    // whether `token` is one we sent a client at `addr` in NEW_TOKEN no more than `lifetime` ago
+    pub fn validate_new_token(
+        &self,
+        token: &[u8],
+        addr: SocketAddr,
+        now: SystemTime,
+        lifetime: Duration,
+    ) -> bool {
+        self.check(token, NEW_TOKEN, addr, now, lifetime)
+            .is_some_and(|body| body.len() == NONCE_LEN)
+    }
+
+    // what follows the issue time, if the token is of `kind`, ours, for `addr` and still fresh
+    fn check<'a>(
+        &self,
+        token: &'a [u8],
+        kind: u8,
+        addr: SocketAddr,
+        now: SystemTime,
+        lifetime: Duration,
+    ) -> Option<&'a [u8]> {
+        let (body, tag) = token.split_at(token.len().checked_sub(TAG_LEN)?);
+        if tag != self.tag(addr.ip(), body) || *body.first()? != kind {
+            return None;
+        }
+        let issued_at = u64::from_be_bytes(body.get(1..9)?.try_into().ok()?);
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now.saturating_sub(issued_at) > lifetime.as_secs() {
+            return None;
+        }
+        Some(&body[9..])
+    }
+
+    // TODO: this should be an HMAC, siphash is what std has until there's a crypto dependency
+    #[allow(deprecated)]
+    fn tag(&self, ip: IpAddr, body: &[u8]) -> [u8; TAG_LEN] {
+        let k0 = u64::from_le_bytes(self.0[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(self.0[8..].try_into().unwrap());
+        let mut tag = [0; TAG_LEN];
+        for (half, chunk) in tag.chunks_mut(8).enumerate() {
+            let mut hasher = SipHasher::new_with_keys(k0, k1);
+            hasher.write_u8(half as u8);
+            match ip {
+                IpAddr::V4(ip) => hasher.write(&ip.octets()),
+                IpAddr::V6(ip) => hasher.write(&ip.octets()),
+            }
+            hasher.write(body);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+        tag
+    }
+}
+
+fn issued(kind: u8, now: SystemTime) -> Vec<u8> {
+    let issued_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut token = vec![kind];
+    token.extend(issued_at.to_be_bytes());
+    token
+}
+
+// the key itself stays out of logs
+impl std::fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_token() {
+        let key = TokenKey::new([3; 16]);
+        let addr: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let now = SystemTime::now();
+        let lifetime = Duration::from_secs(10);
+        let token = key.mint_retry_token(addr, &[1, 2, 3, 4], now);
+
+        assert_eq!(
+            key.validate_retry_token(&token, addr, now, lifetime),
+            Some(vec![1, 2, 3, 4])
+        );
+        // the port is allowed to change, a NAT may well move it
+        let rebound = SocketAddr::new(addr.ip(), 5000);
+        assert!(key
+            .validate_retry_token(&token, rebound, now, lifetime)
+            .is_some());
+
+        let other: SocketAddr = "192.0.2.2:4433".parse().unwrap();
+        assert!(key
+            .validate_retry_token(&token, other, now, lifetime)
+            .is_none());
+        let later = now + Duration::from_secs(11);
+        assert!(key
+            .validate_retry_token(&token, addr, later, lifetime)
+            .is_none());
+        assert!(TokenKey::new([4; 16])
+            .validate_retry_token(&token, addr, now, lifetime)
+            .is_none());
+        let mut tampered = token.clone();
+        tampered[9] ^= 1;
+        assert!(key
+            .validate_retry_token(&tampered, addr, now, lifetime)
+            .is_none());
+        assert!(key
+            .validate_retry_token(&[0; 8], addr, now, lifetime)
+            .is_none());
+        // default keys aren't shared between configs, or guessable from another process
+        assert!(TokenKey::random()
+            .validate_retry_token(
+                &TokenKey::random().mint_retry_token(addr, &[1], now),
+                addr,
+                now,
+                lifetime
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_new_token() {
+        let key = TokenKey::new([3; 16]);
+        let addr: SocketAddr = "192.0.2.1:4433".parse().unwrap();
+        let now = SystemTime::now();
+        let lifetime = Duration::from_secs(3_600);
+        let token = key.mint_new_token(addr, now);
+        assert_ne!(key.mint_new_token(addr, now), token);
+
+        assert!(key.validate_new_token(&token, addr, now, lifetime));
+        assert!(!key.validate_new_token(&token, addr, now + lifetime * 2, lifetime));
+        let other: SocketAddr = "192.0.2.2:4433".parse().unwrap();
+        assert!(!key.validate_new_token(&token, other, now, lifetime));
+
+        // neither kind passes for the other
+        assert!(key
+            .validate_retry_token(&token, addr, now, lifetime)
+            .is_none());
+        let retry = key.mint_retry_token(addr, &[], now);
+        assert!(!key.validate_new_token(&retry, addr, now, lifetime));
+    }
+}
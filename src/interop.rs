@@ -0,0 +1,111 @@
+use std::env;
+
+// the quic interop runner (https://github.com/quic-interop/quic-interop-runner) drives each endpoint through env vars
+// the endpoint MUST exit with 127 for any testcase it doesn't support, so the runner can skip it instead of failing it
+pub const UNSUPPORTED_EXIT_CODE: i32 = 127;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TestCase {
+    // a single handshake, then a small file transfer
+    Handshake,
+    // a multi-megabyte transfer, exercises flow control & stream multiplexing
+    Transfer,
+    // the server MUST send a Retry before accepting the connection
+    Retry,
+    // the client resumes a session using a ticket from a previous connection
+    Resumption,
+    // the client sends its requests in 0-RTT packets on a resumed connection
+    ZeroRtt,
+}
+
+impl TestCase {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "handshake" => Some(TestCase::Handshake),
+            "transfer" => Some(TestCase::Transfer),
+            "retry" => Some(TestCase::Retry),
+            "resumption" => Some(TestCase::Resumption),
+            "zerortt" => Some(TestCase::ZeroRtt),
+            _ => None,
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        env::var("TESTCASE")
+            .ok()
+            .and_then(|name| Self::from_name(&name))
+    }
+
+    // every testcase starts with a TLS handshake, which mini-quiche doesn't do yet
+    // flip these on one at a time as the handshake / streams / retry / tickets land
+    pub fn supported(&self, _role: Role) -> bool {
+        match self {
+            TestCase::Handshake
+            | TestCase::Transfer
+            | TestCase::Retry
+            | TestCase::Resumption
+            | TestCase::ZeroRtt => false,
+        }
+    }
+}
+
+// everything the runner hands an endpoint, besides the testcase itself
+#[derive(Debug, Default, Clone)]
+pub struct InteropEnv {
+    // space separated urls the client has to download (client only)
+    pub requests: Vec<String>,
+    // where tls secrets should be written for wireshark
+    pub keylog_file: Option<String>,
+    pub qlog_dir: Option<String>,
+}
+
+impl InteropEnv {
+    pub fn from_env() -> Self {
+        Self {
+            requests: env::var("REQUESTS")
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(|url| url.to_string())
+                .collect(),
+            keylog_file: env::var("SSLKEYLOGFILE").ok(),
+            qlog_dir: env::var("QLOGDIR").ok(),
+        }
+    }
+}
+
+// shared entrypoint for the `quic-server` and `quic-client` binaries, returns the process exit code
+pub fn run(role: Role) -> i32 {
+    let testcase = match TestCase::from_env() {
+        Some(testcase) if testcase.supported(role) => testcase,
+        _ => return UNSUPPORTED_EXIT_CODE,
+    };
+    // TODO: drive a `Connection` through the testcase, with `InteropEnv::from_env`, once one is
+    // supported
+    let _ = testcase;
+    UNSUPPORTED_EXIT_CODE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_testcase_names() {
+        assert_eq!(TestCase::from_name("handshake"), Some(TestCase::Handshake));
+        assert_eq!(TestCase::from_name("transfer"), Some(TestCase::Transfer));
+        assert_eq!(TestCase::from_name("retry"), Some(TestCase::Retry));
+        assert_eq!(
+            TestCase::from_name("resumption"),
+            Some(TestCase::Resumption)
+        );
+        assert_eq!(TestCase::from_name("zerortt"), Some(TestCase::ZeroRtt));
+        // unknown testcases MUST be reported as unsupported, never as failures
+        assert_eq!(TestCase::from_name("chacha20"), None);
+    }
+}
@@ -0,0 +1,25 @@
+#![allow(
+    clippy::module_inception,
+    clippy::too_many_arguments,
+    clippy::len_without_is_empty
+)]
+
+pub mod primitives;
+pub use primitives::*;
+
+pub mod connection;
+pub mod consts;
+pub mod crypto;
+pub mod endpoint;
+pub mod interop;
+pub(crate) mod macros;
+pub mod metrics;
+pub mod packet;
+pub mod pcap;
+pub mod prelude;
+pub mod result;
+pub mod stream;
+pub mod sync;
+pub mod testing;
+
+pub use consts::MINI_QUICHE_VERSION;
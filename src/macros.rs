@@ -1,3 +1,4 @@
+#[doc(hidden)]
 #[macro_export]
 macro_rules! bits_ext {
     ($structname:ident, $trait:path, $len:literal, $t:ty) => {
@@ -40,10 +41,21 @@ macro_rules! bits_ext {
 #[derive(PartialEq, Eq, PartialOrd)]
 pub struct FrameType(pub(crate) u8);
 
+// everything about a frame type that isn't its fields, in one place: the `FrameType` constant for
+// its encoding, its `FrameKind` variant, the decoder it's dispatched to and the packets it's allowed
+// in (RFC 9000 table 3).  a kind whose type has flag bits (STREAM) is listed once, under its base
+// encoding.  the decoders are called as `decode(ty, bytes, limits)` and the dispatch is an
+// exhaustive match, so a kind without one doesn't compile
+#[doc(hidden)]
 #[macro_export]
 macro_rules! frame {
-    {$($frame:ident = $encoding:expr,)*} => {
-        use crate::macros::FrameType;
+    {$(
+        $frame:ident = $encoding:expr => $kind:ident {
+            decode: $decode:ident,
+            packets: [$($level:ident),*] $(,)?
+        },
+    )*} => {
+        pub use $crate::macros::FrameType;
 
         impl FrameType {
             $(pub const $frame: FrameType = FrameType($encoding);)*
@@ -52,118 +64,68 @@ macro_rules! frame {
                 self.0
             }
         }
-    }
-}
 
-#[macro_export]
-macro_rules! frame_size {
-    ($frame:expr) => {{
-        let size = match $frame {
-            Frame::Padding => 1,
-            Frame::Ping => 1,
-            Frame::Ack {
-                largest_acknowledged,
-                ack_delay,
-                ack_range_count,
-                first_ack_range,
-                ref ack_ranges,
-            } => {
-                1 + largest_acknowledged.size()
-                    + ack_delay.size()
-                    + ack_range_count.size()
-                    + first_ack_range.size()
-                    + ack_ranges
-                        .iter()
-                        .map(|(gap, len)| gap.size() + len.size())
-                        .sum::<usize>()
-            }
-            Frame::AckEcn {
-                largest_acknowledged,
-                ack_delay,
-                ack_range_count,
-                first_ack_range,
-                ref ack_ranges,
-                ect0_count,
-                ect1_count,
-                ecn_ce_count,
-            } => {
-                1 + largest_acknowledged.size()
-                    + ack_delay.size()
-                    + ack_range_count.size()
-                    + first_ack_range.size()
-                    + ack_ranges
-                        .iter()
-                        .map(|(gap, len)| gap.size() + len.size())
-                        .sum::<usize>()
-                    + ect0_count.size()
-                    + ect1_count.size()
-                    + ecn_ce_count.size()
-            }
-            Frame::ResetStream {
-                stream_id,
-                application_protocol_error_code,
-                final_size,
-            } => 1 + stream_id.size() + application_protocol_error_code.size() + final_size.size(),
-            Frame::StopSending {
-                stream_id,
-                application_protocol_error_code,
-            } => 1 + stream_id.size() + application_protocol_error_code.size(),
-            Frame::Crypto {
-                offset,
-                crypto_length,
-                ref crypto_data,
-            } => 1 + offset.size() + crypto_length.size() + crypto_data.len(),
-            Frame::NewToken {
-                token_length,
-                token,
-            } => 1 + token_length.size() + token.len(),
-            Frame::Stream {
-                stream_id,
-                offset,
-                length,
-                fin: _,
-                stream_data,
-            } => 1 + stream_id.size() + offset.size() + length.size() + 1 + stream_data.len(),
-            Frame::MaxData(max_data) => 1 + max_data.size(),
-            Frame::MaxStreamData {
-                stream_id,
-                max_stream_data,
-            } => 1 + stream_id.size() + max_stream_data.size(),
-            Frame::MaxStreams { max_streams, .. } => 1 + 1 + max_streams.size(),
-            Frame::DataBlocked(max_data) => 1 + max_data.size(),
-            Frame::StreamDataBlocked {
-                stream_id,
-                stream_data_limit,
-            } => 1 + stream_id.size() + stream_data_limit.size(),
-            Frame::StreamsBlocked { max_streams, .. } => 1 + 1 + max_streams.size(),
-            Frame::NewConnectionId {
-                sequence_number,
-                retire_prior_to,
-                connection_id,
-                stateless_reset_token: _,
-            } => {
-                1 + sequence_number.size()
-                    + retire_prior_to.size()
-                    + 1
-                    + connection_id.cid.len()
-                    + 16
-            }
-            Frame::RetireConnectionId(sequence_number) => 1 + sequence_number.size(),
-            Frame::PathChallenge(_) => 1 + 8,
-            Frame::PathResponse(_) => 1 + 8,
-            Frame::ConnectionClose {
-                error_code,
-                frame_type,
-                reason_phrase_length,
-                reason_phrase,
-            } => {
-                1 + error_code.size()
-                    + frame_type.map_or(1, |_| 2)
-                    + reason_phrase_length.size()
-                    + reason_phrase.len()
-            }
-            Frame::HandshakeDone => 1,
-        };
-        size
-    }};
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum FrameKind {
+            $($kind,)*
+        }
+
+        impl FrameKind {
+            // every known kind, in encoding order
+            pub const ALL: &'static [FrameKind] = &[$(FrameKind::$kind,)*];
+
+            pub fn iter() -> impl Iterator<Item = FrameKind> {
+                Self::ALL.iter().copied()
+            }
+
+            // the type as in the RFC, e.g. MAX_STREAMS_BIDI
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(FrameKind::$kind => stringify!($frame),)*
+                }
+            }
+
+            // the base encoding, without any flag bits
+            pub fn ty(&self) -> FrameType {
+                match self {
+                    $(FrameKind::$kind => FrameType::$frame,)*
+                }
+            }
+
+            fn from_base(ty: u8) -> Option<FrameKind> {
+                match ty {
+                    $(t if t == $encoding => Some(FrameKind::$kind),)*
+                    _ => None,
+                }
+            }
+
+            // the encryption levels a packet carrying this frame can be sent at
+            pub fn permitted(&self) -> &'static [$crate::packet::EncryptionLevel] {
+                match self {
+                    $(FrameKind::$kind => &[$($crate::packet::EncryptionLevel::$level),*],)*
+                }
+            }
+
+            pub fn is_permitted(&self, level: $crate::packet::EncryptionLevel) -> bool {
+                self.permitted().contains(&level)
+            }
+        }
+
+        fn decode_frame<B: $crate::DecodeBuf>(
+            kind: FrameKind,
+            ty: u8,
+            bytes: &mut B,
+            limits: &$crate::packet::limits::DecodeLimits,
+        ) -> $crate::result::QuicheResult<Frame> {
+            match kind {
+                $(FrameKind::$kind => $decode(ty, bytes, limits),)*
+            }
+        }
+
+        impl std::fmt::Display for FrameKind {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    }
 }
@@ -0,0 +1,82 @@
+// what connections and endpoints report through the `metrics` facade, for whatever recorder the
+// application installs (e.g. metrics-exporter-prometheus).  without the `metrics` feature every
+// function here is a no-op, so the call sites don't need to care
+
+pub const ACTIVE_CONNECTIONS: &str = "mini_quiche_active_connections";
+pub const DRAINING_CONNECTIONS: &str = "mini_quiche_draining_connections";
+pub const HANDSHAKES_COMPLETED: &str = "mini_quiche_handshakes_completed_total";
+pub const HANDSHAKES_FAILED: &str = "mini_quiche_handshakes_failed_total";
+pub const DATAGRAMS_SENT: &str = "mini_quiche_datagrams_sent_total";
+pub const DATAGRAMS_RECEIVED: &str = "mini_quiche_datagrams_received_total";
+pub const BYTES_SENT: &str = "mini_quiche_bytes_sent_total";
+pub const BYTES_RECEIVED: &str = "mini_quiche_bytes_received_total";
+// received and dropped before processing: oversized, or shed because the application fell behind
+pub const DATAGRAMS_DROPPED: &str = "mini_quiche_datagrams_dropped_total";
+
+// registers units and help text for everything above, call it once after installing a recorder
+#[cfg(feature = "metrics")]
+pub fn describe() {
+    use ::metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_gauge!(
+        ACTIVE_CONNECTIONS,
+        "connections that haven't started closing"
+    );
+    describe_gauge!(
+        DRAINING_CONNECTIONS,
+        "connections closing or draining that still hold their cids"
+    );
+    describe_counter!(HANDSHAKES_COMPLETED, "handshakes that completed");
+    describe_counter!(HANDSHAKES_FAILED, "handshakes that failed or timed out");
+    describe_counter!(DATAGRAMS_SENT, "udp datagrams sent");
+    describe_counter!(DATAGRAMS_RECEIVED, "udp datagrams received");
+    describe_counter!(BYTES_SENT, Unit::Bytes, "udp payload bytes sent");
+    describe_counter!(BYTES_RECEIVED, Unit::Bytes, "udp payload bytes received");
+    describe_counter!(DATAGRAMS_DROPPED, "received datagrams dropped unprocessed");
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn connections(active: usize, draining: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::gauge!(ACTIVE_CONNECTIONS).set(active as f64);
+        ::metrics::gauge!(DRAINING_CONNECTIONS).set(draining as f64);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn handshake(completed: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let name = if completed {
+            HANDSHAKES_COMPLETED
+        } else {
+            HANDSHAKES_FAILED
+        };
+        ::metrics::counter!(name).increment(1);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn datagrams_sent(datagrams: usize, bytes: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(DATAGRAMS_SENT).increment(datagrams as u64);
+        ::metrics::counter!(BYTES_SENT).increment(bytes as u64);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn datagrams_received(datagrams: usize, bytes: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(DATAGRAMS_RECEIVED).increment(datagrams as u64);
+        ::metrics::counter!(BYTES_RECEIVED).increment(bytes as u64);
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn datagrams_dropped(datagrams: usize) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(DATAGRAMS_DROPPED).increment(datagrams as u64);
+}
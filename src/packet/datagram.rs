@@ -0,0 +1,200 @@
+use crate::{
+    result::{QuicheError, QuicheResult},
+    DecodeBuf,
+};
+
+use super::{
+    frame::Frame,
+    header::{Header, LongHeader, LongHeaderExtension, ShortHeader},
+    packet::Packet,
+    EncryptionLevel,
+};
+
+// a decoded packet, split by whether it can carry frames at all.  `Packet` lets a retry or
+// version negotiation packet hold a payload and leaves it to `contains_frames` to catch that,
+// here there's simply nowhere to put one
+#[derive(PartialEq, Debug, Clone)]
+pub enum Datagram {
+    // initial / 0-rtt / handshake / 1-rtt
+    Frames(FramesHeader, Vec<Frame>),
+    Retry(LongHeader),
+    VersionNegotiation(LongHeader),
+}
+
+// the headers of the packets that carry frames, i.e. every `Header` but retry and version negotiation
+#[derive(PartialEq, Debug, Clone)]
+pub enum FramesHeader {
+    Initial(LongHeader),
+    // 0-rtt / handshake
+    Long(LongHeader),
+    Short(ShortHeader),
+}
+
+impl From<FramesHeader> for Header {
+    fn from(header: FramesHeader) -> Self {
+        match header {
+            FramesHeader::Initial(header) => Header::Initial(header),
+            FramesHeader::Long(header) => Header::Long(header),
+            FramesHeader::Short(header) => Header::Short(header),
+        }
+    }
+}
+
+impl FramesHeader {
+    pub fn encode(&self) -> QuicheResult<Vec<u8>> {
+        match self {
+            FramesHeader::Initial(header) | FramesHeader::Long(header) => header.encode(),
+            FramesHeader::Short(header) => header.encode(),
+        }
+    }
+
+    pub fn encryption_level(&self) -> EncryptionLevel {
+        match self {
+            FramesHeader::Initial(_) => EncryptionLevel::Initial,
+            FramesHeader::Long(header) => match header.extension() {
+                LongHeaderExtension::ZeroRTT { .. } => EncryptionLevel::ZeroRtt,
+                _ => EncryptionLevel::Handshake,
+            },
+            FramesHeader::Short(_) => EncryptionLevel::OneRtt,
+        }
+    }
+}
+
+impl Datagram {
+    pub fn decode<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Self> {
+        Packet::decode(bytes)?.try_into()
+    }
+
+    pub fn encode(&self) -> QuicheResult<Vec<u8>> {
+        let mut encoded = Vec::new();
+        self.encode_into(&mut encoded)?;
+        Ok(encoded)
+    }
+
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> QuicheResult<()> {
+        match self {
+            Datagram::Frames(header, frames) => {
+                buf.extend(header.encode()?);
+                frames.iter().for_each(|frame| buf.extend(frame.encode()));
+            }
+            Datagram::Retry(header) | Datagram::VersionNegotiation(header) => {
+                buf.extend(header.encode()?)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        match self {
+            Datagram::Frames(_, frames) => frames,
+            Datagram::Retry(_) | Datagram::VersionNegotiation(_) => &[],
+        }
+    }
+
+    pub fn encryption_level(&self) -> Option<EncryptionLevel> {
+        match self {
+            Datagram::Frames(header, _) => Some(header.encryption_level()),
+            Datagram::Retry(_) | Datagram::VersionNegotiation(_) => None,
+        }
+    }
+}
+
+impl TryFrom<Packet> for Datagram {
+    type Error = QuicheError;
+
+    fn try_from(packet: Packet) -> QuicheResult<Self> {
+        match packet.header {
+            Header::Retry(header) | Header::VersionNegotiate(header)
+                if !packet.payload.is_empty() =>
+            {
+                Err(QuicheError(format!(
+                    "Datagram::try_from: {} frames in a packet that can't carry any ({:?})",
+                    packet.payload.len(),
+                    header
+                )))
+            }
+            Header::Retry(header) => Ok(Datagram::Retry(header)),
+            Header::VersionNegotiate(header) => Ok(Datagram::VersionNegotiation(header)),
+            Header::Initial(header) => Ok(Datagram::Frames(
+                FramesHeader::Initial(header),
+                packet.payload,
+            )),
+            Header::Long(header) => {
+                Ok(Datagram::Frames(FramesHeader::Long(header), packet.payload))
+            }
+            Header::Short(header) => Ok(Datagram::Frames(
+                FramesHeader::Short(header),
+                packet.payload,
+            )),
+        }
+    }
+}
+
+impl From<Datagram> for Packet {
+    fn from(datagram: Datagram) -> Self {
+        let (header, payload) = match datagram {
+            Datagram::Frames(header, frames) => (header.into(), frames),
+            Datagram::Retry(header) => (Header::Retry(header), Vec::new()),
+            Datagram::VersionNegotiation(header) => (Header::VersionNegotiate(header), Vec::new()),
+        };
+        Packet {
+            header,
+            payload,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::{ConnectionId, PnLen, SingleBit, TwoBits},
+        BitsExt, MINI_QUICHE_VERSION,
+    };
+
+    #[test]
+    fn test_datagram() {
+        let cid = || ConnectionId::new(8, vec![3; 8]);
+
+        let version_negotiation =
+            LongHeader::version_negotiate(cid(), cid(), vec![MINI_QUICHE_VERSION]);
+        let bytes = Datagram::VersionNegotiation(version_negotiation.clone())
+            .encode()
+            .unwrap();
+        let decoded = Datagram::decode(&mut bytes.clone()).unwrap();
+        assert_eq!(
+            decoded,
+            Datagram::VersionNegotiation(version_negotiation.clone())
+        );
+        assert!(decoded.frames().is_empty());
+        assert_eq!(decoded.encryption_level(), None);
+        assert_eq!(
+            Packet::decode_coalesced(&mut bytes.clone()).unwrap(),
+            vec![decoded]
+        );
+
+        // the state `Datagram` rules out
+        let bogus = Packet {
+            header: Header::VersionNegotiate(version_negotiation),
+            payload: vec![Frame::Ping],
+            warnings: Vec::new(),
+        };
+        assert!(Datagram::try_from(bogus).is_err());
+
+        let one_rtt = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid(),
+            vec![9],
+            vec![Frame::Ping, Frame::HandshakeDone],
+        );
+        let mut bytes = one_rtt.encode().unwrap();
+        let decoded = Datagram::decode(&mut bytes).unwrap();
+        assert_eq!(decoded.frames(), one_rtt.payload.as_slice());
+        assert_eq!(decoded.encryption_level(), Some(EncryptionLevel::OneRtt));
+        assert_eq!(Packet::from(decoded), one_rtt);
+    }
+}
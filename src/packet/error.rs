@@ -48,13 +48,36 @@ impl ProtocolError {
         }
     }
 
+    pub fn code(&self) -> u64 {
+        match self {
+            ProtocolError::NoError => 0x00,
+            ProtocolError::InternalError => 0x01,
+            ProtocolError::ConnectionRefused => 0x02,
+            ProtocolError::FlowControlError => 0x03,
+            ProtocolError::StreamLimitError => 0x04,
+            ProtocolError::StreamStateError => 0x05,
+            ProtocolError::FinalSizeError => 0x06,
+            ProtocolError::FrameEncodingError => 0x07,
+            ProtocolError::TransportParameterError => 0x08,
+            ProtocolError::ConnectionIdLimitError => 0x09,
+            ProtocolError::ProtocolViolation => 0x0a,
+            ProtocolError::InvalidToken => 0x0b,
+            ProtocolError::ApplicationError => 0x0c,
+            ProtocolError::CryptoBufferExceeded => 0x0d,
+            ProtocolError::KeyUpdateError => 0x0e,
+            ProtocolError::AeadLimitReached => 0x0f,
+            ProtocolError::NoViablePath => 0x10,
+            ProtocolError::CryptoError(code) => *code,
+        }
+    }
+
     pub fn is_protocol_error(code: u64) -> bool {
         matches!(code, 0x00..=0x10) || matches!(code, 0x0100..=0x01ff)
     }
 }
 
-impl Into<QuicheError> for ProtocolError {
-    fn into(self) -> QuicheError {
-        QuicheError(format!("Transport error: {:?}", self))
+impl From<ProtocolError> for QuicheError {
+    fn from(err: ProtocolError) -> Self {
+        QuicheError(format!("Transport error: {:?}", err))
     }
 }
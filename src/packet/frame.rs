@@ -1,8 +1,17 @@
-use std::ops::RangeInclusive;
+use std::{borrow::Cow, ops::RangeInclusive};
 
-use crate::{frame, packet::error::ProtocolError, result::QuicheResult, BitsExt, VarInt};
+use crate::{
+    consts::STATELESS_RESET_TOKEN_LEN,
+    frame,
+    packet::error::ProtocolError,
+    result::{require, QuicheError, QuicheResult},
+    BitsExt, DecodeBuf, EncodeBuf, LenCounter, SmallBytes, VarInt,
+};
 
-use super::{ConnectionId, SingleBit};
+use super::{
+    limits::{DecodeLimits, DecodeMode, DecodeWarning},
+    ConnectionId, SingleBit,
+};
 
 const STREAM_FIN: u8 = 0x01;
 const STREAM_LEN: u8 = 0x02;
@@ -15,10 +24,10 @@ frame! {
     // padding frames have no semantic value, they can be used to increase the size of a packet
     // i.e. increase an initial packet to the minimum required size or provide protection against traffic analysis
     // padding frames contain no content
-    PADDING = 0x00,
+    PADDING = 0x00 => Padding { decode: decode_padding, packets: [Initial, Handshake, ZeroRtt, OneRtt] },
     // ping frames contain no content
     // a ping frame should elicit an ack or ack ecn frame from the receiver
-    PING = 0x01,
+    PING = 0x01 => Ping { decode: decode_ping, packets: [Initial, Handshake, ZeroRtt, OneRtt] },
     // ack frames contain one or more "ack ranges", which identify acknowledged packets
     // version negotiation & retry packets CANNOT be acknowledged because they do not contain a packet number
     // rather than relying on an ack frame, they are implicitly acknowledged by the next initial packet sent by the client
@@ -46,7 +55,7 @@ frame! {
     // each gap indicates a range of unack'd packets. the number of packets in the gap is gap + 1.
     //
     // if any computed packet number is negative, a connection error of type FRAME_ENCODING_ERROR MUST be generated.
-    ACK = 0x02,
+    ACK = 0x02 => Ack { decode: decode_ack, packets: [Initial, Handshake, OneRtt] },
     // ack ecn frames also contain the cumulative count of quic packets with associated ecn marks received on the connection
     // the information in here should be used to manage congestion state
     // three ecn counts are included in the ack ecn frame:
@@ -57,7 +66,7 @@ frame! {
     // 3. ecn-ce count: a variable-length int representing the total num packets received with the ECN-CE codepoint in the num space of the frame
     //
     // ecn counts are maintained separately for each packet number space
-    ACK_ECN = 0x03,
+    ACK_ECN = 0x03 => AckEcn { decode: decode_ack, packets: [Initial, Handshake, OneRtt] },
     // a reset stream frame is used to abruptly terminate the sending part of a stream
     // a receiver of reset stream can discard any data it's already received
     // an endpoint that receives a reset stream frame for a send only stream MUST terminate the connection with STREAM_STATE_ERROR
@@ -72,14 +81,14 @@ frame! {
     // this is one higher than the offset of the byte with the largest offset sent on the stream, or zero if nothing was sent.
     // a sender always communicates the final size of a stream reliability, regardless of how the stream is terminated.
     // an endpoint MUST NOT send data on a stream at or beyond the final size.
-    RESET_STREAM = 0x04,
+    RESET_STREAM = 0x04 => ResetStream { decode: decode_reset_stream, packets: [ZeroRtt, OneRtt] },
     // a stop sending frame is used to communicate that incoming data is being discarded on receipt per application request.
     // or, requesting that a peer cease transmission of data on a stream.
     // stop sending streams contain the following fields:
     // 1. stream id: a variable-length int encoding of the stream id being ignored
     //
     // 2. application protocol error code: a variable-length int containing the application protocol error code
-    STOP_SENDING = 0x05,
+    STOP_SENDING = 0x05 => StopSending { decode: decode_stop_sending, packets: [ZeroRtt, OneRtt] },
     // a crypto frame is used to communicate cryptographic handshake messages
     // it can be sent in all packet types EXCEPT 0-RTT packets
     // crypto frames contain the following fields
@@ -90,7 +99,7 @@ frame! {
     // 3. crypto data: the cryptographic message data
     // the largest offset delivered on a stream (offset + data len) cannot exceed 2^62 - 1. FRAME_ENCODING_ERROR or CRYPTO_BUFFER_EXCEEDED.
     // the crypto frame stream does not have an explicit end, so they do not contain a "FIN" bit.
-    CRYPTO = 0x06,
+    CRYPTO = 0x06 => Crypto { decode: decode_crypto, packets: [Initial, Handshake, OneRtt] },
     // a new token frame is used to provide a client with a token to send in their Initial header of a future connection
     // new token frames contain the following fields:
     // 1. token length: a variable-length int specifying the length of the token in bytes
@@ -99,15 +108,15 @@ frame! {
     // a client may receive the same token value if packets containing this frame are incorrectly determined to be lost.
     // clients are responsible for discarding duplicates.
     // clients MUST NOT send these frames. PROTOCOL_VIOLATION
-    NEW_TOKEN = 0x07,
-    STREAM = 0x08,
+    NEW_TOKEN = 0x07 => NewToken { decode: decode_new_token, packets: [OneRtt] },
+    STREAM = 0x08 => Stream { decode: decode_stream, packets: [ZeroRtt, OneRtt] },
     // STREAM
     // a max data frame is used in flow control to inform the peer of the maximum amount of total data that can be sent on the connection
     // max data frames contain the following fields:
     // 1. maximum data: a variable-length int indicating teh maximum amount of data that can be sent on the entire connection
     // all data sent in streams counts towards this limit.  the sum of the final sizes on ALL streams MUST NOT exceed the value advertised by a receiver.
     // an endpoint MUST terminate with FLOW_CONTROL_ERROR if it receives more data than the maximum data value it has sent.
-    MAX_DATA = 0x10,
+    MAX_DATA = 0x10 => MaxData { decode: decode_max_data, packets: [ZeroRtt, OneRtt] },
     // a max stream data frame is used in flow control to inform a peer of the maximum amount of data that can be sent on a stream
     // receiving a max stream data frame for a locally initiated stream that has not yet been created MUST throw STREAM_STATE_ERROR
     // an endpoint that receives a max stream data frame for a recv-only stream MUST terminate the connection with STREAM_STATE_ERROR
@@ -119,18 +128,18 @@ frame! {
     // loss or reordering can mean that the largest recv offset can be gt the total size of data recv on that stream
     // receiving stream frames might not increase the largest recv offset
     // an endpoint MUST terminate with FLOW_CONTROL_ERROR if it receives more data on a stream than the maximum data value it has sent.
-    MAX_STREAM_DATA = 0x11,
+    MAX_STREAM_DATA = 0x11 => MaxStreamData { decode: decode_max_stream_data, packets: [ZeroRtt, OneRtt] },
     // max streams frames inform peers of the cumulative number of streams of a given type it is permitted to open
     // max stream frames contain the following fields:
     // 1. maximum streams: a count of the cumulative number of streams of the corresponding type that can be opened over the lifetime of the connection.
     // receipt of a frame that permits opening of a stream gt this limit MUST FRAME_ENCODING_ERROR
-    MAX_STREAMS_BIDI = 0x12,
-    MAX_STREAMS_UNI = 0x13,
+    MAX_STREAMS_BIDI = 0x12 => MaxStreamsBidi { decode: decode_max_streams, packets: [ZeroRtt, OneRtt] },
+    MAX_STREAMS_UNI = 0x13 => MaxStreamsUni { decode: decode_max_streams, packets: [ZeroRtt, OneRtt] },
     // a sender SHOULD send a data blocked frame when it wishes to send data but is unable to do so due to connection-level flow control
     // these frames can be used as input to tuning of flow control algorithms
     // data blocked frames contain the following fields:
     // 1. maximum data: a variable-length int indicating the connection-level limit at which blocking occured.
-    DATA_BLOCKED = 0x14,
+    DATA_BLOCKED = 0x14 => DataBlocked { decode: decode_data_blocked, packets: [ZeroRtt, OneRtt] },
     // a sender SHOULD send a stream data blocked frame when it wishes to send data but is unable to do so due to stream-level flow control
     // analogous to data blocked
     // an endpoint that receives a stream data blocked frame for a send only stream MUST terminate the connection with STREAM_STATE_ERROR
@@ -138,13 +147,13 @@ frame! {
     // 1. stream id - a variable-length int encoding of the stream that is blocked
     //
     // 2. maximum stream data - a variable-length int indicating the offset of the stream at which blocking occured
-    STREAM_DATA_BLOCKED = 0x15,
+    STREAM_DATA_BLOCKED = 0x15 => StreamDataBlocked { decode: decode_stream_data_blocked, packets: [ZeroRtt, OneRtt] },
     // a sender SHOULD send a streams blocked frame when it wishes to open a stream but is unable to do so due to the maximum stream limit set by its peer (see: max streams frames)
     // a streams blocked frame does not open the stream, but informs the peer that a new stream was needed and that it was unable to be opened
     // streams blocked frames contain the following fields:
     // 1. maximum streams: a variable-length int indicating the number of streams of the corresponding type allowed at the time the frame was sent.
-    STREAMS_BLOCKED_BIDI = 0x16,
-    STREAMS_BLOCKED_UNI = 0x17,
+    STREAMS_BLOCKED_BIDI = 0x16 => StreamsBlockedBidi { decode: decode_streams_blocked, packets: [ZeroRtt, OneRtt] },
+    STREAMS_BLOCKED_UNI = 0x17 => StreamsBlockedUni { decode: decode_streams_blocked, packets: [ZeroRtt, OneRtt] },
     // a new connection id frame is sent to inform the peer of alternative connection ids that can be used to break linkability when migrating connections
     // new connection id frames contain the following fields:
     // 1. sequence number: a variable-length int indicating the sequence number for this connection id, assigned by the sender
@@ -162,7 +171,7 @@ frame! {
     // endpoints MAY treat receipt of a new cid w/ different reset token or a diff sequence number as PROTOCOL_VIOLATION
     // retire prior to applies to cids established during setup & the preferred address transport parameter. receiving a value here gt sequence num MUST FRAME_ENCODING_ERROR
     // an endpoint which receives a sequence number < the rpt field of a previously received new cid frame MUST send a corresponding retire cid frame unless it has already done so for that seq num
-    NEW_CONNECTION_ID = 0x18,
+    NEW_CONNECTION_ID = 0x18 => NewConnectionId { decode: decode_new_connection_id, packets: [ZeroRtt, OneRtt] },
     // retire connection id frames are sent to indicate that an endpoint will no longer use a cid issued by its peer
     // this includes the cid provided during the handshake.
     // sending this also serves as a request to the peer to send additional cid's for future use, using the new connection id frame
@@ -171,17 +180,17 @@ frame! {
     // receipt of a retire connection id frame containing a seq num > any previously sent to the peer MUST PROTOCOL_VIOLATION
     // the sequence number specified in a retire connection id frame MUST NOT refer to the dst_cid of the packet in while the frame is contained.  peer MAY PROTOCOL_VIOLATION
     // an endpoin that provides a zero-length cid MUST treat receipt of this frame as PROTOCOL_VIOLATION
-    RETIRE_CONNECTION_ID = 0x19,
+    RETIRE_CONNECTION_ID = 0x19 => RetireConnectionId { decode: decode_retire_connection_id, packets: [ZeroRtt, OneRtt] },
     // a path challenge frame is used to check reachability to the peer and for path validation during connection migration
     // path challenge frames contain the following fields:
     // 1. data: an 8 byte field of arbitrary data, chosen by the sender.
     // the receipient of this frame MUST generate a path response frame, containing the same data value.
-    PATH_CHALLENGE = 0x1a,
+    PATH_CHALLENGE = 0x1a => PathChallenge { decode: decode_path_challenge, packets: [ZeroRtt, OneRtt] },
     // a path response frame is sent in response to a path challenge frame
     // a path response frame contains the following fields:
     // 1. data: an 8 byte value that was sent in the corresponding path challenge frame
     // if the data field does not match a previously sent path challenge frame, the endpoint MAY PROTOCOL_VIOLATION
-    PATH_RESPONSE = 0x1b,
+    PATH_RESPONSE = 0x1b => PathResponse { decode: decode_path_response, packets: [OneRtt] },
     // an endpoint sends a connection close frame to inform its peer that the connection is being closed
     // if there are open streams that have not been closed, they are implicitly closed when the conn is closed
     // connection close frames contain the following fields:
@@ -192,15 +201,44 @@ frame! {
     // 3. reason phrase length: a variable-length int indicating the length of the reason phrase
     //
     // 4. reason phrase: additional diagnostic information of the closure, this can be 0 length and SHOULD be be utf-8 encoded string
-    CONNECTION_CLOSE_TRANSPORT = 0x1c,
+    CONNECTION_CLOSE_TRANSPORT = 0x1c => ConnectionCloseTransport { decode: decode_connection_close, packets: [Initial, Handshake, ZeroRtt, OneRtt] },
     // this type of connection close frame can only be sent using 0-RTT or 1-RTT packets.
     // if an application wishes to abandon a connection during the handshake, an endpoint can send a 0x1c frame with an error code of APPLICATION_ERROR in an initial or handshake packet
-    CONNECTION_CLOSE_APPLICATION = 0x1d,
+    CONNECTION_CLOSE_APPLICATION = 0x1d => ConnectionCloseApplication { decode: decode_connection_close, packets: [ZeroRtt, OneRtt] },
     // the server sends a handshake done frame to signal completion of the handshake to the client
     // these frames have no content
     // a handshake done frame can only be sent by the server.  servers MUST NOT send a handshake done frame before completing the handshake
     // a server MUST treat receipt of this frame as PROTOCOL_VIOLATION
-    HANDSHAKE_DONE = 0x1e,
+    HANDSHAKE_DONE = 0x1e => HandshakeDone { decode: decode_handshake_done, packets: [OneRtt] },
+}
+
+impl FrameKind {
+    pub fn is_stream(&self) -> bool {
+        *self == FrameKind::Stream
+    }
+}
+
+// a frame type off the wire, which is a varint even though every type we know fits in a byte
+impl TryFrom<u64> for FrameKind {
+    type Error = QuicheError;
+
+    fn try_from(ty: u64) -> QuicheResult<FrameKind> {
+        let kind = u8::try_from(ty).ok().and_then(|ty| {
+            if STREAM_RANGE.contains(&FrameType(ty)) {
+                return Some(FrameKind::Stream);
+            }
+            FrameKind::from_base(ty)
+        });
+        kind.ok_or_else(|| {
+            QuicheError(format!("FrameKind::try_from: unknown frame type {:#x}", ty))
+        })
+    }
+}
+
+impl FrameType {
+    pub fn kind(&self) -> QuicheResult<FrameKind> {
+        FrameKind::try_from(self.0 as u64)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -243,12 +281,12 @@ pub enum Frame {
     Crypto {
         offset: VarInt,
         crypto_length: VarInt,
-        crypto_data: Vec<u8>,
+        crypto_data: SmallBytes,
     },
     // 0x07
     NewToken {
         token_length: VarInt,
-        token: Vec<u8>,
+        token: SmallBytes,
     },
     // 0x08 - 0x0f
     Stream {
@@ -257,7 +295,7 @@ pub enum Frame {
         length: VarInt,
         fin: SingleBit,
         // if length is not present this extends to the end of the packet
-        stream_data: Vec<u8>,
+        stream_data: SmallBytes,
     },
     // 0x10
     MaxData(VarInt),
@@ -288,7 +326,7 @@ pub enum Frame {
         sequence_number: VarInt,
         retire_prior_to: VarInt,
         connection_id: ConnectionId,
-        stateless_reset_token: [u8; 16],
+        stateless_reset_token: [u8; STATELESS_RESET_TOKEN_LEN],
     },
     // 0x19
     RetireConnectionId(VarInt),
@@ -301,12 +339,17 @@ pub enum Frame {
         error_code: VarInt,
         frame_type: Option<u8>,
         reason_phrase_length: VarInt,
-        reason_phrase: String,
+        // peer controlled and not necessarily utf-8, see `reason_str`
+        reason_phrase: SmallBytes,
     },
     // 0x1e
     HandshakeDone,
 }
 
+// the longest reason phrase we send, and by default accept (see `DecodeLimits`).  they're only
+// diagnostics
+pub const MAX_REASON_PHRASE_LEN: usize = 1_024;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StreamType {
     Bidirectional,
@@ -314,6 +357,96 @@ pub enum StreamType {
 }
 
 impl Frame {
+    // a CONNECTION_CLOSE's reason phrase, with anything that isn't utf-8 replaced
+    pub fn reason_str(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Frame::ConnectionClose { reason_phrase, .. } => {
+                Some(String::from_utf8_lossy(reason_phrase))
+            }
+            _ => None,
+        }
+    }
+
+    // everything but ACK, PADDING and CONNECTION_CLOSE makes the peer send an ack (RFC 9002 section 2)
+    pub fn is_ack_eliciting(&self) -> bool {
+        !matches!(
+            self,
+            Frame::Ack { .. } | Frame::Padding | Frame::ConnectionClose { .. }
+        )
+    }
+
+    // splits a STREAM frame so the first half encodes to at most `max_bytes`, e.g. to fill what's left
+    // of a datagram.  the first half always carries an explicit length and never the fin bit.
+    // returns the frame untouched (and no second half) if it already fits
+    pub fn split_stream_at(self, max_bytes: usize) -> QuicheResult<(Frame, Option<Frame>)> {
+        if matches!(self, Frame::Stream { .. }) && self.size() <= max_bytes {
+            return Ok((self, None));
+        }
+        let Frame::Stream {
+            stream_id,
+            offset,
+            length,
+            fin,
+            stream_data,
+        } = self
+        else {
+            return Err(QuicheError(
+                "Frame::split_stream_at: not a STREAM frame".to_string(),
+            ));
+        };
+
+        let offset_size = if offset.to_inner() > 0 {
+            offset.size()
+        } else {
+            0
+        };
+        let fixed = 2 + stream_id.size() + offset_size;
+        // the length field's own size depends on how much data it covers, try the biggest that fits
+        let take = [8, 4, 2, 1]
+            .into_iter()
+            .filter_map(|length_size| {
+                let take = max_bytes
+                    .checked_sub(fixed + length_size)?
+                    .min(stream_data.len());
+                (VarInt::new_u32(take as u32).size() <= length_size).then_some(take)
+            })
+            .max()
+            .unwrap_or(0);
+        if take == 0 {
+            return Err(QuicheError(format!(
+                "Frame::split_stream_at: no stream data fits in {} bytes",
+                max_bytes
+            )));
+        }
+
+        let first = Frame::Stream {
+            stream_id,
+            offset,
+            length: VarInt::new_u32(take as u32),
+            fin: SingleBit::zero(),
+            stream_data: stream_data.slice(0..take),
+        };
+        let rest_len = stream_data.len() - take;
+        let rest = Frame::Stream {
+            stream_id,
+            offset: offset.addn(take as u64)?,
+            // an open-ended frame stays open-ended
+            length: match length.to_inner() {
+                0 => VarInt::zero(),
+                _ => VarInt::new_u32(rest_len as u32),
+            },
+            fin,
+            stream_data: stream_data.slice(take..stream_data.len()),
+        };
+        Ok((first, Some(rest)))
+    }
+
+    pub fn kind(&self) -> FrameKind {
+        self.ty()
+            .kind()
+            .expect("Frame::kind: every frame has a known type")
+    }
+
     pub(crate) fn ty(&self) -> FrameType {
         use self::Frame::*;
         match *self {
@@ -338,7 +471,7 @@ impl Frame {
                 if length.to_inner() > 0 {
                     ty |= 0x02;
                 }
-                if offset.to_inner() == 1 {
+                if offset.to_inner() > 0 {
                     ty |= 0x04;
                 }
                 FrameType(ty)
@@ -359,21 +492,32 @@ impl Frame {
             RetireConnectionId(_) => FrameType::RETIRE_CONNECTION_ID,
             PathChallenge(_) => FrameType::PATH_CHALLENGE,
             PathResponse(_) => FrameType::PATH_RESPONSE,
-            ConnectionClose { error_code, .. } => {
-                if ProtocolError::is_protocol_error(error_code.to_inner()) {
-                    FrameType::CONNECTION_CLOSE_TRANSPORT
-                } else {
-                    FrameType::CONNECTION_CLOSE_APPLICATION
-                }
-            }
+            // only the transport variant carries the triggering frame type.  going off the error
+            // code instead would mistake application codes that overlap transport ones
+            ConnectionClose { frame_type, .. } => match frame_type {
+                Some(_) => FrameType::CONNECTION_CLOSE_TRANSPORT,
+                None => FrameType::CONNECTION_CLOSE_APPLICATION,
+            },
             HandshakeDone => FrameType::HANDSHAKE_DONE,
         }
     }
 
+    // encoded length in bytes, worked out by encoding
+    pub fn size(&self) -> usize {
+        let mut len = LenCounter::default();
+        self.encode_to(&mut len);
+        len.len()
+    }
+
     pub fn encode(&self) -> Vec<u8> {
-        use self::Frame::*;
         let mut buf = Vec::new();
-        buf.push(self.ty().to_inner());
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn encode_to<B: EncodeBuf>(&self, buf: &mut B) {
+        use self::Frame::*;
+        buf.put_u8(self.ty().to_inner());
         match *self {
             Padding | Ping | HandshakeDone => {}
             Ack {
@@ -383,13 +527,13 @@ impl Frame {
                 first_ack_range,
                 ref ack_ranges,
             } => {
-                buf.extend(largest_acknowledged.encode());
-                buf.extend(ack_delay.encode());
-                buf.extend(ack_range_count.encode());
-                buf.extend(first_ack_range.encode());
+                largest_acknowledged.encode_to(buf);
+                ack_delay.encode_to(buf);
+                ack_range_count.encode_to(buf);
+                first_ack_range.encode_to(buf);
                 for (gap, len) in ack_ranges {
-                    buf.extend(gap.encode());
-                    buf.extend(len.encode());
+                    gap.encode_to(buf);
+                    len.encode_to(buf);
                 }
             }
             AckEcn {
@@ -402,49 +546,49 @@ impl Frame {
                 ect1_count,
                 ecn_ce_count,
             } => {
-                buf.extend(largest_acknowledged.encode());
-                buf.extend(ack_delay.encode());
-                buf.extend(ack_range_count.encode());
-                buf.extend(first_ack_range.encode());
+                largest_acknowledged.encode_to(buf);
+                ack_delay.encode_to(buf);
+                ack_range_count.encode_to(buf);
+                first_ack_range.encode_to(buf);
                 for (gap, len) in ack_ranges {
-                    buf.extend(gap.encode());
-                    buf.extend(len.encode());
+                    gap.encode_to(buf);
+                    len.encode_to(buf);
                 }
-                buf.extend(ect0_count.encode());
-                buf.extend(ect1_count.encode());
-                buf.extend(ecn_ce_count.encode());
+                ect0_count.encode_to(buf);
+                ect1_count.encode_to(buf);
+                ecn_ce_count.encode_to(buf);
             }
             ResetStream {
                 stream_id,
                 application_protocol_error_code,
                 final_size,
             } => {
-                buf.extend(stream_id.encode());
-                buf.extend(application_protocol_error_code.encode());
-                buf.extend(final_size.encode());
+                stream_id.encode_to(buf);
+                application_protocol_error_code.encode_to(buf);
+                final_size.encode_to(buf);
             }
             StopSending {
                 stream_id,
                 application_protocol_error_code,
             } => {
-                buf.extend(stream_id.encode());
-                buf.extend(application_protocol_error_code.encode());
+                stream_id.encode_to(buf);
+                application_protocol_error_code.encode_to(buf);
             }
             Crypto {
                 offset,
                 crypto_length,
                 ref crypto_data,
             } => {
-                buf.extend(offset.encode());
-                buf.extend(crypto_length.encode());
-                buf.extend(crypto_data);
+                offset.encode_to(buf);
+                crypto_length.encode_to(buf);
+                buf.put_slice(crypto_data);
             }
             NewToken {
                 token_length,
                 ref token,
             } => {
-                buf.extend(token_length.encode());
-                buf.extend(token);
+                token_length.encode_to(buf);
+                buf.put_slice(token);
             }
             Stream {
                 stream_id,
@@ -463,41 +607,41 @@ impl Frame {
                 if offset.to_inner() > 0 {
                     ty |= 0x04;
                 }
-                buf.push(ty);
-                buf.extend(stream_id.encode());
+                buf.put_u8(ty);
+                stream_id.encode_to(buf);
                 if offset.to_inner() > 0 {
-                    buf.extend(offset.encode());
+                    offset.encode_to(buf);
                 }
                 if length.to_inner() > 0 {
-                    buf.extend(length.encode());
+                    length.encode_to(buf);
                 }
-                buf.extend(stream_data);
+                buf.put_slice(stream_data);
             }
             MaxData(maximum_data) => {
-                buf.extend(maximum_data.encode());
+                maximum_data.encode_to(buf);
             }
             MaxStreamData {
                 stream_id,
                 max_stream_data,
             } => {
-                buf.extend(stream_id.encode());
-                buf.extend(max_stream_data.encode());
+                stream_id.encode_to(buf);
+                max_stream_data.encode_to(buf);
             }
             MaxStreams { max_streams, .. } => {
-                buf.extend(max_streams.encode());
+                max_streams.encode_to(buf);
             }
             DataBlocked(maximum_data) => {
-                buf.extend(maximum_data.encode());
+                maximum_data.encode_to(buf);
             }
             StreamDataBlocked {
                 stream_id,
                 stream_data_limit,
             } => {
-                buf.extend(stream_id.encode());
-                buf.extend(stream_data_limit.encode());
+                stream_id.encode_to(buf);
+                stream_data_limit.encode_to(buf);
             }
             StreamsBlocked { max_streams, .. } => {
-                buf.extend(max_streams.encode());
+                max_streams.encode_to(buf);
             }
             NewConnectionId {
                 sequence_number,
@@ -505,20 +649,20 @@ impl Frame {
                 ref connection_id,
                 stateless_reset_token,
             } => {
-                buf.extend(sequence_number.encode());
-                buf.extend(retire_prior_to.encode());
-                buf.push(connection_id.cid_len);
-                buf.extend(&connection_id.cid);
-                buf.extend(&stateless_reset_token);
+                sequence_number.encode_to(buf);
+                retire_prior_to.encode_to(buf);
+                buf.put_u8(connection_id.cid_len);
+                buf.put_slice(&connection_id.cid);
+                buf.put_slice(&stateless_reset_token);
             }
             RetireConnectionId(sequence_number) => {
-                buf.extend(sequence_number.encode());
+                sequence_number.encode_to(buf);
             }
             PathChallenge(ref data) => {
-                buf.extend(data);
+                buf.put_slice(data);
             }
             PathResponse(ref data) => {
-                buf.extend(data);
+                buf.put_slice(data);
             }
             ConnectionClose {
                 error_code,
@@ -526,303 +670,516 @@ impl Frame {
                 reason_phrase_length,
                 ref reason_phrase,
             } => {
-                buf.extend(error_code.encode());
+                error_code.encode_to(buf);
                 if let Some(frame_type) = frame_type {
-                    buf.push(frame_type);
+                    buf.put_u8(frame_type);
                 }
-                buf.extend(reason_phrase_length.encode());
-                buf.extend(reason_phrase.as_bytes());
+                reason_phrase_length.encode_to(buf);
+                buf.put_slice(reason_phrase.as_slice());
             }
         }
+    }
 
+    // `frames` back to back, as a packet payload.  a STREAM frame without a Length runs to the end
+    // of the packet, so one that isn't last gets its Length written out to keep it from
+    // swallowing what follows
+    pub fn encode_all(frames: &[Frame]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(frames.iter().map(Frame::size).sum());
+        Frame::encode_all_to(frames, &mut buf);
         buf
     }
 
-    pub fn decode(bytes: &mut Vec<u8>) -> QuicheResult<Frame> {
-        let ty = FrameType(bytes.remove(0));
-        match ty {
-            FrameType::PADDING => Ok(Frame::Padding {}),
-            FrameType::PING => Ok(Frame::Ping {}),
-            FrameType::HANDSHAKE_DONE => Ok(Frame::HandshakeDone {}),
-            FrameType::ACK => {
-                let largest_acknowledged = VarInt::decode(bytes)?;
-                let ack_delay = VarInt::decode(bytes)?;
-                let ack_range_count = VarInt::decode(bytes)?;
-                let first_ack_range = VarInt::decode(bytes)?;
-                let mut ack_ranges: Vec<(VarInt, VarInt)> =
-                    Vec::with_capacity(ack_range_count.usize());
-                let mut next_smallest = largest_acknowledged.sub(&first_ack_range)?;
-
-                for _ in 0..ack_range_count.to_inner() {
-                    let gap = VarInt::decode(bytes)?;
-                    let ack_range_length = VarInt::decode(bytes)?;
-
-                    if gap.addn(2)?.gt(&next_smallest) {
-                        return Err(ProtocolError::FrameEncodingError.into());
+    pub fn encode_all_to<B: EncodeBuf>(frames: &[Frame], buf: &mut B) {
+        for (i, frame) in frames.iter().enumerate() {
+            match frame {
+                Frame::Stream {
+                    stream_id,
+                    offset,
+                    length,
+                    fin,
+                    stream_data,
+                } if length.to_inner() == 0 && !stream_data.is_empty() && i + 1 < frames.len() => {
+                    // the data goes last, so it's written straight from the frame instead of
+                    // being copied into one with the Length set
+                    Frame::Stream {
+                        stream_id: *stream_id,
+                        offset: *offset,
+                        length: VarInt(stream_data.len() as u64),
+                        fin: fin.clone(),
+                        stream_data: SmallBytes::new(),
                     }
+                    .encode_to(buf);
+                    buf.put_slice(stream_data);
+                }
+                frame => frame.encode_to(buf),
+            }
+        }
+    }
 
-                    next_smallest = next_smallest.sub(&gap.addn(2)?)?;
+    // the frames in a payload that's declared to be `exact_len` bytes, e.g. by a packet's Length.
+    // they have to fill it exactly, and a packet has to carry at least one (RFC 9000 section 12.4).
+    // the payload is read on its own, so a frame that runs to the end of the packet stops at
+    // `exact_len` and not at the end of `bytes`
+    pub fn decode_all(bytes: &[u8], exact_len: usize) -> QuicheResult<Vec<Frame>> {
+        require(exact_len > 0, "Frame::decode_all: a payload can't be empty")?;
+        let mut payload = bytes
+            .get(..exact_len)
+            .ok_or_else(|| {
+                QuicheError("Frame::decode_all: payload is shorter than its length".to_string())
+            })?
+            .to_vec();
+        let mut frames = Vec::new();
+        while !payload.is_empty() {
+            frames.push(Frame::decode(&mut payload)?);
+        }
+        Ok(frames)
+    }
 
-                    if ack_range_length.gt(&next_smallest) {
-                        return Err(ProtocolError::FrameEncodingError.into());
-                    }
+    // decodes a frame, along with how many bytes it took up.  what `limits.mode` let through goes
+    // on `warnings`
+    pub fn decode_len<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+        warnings: &mut Vec<DecodeWarning>,
+    ) -> QuicheResult<(Frame, usize)> {
+        let before = bytes.as_slice().len();
+        let frame = Frame::decode_warned(bytes, limits, warnings)?;
+        Ok((frame, before - bytes.as_slice().len()))
+    }
 
-                    ack_ranges.push((gap, ack_range_length));
-                }
-                Ok(Frame::Ack {
-                    largest_acknowledged,
-                    ack_delay,
-                    ack_range_count,
-                    first_ack_range,
-                    ack_ranges,
-                })
+    pub fn decode<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Frame> {
+        Frame::decode_with_limits(bytes, &DecodeLimits::default())
+    }
+
+    pub fn decode_with_limits<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+    ) -> QuicheResult<Frame> {
+        Frame::decode_warned(bytes, limits, &mut Vec::new())
+    }
+
+    pub fn decode_warned<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+        warnings: &mut Vec<DecodeWarning>,
+    ) -> QuicheResult<Frame> {
+        // the one varint that has to be in its shortest encoding (RFC 9000 section 12.4)
+        let (ty, minimal) = VarInt::decode_minimal(bytes)
+            .map_err(|_| QuicheError::from(ProtocolError::FrameEncodingError))?;
+        if !minimal {
+            match limits.mode {
+                DecodeMode::Strict => return Err(ProtocolError::ProtocolViolation.into()),
+                DecodeMode::Lenient => warnings.push(DecodeWarning::NonMinimalVarInt),
             }
-            FrameType::ACK_ECN => {
-                let largest_acknowledged = VarInt::decode(bytes)?;
-                let ack_delay = VarInt::decode(bytes)?;
-                let ack_range_count = VarInt::decode(bytes)?;
-                let first_ack_range = VarInt::decode(bytes)?;
-                let mut ack_ranges: Vec<(VarInt, VarInt)> =
-                    Vec::with_capacity(ack_range_count.usize());
-                let mut next_smallest = largest_acknowledged.sub(&first_ack_range)?;
-
-                for _ in 0..ack_range_count.to_inner() {
-                    let gap = VarInt::decode(bytes)?;
-                    let ack_range_length = VarInt::decode(bytes)?;
-
-                    if gap.addn(2)?.gt(&next_smallest) {
-                        return Err(ProtocolError::FrameEncodingError.into());
-                    }
+        }
+        let kind = FrameKind::try_from(ty.to_inner())
+            .map_err(|_| QuicheError::from(ProtocolError::FrameEncodingError))?;
+        // every frame type we know fits in a byte
+        decode_frame(kind, ty.to_inner() as u8, bytes, limits)
+    }
+}
 
-                    next_smallest = next_smallest.sub(&gap.addn(2)?)?;
+// the decoders `frame!` dispatches to, one per kind.  kinds that differ only in their type byte
+// share one and tell themselves apart by `ty`
 
-                    if ack_range_length.gt(&next_smallest) {
-                        return Err(ProtocolError::FrameEncodingError.into());
-                    }
+fn decode_padding<B: DecodeBuf>(_: u8, _: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    Ok(Frame::Padding)
+}
 
-                    ack_ranges.push((gap, ack_range_length));
-                }
-                let ect0_count = VarInt::decode(bytes)?;
-                let ect1_count = VarInt::decode(bytes)?;
-                let ecn_ce_count = VarInt::decode(bytes)?;
-                Ok(Frame::AckEcn {
-                    largest_acknowledged,
-                    ack_delay,
-                    ack_range_count,
-                    first_ack_range,
-                    ack_ranges,
-                    ect0_count,
-                    ect1_count,
-                    ecn_ce_count,
-                })
-            }
-            FrameType::RESET_STREAM => {
-                let stream_id = VarInt::decode(bytes)?;
-                let application_protocol_error_code = VarInt::decode(bytes)?;
-                let final_size = VarInt::decode(bytes)?;
-                Ok(Frame::ResetStream {
-                    stream_id,
-                    application_protocol_error_code,
-                    final_size,
-                })
-            }
-            FrameType::STOP_SENDING => {
-                let stream_id = VarInt::decode(bytes)?;
-                let application_protocol_error_code = VarInt::decode(bytes)?;
-                Ok(Frame::StopSending {
-                    stream_id,
-                    application_protocol_error_code,
-                })
-            }
-            FrameType::CRYPTO => {
-                let offset = VarInt::decode(bytes)?;
-                let crypto_length = VarInt::decode(bytes)?;
-                let crypto_data = bytes.drain(..crypto_length.usize()).collect();
+fn decode_ping<B: DecodeBuf>(_: u8, _: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    Ok(Frame::Ping)
+}
 
-                if offset.add(&crypto_length)?.gtn(2 << 62 - 1) {
-                    return Err(ProtocolError::CryptoBufferExceeded.into());
-                }
+fn decode_handshake_done<B: DecodeBuf>(_: u8, _: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    Ok(Frame::HandshakeDone)
+}
 
-                Ok(Frame::Crypto {
-                    offset,
-                    crypto_length,
-                    crypto_data,
-                })
-            }
-            FrameType::NEW_TOKEN => {
-                let token_length = VarInt::decode(bytes)?;
-                let token = bytes.drain(..token_length.usize()).collect();
-                Ok(Frame::NewToken {
-                    token_length,
-                    token,
-                })
-            }
-            ty if STREAM_RANGE.contains(&ty) => {
-                let stream_ty = bytes.remove(0);
-                let stream_id = VarInt::decode(bytes)?;
+fn decode_ack<B: DecodeBuf>(ty: u8, bytes: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    let largest_acknowledged = VarInt::decode(bytes)?;
+    let ack_delay = VarInt::decode(bytes)?;
+    let ack_range_count = VarInt::decode(bytes)?;
+    let first_ack_range = VarInt::decode(bytes)?;
+    // every range takes at least two bytes, a count that can't fit is a lie
+    checked_len(bytes, ack_range_count.to_inner() * 2)?;
+    let mut ack_ranges: Vec<(VarInt, VarInt)> = Vec::with_capacity(ack_range_count.usize()?);
+    let mut next_smallest = largest_acknowledged.sub(&first_ack_range)?;
 
-                let mut offset: Option<VarInt> = None;
-                let mut length: Option<VarInt> = None;
-                let mut fin = SingleBit::zero();
+    for _ in 0..ack_range_count.to_inner() {
+        let gap = VarInt::decode(bytes)?;
+        let ack_range_length = VarInt::decode(bytes)?;
 
-                if (stream_ty & STREAM_FIN) != 0 {
-                    fin = SingleBit::one();
-                }
+        if gap.addn(2)?.gt(&next_smallest) {
+            return Err(ProtocolError::FrameEncodingError.into());
+        }
 
-                if (stream_ty & STREAM_OFF) != 0 {
-                    offset = Some(VarInt::decode(bytes)?);
-                }
+        next_smallest = next_smallest.sub(&gap.addn(2)?)?;
 
-                if (stream_ty & STREAM_LEN) != 0 {
-                    length = Some(VarInt::decode(bytes)?);
-                }
+        if ack_range_length.gt(&next_smallest) {
+            return Err(ProtocolError::FrameEncodingError.into());
+        }
 
-                let stream_data = if let Some(len) = length {
-                    bytes.drain(..len.usize()).collect()
-                } else {
-                    bytes.drain(..).collect()
-                };
+        ack_ranges.push((gap, ack_range_length));
+    }
+    if ty == FrameType::ACK.0 {
+        return Ok(Frame::Ack {
+            largest_acknowledged,
+            ack_delay,
+            ack_range_count,
+            first_ack_range,
+            ack_ranges,
+        });
+    }
+    let ect0_count = VarInt::decode(bytes)?;
+    let ect1_count = VarInt::decode(bytes)?;
+    let ecn_ce_count = VarInt::decode(bytes)?;
+    Ok(Frame::AckEcn {
+        largest_acknowledged,
+        ack_delay,
+        ack_range_count,
+        first_ack_range,
+        ack_ranges,
+        ect0_count,
+        ect1_count,
+        ecn_ce_count,
+    })
+}
 
-                Ok(Frame::Stream {
-                    stream_id,
-                    offset: offset.unwrap_or_default(),
-                    length: length.unwrap_or_default(),
-                    fin,
-                    stream_data,
-                })
-            }
-            FrameType::MAX_DATA => {
-                let maximum_data = VarInt::decode(bytes)?;
-                Ok(Frame::MaxData(maximum_data))
-            }
-            FrameType::MAX_STREAM_DATA => {
-                let stream_id = VarInt::decode(bytes)?;
-                let max_stream_data = VarInt::decode(bytes)?;
-                Ok(Frame::MaxStreamData {
-                    stream_id,
-                    max_stream_data,
-                })
-            }
-            FrameType::MAX_STREAMS_BIDI => {
-                let max_streams = VarInt::decode(bytes)?;
-                Ok(Frame::MaxStreams {
-                    stream_type: StreamType::Bidirectional,
-                    max_streams,
-                })
-            }
-            FrameType::MAX_STREAMS_UNI => {
-                let max_streams = VarInt::decode(bytes)?;
-                Ok(Frame::MaxStreams {
-                    stream_type: StreamType::Unidirectional,
-                    max_streams,
-                })
-            }
-            FrameType::DATA_BLOCKED => {
-                let maximum_data = VarInt::decode(bytes)?;
-                Ok(Frame::DataBlocked(maximum_data))
-            }
-            FrameType::STREAM_DATA_BLOCKED => {
-                let stream_id = VarInt::decode(bytes)?;
-                let stream_data_limit = VarInt::decode(bytes)?;
-                Ok(Frame::StreamDataBlocked {
-                    stream_id,
-                    stream_data_limit,
-                })
-            }
-            FrameType::STREAMS_BLOCKED_BIDI => {
-                let max_streams = VarInt::decode(bytes)?;
-                Ok(Frame::StreamsBlocked {
-                    stream_type: StreamType::Bidirectional,
-                    max_streams,
-                })
-            }
-            FrameType::STREAMS_BLOCKED_UNI => {
-                let max_streams = VarInt::decode(bytes)?;
-                Ok(Frame::StreamsBlocked {
-                    stream_type: StreamType::Unidirectional,
-                    max_streams,
-                })
-            }
-            FrameType::NEW_CONNECTION_ID => {
-                let sequence_number = VarInt::decode(bytes)?;
-                let retire_prior_to = VarInt::decode(bytes)?;
-                let cid_len = bytes.remove(0);
+fn decode_reset_stream<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let stream_id = VarInt::decode(bytes)?;
+    let application_protocol_error_code = VarInt::decode(bytes)?;
+    let final_size = VarInt::decode(bytes)?;
+    Ok(Frame::ResetStream {
+        stream_id,
+        application_protocol_error_code,
+        final_size,
+    })
+}
 
-                if cid_len.lt(&1) || cid_len.gt(&20) {
-                    return Err(ProtocolError::FrameEncodingError.into());
-                }
+fn decode_stop_sending<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let stream_id = VarInt::decode(bytes)?;
+    let application_protocol_error_code = VarInt::decode(bytes)?;
+    Ok(Frame::StopSending {
+        stream_id,
+        application_protocol_error_code,
+    })
+}
 
-                if retire_prior_to.gt(&sequence_number) {
-                    return Err(ProtocolError::FrameEncodingError.into());
-                }
+fn decode_crypto<B: DecodeBuf>(_: u8, bytes: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    let offset = VarInt::decode(bytes)?;
+    let crypto_length = VarInt::decode(bytes)?;
+    let crypto_data = bytes.take_payload(checked_len(bytes, crypto_length.to_inner())?);
 
-                let cid = bytes.drain(..cid_len as usize).collect();
-                let stateless_reset_token = bytes.drain(..16).collect::<Vec<u8>>();
-                Ok(Frame::NewConnectionId {
-                    sequence_number,
-                    retire_prior_to,
-                    connection_id: ConnectionId { cid_len, cid },
-                    stateless_reset_token: stateless_reset_token.try_into().unwrap(),
-                })
-            }
-            FrameType::RETIRE_CONNECTION_ID => {
-                let sequence_number = VarInt::decode(bytes)?;
-                Ok(Frame::RetireConnectionId(sequence_number))
-            }
-            FrameType::PATH_CHALLENGE => {
-                let challenge = bytes.drain(..8).collect::<Vec<u8>>();
-                Ok(Frame::PathChallenge(challenge.try_into().unwrap()))
-            }
-            FrameType::PATH_RESPONSE => {
-                let response = bytes.drain(..8).collect::<Vec<u8>>();
-                Ok(Frame::PathResponse(response.try_into().unwrap()))
-            }
-            FrameType::CONNECTION_CLOSE_TRANSPORT => {
-                let error_code = VarInt::decode(bytes)?;
-                let frame_type = bytes.remove(0);
-                let reason_phrase_length = VarInt::decode(bytes)?;
-                let reason_phrase_bytes = bytes.drain(..reason_phrase_length.usize()).collect();
-                let reason_phrase = String::from_utf8(reason_phrase_bytes).unwrap();
-                Ok(Frame::ConnectionClose {
-                    error_code,
-                    frame_type: Some(frame_type),
-                    reason_phrase_length,
-                    reason_phrase,
-                })
-            }
-            FrameType::CONNECTION_CLOSE_APPLICATION => {
-                let error_code = VarInt::decode(bytes)?;
-                let reason_phrase_length = VarInt::decode(bytes)?;
-                let reason_phrase_bytes = bytes.drain(..reason_phrase_length.usize()).collect();
-                let reason_phrase = String::from_utf8(reason_phrase_bytes).unwrap();
-                Ok(Frame::ConnectionClose {
-                    error_code,
-                    frame_type: None,
-                    reason_phrase_length,
-                    reason_phrase,
-                })
+    if offset.add(&crypto_length)?.gt(&VarInt::MAX) {
+        return Err(ProtocolError::CryptoBufferExceeded.into());
+    }
+
+    Ok(Frame::Crypto {
+        offset,
+        crypto_length,
+        crypto_data,
+    })
+}
+
+fn decode_new_token<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    limits: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let token_length = VarInt::decode(bytes)?;
+    if token_length.to_inner() > limits.max_new_token_len as u64 {
+        return Err(ProtocolError::FrameEncodingError.into());
+    }
+    let token = bytes.take_payload(checked_len(bytes, token_length.to_inner())?);
+    Ok(Frame::NewToken {
+        token_length,
+        token,
+    })
+}
+
+fn decode_stream<B: DecodeBuf>(_: u8, bytes: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    let stream_ty = bytes.take_u8()?;
+    let stream_id = VarInt::decode(bytes)?;
+
+    let mut offset: Option<VarInt> = None;
+    let mut length: Option<VarInt> = None;
+    let mut fin = SingleBit::zero();
+
+    if (stream_ty & STREAM_FIN) != 0 {
+        fin = SingleBit::one();
+    }
+
+    if (stream_ty & STREAM_OFF) != 0 {
+        offset = Some(VarInt::decode(bytes)?);
+    }
+
+    if (stream_ty & STREAM_LEN) != 0 {
+        length = Some(VarInt::decode(bytes)?);
+    }
+
+    let stream_data = if let Some(len) = length {
+        bytes.take_payload(checked_len(bytes, len.to_inner())?)
+    } else {
+        bytes.take_rest()
+    };
+
+    Ok(Frame::Stream {
+        stream_id,
+        offset: offset.unwrap_or_default(),
+        length: length.unwrap_or_default(),
+        fin,
+        stream_data,
+    })
+}
+
+fn decode_max_data<B: DecodeBuf>(_: u8, bytes: &mut B, _: &DecodeLimits) -> QuicheResult<Frame> {
+    Ok(Frame::MaxData(VarInt::decode(bytes)?))
+}
+
+fn decode_max_stream_data<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let stream_id = VarInt::decode(bytes)?;
+    let max_stream_data = VarInt::decode(bytes)?;
+    Ok(Frame::MaxStreamData {
+        stream_id,
+        max_stream_data,
+    })
+}
+
+fn decode_max_streams<B: DecodeBuf>(
+    ty: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let max_streams = VarInt::decode(bytes)?;
+    Ok(Frame::MaxStreams {
+        stream_type: if ty == FrameType::MAX_STREAMS_BIDI.0 {
+            StreamType::Bidirectional
+        } else {
+            StreamType::Unidirectional
+        },
+        max_streams,
+    })
+}
+
+fn decode_data_blocked<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    Ok(Frame::DataBlocked(VarInt::decode(bytes)?))
+}
+
+fn decode_stream_data_blocked<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let stream_id = VarInt::decode(bytes)?;
+    let stream_data_limit = VarInt::decode(bytes)?;
+    Ok(Frame::StreamDataBlocked {
+        stream_id,
+        stream_data_limit,
+    })
+}
+
+fn decode_streams_blocked<B: DecodeBuf>(
+    ty: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let max_streams = VarInt::decode(bytes)?;
+    Ok(Frame::StreamsBlocked {
+        stream_type: if ty == FrameType::STREAMS_BLOCKED_BIDI.0 {
+            StreamType::Bidirectional
+        } else {
+            StreamType::Unidirectional
+        },
+        max_streams,
+    })
+}
+
+fn decode_new_connection_id<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let sequence_number = VarInt::decode(bytes)?;
+    let retire_prior_to = VarInt::decode(bytes)?;
+    let cid_len = bytes.take_u8()?;
+
+    if cid_len.lt(&1) || cid_len.gt(&20) {
+        return Err(ProtocolError::FrameEncodingError.into());
+    }
+
+    if retire_prior_to.gt(&sequence_number) {
+        return Err(ProtocolError::FrameEncodingError.into());
+    }
+
+    checked_len(bytes, cid_len as u64 + 16)?;
+    let cid = bytes.take_vec(cid_len as usize);
+    let stateless_reset_token = bytes.take_vec(16);
+    Ok(Frame::NewConnectionId {
+        sequence_number,
+        retire_prior_to,
+        connection_id: ConnectionId { cid_len, cid },
+        stateless_reset_token: stateless_reset_token.try_into().unwrap(),
+    })
+}
+
+fn decode_retire_connection_id<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    Ok(Frame::RetireConnectionId(VarInt::decode(bytes)?))
+}
+
+fn decode_path_challenge<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let challenge = bytes.take_vec(checked_len(bytes, 8)?);
+    Ok(Frame::PathChallenge(challenge.try_into().unwrap()))
+}
+
+fn decode_path_response<B: DecodeBuf>(
+    _: u8,
+    bytes: &mut B,
+    _: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let response = bytes.take_vec(checked_len(bytes, 8)?);
+    Ok(Frame::PathResponse(response.try_into().unwrap()))
+}
+
+// only the transport variant carries the type of the frame that triggered it
+fn decode_connection_close<B: DecodeBuf>(
+    ty: u8,
+    bytes: &mut B,
+    limits: &DecodeLimits,
+) -> QuicheResult<Frame> {
+    let error_code = VarInt::decode(bytes)?;
+    let frame_type = match ty == FrameType::CONNECTION_CLOSE_TRANSPORT.0 {
+        true => Some(bytes.take_u8()?),
+        false => None,
+    };
+    let (reason_phrase_length, reason_phrase) =
+        decode_reason_phrase(bytes, limits.max_reason_phrase_len)?;
+    Ok(Frame::ConnectionClose {
+        error_code,
+        frame_type,
+        reason_phrase_length,
+        reason_phrase,
+    })
+}
+
+// combines STREAM frames that pick up exactly where another left off on the same stream, e.g. when
+// re-bundling retransmissions.  everything else keeps its order, the stream frames come after it
+// sorted by stream and offset.  merged frames carry explicit lengths, use `split_stream_at` if one
+// ends up too big for a datagram
+pub fn merge_stream_frames(frames: Vec<Frame>) -> Vec<Frame> {
+    let (mut streams, mut merged): (Vec<Frame>, Vec<Frame>) = frames
+        .into_iter()
+        .partition(|frame| matches!(frame, Frame::Stream { .. }));
+    let key = |frame: &Frame| match frame {
+        Frame::Stream {
+            stream_id, offset, ..
+        } => (stream_id.to_inner(), offset.to_inner()),
+        _ => unreachable!(),
+    };
+    streams.sort_by_key(key);
+
+    let mut current: Option<(VarInt, VarInt, SingleBit, Vec<u8>)> = None;
+    for frame in streams {
+        let Frame::Stream {
+            stream_id,
+            offset,
+            fin,
+            stream_data,
+            ..
+        } = frame
+        else {
+            unreachable!()
+        };
+        if let Some((current_id, current_offset, current_fin, data)) = current.as_mut() {
+            let contiguous = *current_id == stream_id
+                && current_fin.to_inner() == 0
+                && current_offset.to_inner() + data.len() as u64 == offset.to_inner();
+            if contiguous {
+                data.extend_from_slice(&stream_data);
+                *current_fin = fin;
+                continue;
             }
-            _ => unreachable!(),
         }
+        merged.extend(current.take().map(stream_frame));
+        current = Some((stream_id, offset, fin, stream_data.into()));
+    }
+    merged.extend(current.map(stream_frame));
+    merged
+}
+
+fn stream_frame((stream_id, offset, fin, data): (VarInt, VarInt, SingleBit, Vec<u8>)) -> Frame {
+    Frame::Stream {
+        stream_id,
+        offset,
+        length: VarInt::new_u32(data.len() as u32),
+        fin,
+        stream_data: data.into(),
+    }
+}
+
+// the reason phrase is whatever the peer sent, so it's checked against what's actually left and
+// against `max_len` before any of it is taken
+fn decode_reason_phrase<B: DecodeBuf>(
+    bytes: &mut B,
+    max_len: usize,
+) -> QuicheResult<(VarInt, SmallBytes)> {
+    let reason_phrase_length = VarInt::decode(bytes)?;
+    if reason_phrase_length.to_inner() > max_len as u64 {
+        return Err(ProtocolError::FrameEncodingError.into());
+    }
+    let reason_phrase = bytes.take_payload(checked_len(bytes, reason_phrase_length.to_inner())?);
+    Ok((reason_phrase_length, reason_phrase))
+}
+
+// a length the peer declared, checked against what's actually left before anything is allocated
+// or taken for it
+fn checked_len<B: DecodeBuf>(bytes: &B, len: u64) -> QuicheResult<usize> {
+    if len > bytes.as_slice().len() as u64 {
+        return Err(ProtocolError::FrameEncodingError.into());
     }
+    Ok(len as usize)
 }
 
 #[cfg(test)]
 pub(crate) mod test_frame {
+    use bytes::Bytes;
+
     use super::*;
-    use crate::rand::rand;
+    use crate::{rand::Rng, testing::Iterations};
 
-    pub fn generate_random_frame() -> Frame {
-        let ty = rand(31);
+    pub fn generate_random_frame(rng: &mut Rng) -> Frame {
+        let ty = rng.rand(31);
         match ty {
             0x00 => Frame::Padding,
             0x01 => Frame::Ping,
             0x02 => {
-                let largest_acknowledged = VarInt::new_u32(rand(1000) as u32);
+                let largest_acknowledged = VarInt::new_u32(rng.rand(1000) as u32);
                 let ack_delay = VarInt::new_u32(7);
                 let ack_range_count = VarInt::new_u32(4);
                 let first_ack_range =
-                    VarInt::new_u32(rand((largest_acknowledged.to_inner() + 1) as u128) as u32);
+                    VarInt::new_u32(rng.rand((largest_acknowledged.to_inner() + 1) as u128) as u32);
 
                 let mut remaining = largest_acknowledged.sub(&first_ack_range).unwrap();
                 if remaining.lt(&VarInt::new_u32(8)) {
@@ -839,7 +1196,7 @@ pub(crate) mod test_frame {
                     .map(|_| {
                         let gap = if remaining.to_inner() > 2 {
                             let max_gap = remaining.to_inner() - 2;
-                            VarInt::new_u32(rand((max_gap + 1) as u128) as u32)
+                            VarInt::new_u32(rng.rand((max_gap + 1) as u128) as u32)
                         } else {
                             VarInt::zero()
                         };
@@ -851,7 +1208,7 @@ pub(crate) mod test_frame {
                         };
 
                         let ack_range_length = if remaining.to_inner() > 0 {
-                            VarInt::new_u32(rand((remaining.to_inner() + 1) as u128) as u32)
+                            VarInt::new_u32(rng.rand((remaining.to_inner() + 1) as u128) as u32)
                         } else {
                             VarInt::zero()
                         };
@@ -880,10 +1237,10 @@ pub(crate) mod test_frame {
                 }
             }
             0x03 => {
-                let largest_acknowledged = VarInt::new_u32(rand(1000) as u32);
+                let largest_acknowledged = VarInt::new_u32(rng.rand(1000) as u32);
                 let ack_delay = VarInt::new_u32(7);
                 let first_ack_range =
-                    VarInt::new_u32(rand((largest_acknowledged.to_inner() + 1) as u128) as u32);
+                    VarInt::new_u32(rng.rand((largest_acknowledged.to_inner() + 1) as u128) as u32);
                 let ect0_count = VarInt::new_u32(7);
                 let ect1_count = VarInt::new_u32(7);
                 let ecn_ce_count = VarInt::new_u32(7);
@@ -919,7 +1276,7 @@ pub(crate) mod test_frame {
                     } else {
                         0
                     };
-                    let gap = VarInt::new_u32(rand((max_gap + 1) as u128) as u32);
+                    let gap = VarInt::new_u32(rng.rand((max_gap + 1) as u128) as u32);
 
                     if gap.to_inner() + 2 >= remaining.to_inner() {
                         // If gap would make next_smallest zero or negative, break the loop
@@ -930,7 +1287,7 @@ pub(crate) mod test_frame {
 
                     let max_ack_range_length = remaining.to_inner();
                     let ack_range_length =
-                        VarInt::new_u32(rand((max_ack_range_length + 1) as u128) as u32);
+                        VarInt::new_u32(rng.rand((max_ack_range_length + 1) as u128) as u32);
 
                     remaining = if ack_range_length.to_inner() < remaining.to_inner() {
                         remaining.sub(&ack_range_length).unwrap()
@@ -953,9 +1310,9 @@ pub(crate) mod test_frame {
                 }
             }
             0x04 => {
-                let stream_id = VarInt::new_u32(rand(255) as u32);
-                let application_protocol_error_code = VarInt::new_u32(rand(255) as u32);
-                let final_size = VarInt::new_u32(rand(255) as u32);
+                let stream_id = VarInt::new_u32(rng.rand(255) as u32);
+                let application_protocol_error_code = VarInt::new_u32(rng.rand(255) as u32);
+                let final_size = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::ResetStream {
                     stream_id,
                     application_protocol_error_code,
@@ -963,35 +1320,35 @@ pub(crate) mod test_frame {
                 }
             }
             0x05 => {
-                let stream_id = VarInt::new_u32(rand(255) as u32);
-                let application_protocol_error_code = VarInt::new_u32(rand(255) as u32);
+                let stream_id = VarInt::new_u32(rng.rand(255) as u32);
+                let application_protocol_error_code = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::StopSending {
                     stream_id,
                     application_protocol_error_code,
                 }
             }
             0x06 => {
-                let offset = VarInt::new_u32(rand(255) as u32);
+                let offset = VarInt::new_u32(rng.rand(255) as u32);
                 let crypto_length = VarInt::new_u32(65);
-                let mut crypto_data = Vec::with_capacity(crypto_length.usize());
+                let mut crypto_data = Vec::with_capacity(crypto_length.usize().unwrap());
                 for _ in 0..crypto_length.to_inner() {
-                    crypto_data.push(rand(255) as u8);
+                    crypto_data.push(rng.rand(255));
                 }
                 Frame::Crypto {
                     offset,
                     crypto_length,
-                    crypto_data,
+                    crypto_data: crypto_data.into(),
                 }
             }
             0x07 => {
                 let token_length = VarInt::new_u32(65);
-                let mut token = Vec::with_capacity(token_length.usize());
+                let mut token = Vec::with_capacity(token_length.usize().unwrap());
                 for _ in 0..token_length.to_inner() {
-                    token.push(rand(255) as u8);
+                    token.push(rng.rand(255));
                 }
                 Frame::NewToken {
                     token_length,
-                    token,
+                    token: token.into(),
                 }
             }
             stream_ty @ 0x08
@@ -1002,15 +1359,15 @@ pub(crate) mod test_frame {
             | stream_ty @ 0x0d
             | stream_ty @ 0x0e
             | stream_ty @ 0x0f => {
-                let stream_id = VarInt::new_u32(rand(1_000_000) as u32); // Random stream_id
+                let stream_id = VarInt::new_u32(rng.rand(1_000_000) as u32); // Random stream_id
                 let offset = if (stream_ty & 0x04) != 0 {
-                    VarInt::new_u32(rand(1_000) as u32)
+                    VarInt::new_u32(rng.rand(1_000) as u32)
                 } else {
                     VarInt::default()
                 };
 
                 let length = if (stream_ty & 0x02) != 0 {
-                    VarInt::new_u32(rand(1024) as u32)
+                    VarInt::new_u32(rng.rand(1024) as u32)
                 } else {
                     VarInt::default()
                 };
@@ -1022,9 +1379,9 @@ pub(crate) mod test_frame {
                 };
 
                 let stream_data = if length.0 > 0 {
-                    (0..length.0).map(|_| rand(256) as u8).collect()
+                    (0..length.0).map(|_| rng.rand(256)).collect()
                 } else {
-                    vec![rand(256) as u8; 64]
+                    vec![rng.rand(256); 64].into()
                 };
 
                 Frame::Stream {
@@ -1036,12 +1393,12 @@ pub(crate) mod test_frame {
                 }
             }
             0x10 => {
-                let maximum_data = VarInt::new_u32(rand(255) as u32);
+                let maximum_data = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::MaxData(maximum_data)
             }
             0x11 => {
-                let stream_id = VarInt::new_u32(rand(255) as u32);
-                let max_stream_data = VarInt::new_u32(rand(255) as u32);
+                let stream_id = VarInt::new_u32(rng.rand(255) as u32);
+                let max_stream_data = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::MaxStreamData {
                     stream_id,
                     max_stream_data,
@@ -1049,7 +1406,7 @@ pub(crate) mod test_frame {
             }
             0x12 => {
                 let stream_type = StreamType::Bidirectional;
-                let max_streams = VarInt::new_u32(rand(255) as u32);
+                let max_streams = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::MaxStreams {
                     stream_type,
                     max_streams,
@@ -1057,19 +1414,19 @@ pub(crate) mod test_frame {
             }
             0x13 => {
                 let stream_type = StreamType::Unidirectional;
-                let max_streams = VarInt::new_u32(rand(255) as u32);
+                let max_streams = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::MaxStreams {
                     stream_type,
                     max_streams,
                 }
             }
             0x14 => {
-                let maximum_data = VarInt::new_u32(rand(255) as u32);
+                let maximum_data = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::DataBlocked(maximum_data)
             }
             0x15 => {
-                let stream_id = VarInt::new_u32(rand(255) as u32);
-                let stream_data_limit = VarInt::new_u32(rand(255) as u32);
+                let stream_id = VarInt::new_u32(rng.rand(255) as u32);
+                let stream_data_limit = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::StreamDataBlocked {
                     stream_id,
                     stream_data_limit,
@@ -1077,7 +1434,7 @@ pub(crate) mod test_frame {
             }
             0x16 => {
                 let stream_type = StreamType::Bidirectional;
-                let max_streams = VarInt::new_u32(rand(255) as u32);
+                let max_streams = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::StreamsBlocked {
                     stream_type,
                     max_streams,
@@ -1085,24 +1442,24 @@ pub(crate) mod test_frame {
             }
             0x17 => {
                 let stream_type = StreamType::Unidirectional;
-                let max_streams = VarInt::new_u32(rand(255) as u32);
+                let max_streams = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::StreamsBlocked {
                     stream_type,
                     max_streams,
                 }
             }
             0x18 => {
-                let sequence_number = VarInt::new_u32(rand(255) as u32);
+                let sequence_number = VarInt::new_u32(rng.rand(255) as u32);
                 let retire_prior_to =
-                    VarInt::new_u32(rand(sequence_number.to_inner() as u128) as u32);
-                let cid_len = rand(20) as u8 + 1;
+                    VarInt::new_u32(rng.rand(sequence_number.to_inner() as u128) as u32);
+                let cid_len = rng.rand(20) + 1;
                 let mut cid = Vec::with_capacity(cid_len as usize);
                 for _ in 0..cid_len {
-                    cid.push(rand(255) as u8);
+                    cid.push(rng.rand(255));
                 }
                 let mut stateless_reset_token = [0; 16];
-                for i in 0..16 {
-                    stateless_reset_token[i] = rand(255) as u8;
+                for byte in stateless_reset_token.iter_mut() {
+                    *byte = rng.rand(255);
                 }
                 Frame::NewConnectionId {
                     sequence_number,
@@ -1112,67 +1469,67 @@ pub(crate) mod test_frame {
                 }
             }
             0x19 => {
-                let sequence_number = VarInt::new_u32(rand(255) as u32);
+                let sequence_number = VarInt::new_u32(rng.rand(255) as u32);
                 Frame::RetireConnectionId(sequence_number)
             }
             0x1a => {
                 let mut challenge = [0; 8];
-                for i in 0..8 {
-                    challenge[i] = rand(255) as u8;
+                for byte in challenge.iter_mut() {
+                    *byte = rng.rand(255);
                 }
                 Frame::PathChallenge(challenge)
             }
             0x1b => {
                 let mut response = [0; 8];
-                for i in 0..8 {
-                    response[i] = rand(255) as u8;
+                for byte in response.iter_mut() {
+                    *byte = rng.rand(255);
                 }
                 Frame::PathResponse(response)
             }
             0x1c => {
-                let error_code: u16 = match rand(2) {
-                    0 => rand(0x11) as u16,
-                    1 => 0x0100 + rand(0x0100) as u16,
+                let error_code: u16 = match rng.rand(2) {
+                    0 => rng.rand(0x11) as u16,
+                    1 => 0x0100 + rng.rand(0x0100) as u16,
                     _ => unreachable!(),
                 };
-                let frame_type = rand(31);
-                let reason_phrase_length = VarInt::new_u32(rand(1948) as u32);
-                let mut reason_phrase = Vec::with_capacity(reason_phrase_length.usize());
+                let frame_type = rng.rand(31);
+                let reason_phrase_length = VarInt::new_u32(rng.rand(1948) as u32);
+                let mut reason_phrase = Vec::with_capacity(reason_phrase_length.usize().unwrap());
                 for _ in 0..reason_phrase_length.to_inner() {
-                    let valid_char = rand(95) as u8 + 32;
+                    let valid_char = rng.rand(95) + 32;
                     reason_phrase.push(valid_char);
                 }
                 Frame::ConnectionClose {
                     error_code: VarInt::new_u64(error_code as u64).unwrap(),
                     frame_type: Some(frame_type),
                     reason_phrase_length,
-                    reason_phrase: String::from_utf8(reason_phrase).unwrap(),
+                    reason_phrase: reason_phrase.into(),
                 }
             }
             0x1d => {
-                let error_code = match rand(2) {
+                let error_code = match rng.rand(2) {
                     0 => {
-                        let temp = rand(0x00EF) as u16;
+                        let temp = rng.rand(0x00EF) as u16;
                         if temp >= 0xEF {
                             temp + 0x0110
                         } else {
                             temp + 0x11
                         }
                     }
-                    1 => 0x0200 + rand((u64::MAX - 0x0200).into()) as u16,
+                    1 => 0x0200 + rng.rand((u64::MAX - 0x0200).into()) as u16,
                     _ => unreachable!(),
                 };
-                let reason_phrase_length = VarInt::new_u32(rand(1948) as u32);
-                let mut reason_phrase = Vec::with_capacity(reason_phrase_length.usize());
+                let reason_phrase_length = VarInt::new_u32(rng.rand(1948) as u32);
+                let mut reason_phrase = Vec::with_capacity(reason_phrase_length.usize().unwrap());
                 for _ in 0..reason_phrase_length.to_inner() {
-                    let valid_char = rand(95) as u8 + 32;
+                    let valid_char = rng.rand(95) + 32;
                     reason_phrase.push(valid_char);
                 }
                 Frame::ConnectionClose {
                     error_code: VarInt::new_u64(error_code as u64).unwrap(),
                     frame_type: None,
                     reason_phrase_length,
-                    reason_phrase: String::from_utf8(reason_phrase).unwrap(),
+                    reason_phrase: reason_phrase.into(),
                 }
             }
             0x1e => Frame::HandshakeDone,
@@ -1180,12 +1537,241 @@ pub(crate) mod test_frame {
         }
     }
 
+    fn stream(offset: u32, data: &[u8], open_ended: bool, fin: bool) -> Frame {
+        Frame::Stream {
+            stream_id: VarInt::new_u32(4),
+            offset: VarInt::new_u32(offset),
+            length: match open_ended {
+                true => VarInt::zero(),
+                false => VarInt::new_u32(data.len() as u32),
+            },
+            fin: SingleBit::from_num(fin as u8),
+            stream_data: SmallBytes::from_slice(data),
+        }
+    }
+
+    #[test]
+    fn test_split_stream() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let frame = stream(0, &data, true, true);
+        assert_eq!(
+            frame.clone().split_stream_at(200).unwrap(),
+            (frame.clone(), None)
+        );
+        assert!(frame.clone().split_stream_at(4).is_err());
+        assert!(Frame::Ping.split_stream_at(100).is_err());
+
+        let (first, rest) = frame.split_stream_at(40).unwrap();
+        assert_eq!(first.encode().len(), 40);
+        // type, flags, stream id, length
+        assert_eq!(first, stream(0, &data[..36], false, false));
+        let rest = rest.unwrap();
+        assert_eq!(rest, stream(36, &data[36..], true, true));
+
+        // and back together
+        assert_eq!(
+            merge_stream_frames(vec![rest, Frame::Ping, first]),
+            vec![Frame::Ping, stream(0, &data, false, true)]
+        );
+    }
+
+    #[test]
+    fn test_merge_stream_frames() {
+        let other_stream = Frame::Stream {
+            stream_id: VarInt::new_u32(8),
+            offset: VarInt::new_u32(3),
+            length: VarInt::new_u32(1),
+            fin: SingleBit::zero(),
+            stream_data: SmallBytes::from_slice(b"x"),
+        };
+        let merged = merge_stream_frames(vec![
+            stream(6, b"gh", false, false),
+            other_stream.clone(),
+            stream(0, b"abc", false, false),
+            stream(3, b"def", false, false),
+            // a gap before this one
+            stream(10, b"k", false, true),
+        ]);
+        assert_eq!(
+            merged,
+            vec![
+                stream(0, b"abcdefgh", false, false),
+                stream(10, b"k", false, true),
+                other_stream,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_all() {
+        let frames = vec![
+            Frame::Ping,
+            stream(0, b"abc", true, false),
+            Frame::Padding,
+            stream(3, b"def", true, true),
+        ];
+        let encoded = Frame::encode_all(&frames);
+        // the first STREAM frame got a Length so the rest survive it, the last one doesn't need one
+        let decoded = Frame::decode_all(&encoded, encoded.len()).unwrap();
+        assert_eq!(
+            decoded,
+            vec![
+                Frame::Ping,
+                stream(0, b"abc", false, false),
+                Frame::Padding,
+                stream(3, b"def", true, true),
+            ]
+        );
+
+        // the last frame runs to the declared length, not to whatever comes after it
+        let mut trailing = encoded.clone();
+        trailing.extend(Frame::Ping.encode());
+        assert_eq!(
+            Frame::decode_all(&trailing, encoded.len()).unwrap(),
+            decoded
+        );
+        let closed = Frame::encode_all(&[Frame::Ping, Frame::HandshakeDone]);
+        assert_eq!(Frame::decode_all(&closed, 1).unwrap(), vec![Frame::Ping]);
+        assert!(Frame::decode_all(&closed, 3).is_err());
+        assert!(Frame::decode_all(&closed, 0).is_err());
+    }
+
+    #[test]
+    fn test_reason_phrase() {
+        let close = |reason_phrase: &[u8]| {
+            let mut bytes = vec![FrameType::CONNECTION_CLOSE_APPLICATION.0, 0x01];
+            bytes.extend(VarInt::new_u32(reason_phrase.len() as u32).encode());
+            bytes.extend(reason_phrase);
+            bytes
+        };
+
+        // not utf-8, which used to panic
+        let frame = Frame::decode(&mut close(&[b'o', b'k', 0xFF])).unwrap();
+        assert_eq!(frame.reason_str().unwrap(), "ok\u{FFFD}");
+        assert_eq!(Frame::Ping.reason_str(), None);
+
+        let frame = Frame::decode(&mut close(&[b'a'; MAX_REASON_PHRASE_LEN])).unwrap();
+        assert_eq!(frame.reason_str().unwrap().len(), MAX_REASON_PHRASE_LEN);
+        // anything longer isn't kept around, however much of it there is
+        let error = Frame::decode(&mut close(&[b'a'; MAX_REASON_PHRASE_LEN + 1])).unwrap_err();
+        assert_eq!(
+            error.0,
+            QuicheError::from(ProtocolError::FrameEncodingError).0
+        );
+        let limits = DecodeLimits {
+            max_reason_phrase_len: 2,
+            ..Default::default()
+        };
+        assert!(Frame::decode_with_limits(&mut close(b"ok"), &limits).is_ok());
+        assert!(Frame::decode_with_limits(&mut close(b"bad"), &limits).is_err());
+
+        // claims more than there is
+        let mut bytes = close(b"short");
+        bytes.truncate(bytes.len() - 1);
+        assert!(Frame::decode(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn test_huge_declared_lengths() {
+        let huge = VarInt::MAX.encode();
+        let limits = DecodeLimits {
+            max_reason_phrase_len: usize::MAX,
+            max_new_token_len: usize::MAX,
+            ..Default::default()
+        };
+        let frames = [
+            // CRYPTO, offset 0 and a length of 2^62 - 1
+            [&[FrameType::CRYPTO.0, 0x00][..], &huge, &[1, 2, 3]].concat(),
+            [&[FrameType::NEW_TOKEN.0][..], &huge, &[1, 2, 3]].concat(),
+            // STREAM with a length, stream 0.  the codec writes the type byte twice
+            [&[0x0a, 0x0a, 0x00][..], &huge, &[1, 2, 3]].concat(),
+            [
+                &[FrameType::CONNECTION_CLOSE_APPLICATION.0, 0x01][..],
+                &huge,
+                &[1],
+            ]
+            .concat(),
+            // ACK claiming 2^62 - 1 ranges after largest 10, delay 0, first range 0
+            [
+                &[FrameType::ACK.0, 0x0a, 0x00][..],
+                &huge,
+                &[0x00, 0x00, 0x00],
+            ]
+            .concat(),
+            // NEW_CONNECTION_ID, an 8 byte cid that isn't there
+            vec![FrameType::NEW_CONNECTION_ID.0, 0x01, 0x00, 0x08, 1, 2],
+            vec![FrameType::PATH_CHALLENGE.0, 1, 2, 3],
+        ];
+        for frame in frames {
+            assert!(Frame::decode_with_limits(&mut frame.clone(), &limits).is_err());
+            assert!(Frame::decode_with_limits(&mut Bytes::from(frame), &limits).is_err());
+        }
+    }
+
+    #[test]
+    fn test_new_token_limit() {
+        let new_token = |len: usize| {
+            Frame::NewToken {
+                token_length: VarInt::new_u32(len as u32),
+                token: SmallBytes::from_slice(&vec![1; len]),
+            }
+            .encode()
+        };
+        let limits = DecodeLimits {
+            max_new_token_len: 16,
+            ..Default::default()
+        };
+        assert!(Frame::decode_with_limits(&mut new_token(16), &limits).is_ok());
+        assert!(Frame::decode_with_limits(&mut new_token(17), &limits).is_err());
+        assert!(Frame::decode(&mut new_token(17)).is_ok());
+    }
+
+    #[test]
+    fn test_frame_kind() {
+        assert_eq!(FrameKind::ALL.len(), 24);
+        for kind in FrameKind::iter() {
+            assert_eq!(
+                FrameKind::try_from(kind.ty().to_inner() as u64).unwrap(),
+                kind
+            );
+        }
+        // every flag combination is still a stream
+        for ty in 0x08..=0x0f {
+            assert!(FrameKind::try_from(ty).unwrap().is_stream());
+        }
+        assert!(!FrameKind::Crypto.is_stream());
+        assert!(FrameKind::try_from(0x1f).is_err());
+        assert!(FrameKind::try_from(0x108).is_err());
+        assert_eq!(FrameKind::MaxStreamsBidi.to_string(), "MAX_STREAMS_BIDI");
+
+        // RFC 9000 table 3
+        use crate::packet::EncryptionLevel::*;
+        assert_eq!(
+            FrameKind::Ping.permitted(),
+            [Initial, Handshake, ZeroRtt, OneRtt]
+        );
+        assert_eq!(FrameKind::Ack.permitted(), [Initial, Handshake, OneRtt]);
+        assert!(FrameKind::ConnectionCloseTransport.is_permitted(Initial));
+        assert!(!FrameKind::ConnectionCloseApplication.is_permitted(Initial));
+        assert!(!FrameKind::Stream.is_permitted(Handshake));
+        assert_eq!(FrameKind::HandshakeDone.permitted(), [OneRtt]);
+
+        // an unknown type is an encoding error, not a crash
+        assert!(Frame::decode(&mut vec![0x1f, 0x00]).is_err());
+
+        let mut rng = Rng::from_env();
+        for _ in 0..Iterations::from_env().frames {
+            let frame = generate_random_frame(&mut rng);
+            assert_eq!(frame.ty().kind().unwrap(), frame.kind());
+            assert_eq!(frame.size(), frame.encode().len());
+        }
+    }
+
     #[test]
     fn test_frame() {
-        let num_frames = 1_000_000;
-        for i in 0..num_frames {
-            println!("frame test: {}", i);
-            let frame = generate_random_frame();
+        let mut rng = Rng::from_env();
+        for _ in 0..Iterations::from_env().frames {
+            let frame = generate_random_frame(&mut rng);
             let encoded = frame.encode();
             let decoded = Frame::decode(&mut encoded.clone()).unwrap();
             assert_eq!(frame, decoded, "frame ty: {}", frame.ty().to_inner());
@@ -1,10 +1,11 @@
 use crate::{
     bits::{compose_bits, decompose_bits, BitsExt},
-    result::{require, QuicheResult},
-    VarInt,
+    consts::RETRY_INTEGRITY_TAG_LEN,
+    result::{require, QuicheError, QuicheResult},
+    EncodeBuf, VarInt,
 };
 
-use super::types::*;
+use super::{error::ProtocolError, limits::DecodeLimits, types::*, version::Version};
 
 // From QUIC spec
 // Upon first receiving an Initial or Retry packet from the server, the client uses the Source Connection ID supplied by the server as the Destination Connection ID for subsequent packets, including any 0-RTT packets.
@@ -30,6 +31,38 @@ pub enum Header {
     Short(ShortHeader),
 }
 
+pub use crate::consts::MAX_CID_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketType {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    Retry,
+    VersionNegotiation,
+    Short,
+}
+
+// what `Header::peek` reads out of a packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketInfo {
+    pub packet_type: PacketType,
+    // long headers only
+    pub version: Option<u32>,
+    pub dst_cid: ConnectionId,
+    // long headers only
+    pub src_cid: Option<ConnectionId>,
+}
+
+// what `Header::peek_initial` reads out of an Initial
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitialPeek<'a> {
+    pub version: u32,
+    pub dst_cid: &'a [u8],
+    pub src_cid: &'a [u8],
+    pub token: &'a [u8],
+}
+
 impl Header {
     pub fn decode(bytes: &mut Vec<u8>) -> Header {
         match bytes[0] & 0b10_000000 == HeaderForm::short().to_inner() {
@@ -38,6 +71,104 @@ impl Header {
         }
     }
 
+    // the type, version and cids of a packet, read in place without decoding (or decrypting) the
+    // rest, for a load balancer or the endpoint's demux.  short headers don't say who sent them,
+    // and carry a dst cid of our own choosing, `local_cid_len` bytes long.  this codec writes a
+    // length byte ahead of it anyway, a short header that disagrees with `local_cid_len` is an
+    // error rather than a guess
+    pub fn peek(bytes: &[u8], local_cid_len: usize) -> QuicheResult<PacketInfo> {
+        let truncated = || QuicheError("Header::peek: packet is truncated".to_string());
+        let first = *bytes.first().ok_or_else(truncated)?;
+
+        if first & 0b10_000000 == HeaderForm::short().to_inner() {
+            let dst_cid = Self::peek_dst_cid(bytes).ok_or_else(truncated)?;
+            require(
+                dst_cid.len() == local_cid_len,
+                "Header::peek: short header dst cid isn't local_cid_len long",
+            )?;
+            return Ok(PacketInfo {
+                packet_type: PacketType::Short,
+                version: None,
+                dst_cid: ConnectionId::new(dst_cid.len() as u8, dst_cid.to_vec()),
+                src_cid: None,
+            });
+        }
+
+        let version =
+            u32::from_le_bytes(bytes.get(1..5).ok_or_else(truncated)?.try_into().unwrap());
+        let dst_cid = Self::peek_dst_cid(bytes).ok_or_else(truncated)?;
+        let src_at = 6 + dst_cid.len();
+        let src_len = *bytes.get(src_at).ok_or_else(truncated)? as usize;
+        let src_cid = bytes
+            .get(src_at + 1..src_at + 1 + src_len)
+            .ok_or_else(truncated)?;
+        require(
+            !Version::is_reserved(version),
+            "Header::peek: reserved version",
+        )?;
+        // told apart the way `LongHeader::decode` does it, version negotiation is the type 0
+        // packet without the fixed bit.  it's also the only one allowed cids over 20 bytes, the
+        // versions it answers might allow them
+        let fixed_bit = first & 0b01_000000 != 0;
+        let packet_type = match (long_packet_type(first, version), fixed_bit) {
+            (0, false) => PacketType::VersionNegotiation,
+            (0, true) => PacketType::Initial,
+            (1, _) => PacketType::ZeroRtt,
+            (2, _) => PacketType::Handshake,
+            _ => PacketType::Retry,
+        };
+        require(
+            packet_type == PacketType::VersionNegotiation
+                || (dst_cid.len() <= MAX_CID_LEN && src_len <= MAX_CID_LEN),
+            "Header::peek: cid longer than 20 bytes",
+        )?;
+        Ok(PacketInfo {
+            packet_type,
+            version: Some(version),
+            dst_cid: ConnectionId::new(dst_cid.len() as u8, dst_cid.to_vec()),
+            src_cid: Some(ConnectionId::new(src_len as u8, src_cid.to_vec())),
+        })
+    }
+
+    // the destination cid of a packet without decoding the rest, for routing a datagram to its
+    // connection.  None if it's too short to hold one.  long headers carry it after the version,
+    // short headers after their cid_len byte
+    pub fn peek_dst_cid(bytes: &[u8]) -> Option<&[u8]> {
+        let first = *bytes.first()?;
+        let len_at = match first & 0b10_000000 == HeaderForm::short().to_inner() {
+            true => 1,
+            false => 5,
+        };
+        let len = *bytes.get(len_at)? as usize;
+        bytes.get(len_at + 1..len_at + 1 + len)
+    }
+
+    // the cids and token of an Initial without decoding the rest, for deciding what to do about a
+    // client's first packet before there's a connection to hand it to.  None if it isn't an
+    // Initial or is cut short
+    pub fn peek_initial(bytes: &[u8]) -> Option<InitialPeek<'_>> {
+        let first = *bytes.first()?;
+        // long header, with the fixed bit and the type bits of an Initial in its version
+        let version = u32::from_le_bytes(bytes.get(1..5)?.try_into().unwrap());
+        if first & 0b11_000000 != 0b11_000000 || long_packet_type(first, version) != 0 {
+            return None;
+        }
+        let dst_cid = Self::peek_dst_cid(bytes)?;
+        let mut at = 6 + dst_cid.len();
+        let src_len = *bytes.get(at)? as usize;
+        let src_cid = bytes.get(at + 1..at + 1 + src_len)?;
+        at += 1 + src_len;
+        let (token_len, varint_len) = peek_varint(bytes, at)?;
+        at += varint_len;
+        let token = bytes.get(at..at.checked_add(token_len as usize)?)?;
+        Some(InitialPeek {
+            version,
+            dst_cid,
+            src_cid,
+            token,
+        })
+    }
+
     pub fn encode(&self) -> QuicheResult<Vec<u8>> {
         match self {
             Header::Initial(header)
@@ -47,6 +178,56 @@ impl Header {
             Header::Short(header) => header.encode(),
         }
     }
+
+    // retry and version negotiation packets aren't protected, so they don't have one
+    pub fn encryption_level(&self) -> Option<EncryptionLevel> {
+        match self {
+            Header::Initial(_) => Some(EncryptionLevel::Initial),
+            Header::Long(header) => match header.long_packet_type.to_inner() {
+                1 => Some(EncryptionLevel::ZeroRtt),
+                _ => Some(EncryptionLevel::Handshake),
+            },
+            Header::Short(_) => Some(EncryptionLevel::OneRtt),
+            Header::Retry(_) | Header::VersionNegotiate(_) => None,
+        }
+    }
+
+    pub fn space(&self) -> Option<PacketSpace> {
+        self.encryption_level().map(EncryptionLevel::space)
+    }
+
+    // the Length field (packet number + payload), which only initial / 0-rtt / handshake packets have
+    pub fn length(&self) -> Option<usize> {
+        match self {
+            Header::Initial(header)
+            | Header::Retry(header)
+            | Header::VersionNegotiate(header)
+            | Header::Long(header) => header.length(),
+            Header::Short(_) => None,
+        }
+    }
+
+    // how many bytes the packet number takes on the wire, None where there isn't one
+    pub fn packet_number_len(&self) -> Option<usize> {
+        match self {
+            Header::Initial(header)
+            | Header::Retry(header)
+            | Header::VersionNegotiate(header)
+            | Header::Long(header) => header.packet_number_len(),
+            Header::Short(header) => Some(header.packet_number_len()),
+        }
+    }
+
+    // the (truncated, as sent) packet number.  retry and version negotiation packets don't carry one
+    pub fn packet_number(&self) -> Option<u64> {
+        match self {
+            Header::Initial(header)
+            | Header::Retry(header)
+            | Header::VersionNegotiate(header)
+            | Header::Long(header) => header.packet_number(),
+            Header::Short(header) => Some(header.packet_number()),
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -67,7 +248,7 @@ pub enum LongHeaderExtension {
     },
     Retry {
         retry_token: Vec<u8>,
-        retry_integrity_tag: [u8; 16],
+        retry_integrity_tag: [u8; RETRY_INTEGRITY_TAG_LEN],
     },
     VersionNegotiation {
         supported_versions: Vec<u32>,
@@ -75,14 +256,25 @@ pub enum LongHeaderExtension {
 }
 
 impl LongHeaderExtension {
-    pub fn decode(bytes: &mut Vec<u8>, ty: u8) -> QuicheResult<Self> {
+    // `packet_number_len` is what the header's type specific bits say, see `PnLen::from_long_header_bits`
+    pub fn decode(
+        bytes: &mut Vec<u8>,
+        ty: u8,
+        packet_number_len: usize,
+        limits: &DecodeLimits,
+    ) -> QuicheResult<Self> {
         // really cheap hacky way of identifying what type of LongHeaderExtension this is...
         match ty {
             0 => {
                 let token_length = VarInt::decode(bytes)?;
-                let token = bytes.drain(..token_length.usize()).collect::<Vec<u8>>();
+                if token_length.to_inner() > limits.max_initial_token_len() as u64
+                    || token_length.to_inner() > bytes.len() as u64
+                {
+                    return Err(ProtocolError::FrameEncodingError.into());
+                }
+                let token = bytes.drain(..token_length.usize()?).collect::<Vec<u8>>();
                 let length = VarInt::decode(bytes)?;
-                let packet_number = PacketNumber(VarInt::decode(bytes)?);
+                let packet_number = take_packet_number(bytes, packet_number_len)?;
                 Ok(LongHeaderExtension::Initial {
                     token_length,
                     token,
@@ -92,7 +284,7 @@ impl LongHeaderExtension {
             }
             1 => {
                 let length = VarInt::decode(bytes)?;
-                let packet_number = PacketNumber(VarInt::decode(bytes)?);
+                let packet_number = take_packet_number(bytes, packet_number_len)?;
                 Ok(LongHeaderExtension::ZeroRTT {
                     length,
                     packet_number,
@@ -100,17 +292,18 @@ impl LongHeaderExtension {
             }
             2 => {
                 let length = VarInt::decode(bytes)?;
-                let packet_number = PacketNumber(VarInt::decode(bytes)?);
+                let packet_number = take_packet_number(bytes, packet_number_len)?;
                 Ok(LongHeaderExtension::Handshake {
                     length,
                     packet_number,
                 })
             }
             3 => {
+                if bytes.len() < 16 || bytes.len() - 16 > limits.max_retry_token_len {
+                    return Err(ProtocolError::FrameEncodingError.into());
+                }
                 let retry_token = bytes.drain(..bytes.len() - 16).collect::<Vec<u8>>();
-                let retry_integrity_tag = bytes
-                    .drain(..)
-                    .collect::<Vec<u8>>()
+                let retry_integrity_tag = std::mem::take(bytes)
                     .try_into()
                     .expect("retry integrity tag bytes");
                 Ok(LongHeaderExtension::Retry {
@@ -119,6 +312,10 @@ impl LongHeaderExtension {
                 })
             }
             4 => {
+                require(
+                    bytes.len().is_multiple_of(4),
+                    "LongHeaderExtension::decode: version list isn't whole versions",
+                )?;
                 let supported_versions: Vec<u32> = bytes
                     .chunks(4)
                     .map(|v| u32::from_le_bytes(v.try_into().expect("version bytes")))
@@ -130,8 +327,18 @@ impl LongHeaderExtension {
         }
     }
 
-    pub fn encode(&self) -> QuicheResult<Vec<u8>> {
+    // the packet number goes out in `packet_number_len` bytes, and has to fit in them.  it's
+    // whatever the sender truncated it to, not the full number
+    pub fn encode(&self, packet_number_len: usize) -> QuicheResult<Vec<u8>> {
         let mut bytes = Vec::new();
+        let put_packet_number = |bytes: &mut Vec<u8>, packet_number: &PacketNumber| {
+            require(
+                packet_number.0 .0 >> (8 * packet_number_len) == 0,
+                "LongHeaderExtension::encode: packet number doesn't fit its length bits",
+            )?;
+            bytes.extend(PnLen::from_len(packet_number_len)?.truncate(packet_number.0 .0));
+            Ok::<_, QuicheError>(())
+        };
         match self {
             LongHeaderExtension::Initial {
                 token_length,
@@ -142,7 +349,7 @@ impl LongHeaderExtension {
                 bytes.extend(token_length.encode());
                 bytes.extend(token.iter());
                 bytes.extend(length.encode());
-                bytes.extend(packet_number.0.encode());
+                put_packet_number(&mut bytes, packet_number)?;
             }
             LongHeaderExtension::ZeroRTT {
                 length,
@@ -153,7 +360,7 @@ impl LongHeaderExtension {
                 packet_number,
             } => {
                 bytes.extend(length.encode());
-                bytes.extend(packet_number.0.encode())
+                put_packet_number(&mut bytes, packet_number)?;
             }
             LongHeaderExtension::Retry {
                 retry_token,
@@ -193,7 +400,21 @@ pub struct LongHeader {
     extension: LongHeaderExtension,
 }
 
+// the v1 number of a long header's packet type, read off its first byte as `version` lays it out.
+// the two type bits go out lowest first (see `LongHeader::decode`)
+fn long_packet_type(first: u8, version: u32) -> u8 {
+    let type_bits = (first & 0b00_110000) >> 4;
+    Version::codec(version).decode_packet_type((type_bits & 1) << 1 | type_bits >> 1)
+}
+
 impl LongHeader {
+    // what goes in the type bits, `long_packet_type` as this header's version numbers it
+    fn wire_packet_type(&self) -> LongPacketType {
+        LongPacketType::from_num(
+            Version::codec(self.version_id).encode_packet_type(self.long_packet_type.to_inner()),
+        )
+    }
+
     // testing only. this is definitely bad practice.
     #[allow(dead_code)]
     pub(crate) fn ty(&self) -> u8 {
@@ -206,12 +427,75 @@ impl LongHeader {
         match &self.extension {
             LongHeaderExtension::Initial { length, .. }
             | LongHeaderExtension::ZeroRTT { length, .. }
-            | LongHeaderExtension::Handshake { length, .. } => length.usize(),
+            | LongHeaderExtension::Handshake { length, .. } => length.usize().unwrap(),
             LongHeaderExtension::Retry { .. } => 0,
             LongHeaderExtension::VersionNegotiation { .. } => 0,
         }
     }
 
+    pub fn version(&self) -> u32 {
+        self.version_id
+    }
+
+    pub fn dst_cid(&self) -> &ConnectionId {
+        &self.dst_cid
+    }
+
+    pub fn src_cid(&self) -> &ConnectionId {
+        &self.src_cid
+    }
+
+    pub fn extension(&self) -> &LongHeaderExtension {
+        &self.extension
+    }
+
+    // only mean anything for Initial, 0-RTT and Handshake, see `LongHeader::initial`
+    pub fn reserved_bits(&self) -> u8 {
+        self.type_specific_bits.to_inner() & 0b11
+    }
+
+    pub fn length(&self) -> Option<usize> {
+        match &self.extension {
+            LongHeaderExtension::Initial { length, .. }
+            | LongHeaderExtension::ZeroRTT { length, .. }
+            // one too big for a usize can't fit in the datagram either, which decoding checks
+            | LongHeaderExtension::Handshake { length, .. } => {
+                Some(length.usize().unwrap_or(usize::MAX))
+            }
+            LongHeaderExtension::Retry { .. } | LongHeaderExtension::VersionNegotiation { .. } => {
+                None
+            }
+        }
+    }
+
+    // does nothing for retry / version negotiation headers, which don't have one
+    pub fn set_length(&mut self, new_length: VarInt) {
+        match &mut self.extension {
+            LongHeaderExtension::Initial { length, .. }
+            | LongHeaderExtension::ZeroRTT { length, .. }
+            | LongHeaderExtension::Handshake { length, .. } => *length = new_length,
+            LongHeaderExtension::Retry { .. } | LongHeaderExtension::VersionNegotiation { .. } => {}
+        }
+    }
+
+    // from the length bits, None for retry / version negotiation headers
+    pub fn packet_number_len(&self) -> Option<usize> {
+        self.packet_number()
+            .map(|_| PnLen::from_long_header_bits(&self.type_specific_bits).len())
+    }
+
+    // the truncated packet number, as on the wire
+    pub fn packet_number(&self) -> Option<u64> {
+        match &self.extension {
+            LongHeaderExtension::Initial { packet_number, .. }
+            | LongHeaderExtension::ZeroRTT { packet_number, .. }
+            | LongHeaderExtension::Handshake { packet_number, .. } => Some(packet_number.0 .0),
+            LongHeaderExtension::Retry { .. } | LongHeaderExtension::VersionNegotiation { .. } => {
+                None
+            }
+        }
+    }
+
     pub fn len(&self) -> QuicheResult<usize> {
         let len = 1 + 4 + 1 + self.dst_cid.cid_len + 1 + self.src_cid.cid_len;
         // TODO: this is horrible why is this check here
@@ -287,6 +571,10 @@ impl LongHeader {
     }
 
     pub fn decode(bytes: &mut Vec<u8>) -> QuicheResult<Header> {
+        Self::decode_with_limits(bytes, &DecodeLimits::default())
+    }
+
+    pub fn decode_with_limits(bytes: &mut Vec<u8>, limits: &DecodeLimits) -> QuicheResult<Header> {
         let first_byte = bytes.remove(0);
         let bitvec = decompose_bits(first_byte, &[4, 2, 1, 1]);
 
@@ -299,23 +587,29 @@ impl LongHeader {
         let mut long_packet_bits = bitvec[1].clone();
         // TODO: this feels horrible and wrong
         long_packet_bits.reverse();
-        let long_packet_type = LongPacketType::from_bits(long_packet_bits);
+        let type_field = LongPacketType::from_bits(long_packet_bits);
 
-        let mut type_specific_four_bits = bitvec[0].clone();
-        // TODO: this feels horrible and wrong
-        type_specific_four_bits.reverse();
-        let type_specific_bits = FourBits::from_bits(type_specific_four_bits);
+        let type_specific_bits = type_specific_bits(first_byte);
 
+        require(bytes.len() >= 4, "LongHeader::decode: header is truncated")?;
         let version_id_bytes = bytes.drain(..4).collect::<Vec<u8>>();
         let version_id = u32::from_le_bytes(version_id_bytes.try_into().expect("version_id bytes"));
+        require(
+            !Version::is_reserved(version_id),
+            "LongHeader::decode: reserved version",
+        )?;
+        // kept as its v1 number, whatever the version puts on the wire
+        let long_packet_type = LongPacketType::from_num(
+            Version::codec(version_id).decode_packet_type(type_field.to_inner()),
+        );
 
-        let dst_cid_len = bytes.remove(0);
+        let dst_cid_len = take_cid_len(bytes)?;
 
         let dst_cid_data = bytes.drain(..dst_cid_len as usize).collect::<Vec<u8>>();
 
         let dst_cid = ConnectionId::new(dst_cid_len, dst_cid_data);
 
-        let src_cid_len = bytes.remove(0);
+        let src_cid_len = take_cid_len(bytes)?;
 
         let src_cid_data = bytes.drain(..src_cid_len as usize).collect::<Vec<u8>>();
 
@@ -333,7 +627,9 @@ impl LongHeader {
             _ => unreachable!(),
         };
 
-        let extension = LongHeaderExtension::decode(bytes, extension_ty)?;
+        let packet_number_len = PnLen::from_long_header_bits(&type_specific_bits).len();
+        let extension =
+            LongHeaderExtension::decode(bytes, extension_ty, packet_number_len, limits)?;
 
         // TODO: this feels hacky and wrong
         let header_enum = match long_packet_type.to_inner() {
@@ -370,7 +666,7 @@ impl LongHeader {
         let bitvec = [
             self.header_form.bits(),        // 1
             self.fixed_bit.bits(),          // 1
-            self.long_packet_type.bits(),   // 2
+            self.wire_packet_type().bits(), // 2
             self.type_specific_bits.bits(), // 4
         ]
         .concat();
@@ -386,58 +682,110 @@ impl LongHeader {
         bytes.push(self.src_cid.cid_len);
         bytes.extend(self.src_cid.cid.iter());
 
-        bytes.extend(self.extension.encode()?);
+        let packet_number_len = PnLen::from_long_header_bits(&self.type_specific_bits).len();
+        bytes.extend(self.extension.encode(packet_number_len)?);
 
         Ok(bytes)
     }
 
-    pub fn extension_length(bytes: &mut Vec<u8>) -> usize {
-        let packet_type = (bytes[0] & 0b00_110000) >> 4;
-        let fixed_bit = (bytes[0] & 0b01_000000) >> 6;
-        let dst_cid_len = bytes[5] as usize;
-        let src_cid_len = bytes[5 + dst_cid_len + 1] as usize;
+    // how long the header extension (token, Length and packet number) of the long header packet
+    // at the start of `bytes` is, read in place.  the token length is the peer's word, so it's
+    // checked against the packet before anything is read past it
+    pub fn extension_length(bytes: &[u8]) -> QuicheResult<usize> {
+        let truncated =
+            || QuicheError("LongHeader::extension_length: header is truncated".to_string());
+        let first = *bytes.first().ok_or_else(truncated)?;
+        let version =
+            u32::from_le_bytes(bytes.get(1..5).ok_or_else(truncated)?.try_into().unwrap());
+        let packet_type = long_packet_type(first, version);
+        let fixed_bit = (first & 0b01_000000) >> 6;
+        let dst_cid_len = *bytes.get(5).ok_or_else(truncated)? as usize;
+        let src_cid_len = *bytes.get(6 + dst_cid_len).ok_or_else(truncated)? as usize;
         let base_header_len = 7 + dst_cid_len + src_cid_len;
-
-        let mut ext_bytes = bytes[base_header_len..].to_vec();
-        match packet_type {
-            0x00 => {
-                match fixed_bit {
-                    // version negotiation
-                    0 => {
-                        // don't contain frames, the rest of the packet is the header extension
-                        bytes.len() - base_header_len
-                    }
-                    // initial
-                    1 => {
-                        let token_length = VarInt::decode(&mut ext_bytes).unwrap();
-                        ext_bytes.drain(..token_length.usize());
-                        let length = VarInt::decode(&mut ext_bytes).unwrap();
-                        let packet_number = VarInt::decode(&mut ext_bytes).unwrap();
-                        return token_length.size()
-                            + length.size()
-                            + packet_number.size()
-                            + token_length.usize();
-                    }
-                    _ => unreachable!(),
-                }
+        require(
+            base_header_len <= bytes.len(),
+            "LongHeader::extension_length: header is truncated",
+        )?;
+        let varint_len = |at| {
+            peek_varint(bytes, at)
+                .map(|(_, len)| len)
+                .ok_or_else(truncated)
+        };
+        let packet_number_len = PnLen::from_long_header_bits(&type_specific_bits(first)).len();
+
+        let extension_length = match (packet_type, fixed_bit) {
+            // version negotiation and retry don't contain frames, the rest of the packet is the
+            // header extension
+            (0, 0) | (3, _) => return Ok(bytes.len() - base_header_len),
+            // initial
+            (0, _) => {
+                let (token_length, token_length_len) =
+                    peek_varint(bytes, base_header_len).ok_or_else(truncated)?;
+                let token_end = (base_header_len + token_length_len) as u64 + token_length;
+                require(
+                    token_end <= bytes.len() as u64,
+                    "LongHeader::extension_length: token runs past the end of the packet",
+                )?;
+                let length_len = varint_len(token_end as usize)?;
+                token_length_len + token_length as usize + length_len + packet_number_len
             }
             // zero rtt / handshake
-            0x01 | 0x02 => {
-                // invariant here is that packet_number.size() + (bytes.len() - base_header_len + length.size() + packet_number.size()) == length
-                let length = VarInt::decode(&mut ext_bytes).unwrap();
-                let packet_number = VarInt::decode(&mut ext_bytes).unwrap();
-                return length.size() + packet_number.size();
-            }
-            // retry
-            0x03 => {
-                // don't contain frames, the rest of the packet is the header extension
-                bytes.len() - base_header_len
-            }
-            _ => unreachable!(),
-        }
+            _ => varint_len(base_header_len)? + packet_number_len,
+        };
+        // the packet number's length comes from the first byte, nothing's checked it's there
+        require(
+            base_header_len + extension_length <= bytes.len(),
+            "LongHeader::extension_length: header is truncated",
+        )?;
+        Ok(extension_length)
     }
 }
 
+// the low four bits of a long header's first byte, which go out lowest first like the type bits
+fn type_specific_bits(first: u8) -> FourBits {
+    let mut bits = decompose_bits(first, &[4])[0].clone();
+    // TODO: this feels horrible and wrong
+    bits.reverse();
+    FourBits::from_bits(bits)
+}
+
+// a packet number of `len` bytes, big-endian
+fn take_packet_number(bytes: &mut Vec<u8>, len: usize) -> QuicheResult<PacketNumber> {
+    require(
+        bytes.len() >= len,
+        "LongHeader::decode: packet number is truncated",
+    )?;
+    let packet_number = bytes
+        .drain(..len)
+        .fold(0, |number, byte| number << 8 | byte as u64);
+    Ok(PacketNumber(VarInt(packet_number)))
+}
+
+// a cid length byte, checked against what's left
+fn take_cid_len(bytes: &mut Vec<u8>) -> QuicheResult<u8> {
+    require(!bytes.is_empty(), "LongHeader::decode: header is truncated")?;
+    let len = bytes.remove(0);
+    require(
+        len as usize <= bytes.len(),
+        "LongHeader::decode: cid runs past the end of the header",
+    )?;
+    Ok(len)
+}
+
+// a varint read in place at `at`, and how many bytes it takes.  None if it's cut short
+fn peek_varint(bytes: &[u8], at: usize) -> Option<(u64, usize)> {
+    let first = *bytes.get(at)?;
+    // the top two bits say how many bytes it takes
+    let len = 1 << (first >> 6);
+    let value = bytes
+        .get(at + 1..at + len)?
+        .iter()
+        .fold((first & 0b00_111111) as u64, |value, &byte| {
+            value << 8 | byte as u64
+        });
+    Some((value, len))
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct ShortHeader {
     header_form: HeaderForm,
@@ -456,7 +804,8 @@ pub struct ShortHeader {
     key_phase: SingleBit,
     // length of the packet number field, one less than the length of the packet number field in bytes
     // protected using header protection
-    number_len: TwoBits,
+    // written with its bits reversed, so 0b01 (2 bytes) is 0b10 on the wire.  see `PnLen`
+    number_len: PnLen,
     // a connection id that is chosen by the intended recipient of the packet.
     dst_cid: ConnectionId,
     // 1-4 bytes long.
@@ -465,6 +814,34 @@ pub struct ShortHeader {
 }
 
 impl ShortHeader {
+    pub fn dst_cid(&self) -> &ConnectionId {
+        &self.dst_cid
+    }
+
+    pub fn spin_bit(&self) -> bool {
+        self.spin_bit.to_inner() == 1
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.key_phase.to_inner() == 1
+    }
+
+    pub fn reserved_bits(&self) -> u8 {
+        self.reserved_bits.to_inner()
+    }
+
+    // how many bytes the (truncated) packet number takes up
+    pub fn packet_number_len(&self) -> usize {
+        self.number.len()
+    }
+
+    // the number bytes are big-endian
+    pub fn packet_number(&self) -> u64 {
+        self.number
+            .iter()
+            .fold(0, |number, byte| number << 8 | *byte as u64)
+    }
+
     pub fn len(&self) -> QuicheResult<usize> {
         let len = 1 + 1 + 1 + 2 + 1 + 2 + 1 + self.dst_cid.cid_len + 4;
         require(len <= 33, "ShortHeader length must not exceed 33 bytes")?;
@@ -475,7 +852,7 @@ impl ShortHeader {
         spin_bit: SingleBit,
         reserved_bits: TwoBits,
         key_phase: SingleBit,
-        number_len: TwoBits,
+        number_len: PnLen,
         dst_cid: ConnectionId,
         number: Vec<u8>,
     ) -> Self {
@@ -495,7 +872,7 @@ impl ShortHeader {
         spin_bit: SingleBit,
         reserved_bits: TwoBits,
         key_phase: SingleBit,
-        number_len: TwoBits,
+        number_len: PnLen,
         dst_cid: ConnectionId,
         number: Vec<u8>,
     ) -> Self {
@@ -532,32 +909,26 @@ impl ShortHeader {
         let key_phase_bits = bitvec[1].clone();
         let key_phase = SingleBit::from_bits(key_phase_bits);
 
-        let number_len_bits = bitvec[0].clone();
-        let number_len = TwoBits::from_bits(number_len_bits.clone());
+        let number_len = PnLen::from_wire(first_byte);
 
         let dst_cid_len = bytes.remove(0);
 
         let dst_cid_data = bytes.drain(..dst_cid_len as usize).collect::<Vec<u8>>();
 
-        // +1 because number len is one less than size of number in bytes
-        // this `invert` function is just terrible, i need to get rid of it eventually
-        let number = bytes
-            .drain(..(number_len.invert().to_inner() as usize + 1))
-            .collect::<Vec<u8>>();
+        let number = bytes.drain(..number_len.len()).collect::<Vec<u8>>();
 
         require(
             bytes.is_empty(),
             "ShortHeader::decode: Failed to read all bytes",
         )?;
 
-        number_len.invert();
         Ok(Header::Short(Self {
             header_form,
             fixed_bit,
             spin_bit,
             reserved_bits,
             key_phase,
-            number_len: number_len.invert(),
+            number_len,
             dst_cid: ConnectionId::new(dst_cid_len, dst_cid_data),
             number,
         }))
@@ -566,155 +937,36 @@ impl ShortHeader {
     // returns a Vec<u8> which MUST NOT exceed 33 bytes
     pub fn encode(&self) -> QuicheResult<Vec<u8>> {
         let mut bytes = Vec::with_capacity(self.len()?);
+        self.encode_to(&mut bytes);
+        Ok(bytes)
+    }
 
-        let bitvec = [
+    pub fn encode_to<B: EncodeBuf>(&self, buf: &mut B) {
+        let fields = [
             self.header_form.bits(),   // 1
             self.fixed_bit.bits(),     // 1
             self.spin_bit.bits(),      // 1
             self.reserved_bits.bits(), // 2
             self.key_phase.bits(),     // 1
             self.number_len.bits(),    // 2
-        ]
-        .concat();
-
-        let first_byte = compose_bits(&bitvec);
-        bytes.push(first_byte);
-
-        bytes.push(self.dst_cid.cid_len);
-        bytes.extend(self.dst_cid.cid.iter());
+        ];
+        let mut bits = [false; 8];
+        for (bit, field) in bits.iter_mut().zip(fields.into_iter().flatten()) {
+            *bit = *field;
+        }
+        buf.put_u8(compose_bits(&bits));
 
-        bytes.extend(self.number.iter());
+        buf.put_u8(self.dst_cid.cid_len);
+        buf.put_slice(&self.dst_cid.cid);
 
-        Ok(bytes)
+        buf.put_slice(&self.number);
     }
 }
 
 #[cfg(test)]
-pub(crate) mod test_header {
+mod test_header {
     use super::*;
-    use crate::rand::rand;
-
-    pub fn generate_random_long_header() -> Header {
-        let header_type = rand(4);
-        let header_enum_gen = vec![
-            Header::Initial,
-            Header::Retry,
-            Header::Long,
-            Header::VersionNegotiate,
-        ];
-        let header_enum = header_enum_gen[header_type as usize];
-
-        let header_form = HeaderForm::long();
-        let long_packet_type = match header_type {
-            0 => LongPacketType::initial(),
-            1 => LongPacketType::retry(),
-            2 => {
-                if rand(2) == 0 {
-                    LongPacketType::zero_rtt()
-                } else {
-                    LongPacketType::handshake()
-                }
-            }
-            3 => LongPacketType::zero(), // version negotiate
-            _ => unreachable!("header_type should be 0, 1, 2, 3"),
-        };
-
-        let fixed_bit = match header_type {
-            3 => SingleBit::zero(),
-            _ => SingleBit::one(),
-        };
-
-        let extension = match long_packet_type.to_inner() {
-            0 => match fixed_bit.to_inner() {
-                0 => LongHeaderExtension::VersionNegotiation {
-                    supported_versions: vec![
-                        rand(32).into(),
-                        rand(32).into(),
-                        rand(32).into(),
-                        rand(32).into(),
-                    ],
-                },
-                1 => {
-                    let token_length = VarInt::new_u32(rand(39) as u32 + 1);
-                    LongHeaderExtension::Initial {
-                        token_length,
-                        token: vec![rand(256); token_length.usize()],
-                        length: VarInt::new_u32(rand(39) as u32 + 1),
-                        packet_number: PacketNumber(VarInt::new_u32(rand(32) as u32)),
-                    }
-                }
-                _ => unreachable!("fixed_bit should be 0 or 1"),
-            },
-            1 => LongHeaderExtension::ZeroRTT {
-                length: VarInt::new_u32(rand(39) as u32 + 1),
-                packet_number: PacketNumber(VarInt::new_u32(rand(32) as u32)),
-            },
-            2 => LongHeaderExtension::Handshake {
-                length: VarInt::new_u32(rand(39) as u32 + 1),
-                packet_number: PacketNumber(VarInt::new_u32(rand(32) as u32)),
-            },
-            3 => LongHeaderExtension::Retry {
-                retry_token: vec![rand(256); rand(20) as usize],
-                retry_integrity_tag: vec![rand(256); 16].try_into().unwrap(),
-            },
-            _ => unreachable!("long_packet_type should be 0, 1, 2, or 3"),
-        };
-
-        let type_specific_bits = FourBits::from_num(rand(16));
-        let version_id = rand(32);
-        let dst_cid_len = rand(20);
-        let src_cid_len = rand(20);
-        let mut dst_cid_data = Vec::with_capacity(dst_cid_len as usize);
-        let mut src_cid_data = Vec::with_capacity(src_cid_len as usize);
-        for _ in 0..dst_cid_len {
-            dst_cid_data.push(rand(256));
-        }
-        for _ in 0..src_cid_len {
-            src_cid_data.push(rand(256));
-        }
-        let dst_cid = ConnectionId::new(dst_cid_len, dst_cid_data);
-        let src_cid = ConnectionId::new(src_cid_len, src_cid_data);
-
-        header_enum(LongHeader {
-            header_form,
-            fixed_bit,
-            long_packet_type,
-            type_specific_bits,
-            version_id: version_id as u32,
-            dst_cid,
-            src_cid,
-            extension,
-        })
-    }
-
-    pub fn generate_random_short_header() -> Header {
-        let header_form = HeaderForm::short();
-        let fixed_bit = SingleBit::from_num(rand(2));
-        let spin_bit = SingleBit::from_num(rand(2));
-        let reserved_bits = TwoBits::from_num(rand(4));
-        let key_phase = SingleBit::from_num(rand(2));
-        let number_len = TwoBits::from_num(rand(3));
-        let dst_cid_len = rand(19);
-        let mut dst_cid_data = Vec::with_capacity(dst_cid_len as usize);
-        for _ in 0..dst_cid_len {
-            dst_cid_data.push(rand(256));
-        }
-        let mut number = Vec::with_capacity(number_len.to_inner() as usize);
-        for _ in 0..number_len.to_inner() + 1 {
-            number.push(rand(256));
-        }
-
-        Header::Short(ShortHeader {
-            header_form,
-            fixed_bit,
-            spin_bit,
-            reserved_bits,
-            key_phase,
-            number_len,
-            dst_cid: ConnectionId::new(dst_cid_len, dst_cid_data),
-            number,
-        })
-    }
+    use crate::testing::{Iterations, PacketGenerator};
 
     #[test]
     fn test_long_encode_decode() {
@@ -736,11 +988,20 @@ pub(crate) mod test_header {
         let reconstructed_initial_header = Header::decode(&mut initial_header_bytes);
 
         assert_eq!(original_initial_header, reconstructed_initial_header);
+        let Header::Initial(initial) = &reconstructed_initial_header else {
+            panic!("expected an initial header");
+        };
+        assert_eq!(initial.version(), 1);
+        assert_eq!(initial.dst_cid(), &ConnectionId::new(8, vec![0; 8]));
+        assert_eq!(initial.src_cid(), &ConnectionId::new(8, vec![0; 8]));
+        assert!(matches!(
+            initial.extension(),
+            LongHeaderExtension::Initial { .. }
+        ));
 
-        let num_headers = 1_000;
-        for i in 0..num_headers {
-            println!("Testing random long header {}", i);
-            let original_header = generate_random_long_header();
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..Iterations::from_env().headers {
+            let original_header = generator.long_header();
             let mut header_bytes = original_header.encode().unwrap();
             let reconstructed_header = Header::decode(&mut header_bytes);
             assert_eq!(original_header, reconstructed_header);
@@ -753,7 +1014,7 @@ pub(crate) mod test_header {
             SingleBit::zero(),
             TwoBits::zero(),
             SingleBit::one(),
-            TwoBits::from_num(3),
+            PnLen::from_len(4).unwrap(),
             ConnectionId::new(8, vec![0; 8]),
             vec![0, 1, 0, 1],
         ));
@@ -766,13 +1027,254 @@ pub(crate) mod test_header {
 
         assert_eq!(original_one_rtt_header, reconstructed_one_rtt_header);
 
-        let num_headers = 1_000;
-        for i in 0..num_headers {
-            println!("Testing random short header {}", i);
-            let original_header = generate_random_short_header();
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..Iterations::from_env().headers {
+            let original_header = generator.short_header();
             let mut header_bytes = original_header.encode().unwrap();
             let reconstructed_header = Header::decode(&mut header_bytes);
             assert_eq!(original_header, reconstructed_header);
         }
     }
+
+    #[test]
+    fn test_number_len() {
+        for len in 1..=4 {
+            let number_len = PnLen::from_len(len).unwrap();
+            assert_eq!(number_len.len(), len);
+            let header = Header::Short(ShortHeader::one_rtt(
+                SingleBit::zero(),
+                TwoBits::zero(),
+                SingleBit::zero(),
+                number_len.clone(),
+                ConnectionId::new(4, vec![1; 4]),
+                vec![7; len],
+            ));
+            let mut bytes = header.encode().unwrap();
+            assert_eq!(
+                bytes[0] & 0b11,
+                TwoBits::from_num(len as u8 - 1).invert().to_inner()
+            );
+            assert_eq!(PnLen::from_wire(bytes[0]), number_len);
+            assert_eq!(bytes.len(), 1 + 1 + 4 + len);
+            let decoded = Header::decode(&mut bytes);
+            let Header::Short(short) = &decoded else {
+                panic!("expected a short header");
+            };
+            assert_eq!(short.packet_number_len(), len);
+            assert_eq!(decoded, header);
+        }
+        assert!(PnLen::from_len(0).is_err());
+        assert!(PnLen::from_len(5).is_err());
+    }
+
+    #[test]
+    fn test_long_header_packet_number_len() {
+        let handshake = |len: usize, packet_number: u64| {
+            Header::Long(LongHeader::new(
+                LongPacketType::handshake(),
+                PnLen::from_len(len).unwrap().long_header_bits(),
+                1,
+                ConnectionId::new(4, vec![1; 4]),
+                ConnectionId::new(4, vec![2; 4]),
+                LongHeaderExtension::Handshake {
+                    length: VarInt::new_u32(len as u32),
+                    packet_number: PacketNumber(VarInt(packet_number)),
+                },
+            ))
+        };
+        for len in 1..=4 {
+            let header = handshake(len, 0x0102_0304 >> (8 * (4 - len)));
+            let mut bytes = header.encode().unwrap();
+            // first byte, version, two cids, a one byte Length, then only `len` bytes of number
+            assert_eq!(bytes.len(), 1 + 4 + 5 + 5 + 1 + len);
+            assert_eq!(bytes[16..], [1, 2, 3, 4][..len]);
+            assert_eq!(LongHeader::extension_length(&bytes).unwrap(), 1 + len);
+            assert_eq!(header.packet_number_len(), Some(len));
+            assert_eq!(Header::decode(&mut bytes), header);
+        }
+        // the number is already truncated, one the length bits can't hold is a bug
+        assert!(handshake(1, 0x100).encode().is_err());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..1_000 {
+            let header = generator.long_header();
+            let bytes = header.encode().unwrap();
+            let info = Header::peek(&bytes, 0).unwrap();
+            let (expected, long) = match &header {
+                Header::Initial(long) => (PacketType::Initial, long),
+                Header::Retry(long) => (PacketType::Retry, long),
+                Header::VersionNegotiate(long) => (PacketType::VersionNegotiation, long),
+                Header::Long(long) if long.ty() == 1 => (PacketType::ZeroRtt, long),
+                Header::Long(long) => (PacketType::Handshake, long),
+                Header::Short(_) => unreachable!(),
+            };
+            assert_eq!(info.packet_type, expected);
+            assert_eq!(info.version, Some(long.version_id));
+            assert_eq!(info.dst_cid, long.dst_cid);
+            assert_eq!(info.src_cid.as_ref(), Some(&long.src_cid));
+            // and it can be read again, nothing was taken
+            assert_eq!(Header::peek(&bytes, 0).unwrap(), info);
+            assert!(Header::peek(&bytes[..5], 0).is_err());
+        }
+
+        let short = Header::Short(ShortHeader::one_rtt(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            ConnectionId::new(8, vec![5; 8]),
+            vec![0],
+        ))
+        .encode()
+        .unwrap();
+        let info = Header::peek(&short, 8).unwrap();
+        assert_eq!(info.packet_type, PacketType::Short);
+        assert_eq!((info.version, info.src_cid), (None, None));
+        assert_eq!(info.dst_cid, ConnectionId::new(8, vec![5; 8]));
+        assert!(Header::peek(&short, 4).is_err());
+        assert!(Header::peek(&[], 8).is_err());
+    }
+
+    #[test]
+    fn test_versioned_packet_types() {
+        let initial = |version| {
+            LongHeader::initial(
+                version,
+                ConnectionId::new(4, vec![1, 2, 3, 4]),
+                ConnectionId::new(2, vec![9, 9]),
+                FourBits::from_num(0),
+                VarInt::zero(),
+                Vec::new(),
+                VarInt::new_u32(1),
+                PacketNumber(VarInt::zero()),
+            )
+        };
+        let v1 = Header::Initial(initial(Version::V1.to_u32()))
+            .encode()
+            .unwrap();
+        let v2 = Header::Initial(initial(Version::V2.to_u32()))
+            .encode()
+            .unwrap();
+        // the same Initial, with other type bits on the wire
+        assert_ne!(v1[0], v2[0]);
+        for bytes in [&v1, &v2] {
+            assert_eq!(
+                Header::peek(bytes, 0).unwrap().packet_type,
+                PacketType::Initial
+            );
+            assert!(Header::peek_initial(bytes).is_some());
+            assert!(matches!(
+                Header::decode(&mut bytes.clone()),
+                Header::Initial(_)
+            ));
+        }
+
+        let mut greased = v1.clone();
+        greased[1..5].copy_from_slice(&0x1a2a_3a4a_u32.to_le_bytes());
+        assert!(Header::peek(&greased, 0).is_err());
+        assert!(LongHeader::decode(&mut greased).is_err());
+    }
+
+    #[test]
+    fn test_token_limits() {
+        let initial = |token: Vec<u8>| {
+            Header::Initial(LongHeader::initial(
+                1,
+                ConnectionId::new(4, vec![1, 2, 3, 4]),
+                ConnectionId::new(2, vec![9, 9]),
+                FourBits::from_num(0),
+                VarInt::new_u32(token.len() as u32),
+                token,
+                VarInt::new_u32(1),
+                PacketNumber(VarInt::zero()),
+            ))
+            .encode()
+            .unwrap()
+        };
+        let retry = |token: Vec<u8>| {
+            Header::Retry(LongHeader::new(
+                LongPacketType::retry(),
+                FourBits::zero(),
+                1,
+                ConnectionId::new(2, vec![9, 9]),
+                ConnectionId::new(4, vec![1, 2, 3, 4]),
+                LongHeaderExtension::Retry {
+                    retry_token: token,
+                    retry_integrity_tag: [0; 16],
+                },
+            ))
+            .encode()
+            .unwrap()
+        };
+        let limits = DecodeLimits {
+            max_new_token_len: 32,
+            max_retry_token_len: 64,
+            ..Default::default()
+        };
+
+        assert!(LongHeader::decode_with_limits(&mut initial(vec![7; 64]), &limits).is_ok());
+        assert!(LongHeader::decode_with_limits(&mut initial(vec![7; 65]), &limits).is_err());
+        assert!(LongHeader::decode_with_limits(&mut retry(vec![7; 64]), &limits).is_ok());
+        assert!(LongHeader::decode_with_limits(&mut retry(vec![7; 65]), &limits).is_err());
+        // the defaults leave room for anyone's tokens, but not for megabytes of them
+        assert!(LongHeader::decode(&mut initial(vec![7; 512])).is_ok());
+        assert!(LongHeader::decode(&mut initial(vec![7; 5_000])).is_err());
+    }
+
+    #[test]
+    fn test_peek_dst_cid() {
+        let long = Header::Initial(LongHeader::initial(
+            1,
+            ConnectionId::new(4, vec![1, 2, 3, 4]),
+            ConnectionId::new(2, vec![9, 9]),
+            FourBits::from_num(0),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::new_u32(1),
+            PacketNumber(VarInt::zero()),
+        ))
+        .encode()
+        .unwrap();
+        assert_eq!(Header::peek_dst_cid(&long), Some(&[1, 2, 3, 4][..]));
+
+        let short = Header::Short(ShortHeader::one_rtt(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            ConnectionId::new(3, vec![5, 6, 7]),
+            vec![0],
+        ))
+        .encode()
+        .unwrap();
+        assert_eq!(Header::peek_dst_cid(&short), Some(&[5, 6, 7][..]));
+
+        // cut off in the middle of the cid
+        assert_eq!(Header::peek_dst_cid(&short[..3]), None);
+        assert_eq!(Header::peek_dst_cid(&[]), None);
+
+        // a token long enough that its length takes two bytes
+        let initial = Header::Initial(LongHeader::initial(
+            1,
+            ConnectionId::new(4, vec![1, 2, 3, 4]),
+            ConnectionId::new(2, vec![9, 9]),
+            FourBits::from_num(0),
+            VarInt::new_u32(300),
+            vec![7; 300],
+            VarInt::new_u32(1),
+            PacketNumber(VarInt::zero()),
+        ))
+        .encode()
+        .unwrap();
+        let peeked = Header::peek_initial(&initial).unwrap();
+        assert_eq!(peeked.dst_cid, &[1, 2, 3, 4]);
+        assert_eq!(peeked.src_cid, &[9, 9]);
+        assert_eq!(peeked.token, &[7; 300]);
+        assert_eq!(Header::peek_initial(&long).unwrap().token, &[] as &[u8]);
+        assert_eq!(Header::peek_initial(&initial[..100]), None);
+        assert_eq!(Header::peek_initial(&short), None);
+    }
 }
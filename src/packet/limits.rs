@@ -0,0 +1,66 @@
+use super::frame::MAX_REASON_PHRASE_LEN;
+
+// what to do about input that breaks the rules but can still be made sense of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+    // an error, which is what an endpoint has to do with it
+    #[default]
+    Strict,
+    // decoded anyway, with a `DecodeWarning` on the packet.  for tools looking at captures of
+    // other implementations, where the point is to see what was sent
+    Lenient,
+}
+
+// a rule the input broke that `DecodeMode::Lenient` let through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeWarning {
+    // the header's reserved bits weren't zero (RFC 9000 section 17.2)
+    ReservedBits,
+    // a varint that has to be in its shortest encoding wasn't, only frame types do
+    NonMinimalVarInt,
+    // this many bytes were left over after a packet that had to fill the datagram
+    TrailingBytes(usize),
+}
+
+// how long the variable-length fields a peer sends may be.  each of them comes with a length
+// the peer picked, anything longer than these is a FRAME_ENCODING_ERROR at decode rather than
+// something we hold on to.  and how strictly the rest is held to the spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    // CONNECTION_CLOSE, only ever diagnostics
+    pub max_reason_phrase_len: usize,
+    // NEW_TOKEN, and the token a client brings back in an Initial
+    pub max_new_token_len: usize,
+    // Retry, and the token a client brings back in an Initial
+    pub max_retry_token_len: usize,
+    pub mode: DecodeMode,
+}
+
+// generous next to what anyone mints, ours are under 50 bytes
+pub const DEFAULT_MAX_TOKEN_LEN: usize = 512;
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_reason_phrase_len: MAX_REASON_PHRASE_LEN,
+            max_new_token_len: DEFAULT_MAX_TOKEN_LEN,
+            max_retry_token_len: DEFAULT_MAX_TOKEN_LEN,
+            mode: DecodeMode::Strict,
+        }
+    }
+}
+
+impl DecodeLimits {
+    // the default limits, decoded leniently
+    pub fn lenient() -> Self {
+        Self {
+            mode: DecodeMode::Lenient,
+            ..Self::default()
+        }
+    }
+
+    // an Initial's token can be from either a Retry or a NEW_TOKEN
+    pub fn max_initial_token_len(&self) -> usize {
+        self.max_new_token_len.max(self.max_retry_token_len)
+    }
+}
@@ -1,8 +1,14 @@
+pub mod datagram;
 pub mod error;
 pub mod frame;
 pub mod header;
+pub mod limits;
+pub mod one_rtt;
 pub mod packet;
+pub mod transport_parameters;
 
 pub mod types;
+pub mod version;
 
 pub use types::*;
+pub use version::*;
@@ -0,0 +1,523 @@
+use crate::{
+    crypto::{KeyGeneration, KeyPhaseError, KeyPhases},
+    result::{require, QuicheError, QuicheResult},
+    DecodeBuf, SliceWriter,
+};
+
+use super::{error::ProtocolError, frame::Frame, header::ShortHeader, limits::DecodeLimits, PnLen};
+
+// header protection samples 16 bytes, starting 4 past where the packet number starts
+// (RFC 9001 section 5.4.2)
+pub const SAMPLE_OFFSET: usize = 4;
+pub const SAMPLE_LEN: usize = 16;
+
+// removes a 1-RTT packet's protection where it lies in the receive buffer
+pub trait PacketOpener {
+    // the 5 byte header protection mask for a packet whose number starts at `pn_offset`.  only
+    // the low 5 bits of the first byte are masked in a short header
+    fn header_mask(&self, datagram: &[u8], pn_offset: usize) -> QuicheResult<[u8; 5]>;
+
+    // decrypts `payload` (ciphertext, then the tag) over itself, with `header` unprotected as the
+    // associated data.  returns how long the plaintext is
+    fn open_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+    ) -> QuicheResult<usize>;
+
+    // the keys after a key update (RFC 9001 section 6).  the header protection key stays the same
+    fn next_keys(&self) -> QuicheResult<Self>
+    where
+        Self: Sized;
+}
+
+// packets as the codec writes them today, with neither header protection nor an AEAD
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlaintextOpener;
+
+impl PacketOpener for PlaintextOpener {
+    fn header_mask(&self, _datagram: &[u8], _pn_offset: usize) -> QuicheResult<[u8; 5]> {
+        Ok([0; 5])
+    }
+
+    fn open_in_place(
+        &self,
+        _packet_number: u64,
+        _header: &[u8],
+        payload: &mut [u8],
+    ) -> QuicheResult<usize> {
+        Ok(payload.len())
+    }
+
+    fn next_keys(&self) -> QuicheResult<Self> {
+        Ok(Self)
+    }
+}
+
+// protects a 1-RTT packet where it's been written in the send buffer
+pub trait PacketSealer {
+    // how much the AEAD adds on the end of the payload
+    fn tag_len(&self) -> usize;
+
+    // encrypts the first `plaintext_len` bytes of `payload` over themselves and writes the tag
+    // after them, `payload` being exactly long enough for both
+    fn seal_in_place(
+        &self,
+        packet_number: u64,
+        header: &[u8],
+        payload: &mut [u8],
+        plaintext_len: usize,
+    ) -> QuicheResult<()>;
+
+    // the header protection mask, sampled from the sealed packet like `PacketOpener::header_mask`
+    fn header_mask(&self, packet: &[u8], pn_offset: usize) -> QuicheResult<[u8; 5]>;
+}
+
+// the send side of `PlaintextOpener`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlaintextSealer;
+
+impl PacketSealer for PlaintextSealer {
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    fn seal_in_place(
+        &self,
+        _packet_number: u64,
+        _header: &[u8],
+        _payload: &mut [u8],
+        _plaintext_len: usize,
+    ) -> QuicheResult<()> {
+        Ok(())
+    }
+
+    fn header_mask(&self, _packet: &[u8], _pn_offset: usize) -> QuicheResult<[u8; 5]> {
+        Ok([0; 5])
+    }
+}
+
+// writes a whole 1-RTT packet into `out`, header, frames and seal, without building it anywhere
+// else first.  returns how many bytes of `out` it took, the datagram to hand to the socket
+pub fn write_short_packet(
+    header: &ShortHeader,
+    frames: &[Frame],
+    sealer: &impl PacketSealer,
+    out: &mut [u8],
+) -> QuicheResult<usize> {
+    require(!frames.is_empty(), "write_short_packet: no frames")?;
+    let mut writer = SliceWriter::new(out);
+    header.encode_to(&mut writer);
+    let header_len = writer.len();
+    Frame::encode_all_to(frames, &mut writer);
+    let plaintext_len = writer.len() - header_len;
+    let packet_len = writer.len() + sealer.tag_len();
+    if writer.overflowed() || packet_len > out.len() {
+        return Err(QuicheError(format!(
+            "write_short_packet: packet doesn't fit in {} bytes",
+            out.len()
+        )));
+    }
+
+    let packet = &mut out[..packet_len];
+    let (head, payload) = packet.split_at_mut(header_len);
+    sealer.seal_in_place(header.packet_number(), head, payload, plaintext_len)?;
+
+    let pn_offset = 2 + header.dst_cid().cid_len as usize;
+    let mask = sealer.header_mask(packet, pn_offset)?;
+    packet[0] ^= mask[0] & 0b00_011111;
+    for (byte, mask) in packet[pn_offset..header_len].iter_mut().zip(&mask[1..]) {
+        *byte ^= mask;
+    }
+    Ok(packet_len)
+}
+
+// a 1-RTT packet read straight out of the datagram it arrived in, the fast path for what nearly
+// every packet after the handshake is.  nothing is copied out of the buffer until the frames are
+// decoded, and then only the data frames carry.  `Packet::decode` is still what reads everything
+// else, and what a short header it turns down gets a proper error from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShortPacket<'a> {
+    // with its protection removed
+    first: u8,
+    pub dst_cid: &'a [u8],
+    // truncated, as sent
+    pub packet_number: u64,
+    pub packet_number_len: usize,
+    // the plaintext
+    pub payload: &'a [u8],
+}
+
+impl<'a> ShortPacket<'a> {
+    // unprotects the packet in `datagram` in place and reads its header.  the whole datagram is
+    // taken as the one packet, a short header packet can't have anything coalesced after it
+    pub fn open(datagram: &'a mut [u8], opener: &impl PacketOpener) -> QuicheResult<Self> {
+        let (first, packet_number, header_len) = Self::unprotect_header(datagram, opener)?;
+        let (header, payload) = datagram.split_at_mut(header_len);
+        let plaintext_len = opener.open_in_place(packet_number, header, payload)?;
+        Self::opened(first, packet_number, header, payload, plaintext_len)
+    }
+
+    // like `open`, with whichever generation of keys the key phase bit points at.  the packet
+    // number is expanded against `largest_received` to tell a reordered packet from before the
+    // last key update from the peer starting another one
+    pub fn open_with_key_phases<O: PacketOpener>(
+        datagram: &'a mut [u8],
+        keys: &mut KeyPhases<O>,
+        largest_received: Option<u64>,
+    ) -> Result<(Self, KeyGeneration), KeyPhaseError> {
+        let (first, packet_number, header_len) =
+            Self::unprotect_header(datagram, keys.header_keys()).map_err(KeyPhaseError::header)?;
+        let full_packet_number = PnLen::from_wire(first).expand(packet_number, largest_received);
+        let (header, payload) = datagram.split_at_mut(header_len);
+        let key_phase = first & 0b00_000100 != 0;
+        let (plaintext_len, generation) =
+            keys.open(key_phase, full_packet_number, header, payload)?;
+        let packet = Self::opened(first, packet_number, header, payload, plaintext_len)
+            .map_err(KeyPhaseError::header)?;
+        Ok((packet, generation))
+    }
+
+    // takes the header protection off.  returns the first byte, the truncated packet number and
+    // where the payload starts
+    fn unprotect_header(
+        datagram: &mut [u8],
+        opener: &impl PacketOpener,
+    ) -> QuicheResult<(u8, u64, usize)> {
+        require(
+            datagram.len() >= 2,
+            "ShortPacket::open: header is truncated",
+        )?;
+        require(
+            datagram[0] & 0b11_000000 == 0b01_000000,
+            "ShortPacket::open: not a short header",
+        )?;
+        let dst_cid_len = datagram[1] as usize;
+        let pn_offset = 2 + dst_cid_len;
+        require(
+            pn_offset < datagram.len(),
+            "ShortPacket::open: header is truncated",
+        )?;
+
+        let mask = opener.header_mask(datagram, pn_offset)?;
+        datagram[0] ^= mask[0] & 0b00_011111;
+        let first = datagram[0];
+        let packet_number_len = PnLen::from_wire(first).len();
+        let header_len = pn_offset + packet_number_len;
+        require(
+            header_len < datagram.len(),
+            "ShortPacket::open: packet has no payload",
+        )?;
+        let mut packet_number = 0;
+        for (byte, mask) in datagram[pn_offset..header_len].iter_mut().zip(&mask[1..]) {
+            *byte ^= mask;
+            packet_number = packet_number << 8 | *byte as u64;
+        }
+        Ok((first, packet_number, header_len))
+    }
+
+    fn opened(
+        first: u8,
+        packet_number: u64,
+        header: &'a [u8],
+        payload: &'a [u8],
+        plaintext_len: usize,
+    ) -> QuicheResult<Self> {
+        require(
+            plaintext_len > 0 && plaintext_len <= payload.len(),
+            "ShortPacket::open: empty payload",
+        )?;
+        let packet_number_len = PnLen::from_wire(first).len();
+        Ok(Self {
+            first,
+            dst_cid: &header[2..header.len() - packet_number_len],
+            packet_number,
+            packet_number_len,
+            payload: &payload[..plaintext_len],
+        })
+    }
+
+    pub fn spin_bit(&self) -> bool {
+        self.first & 0b00_100000 != 0
+    }
+
+    pub fn key_phase(&self) -> bool {
+        self.first & 0b00_000100 != 0
+    }
+
+    // the reserved bits have to be zero once protection is off (RFC 9000 section 17.3.1)
+    pub fn check_reserved_bits(&self) -> QuicheResult<()> {
+        match self.first & 0b00_011000 {
+            0 => Ok(()),
+            _ => Err(ProtocolError::ProtocolViolation.into()),
+        }
+    }
+
+    // the frames in the payload, decoded one at a time as they're asked for
+    pub fn frames(&self) -> Frames<'a> {
+        self.frames_with_limits(DecodeLimits::default())
+    }
+
+    pub fn frames_with_limits(&self, limits: DecodeLimits) -> Frames<'a> {
+        Frames {
+            rest: self.payload,
+            limits,
+        }
+    }
+}
+
+// the frames in a payload, read in place.  stops at the first one that doesn't decode
+#[derive(Debug, Clone)]
+pub struct Frames<'a> {
+    rest: &'a [u8],
+    limits: DecodeLimits,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = QuicheResult<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if DecodeBuf::is_empty(&self.rest) {
+            return None;
+        }
+        let frame = Frame::decode_with_limits(&mut self.rest, &self.limits);
+        if frame.is_err() {
+            self.rest = &[];
+        }
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bits::BitsExt,
+        packet::{
+            error::ProtocolError, header::Header, packet::Packet, types::ConnectionId, PnLen,
+            SingleBit, TwoBits,
+        },
+        result::QuicheError,
+        VarInt,
+    };
+
+    fn short_packet(number: Vec<u8>, frames: Vec<Frame>) -> Packet {
+        Packet::short_header(
+            SingleBit::one(),
+            TwoBits::zero(),
+            SingleBit::one(),
+            PnLen::from_len(number.len()).unwrap(),
+            ConnectionId::new(8, vec![7; 8]),
+            number,
+            frames,
+        )
+    }
+
+    // xors the first payload byte with the packet number and drops a one byte "tag"
+    struct TestOpener;
+
+    impl PacketOpener for TestOpener {
+        fn header_mask(&self, datagram: &[u8], pn_offset: usize) -> QuicheResult<[u8; 5]> {
+            let sample = datagram
+                .get(pn_offset + SAMPLE_OFFSET..pn_offset + SAMPLE_OFFSET + SAMPLE_LEN)
+                .ok_or_else(|| QuicheError("too short to sample".to_string()))?;
+            Ok(sample[..5].try_into().unwrap())
+        }
+
+        fn open_in_place(
+            &self,
+            packet_number: u64,
+            _header: &[u8],
+            payload: &mut [u8],
+        ) -> QuicheResult<usize> {
+            payload[0] ^= packet_number as u8;
+            Ok(payload.len() - 1)
+        }
+
+        fn next_keys(&self) -> QuicheResult<Self> {
+            Ok(TestOpener)
+        }
+    }
+
+    #[test]
+    fn test_short_packet() {
+        let frames = vec![
+            Frame::Ping,
+            Frame::MaxData(VarInt::new_u32(1 << 20)),
+            Frame::Ping,
+        ];
+        let packet = short_packet(vec![0x12, 0x34], frames.clone());
+        let mut datagram = packet.encode().unwrap();
+        let decoded = Packet::decode(&mut datagram.clone()).unwrap();
+
+        let short = ShortPacket::open(&mut datagram, &PlaintextOpener).unwrap();
+        let Header::Short(header) = decoded.header else {
+            panic!("not a short header");
+        };
+        assert_eq!(short.dst_cid, [7; 8]);
+        assert_eq!(short.packet_number, header.packet_number());
+        assert_eq!(short.packet_number_len, 2);
+        assert!(short.spin_bit() && short.key_phase());
+        short.check_reserved_bits().unwrap();
+        assert_eq!(
+            short.frames().collect::<QuicheResult<Vec<_>>>().unwrap(),
+            decoded.payload
+        );
+
+        // protected: the mask comes off the header and the payload is opened where it is
+        let mut protected = packet.encode().unwrap();
+        protected.extend([0; 20]);
+        let pn_offset = 2 + 8;
+        let mask: [u8; 5] = protected[pn_offset + 4..pn_offset + 9].try_into().unwrap();
+        protected[0] ^= mask[0] & 0b00_011111;
+        protected[pn_offset] ^= mask[1];
+        protected[pn_offset + 1] ^= mask[2];
+        protected[pn_offset + 2] ^= 0x34;
+        let short = ShortPacket::open(&mut protected, &TestOpener).unwrap();
+        assert_eq!(short.packet_number, 0x1234);
+        assert_eq!(short.payload.len(), datagram.len() - pn_offset - 2 + 19);
+        assert_eq!(short.frames().next().unwrap().unwrap(), Frame::Ping);
+
+        // a long header, or nothing after the packet number
+        let mut long = vec![0xc0; 32];
+        assert!(ShortPacket::open(&mut long, &PlaintextOpener).is_err());
+        let mut bare = short_packet(vec![1], vec![Frame::Ping]).encode().unwrap();
+        bare.pop();
+        assert!(ShortPacket::open(&mut bare, &PlaintextOpener).is_err());
+
+        // the frames stop at the first bad one
+        let mut garbled = short_packet(vec![1], vec![Frame::Ping]).encode().unwrap();
+        garbled.extend([0x07, 0x44, 0x00]);
+        let short = ShortPacket::open(&mut garbled, &PlaintextOpener).unwrap();
+        let mut frames = short.frames();
+        assert_eq!(frames.next().unwrap().unwrap(), Frame::Ping);
+        assert!(frames.next().unwrap().is_err());
+        assert!(frames.next().is_none());
+
+        // a frame cut off partway is FRAME_ENCODING_ERROR, not a panic: a CONNECTION_CLOSE
+        // without its frame type, a NEW_CONNECTION_ID without its cid length, a bare STREAM type
+        for cut_off in [&[0x1c, 0x00][..], &[0x18, 0x00], &[0x0a], &[0x04, 0x40]] {
+            let mut datagram = short_packet(vec![1], vec![Frame::Ping]).encode().unwrap();
+            datagram.extend(cut_off);
+            let short = ShortPacket::open(&mut datagram, &PlaintextOpener).unwrap();
+            let mut frames = short.frames();
+            assert_eq!(frames.next().unwrap().unwrap(), Frame::Ping);
+            let err = frames.next().unwrap().unwrap_err();
+            assert_eq!(
+                err.0,
+                QuicheError::from(ProtocolError::FrameEncodingError).0
+            );
+            assert!(frames.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_open_with_key_phases() {
+        // the example in RFC 9000 appendix A.3
+        assert_eq!(
+            PnLen::from_len(2)
+                .unwrap()
+                .expand(0x9b32, Some(0xa82f_30ea)),
+            0xa82f_9b32
+        );
+        assert_eq!(PnLen::from_len(1).unwrap().expand(5, None), 5);
+        assert_eq!(PnLen::from_len(1).unwrap().expand(0xff, Some(0x100)), 0xff);
+        assert_eq!(PnLen::from_len(1).unwrap().expand(0x01, Some(0x1ff)), 0x201);
+        // and appendix A.2's
+        let len = PnLen::for_packet(0xac5c02, Some(0xabe8b3));
+        assert_eq!(len.len(), 2);
+        assert_eq!(len.truncate(0xac5c02), [0x5c, 0x02]);
+        assert_eq!(PnLen::for_packet(0xace8fe, Some(0xabe8b3)).len(), 3);
+        assert_eq!(PnLen::for_packet(0, None).len(), 1);
+        assert_eq!(PnLen::for_packet(1 << 40, None).len(), 4);
+
+        // the packet's key phase bit is set, so opening it is the peer's first key update
+        let mut keys = KeyPhases::new(PlaintextOpener).unwrap();
+        let mut datagram = short_packet(vec![0x34], vec![Frame::Ping])
+            .encode()
+            .unwrap();
+        let (short, generation) =
+            ShortPacket::open_with_key_phases(&mut datagram, &mut keys, Some(0x1230)).unwrap();
+        assert_eq!(generation, KeyGeneration::Next);
+        assert!(keys.key_phase());
+        assert_eq!(short.dst_cid, [7; 8]);
+        assert_eq!(short.packet_number, 0x34);
+        assert_eq!(short.frames().next().unwrap().unwrap(), Frame::Ping);
+
+        let mut datagram = short_packet(vec![0x35], vec![Frame::Ping])
+            .encode()
+            .unwrap();
+        let (_, generation) =
+            ShortPacket::open_with_key_phases(&mut datagram, &mut keys, Some(0x1234)).unwrap();
+        assert_eq!(generation, KeyGeneration::Current);
+    }
+
+    // the inverse of `TestOpener`
+    struct TestSealer;
+
+    impl PacketSealer for TestSealer {
+        fn tag_len(&self) -> usize {
+            1
+        }
+
+        fn seal_in_place(
+            &self,
+            packet_number: u64,
+            _header: &[u8],
+            payload: &mut [u8],
+            _plaintext_len: usize,
+        ) -> QuicheResult<()> {
+            payload[0] ^= packet_number as u8;
+            Ok(())
+        }
+
+        fn header_mask(&self, packet: &[u8], pn_offset: usize) -> QuicheResult<[u8; 5]> {
+            TestOpener.header_mask(packet, pn_offset)
+        }
+    }
+
+    #[test]
+    fn test_write_short_packet() {
+        let frames = vec![
+            Frame::Ping,
+            Frame::Stream {
+                stream_id: VarInt::new_u32(4),
+                offset: VarInt::zero(),
+                length: VarInt::zero(),
+                fin: SingleBit::zero(),
+                stream_data: vec![0xAB; 100].into(),
+            },
+            Frame::Padding,
+        ];
+        let packet = short_packet(vec![0x12, 0x34], frames.clone());
+        let Header::Short(header) = &packet.header else {
+            panic!("not a short header");
+        };
+
+        // the same bytes as encoding it, a STREAM frame that isn't last gets its Length
+        let mut out = [0; 1_500];
+        let len = write_short_packet(header, &frames, &PlaintextSealer, &mut out).unwrap();
+        let mut encoded = header.encode().unwrap();
+        encoded.extend(Frame::encode_all(&frames));
+        assert_eq!(out[..len], encoded);
+        assert_eq!(out[len..], [0; 1_500][len..]);
+
+        // and protected, it opens back up
+        let len = write_short_packet(header, &frames, &TestSealer, &mut out).unwrap();
+        let short = ShortPacket::open(&mut out[..len], &TestOpener).unwrap();
+        assert_eq!(short.packet_number, 0x1234);
+        assert_eq!(
+            short.frames().collect::<QuicheResult<Vec<_>>>().unwrap(),
+            Packet::decode(&mut encoded.clone()).unwrap().payload
+        );
+
+        // too small, with or without room for the tag
+        let plaintext_len = encoded.len();
+        assert!(write_short_packet(header, &frames, &PlaintextSealer, &mut out[..50]).is_err());
+        assert!(
+            write_short_packet(header, &frames, &TestSealer, &mut out[..plaintext_len]).is_err()
+        );
+        assert!(write_short_packet(header, &[], &PlaintextSealer, &mut out).is_err());
+    }
+}
@@ -1,9 +1,16 @@
-use crate::{bits::BitsExt, frame_size, result::QuicheResult, VarInt};
+use crate::{
+    bits::BitsExt,
+    result::{require, QuicheError, QuicheResult},
+    DecodeBuf, VarInt,
+};
 
 use super::{
+    datagram::Datagram,
     frame::Frame,
     header::{Header, LongHeader, LongHeaderExtension, ShortHeader},
-    ConnectionId, FourBits, HeaderForm, LongPacketType, PacketNumber, SingleBit, TwoBits,
+    limits::{DecodeLimits, DecodeMode, DecodeWarning},
+    ConnectionId, EncryptionLevel, FourBits, HeaderForm, LongPacketType, PacketNumber, PacketSpace,
+    PnLen, SingleBit, TwoBits,
 };
 
 use crate::MINI_QUICHE_VERSION;
@@ -12,6 +19,8 @@ use crate::MINI_QUICHE_VERSION;
 pub struct Packet {
     pub header: Header,
     pub payload: Vec<Frame>,
+    // what a lenient decode let through, always empty otherwise
+    pub warnings: Vec<DecodeWarning>,
 }
 
 impl Packet {
@@ -20,20 +29,32 @@ impl Packet {
         !matches!(self.header, Header::Retry(_) | Header::VersionNegotiate(_))
     }
 
+    // which keys protect this packet, None for retry / version negotiation
+    pub fn encryption_level(&self) -> Option<EncryptionLevel> {
+        self.header.encryption_level()
+    }
+
+    // which packet number space this packet is numbered and acknowledged in
+    pub fn space(&self) -> Option<PacketSpace> {
+        self.header.space()
+    }
+
     pub fn create_server_hello(
         client_cid: ConnectionId,
         server_cid: ConnectionId,
         crypto: Frame,
         packet_number: PacketNumber,
     ) -> Self {
+        // nothing's been acknowledged yet, so the whole number goes out
+        let packet_number_len = PnLen::for_packet(packet_number.0 .0, None);
         Self::initial(
             MINI_QUICHE_VERSION,
             client_cid,
             server_cid,
-            FourBits::from_num(0b00),
+            packet_number_len.long_header_bits(),
             VarInt::zero(),
             Vec::default(),
-            VarInt::new_u32((frame_size!(crypto.clone()) + packet_number.size()) as u32),
+            VarInt::new_u32((crypto.size() + packet_number_len.len()) as u32),
             packet_number,
             vec![crypto],
         )
@@ -41,18 +62,20 @@ impl Packet {
 
     pub fn create_client_hello(
         server_cid: ConnectionId,
+        client_cid: ConnectionId,
         token: Option<Vec<u8>>,
         crypto: Frame,
         packet_number: PacketNumber,
     ) -> Self {
+        let packet_number_len = PnLen::for_packet(packet_number.0 .0, None);
         Self::initial(
             MINI_QUICHE_VERSION,
             server_cid,
-            ConnectionId::arbitrary(),
-            FourBits::from_num(0b00),
+            client_cid,
+            packet_number_len.long_header_bits(),
             VarInt::new_u32(token.clone().unwrap_or_default().len() as u32),
             token.unwrap_or_default(),
-            VarInt::new_u32((frame_size!(crypto.clone()) + packet_number.size()) as u32),
+            VarInt::new_u32((crypto.size() + packet_number_len.len()) as u32),
             packet_number,
             vec![crypto],
         )
@@ -79,7 +102,11 @@ impl Packet {
             length,
             packet_number,
         ));
-        Self { header, payload }
+        Self {
+            header,
+            payload,
+            warnings: Vec::new(),
+        }
     }
 
     pub fn long_header(
@@ -99,14 +126,18 @@ impl Packet {
             src_cid,
             extension,
         ));
-        Self { header, payload }
+        Self {
+            header,
+            payload,
+            warnings: Vec::new(),
+        }
     }
 
     pub fn short_header(
         spin_bit: SingleBit,
         reserved_bits: TwoBits,
         key_phase: SingleBit,
-        number_len: TwoBits,
+        number_len: PnLen,
         dst_cid: ConnectionId,
         number: Vec<u8>,
         payload: Vec<Frame>,
@@ -119,66 +150,293 @@ impl Packet {
             dst_cid,
             number,
         ));
-        Self { header, payload }
+        Self {
+            header,
+            payload,
+            warnings: Vec::new(),
+        }
     }
 
     pub fn encode(&self) -> QuicheResult<Vec<u8>> {
-        let mut encoded = self.header.encode()?;
-        encoded.extend(self.payload.iter().map(|frame| frame.encode()).flatten());
+        let mut encoded = Vec::new();
+        self.encode_into(&mut encoded)?;
         Ok(encoded)
     }
 
-    pub fn decode(bytes: &mut Vec<u8>) -> QuicheResult<Self> {
-        match bytes[0] & 0b10_000000 == HeaderForm::short().to_inner() {
-            true => return Packet::decode_short_header(bytes),
-            false => return Packet::decode_long_header(bytes),
+    // appends the encoded packet to `buf`, so the caller can reuse a pooled buffer
+    pub fn encode_into(&self, buf: &mut Vec<u8>) -> QuicheResult<()> {
+        require(
+            self.contains_frames() || self.payload.is_empty(),
+            "Packet::encode: retry and version negotiation packets can't carry frames",
+        )?;
+        buf.extend(self.header.encode()?);
+        self.payload
+            .iter()
+            .for_each(|frame| buf.extend(frame.encode()));
+        Ok(())
+    }
+
+    // sets the header's Length field to cover the packet number and the payload as it is now.
+    // call it after the payload changes, short headers (and retry / vn) have no Length to set
+    pub fn update_length(&mut self) {
+        let Some(packet_number_len) = self.header.packet_number_len() else {
+            return;
+        };
+        let payload_len = self.payload.iter().map(Frame::size).sum::<usize>();
+        if let Header::Initial(header) | Header::Long(header) = &mut self.header {
+            header.set_length(VarInt::new_u32((packet_number_len + payload_len) as u32));
         }
     }
 
-    fn decode_long_header(bytes: &mut Vec<u8>) -> QuicheResult<Self> {
-        let dst_cid_len = bytes[5] as usize;
-        let src_cid_len = bytes[5 + dst_cid_len + 1] as usize;
+    // decodes a single packet that has to take up all of `bytes`, see `decode_coalesced` for datagrams
+    // that might hold several.  decoding out of a `Bytes` datagram leaves frame payloads pointing into
+    // it, see `DecodeBuf`
+    pub fn decode<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Self> {
+        Packet::decode_with_limits(bytes, &DecodeLimits::default())
+    }
 
-        let header_len = 1 + 4 + 1 + dst_cid_len + 1 + src_cid_len;
-        let header_ext_len = LongHeader::extension_length(&mut bytes.clone());
+    // with the peer's tokens and reason phrases held to `limits` rather than the defaults
+    pub fn decode_with_limits<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+    ) -> QuicheResult<Self> {
+        let position = Position::new(bytes);
+        let mut packet = Packet::decode_one(bytes, limits, position)?;
+        if !bytes.is_empty() {
+            match limits.mode {
+                DecodeMode::Strict => {
+                    return Err(QuicheError(
+                        "Packet::decode: trailing bytes after the packet".to_string(),
+                    ))
+                }
+                DecodeMode::Lenient => packet
+                    .warnings
+                    .push(DecodeWarning::TrailingBytes(bytes.as_slice().len())),
+            }
+        }
+        Ok(packet)
+    }
 
-        let mut header_bytes = bytes.drain(..header_len + header_ext_len).collect();
+    // every packet coalesced into a datagram (RFC 9000 section 12.2), as `Datagram`s so a retry or
+    // version negotiation packet can't come back holding frames
+    pub fn decode_coalesced<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Vec<Datagram>> {
+        Packet::decode_coalesced_with_limits(bytes, &DecodeLimits::default())
+    }
 
-        // drains everything except payload
-        let decoded_header = LongHeader::decode(&mut header_bytes)?;
+    pub fn decode_coalesced_with_limits<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+    ) -> QuicheResult<Vec<Datagram>> {
+        require(
+            !bytes.is_empty(),
+            "Packet::decode_coalesced: empty datagram",
+        )?;
+        let mut position = Position::new(bytes);
+        let mut packets = Vec::new();
+        while !bytes.is_empty() {
+            packets.push(Packet::decode_one(bytes, limits, position)?.try_into()?);
+            position.packet += 1;
+        }
+        Ok(packets)
+    }
+
+    // a long header packet stops where its Length field says, a short header one runs to the end
+    fn decode_one<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+        position: Position,
+    ) -> QuicheResult<Self> {
+        require(!bytes.is_empty(), "Packet::decode: empty packet")?;
+        match bytes.as_slice()[0] & 0b10_000000 == HeaderForm::short().to_inner() {
+            true => Packet::decode_short_header(bytes, limits, position),
+            false => Packet::decode_long_header(bytes, limits, position),
+        }
+    }
+
+    fn decode_long_header<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+        position: Position,
+    ) -> QuicheResult<Self> {
+        let header_at = position.offset(bytes);
+        let decoded_header = Packet::decode_long_header_only(bytes, limits)
+            .map_err(|err| position.header_error(header_at, err))?;
+        let mut warnings = Vec::new();
+        Packet::check_reserved_bits(&decoded_header, limits, &mut warnings)
+            .map_err(|err| position.header_error(header_at, err))?;
+
+        // retry and version negotiation packets have no Length, and no frames
+        let Some(length) = decoded_header.length() else {
+            return Ok(Self {
+                header: decoded_header,
+                payload: Vec::new(),
+                warnings,
+            });
+        };
+        // the Length covers the packet number too, which has already been read with the header
+        let packet_number_len = decoded_header.packet_number_len().unwrap_or(0);
+        let mut remaining = length
+            .checked_sub(packet_number_len)
+            .ok_or_else(|| {
+                QuicheError("Packet::decode: Length is shorter than the packet number".to_string())
+            })
+            .and_then(|remaining| {
+                require(
+                    remaining <= bytes.as_slice().len(),
+                    "Packet::decode: Length runs past the end of the datagram",
+                )?;
+                Ok(remaining)
+            })
+            .map_err(|err| position.header_error(header_at, err))?;
 
         let mut frames = Vec::new();
-        while !bytes.is_empty() {
-            let frame = Frame::decode(bytes)?;
+        while remaining > 0 {
+            let frame_at = position.offset(bytes);
+            let frame_error = |err| position.frame_error(frames.len(), frame_at, err);
+            let (frame, len) =
+                Frame::decode_len(bytes, limits, &mut warnings).map_err(frame_error)?;
+            remaining = remaining.checked_sub(len).ok_or_else(|| {
+                frame_error(QuicheError(
+                    "Packet::decode: frame runs past the packet's Length".to_string(),
+                ))
+            })?;
             frames.push(frame);
         }
         Ok(Self {
             header: decoded_header,
             payload: frames,
+            warnings,
         })
     }
 
-    fn decode_short_header(bytes: &mut Vec<u8>) -> QuicheResult<Self> {
-        let number_len = TwoBits::from_num(bytes[0] & 0b00_000011);
-        let dst_cid_len = bytes[1] as usize;
+    // the header, up to the payload
+    fn decode_long_header_only<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+    ) -> QuicheResult<Header> {
+        // checks the cid lengths and the token length against the datagram first
+        let header_ext_len = LongHeader::extension_length(bytes.as_slice())?;
+        let dst_cid_len = bytes.as_slice()[5] as usize;
+        let src_cid_len = bytes.as_slice()[5 + dst_cid_len + 1] as usize;
 
-        let header_len = 1 + 1 + dst_cid_len + number_len.invert().to_inner() as usize + 1;
+        let header_len = 1 + 4 + 1 + dst_cid_len + 1 + src_cid_len;
 
-        let mut header_bytes = bytes.drain(..header_len).collect();
+        let mut header_bytes = bytes.take_vec(header_len + header_ext_len);
 
         // drains everything except payload
-        let decoded_header = ShortHeader::decode(&mut header_bytes)?;
+        LongHeader::decode_with_limits(&mut header_bytes, limits)
+    }
+
+    fn decode_short_header<B: DecodeBuf>(
+        bytes: &mut B,
+        limits: &DecodeLimits,
+        position: Position,
+    ) -> QuicheResult<Self> {
+        let header_at = position.offset(bytes);
+        let decoded_header = Packet::decode_short_header_only(bytes)
+            .map_err(|err| position.header_error(header_at, err))?;
+        let mut warnings = Vec::new();
+        Packet::check_reserved_bits(&decoded_header, limits, &mut warnings)
+            .map_err(|err| position.header_error(header_at, err))?;
 
         let mut frames = Vec::new();
         while !bytes.is_empty() {
-            let frame = Frame::decode(bytes)?;
+            let frame_at = position.offset(bytes);
+            let frame = Frame::decode_warned(bytes, limits, &mut warnings)
+                .map_err(|err| position.frame_error(frames.len(), frame_at, err))?;
             frames.push(frame);
         }
         Ok(Self {
             header: decoded_header,
             payload: frames,
+            warnings,
         })
     }
+
+    // the reserved bits have to be zero once header protection is off (RFC 9000 section 17.2 and
+    // 17.3.1).  retry and version negotiation don't have any
+    fn check_reserved_bits(
+        header: &Header,
+        limits: &DecodeLimits,
+        warnings: &mut Vec<DecodeWarning>,
+    ) -> QuicheResult<()> {
+        let reserved = match header {
+            Header::Initial(header) | Header::Long(header) => header.reserved_bits(),
+            Header::Short(header) => header.reserved_bits(),
+            Header::Retry(_) | Header::VersionNegotiate(_) => 0,
+        };
+        if reserved == 0 {
+            return Ok(());
+        }
+        match limits.mode {
+            DecodeMode::Strict => Err(QuicheError(
+                "Packet::decode: reserved bits are set".to_string(),
+            )),
+            DecodeMode::Lenient => {
+                warnings.push(DecodeWarning::ReservedBits);
+                Ok(())
+            }
+        }
+    }
+
+    fn decode_short_header_only<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Header> {
+        require(
+            bytes.as_slice().len() >= 2,
+            "Packet::decode: header is truncated",
+        )?;
+        let number_len = PnLen::from_wire(bytes.as_slice()[0]);
+        let dst_cid_len = bytes.as_slice()[1] as usize;
+
+        let header_len = 1 + 1 + dst_cid_len + number_len.len();
+        require(
+            header_len <= bytes.as_slice().len(),
+            "Packet::decode: header is truncated",
+        )?;
+
+        let mut header_bytes = bytes.take_vec(header_len);
+
+        // drains everything except payload
+        ShortHeader::decode(&mut header_bytes)
+    }
+}
+
+// where in a datagram decoding has got to, so an error can say where it went wrong without
+// anyone having to hex-diff the datagram by hand
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    datagram_len: usize,
+    // which of the datagram's coalesced packets
+    packet: usize,
+}
+
+impl Position {
+    fn new<B: DecodeBuf>(datagram: &B) -> Self {
+        Self {
+            datagram_len: datagram.as_slice().len(),
+            packet: 0,
+        }
+    }
+
+    // how far into the datagram `bytes` is
+    fn offset<B: DecodeBuf>(&self, bytes: &B) -> usize {
+        self.datagram_len - bytes.as_slice().len()
+    }
+
+    // e.g. "Packet::decode: header is truncated (packet 1, header at byte 57)"
+    fn header_error(&self, offset: usize, err: QuicheError) -> QuicheError {
+        QuicheError(format!(
+            "{} (packet {}, header at byte {})",
+            err.0, self.packet, offset
+        ))
+    }
+
+    // e.g. "Transport error: FrameEncodingError (packet 0, frame 2 at byte 31)"
+    fn frame_error(&self, frame: usize, offset: usize, err: QuicheError) -> QuicheError {
+        QuicheError(format!(
+            "{} (packet {}, frame {} at byte {})",
+            err.0, self.packet, frame, offset
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -186,27 +444,16 @@ mod test {
     use std::vec;
 
     use super::*;
-    use crate::frame_size;
-    use crate::macros::FrameType;
     // this might be bad practice, but who cares, it's for tests
     use crate::packet::frame::test_frame::generate_random_frame;
-    use crate::packet::header::test_header::{
-        generate_random_long_header, generate_random_short_header,
+    use crate::SmallBytes;
+    use crate::{
+        rand::Rng,
+        testing::{Iterations, PacketGenerator},
     };
-    use crate::rand::rand;
 
     // testing only. this is definitely bad practice.
     impl Header {
-        pub(crate) fn ty(&self) -> u8 {
-            match self {
-                Header::Initial(header)
-                | Header::Retry(header)
-                | Header::VersionNegotiate(header)
-                | Header::Long(header) => header.ty(),
-                _ => unreachable!(),
-            }
-        }
-
         pub(crate) fn rem_len(&self) -> usize {
             match self {
                 Header::Initial(header)
@@ -228,63 +475,17 @@ mod test {
         }
     }
 
-    // long header packets CANNOT contain:
-    // 1. STREAM
-    // 2. MAX_DATA
-    // 3. MAX_STREAM_DATA
-    // 4. MAX_STREAMS
-    // 5. DATA_BLOCKED
-    // 6. STREAM_DATA_BLOCKED
-    // 7. STREAMS_BLOCKED
-    // 8. NEW_CONNECTION_ID
-    // 9. RETIRE_CONNECTION_ID
-    // 10. PATH_CHALLENGE
-    // 11. PATH_RESPONSE
-    // 12. HANDSHAKE_DONE
-    const PROHIBITED_LONG_HEADER_FRAMES: [FrameType; 14] = [
-        FrameType::STREAM,
-        FrameType::MAX_DATA,
-        FrameType::MAX_STREAM_DATA,
-        FrameType::MAX_STREAMS_BIDI,
-        FrameType::MAX_STREAMS_UNI,
-        FrameType::DATA_BLOCKED,
-        FrameType::STREAM_DATA_BLOCKED,
-        FrameType::STREAMS_BLOCKED_BIDI,
-        FrameType::STREAMS_BLOCKED_UNI,
-        FrameType::NEW_CONNECTION_ID,
-        FrameType::RETIRE_CONNECTION_ID,
-        FrameType::PATH_CHALLENGE,
-        FrameType::PATH_RESPONSE,
-        FrameType::HANDSHAKE_DONE,
-    ];
-    // initial packets can ONLY contain:
-    // 1. CRYPTO
-    // 2. PADDING
-    // 3. CONNECTION_CLOSE_APPLICATION
-    // 4. ACK
-    // 5. ACK_ECN
-    const ALLOWED_INITIAL_FRAMES: [FrameType; 5] = [
-        FrameType::CRYPTO,
-        FrameType::PADDING,
-        FrameType::CONNECTION_CLOSE_APPLICATION,
-        FrameType::ACK,
-        FrameType::ACK_ECN,
-    ];
-    fn generate_random_long_header_payload(len: usize, header: Header) -> Vec<Frame> {
-        let ty = header.ty();
+    fn generate_random_long_header_payload(rng: &mut Rng, len: usize) -> Vec<Frame> {
         let mut curr_size: usize = 0;
         let mut frames = Vec::new();
         while curr_size < len {
-            let frame = generate_random_frame();
-            if PROHIBITED_LONG_HEADER_FRAMES.contains(&frame.ty()) {
+            let frame = generate_random_frame(rng);
+            // 0-rtt can carry more, but a STREAM frame in the middle would need its Length.  what
+            // initial allows is allowed in every long header packet
+            if !frame.kind().is_permitted(EncryptionLevel::Initial) {
                 continue;
             }
-            if ty == LongPacketType::initial().to_inner()
-                && !ALLOWED_INITIAL_FRAMES.contains(&frame.ty())
-            {
-                continue;
-            }
-            let frame_size = frame_size!(frame.clone());
+            let frame_size = frame.size();
             if curr_size + frame_size > len {
                 continue;
             }
@@ -300,16 +501,11 @@ mod test {
         frames
     }
 
-    // short header packets CANNOT contain:
-    // 1. CRYPTO
-    // 2. NEW_TOKEN
-    const PROHIBITED_SHORT_HEADER_FRAMES: [FrameType; 2] =
-        [FrameType::CRYPTO, FrameType::NEW_TOKEN];
-    fn generate_random_short_header_payload(num_packets: u8) -> Vec<Frame> {
+    fn generate_random_short_header_payload(rng: &mut Rng, num_packets: u8) -> Vec<Frame> {
         let mut frames = Vec::new();
         for _ in 0..num_packets {
-            let frame = generate_random_frame();
-            if PROHIBITED_SHORT_HEADER_FRAMES.contains(&frame.ty()) {
+            let frame = generate_random_frame(rng);
+            if !frame.kind().is_permitted(EncryptionLevel::OneRtt) {
                 continue;
             }
             if frame.must_be_last() {
@@ -326,15 +522,16 @@ mod test {
             1,
             ConnectionId::new(8, vec![0; 8]),
             ConnectionId::new(8, vec![0; 8]),
-            FourBits::from_num(3),
+            FourBits::from_num(12),
             VarInt::new_u32(8),
             vec![1, 0, 1, 0, 1, 0, 1, 0],
-            VarInt::new_u32(12),
+            // a 4 byte packet number, going by the length bits, and 13 bytes of CRYPTO
+            VarInt::new_u32(17),
             PacketNumber(VarInt::new_u32(8)),
             vec![Frame::Crypto {
                 offset: VarInt::new_u32(2),
                 crypto_length: VarInt::new_u32(10),
-                crypto_data: vec![1, 0, 1, 0, 1, 0, 1, 0, 1, 0],
+                crypto_data: SmallBytes::from_slice(&[1, 0, 1, 0, 1, 0, 1, 0, 1, 0]),
             }],
         );
 
@@ -344,14 +541,15 @@ mod test {
 
         assert_eq!(original_initial_packet, reconstructed_initial_packet);
 
-        let num_packets = 10_000;
-        for i in 0..num_packets {
-            println!("Testing random long packet {}", i);
-            let header = generate_random_long_header();
-            let packet = Packet {
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..Iterations::from_env().packets {
+            let header = generator.long_header();
+            let mut packet = Packet {
                 header: header.clone(),
-                payload: generate_random_long_header_payload(header.rem_len(), header),
+                payload: generate_random_long_header_payload(generator.rng(), header.rem_len()),
+                warnings: Vec::new(),
             };
+            packet.update_length();
             let mut packet_bytes = packet.encode().unwrap();
             let reconstructed_packet = Packet::decode(&mut packet_bytes).unwrap();
             assert_eq!(packet, reconstructed_packet);
@@ -364,7 +562,7 @@ mod test {
             SingleBit::zero(),
             TwoBits::zero(),
             SingleBit::one(),
-            TwoBits::from_num(3),
+            PnLen::from_len(4).unwrap(),
             ConnectionId::new(8, vec![0; 8]),
             vec![0, 1, 0, 1],
             vec![Frame::Ping, Frame::Padding, Frame::Padding, Frame::Padding],
@@ -376,17 +574,313 @@ mod test {
 
         assert_eq!(original_short_packet, reconstructed_short_packet);
 
-        let num_packets = 10_000;
-        for i in 0..num_packets {
-            println!("Testing random short packet {}", i);
-            let header = generate_random_short_header();
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..Iterations::from_env().packets {
+            let header = generator.short_header();
+            let num_frames = generator.rng().rand(14) + 1;
             let packet = Packet {
                 header,
-                payload: generate_random_short_header_payload(rand(14) + 1),
+                payload: generate_random_short_header_payload(generator.rng(), num_frames),
+                warnings: Vec::new(),
             };
             let mut packet_bytes = packet.encode().unwrap();
             let reconstructed_packet = Packet::decode(&mut packet_bytes).unwrap();
             assert_eq!(packet, reconstructed_packet);
         }
     }
+
+    #[test]
+    fn test_zero_copy_decode() {
+        let packet = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(4).unwrap(),
+            ConnectionId::new(8, vec![0; 8]),
+            vec![0, 0, 0, 1],
+            vec![
+                Frame::Ping,
+                Frame::Stream {
+                    stream_id: VarInt::new_u32(4),
+                    offset: VarInt::zero(),
+                    length: VarInt::new_u32(1_000),
+                    fin: SingleBit::zero(),
+                    stream_data: vec![0xAB; 1_000].into(),
+                },
+                Frame::Stream {
+                    stream_id: VarInt::new_u32(8),
+                    offset: VarInt::zero(),
+                    length: VarInt::zero(),
+                    fin: SingleBit::one(),
+                    stream_data: vec![0xCD; 300].into(),
+                },
+            ],
+        );
+        let datagram = bytes::Bytes::from(packet.encode().unwrap());
+        let decoded = Packet::decode(&mut datagram.clone()).unwrap();
+        assert_eq!(decoded, packet);
+
+        // both payloads (sized and open-ended) point into the datagram, nothing was copied
+        let range = datagram.as_ptr_range();
+        for frame in decoded.payload.iter() {
+            if let Frame::Stream { stream_data, .. } = frame {
+                assert!(stream_data.is_shared());
+                assert!(range.contains(&stream_data.as_ptr()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_length_delimited_decode() {
+        let cid = || ConnectionId::new(8, vec![0; 8]);
+        let mut handshake = Packet::long_header(
+            LongPacketType::handshake(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            cid(),
+            cid(),
+            LongHeaderExtension::Handshake {
+                length: VarInt::zero(),
+                packet_number: PacketNumber(VarInt::new_u32(3)),
+            },
+            vec![Frame::Ping, Frame::Padding, Frame::Padding],
+        );
+        handshake.update_length();
+        assert_eq!(handshake.header.length(), Some(4));
+        let one_rtt = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid(),
+            vec![4],
+            vec![Frame::Ping],
+        );
+
+        let mut datagram = handshake.encode().unwrap();
+        datagram.extend(one_rtt.encode().unwrap());
+        assert_eq!(
+            Packet::decode_coalesced(&mut datagram.clone()).unwrap(),
+            vec![
+                Datagram::try_from(handshake.clone()).unwrap(),
+                Datagram::try_from(one_rtt).unwrap()
+            ]
+        );
+        // a single packet has to fill the datagram
+        assert!(Packet::decode(&mut datagram).is_err());
+
+        // a Length that stops mid-frame, or runs off the end
+        let mut bytes = handshake.encode().unwrap();
+        bytes.push(Frame::Ping.encode()[0]);
+        let length_at = bytes.len() - 1 - 4 - 1;
+        bytes[length_at] = 3;
+        assert!(Packet::decode(&mut bytes.clone()).is_err());
+        bytes[length_at] = 6;
+        assert!(Packet::decode(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn test_decode_error_position() {
+        let cid = || ConnectionId::new(8, vec![0; 8]);
+        let mut handshake = Packet::long_header(
+            LongPacketType::handshake(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            cid(),
+            cid(),
+            LongHeaderExtension::Handshake {
+                length: VarInt::zero(),
+                packet_number: PacketNumber(VarInt::new_u32(3)),
+            },
+            vec![Frame::Ping],
+        );
+        handshake.update_length();
+        let one_rtt = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid(),
+            vec![4],
+            vec![Frame::Ping],
+        );
+        let mut datagram = handshake.encode().unwrap();
+        let one_rtt_at = datagram.len();
+        datagram.extend(one_rtt.encode().unwrap());
+
+        // 0x1f isn't a frame type, and it's the second frame of the second packet
+        let mut bad_frame = datagram.clone();
+        bad_frame.push(0x1f);
+        let err = Packet::decode_coalesced(&mut bad_frame).unwrap_err();
+        assert!(
+            err.0
+                .ends_with(&format!("(packet 1, frame 1 at byte {})", one_rtt_at + 12)),
+            "{}",
+            err
+        );
+
+        // and a short header cut off in its cid
+        let mut truncated = datagram[..one_rtt_at + 4].to_vec();
+        let err = Packet::decode_coalesced(&mut truncated).unwrap_err();
+        assert!(
+            err.0
+                .ends_with(&format!("(packet 1, header at byte {})", one_rtt_at)),
+            "{}",
+            err
+        );
+
+        // a lone packet is packet 0
+        let mut bad_frame = handshake.encode().unwrap();
+        let last = bad_frame.len() - 1;
+        bad_frame[last] = 0x1f;
+        let err = Packet::decode(&mut bad_frame).unwrap_err();
+        assert!(
+            err.0
+                .ends_with(&format!("(packet 0, frame 0 at byte {})", last)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_decode_mode() {
+        let packet = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            ConnectionId::new(8, vec![0; 8]),
+            vec![4],
+            vec![Frame::Ping],
+        );
+        let lenient = DecodeLimits::lenient();
+
+        // reserved bits set
+        let mut reserved = packet.encode().unwrap();
+        reserved[0] |= 0b0000_1000;
+        assert!(Packet::decode(&mut reserved.clone()).is_err());
+        let decoded = Packet::decode_with_limits(&mut reserved, &lenient).unwrap();
+        assert_eq!(decoded.warnings, vec![DecodeWarning::ReservedBits]);
+        assert_eq!(decoded.payload, packet.payload);
+
+        // a handshake packet followed by junk its Length doesn't cover
+        let mut handshake = Packet::long_header(
+            LongPacketType::handshake(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            ConnectionId::new(8, vec![0; 8]),
+            ConnectionId::new(8, vec![0; 8]),
+            LongHeaderExtension::Handshake {
+                length: VarInt::zero(),
+                packet_number: PacketNumber(VarInt::new_u32(3)),
+            },
+            vec![Frame::Ping],
+        );
+        handshake.update_length();
+        let mut trailing = handshake.encode().unwrap();
+        trailing.extend([0; 3]);
+        assert!(Packet::decode(&mut trailing.clone()).is_err());
+        let decoded = Packet::decode_with_limits(&mut trailing, &lenient).unwrap();
+        assert_eq!(decoded.warnings, vec![DecodeWarning::TrailingBytes(3)]);
+
+        // a PING frame type in two bytes
+        let mut non_minimal = packet.encode().unwrap();
+        let last = non_minimal.len() - 1;
+        non_minimal.splice(last.., [0x40, 0x01]);
+        assert!(Packet::decode(&mut non_minimal.clone()).is_err());
+        let decoded = Packet::decode_with_limits(&mut non_minimal, &lenient).unwrap();
+        assert_eq!(decoded.warnings, vec![DecodeWarning::NonMinimalVarInt]);
+        assert_eq!(decoded.payload, packet.payload);
+
+        // nothing to warn about
+        let decoded = Packet::decode_with_limits(&mut packet.encode().unwrap(), &lenient).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn test_huge_declared_lengths() {
+        let initial = Packet::initial(
+            MINI_QUICHE_VERSION,
+            ConnectionId::new(4, vec![1; 4]),
+            ConnectionId::new(4, vec![2; 4]),
+            FourBits::zero(),
+            VarInt::zero(),
+            Vec::new(),
+            VarInt::zero(),
+            PacketNumber(VarInt::zero()),
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap();
+        // the token length is the byte after the src cid
+        let token_at = 1 + 4 + 1 + 4 + 1 + 4;
+        let mut huge_token = initial[..token_at].to_vec();
+        huge_token.extend(VarInt::MAX.encode());
+        huge_token.extend(&initial[token_at + 1..]);
+        assert!(Packet::decode(&mut huge_token.clone()).is_err());
+        assert!(LongHeader::decode(&mut huge_token).is_err());
+
+        // cids longer than the packet
+        let mut huge_cid = initial.clone();
+        huge_cid[5] = 0xff;
+        assert!(Packet::decode(&mut huge_cid.clone()).is_err());
+        assert!(LongHeader::decode(&mut huge_cid).is_err());
+        let mut huge_cid = initial.clone();
+        huge_cid[10] = 0xff;
+        assert!(Packet::decode(&mut huge_cid.clone()).is_err());
+        assert!(LongHeader::decode(&mut huge_cid).is_err());
+
+        // cut off anywhere, nothing panics
+        for len in 0..initial.len() {
+            assert!(Packet::decode(&mut initial[..len].to_vec()).is_err());
+        }
+        let mut short = vec![0b0100_0000, 0xff, 1, 2];
+        assert!(Packet::decode(&mut short).is_err());
+    }
+
+    #[test]
+    fn test_packet_space() {
+        let cid = || ConnectionId::new(8, vec![0; 8]);
+        let one_rtt = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(2).unwrap(),
+            cid(),
+            vec![0x01, 0x02],
+            vec![Frame::Ping],
+        );
+        assert_eq!(one_rtt.encryption_level(), Some(EncryptionLevel::OneRtt));
+        assert_eq!(one_rtt.space(), Some(PacketSpace::ApplicationData));
+        assert_eq!(one_rtt.header.packet_number(), Some(0x0102));
+
+        let zero_rtt = Packet::long_header(
+            LongPacketType::zero_rtt(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            cid(),
+            cid(),
+            LongHeaderExtension::ZeroRTT {
+                length: VarInt::new_u32(1),
+                packet_number: PacketNumber(VarInt::new_u32(7)),
+            },
+            vec![Frame::Ping],
+        );
+        assert_eq!(zero_rtt.encryption_level(), Some(EncryptionLevel::ZeroRtt));
+        // 0-rtt and 1-rtt are numbered in the same space
+        assert_eq!(zero_rtt.space(), one_rtt.space());
+        assert_eq!(zero_rtt.header.packet_number(), Some(7));
+
+        let version_negotiation = Packet {
+            header: Header::VersionNegotiate(LongHeader::version_negotiate(
+                cid(),
+                cid(),
+                vec![MINI_QUICHE_VERSION],
+            )),
+            payload: Vec::new(),
+            warnings: Vec::new(),
+        };
+        assert_eq!(version_negotiation.space(), None);
+        assert_eq!(version_negotiation.header.packet_number(), None);
+    }
 }
@@ -0,0 +1,572 @@
+use std::fmt;
+
+use crate::{
+    consts::{
+        self, DEFAULT_ACK_DELAY_EXPONENT, DEFAULT_ACTIVE_CONNECTION_ID_LIMIT,
+        DEFAULT_MAX_ACK_DELAY, MAX_CID_LEN, MIN_INITIAL_SIZE,
+    },
+    packet::{error::ProtocolError, types::ConnectionId},
+    result::{QuicheError, QuicheResult},
+    DecodeBuf, VarInt,
+};
+
+// the smallest max_udp_payload_size a peer may advertise, and the largest a udp payload can be
+pub const MIN_UDP_PAYLOAD_SIZE: u64 = MIN_INITIAL_SIZE as u64;
+pub const MAX_UDP_PAYLOAD_SIZE: u64 = consts::MAX_UDP_PAYLOAD_SIZE as u64;
+
+const ORIGINAL_DESTINATION_CONNECTION_ID: u64 = 0x00;
+const MAX_IDLE_TIMEOUT: u64 = 0x01;
+const MAX_UDP_PAYLOAD_SIZE_ID: u64 = 0x03;
+const INITIAL_MAX_DATA: u64 = 0x04;
+const INITIAL_MAX_STREAM_DATA_BIDI_LOCAL: u64 = 0x05;
+const INITIAL_MAX_STREAM_DATA_BIDI_REMOTE: u64 = 0x06;
+const INITIAL_MAX_STREAM_DATA_UNI: u64 = 0x07;
+const INITIAL_MAX_STREAMS_BIDI: u64 = 0x08;
+const INITIAL_MAX_STREAMS_UNI: u64 = 0x09;
+const ACK_DELAY_EXPONENT: u64 = 0x0a;
+const MAX_ACK_DELAY: u64 = 0x0b;
+const ACTIVE_CONNECTION_ID_LIMIT: u64 = 0x0e;
+const RETRY_SOURCE_CONNECTION_ID: u64 = 0x10;
+
+// what each endpoint tells the other in the handshake (RFC 9000 section 18).  the ones that
+// aren't implemented yet (reset token, preferred address, initial_source_connection_id) are
+// skipped like unknown ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportParameters {
+    // milliseconds, 0 is no timeout
+    pub max_idle_timeout: u64,
+    pub max_udp_payload_size: u64,
+    pub initial_max_data: u64,
+    pub initial_max_stream_data_bidi_local: u64,
+    pub initial_max_stream_data_bidi_remote: u64,
+    pub initial_max_stream_data_uni: u64,
+    pub initial_max_streams_bidi: u64,
+    pub initial_max_streams_uni: u64,
+    pub ack_delay_exponent: u64,
+    // milliseconds
+    pub max_ack_delay: u64,
+    pub active_connection_id_limit: u64,
+    // server only: the dst_cid of the client's first Initial, and the src_cid of the Retry if
+    // one was sent.  the client checks both against what it saw (RFC 9000 section 7.3)
+    pub original_destination_connection_id: Option<ConnectionId>,
+    pub retry_source_connection_id: Option<ConnectionId>,
+}
+
+// the values an absent parameter takes
+impl Default for TransportParameters {
+    fn default() -> Self {
+        Self {
+            max_idle_timeout: 0,
+            max_udp_payload_size: MAX_UDP_PAYLOAD_SIZE,
+            initial_max_data: 0,
+            initial_max_stream_data_bidi_local: 0,
+            initial_max_stream_data_bidi_remote: 0,
+            initial_max_stream_data_uni: 0,
+            initial_max_streams_bidi: 0,
+            initial_max_streams_uni: 0,
+            ack_delay_exponent: DEFAULT_ACK_DELAY_EXPONENT,
+            max_ack_delay: DEFAULT_MAX_ACK_DELAY,
+            active_connection_id_limit: DEFAULT_ACTIVE_CONNECTION_ID_LIMIT,
+            original_destination_connection_id: None,
+            retry_source_connection_id: None,
+        }
+    }
+}
+
+impl TransportParameters {
+    // every parameter that differs from its default, as (id, length, value) triples
+    pub fn encode(&self) -> QuicheResult<Vec<u8>> {
+        let defaults = Self::default();
+        let mut buf = Vec::new();
+        for (id, value, default) in [
+            (
+                MAX_IDLE_TIMEOUT,
+                self.max_idle_timeout,
+                defaults.max_idle_timeout,
+            ),
+            (
+                MAX_UDP_PAYLOAD_SIZE_ID,
+                self.max_udp_payload_size,
+                defaults.max_udp_payload_size,
+            ),
+            (
+                INITIAL_MAX_DATA,
+                self.initial_max_data,
+                defaults.initial_max_data,
+            ),
+            (
+                INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+                self.initial_max_stream_data_bidi_local,
+                defaults.initial_max_stream_data_bidi_local,
+            ),
+            (
+                INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+                self.initial_max_stream_data_bidi_remote,
+                defaults.initial_max_stream_data_bidi_remote,
+            ),
+            (
+                INITIAL_MAX_STREAM_DATA_UNI,
+                self.initial_max_stream_data_uni,
+                defaults.initial_max_stream_data_uni,
+            ),
+            (
+                INITIAL_MAX_STREAMS_BIDI,
+                self.initial_max_streams_bidi,
+                defaults.initial_max_streams_bidi,
+            ),
+            (
+                INITIAL_MAX_STREAMS_UNI,
+                self.initial_max_streams_uni,
+                defaults.initial_max_streams_uni,
+            ),
+            (
+                ACK_DELAY_EXPONENT,
+                self.ack_delay_exponent,
+                defaults.ack_delay_exponent,
+            ),
+            (MAX_ACK_DELAY, self.max_ack_delay, defaults.max_ack_delay),
+            (
+                ACTIVE_CONNECTION_ID_LIMIT,
+                self.active_connection_id_limit,
+                defaults.active_connection_id_limit,
+            ),
+        ] {
+            if value == default {
+                continue;
+            }
+            let value = VarInt::new_u64(value)?;
+            buf.extend(VarInt(id).encode());
+            buf.extend(VarInt(value.size() as u64).encode());
+            buf.extend(value.encode());
+        }
+        for (id, cid) in [
+            (
+                ORIGINAL_DESTINATION_CONNECTION_ID,
+                &self.original_destination_connection_id,
+            ),
+            (RETRY_SOURCE_CONNECTION_ID, &self.retry_source_connection_id),
+        ] {
+            if let Some(cid) = cid {
+                buf.extend(VarInt(id).encode());
+                buf.extend(VarInt(cid.cid.len() as u64).encode());
+                buf.extend(cid.cid.iter());
+            }
+        }
+        Ok(buf)
+    }
+
+    // a repeated parameter, a value that doesn't fill its length or one out of range are all
+    // TRANSPORT_PARAMETER_ERROR, the error says which parameter it was
+    pub fn decode<B: DecodeBuf>(bytes: &mut B) -> Result<Self, TransportParameterError> {
+        let mut params = Self::default();
+        let mut seen = Vec::new();
+        while !bytes.is_empty() {
+            let id = read_varint(bytes).ok_or(TransportParameterError::unreadable())?;
+            let malformed = TransportParameterError::new(id, ParameterViolation::Malformed);
+            let len = read_varint(bytes).ok_or(malformed)? as usize;
+            if bytes.as_slice().len() < len {
+                return Err(malformed);
+            }
+            if seen.contains(&id) {
+                return Err(TransportParameterError::new(
+                    id,
+                    ParameterViolation::Duplicate,
+                ));
+            }
+            seen.push(id);
+
+            let mut value = bytes.take_vec(len);
+            let cid = match id {
+                ORIGINAL_DESTINATION_CONNECTION_ID => {
+                    Some(&mut params.original_destination_connection_id)
+                }
+                RETRY_SOURCE_CONNECTION_ID => Some(&mut params.retry_source_connection_id),
+                _ => None,
+            };
+            if let Some(cid) = cid {
+                // a cid is its raw bytes, no longer than any other cid
+                if len > MAX_CID_LEN {
+                    return Err(TransportParameterError::new(
+                        id,
+                        ParameterViolation::OutOfRange,
+                    ));
+                }
+                *cid = Some(ConnectionId::new(len as u8, value));
+                continue;
+            }
+            let field = match id {
+                MAX_IDLE_TIMEOUT => &mut params.max_idle_timeout,
+                MAX_UDP_PAYLOAD_SIZE_ID => &mut params.max_udp_payload_size,
+                INITIAL_MAX_DATA => &mut params.initial_max_data,
+                INITIAL_MAX_STREAM_DATA_BIDI_LOCAL => {
+                    &mut params.initial_max_stream_data_bidi_local
+                }
+                INITIAL_MAX_STREAM_DATA_BIDI_REMOTE => {
+                    &mut params.initial_max_stream_data_bidi_remote
+                }
+                INITIAL_MAX_STREAM_DATA_UNI => &mut params.initial_max_stream_data_uni,
+                INITIAL_MAX_STREAMS_BIDI => &mut params.initial_max_streams_bidi,
+                INITIAL_MAX_STREAMS_UNI => &mut params.initial_max_streams_uni,
+                ACK_DELAY_EXPONENT => &mut params.ack_delay_exponent,
+                MAX_ACK_DELAY => &mut params.max_ack_delay,
+                ACTIVE_CONNECTION_ID_LIMIT => &mut params.active_connection_id_limit,
+                // unknown (or not yet supported) parameters are ignored
+                _ => continue,
+            };
+            // the value is a single varint that fills the whole length
+            if value.first().is_none_or(|first| 1 << (first >> 6) != len) {
+                return Err(malformed);
+            }
+            *field = read_varint(&mut value).ok_or(malformed)?;
+        }
+        params.validate()?;
+        Ok(params)
+    }
+
+    fn validate(&self) -> Result<(), TransportParameterError> {
+        for (id, valid) in [
+            (
+                MAX_UDP_PAYLOAD_SIZE_ID,
+                (MIN_UDP_PAYLOAD_SIZE..=MAX_UDP_PAYLOAD_SIZE).contains(&self.max_udp_payload_size),
+            ),
+            (ACK_DELAY_EXPONENT, self.ack_delay_exponent <= 20),
+            (MAX_ACK_DELAY, self.max_ack_delay < 1 << 14),
+            (
+                ACTIVE_CONNECTION_ID_LIMIT,
+                self.active_connection_id_limit >= 2,
+            ),
+        ] {
+            if !valid {
+                return Err(TransportParameterError::new(
+                    id,
+                    ParameterViolation::OutOfRange,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // what a client keeps with a session ticket and holds its 0-rtt to (RFC 9000 section 7.4.1).
+    // everything else takes its default, the resumed connection negotiates it afresh
+    pub fn remembered(&self) -> Self {
+        Self {
+            active_connection_id_limit: self.active_connection_id_limit,
+            initial_max_data: self.initial_max_data,
+            initial_max_stream_data_bidi_local: self.initial_max_stream_data_bidi_local,
+            initial_max_stream_data_bidi_remote: self.initial_max_stream_data_bidi_remote,
+            initial_max_stream_data_uni: self.initial_max_stream_data_uni,
+            initial_max_streams_bidi: self.initial_max_streams_bidi,
+            initial_max_streams_uni: self.initial_max_streams_uni,
+            ..Default::default()
+        }
+    }
+
+    // whether these, a server's current parameters, are at least what a client was told when it
+    // got its ticket.  a server that has lowered any of them has to reject 0-rtt, the client may
+    // already have sent up to the old limits
+    pub fn covers(&self, remembered: &Self) -> bool {
+        [
+            (
+                self.active_connection_id_limit,
+                remembered.active_connection_id_limit,
+            ),
+            (self.initial_max_data, remembered.initial_max_data),
+            (
+                self.initial_max_stream_data_bidi_local,
+                remembered.initial_max_stream_data_bidi_local,
+            ),
+            (
+                self.initial_max_stream_data_bidi_remote,
+                remembered.initial_max_stream_data_bidi_remote,
+            ),
+            (
+                self.initial_max_stream_data_uni,
+                remembered.initial_max_stream_data_uni,
+            ),
+            (
+                self.initial_max_streams_bidi,
+                remembered.initial_max_streams_bidi,
+            ),
+            (
+                self.initial_max_streams_uni,
+                remembered.initial_max_streams_uni,
+            ),
+        ]
+        .into_iter()
+        .all(|(current, remembered)| current >= remembered)
+    }
+
+    // the ones only a server may send (RFC 9000 section 18.2), which a client sending is an error
+    pub fn check_from_client(&self) -> Result<(), TransportParameterError> {
+        for (id, sent) in [
+            (
+                ORIGINAL_DESTINATION_CONNECTION_ID,
+                self.original_destination_connection_id.is_some(),
+            ),
+            (
+                RETRY_SOURCE_CONNECTION_ID,
+                self.retry_source_connection_id.is_some(),
+            ),
+        ] {
+            if sent {
+                return Err(TransportParameterError::new(
+                    id,
+                    ParameterViolation::ForbiddenFromClient,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // a server's parameters against the cids the client saw: the dst_cid of its first Initial,
+    // and the src_cid of the Retry it took if any (RFC 9000 section 7.3)
+    pub fn check_from_server(
+        &self,
+        original_dst_cid: &ConnectionId,
+        retry_src_cid: Option<&ConnectionId>,
+    ) -> Result<(), TransportParameterError> {
+        match &self.original_destination_connection_id {
+            None => {
+                return Err(TransportParameterError::new(
+                    ORIGINAL_DESTINATION_CONNECTION_ID,
+                    ParameterViolation::Missing,
+                ))
+            }
+            Some(cid) if cid != original_dst_cid => {
+                return Err(TransportParameterError::new(
+                    ORIGINAL_DESTINATION_CONNECTION_ID,
+                    ParameterViolation::Mismatch,
+                ))
+            }
+            Some(_) => {}
+        }
+        let violation = match (&self.retry_source_connection_id, retry_src_cid) {
+            (None, None) => return Ok(()),
+            (Some(sent), Some(seen)) if sent == seen => return Ok(()),
+            (None, Some(_)) => ParameterViolation::Missing,
+            _ => ParameterViolation::Mismatch,
+        };
+        Err(TransportParameterError::new(
+            RETRY_SOURCE_CONNECTION_ID,
+            violation,
+        ))
+    }
+}
+
+// a varint that has to be all there, where `VarInt::decode` would run off the end
+fn read_varint<B: DecodeBuf>(bytes: &mut B) -> Option<u64> {
+    let first = *bytes.as_slice().first()?;
+    if bytes.as_slice().len() < 1 << (first >> 6) {
+        return None;
+    }
+    VarInt::decode(bytes).ok().map(VarInt::to_inner)
+}
+
+// the name RFC 9000 section 18.2 gives a parameter, for error messages
+pub fn parameter_name(id: u64) -> &'static str {
+    match id {
+        ORIGINAL_DESTINATION_CONNECTION_ID => "original_destination_connection_id",
+        MAX_IDLE_TIMEOUT => "max_idle_timeout",
+        MAX_UDP_PAYLOAD_SIZE_ID => "max_udp_payload_size",
+        INITIAL_MAX_DATA => "initial_max_data",
+        INITIAL_MAX_STREAM_DATA_BIDI_LOCAL => "initial_max_stream_data_bidi_local",
+        INITIAL_MAX_STREAM_DATA_BIDI_REMOTE => "initial_max_stream_data_bidi_remote",
+        INITIAL_MAX_STREAM_DATA_UNI => "initial_max_stream_data_uni",
+        INITIAL_MAX_STREAMS_BIDI => "initial_max_streams_bidi",
+        INITIAL_MAX_STREAMS_UNI => "initial_max_streams_uni",
+        ACK_DELAY_EXPONENT => "ack_delay_exponent",
+        MAX_ACK_DELAY => "max_ack_delay",
+        ACTIVE_CONNECTION_ID_LIMIT => "active_connection_id_limit",
+        RETRY_SOURCE_CONNECTION_ID => "retry_source_connection_id",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParameterViolation {
+    // sent more than once
+    Duplicate,
+    // a length past the end of the parameters, or a value that doesn't fill its length
+    Malformed,
+    OutOfRange,
+    // one the peer has to send
+    Missing,
+    // one only a server may send
+    ForbiddenFromClient,
+    // a cid that isn't the one we saw
+    Mismatch,
+}
+
+// what closed the connection with TRANSPORT_PARAMETER_ERROR.  `id` is None when the parameters
+// were cut off before a parameter's id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportParameterError {
+    pub id: Option<u64>,
+    pub violation: ParameterViolation,
+}
+
+impl TransportParameterError {
+    pub fn new(id: u64, violation: ParameterViolation) -> Self {
+        Self {
+            id: Some(id),
+            violation,
+        }
+    }
+
+    fn unreadable() -> Self {
+        Self {
+            id: None,
+            violation: ParameterViolation::Malformed,
+        }
+    }
+}
+
+// e.g. "duplicate max_idle_timeout (0x1)", which is what goes in the CONNECTION_CLOSE reason
+impl fmt::Display for TransportParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let violation = match self.violation {
+            ParameterViolation::Duplicate => "duplicate",
+            ParameterViolation::Malformed => "malformed",
+            ParameterViolation::OutOfRange => "out of range",
+            ParameterViolation::Missing => "missing",
+            ParameterViolation::ForbiddenFromClient => "client sent",
+            ParameterViolation::Mismatch => "mismatched",
+        };
+        match self.id {
+            Some(id) => write!(f, "{} {} ({:#x})", violation, parameter_name(id), id),
+            None => write!(f, "{} transport parameters", violation),
+        }
+    }
+}
+
+impl From<TransportParameterError> for QuicheError {
+    fn from(err: TransportParameterError) -> Self {
+        QuicheError(format!(
+            "Transport error: {:?}: {}",
+            ProtocolError::TransportParameterError,
+            err
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transport_parameters() {
+        let params = TransportParameters {
+            max_idle_timeout: 30_000,
+            max_udp_payload_size: 1_472,
+            initial_max_data: 1 << 20,
+            initial_max_streams_bidi: 100,
+            ..Default::default()
+        };
+        let mut encoded = params.encode().unwrap();
+        assert_eq!(TransportParameters::decode(&mut encoded).unwrap(), params);
+        assert_eq!(
+            TransportParameters::decode(&mut Vec::new()).unwrap(),
+            TransportParameters::default()
+        );
+
+        // an unknown parameter is skipped
+        let mut encoded = vec![0x21, 2, 0xAB, 0xCD];
+        encoded.extend(params.encode().unwrap());
+        assert_eq!(TransportParameters::decode(&mut encoded).unwrap(), params);
+
+        // max_udp_payload_size under 1200
+        let mut encoded = vec![0x03, 2, 0x44, 0xAF];
+        assert!(TransportParameters::decode(&mut encoded).is_err());
+        // repeated
+        let mut encoded = vec![0x01, 1, 0x01, 0x01, 1, 0x02];
+        assert!(TransportParameters::decode(&mut encoded).is_err());
+        // longer than what's left
+        let mut encoded = vec![0x01, 4, 0x01];
+        assert!(TransportParameters::decode(&mut encoded).is_err());
+        // a value that doesn't fill its length
+        let mut encoded = vec![0x01, 2, 0x01, 0x00];
+        assert!(TransportParameters::decode(&mut encoded).is_err());
+    }
+
+    #[test]
+    fn test_connection_id_parameters() {
+        let params = TransportParameters {
+            original_destination_connection_id: Some(ConnectionId::new(8, vec![1; 8])),
+            retry_source_connection_id: Some(ConnectionId::new(0, vec![])),
+            ..Default::default()
+        };
+        let mut encoded = params.encode().unwrap();
+        assert_eq!(encoded[..3], [0x00, 8, 1]);
+        assert_eq!(TransportParameters::decode(&mut encoded).unwrap(), params);
+
+        // longer than a cid can be
+        let mut encoded = vec![0x10, 21];
+        encoded.extend([0; 21]);
+        assert!(TransportParameters::decode(&mut encoded).is_err());
+
+        // only a server sends them, and it has to send the ones for the cids the client saw
+        assert_eq!(
+            params.check_from_client(),
+            Err(TransportParameterError::new(
+                ORIGINAL_DESTINATION_CONNECTION_ID,
+                ParameterViolation::ForbiddenFromClient
+            ))
+        );
+        let original = ConnectionId::new(8, vec![1; 8]);
+        let retry = ConnectionId::new(0, vec![]);
+        params.check_from_server(&original, Some(&retry)).unwrap();
+        let err = params.check_from_server(&original, None).unwrap_err();
+        assert_eq!(
+            err,
+            TransportParameterError::new(RETRY_SOURCE_CONNECTION_ID, ParameterViolation::Mismatch)
+        );
+        assert_eq!(
+            err.to_string(),
+            "mismatched retry_source_connection_id (0x10)"
+        );
+        let err = TransportParameters::default()
+            .check_from_server(&original, None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "missing original_destination_connection_id (0x0)"
+        );
+    }
+
+    #[test]
+    fn test_transport_parameter_errors() {
+        let decode = |mut encoded: Vec<u8>| TransportParameters::decode(&mut encoded).unwrap_err();
+        let err = decode(vec![0x01, 1, 0x01, 0x01, 1, 0x02]);
+        assert_eq!(
+            err,
+            TransportParameterError::new(MAX_IDLE_TIMEOUT, ParameterViolation::Duplicate)
+        );
+        assert_eq!(err.to_string(), "duplicate max_idle_timeout (0x1)");
+        assert_eq!(
+            decode(vec![0x03, 2, 0x44, 0xAF]),
+            TransportParameterError::new(MAX_UDP_PAYLOAD_SIZE_ID, ParameterViolation::OutOfRange)
+        );
+        assert_eq!(
+            decode(vec![0x0e, 1, 0x01]),
+            TransportParameterError::new(
+                ACTIVE_CONNECTION_ID_LIMIT,
+                ParameterViolation::OutOfRange
+            )
+        );
+        assert_eq!(
+            decode(vec![0x01, 2, 0x01, 0x00]),
+            TransportParameterError::new(MAX_IDLE_TIMEOUT, ParameterViolation::Malformed)
+        );
+        // cut off in the length, and in the id, which isn't a panic
+        assert_eq!(
+            decode(vec![0x0a, 0x40]),
+            TransportParameterError::new(ACK_DELAY_EXPONENT, ParameterViolation::Malformed)
+        );
+        let err = decode(vec![0x80, 0x00]);
+        assert_eq!(
+            (err.id, err.violation),
+            (None, ParameterViolation::Malformed)
+        );
+        assert_eq!(err.to_string(), "malformed transport parameters");
+    }
+}
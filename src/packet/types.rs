@@ -1,8 +1,9 @@
 use crate::bits::{Bits, BitsExt};
-use crate::{bits_ext, rand, VarInt};
+use crate::result::{QuicheError, QuicheResult};
+use crate::{bits_ext, consts::LOCAL_CID_LEN, fill_secure, rand, VarInt};
 
 // unfortunately it's really annoying to implement a 160 bit integer
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub struct ConnectionId {
     // this MUST NOT exceed 20 bytes
     // endpoints which receive a version 1 long header with a cid_len > 20 must drop the packet
@@ -16,6 +17,14 @@ impl ConnectionId {
         Self { cid_len, cid }
     }
 
+    // a cid no peer can guess or link to another of ours, from the OS's generator
+    pub fn random() -> Self {
+        let mut cid = vec![0; LOCAL_CID_LEN];
+        fill_secure(&mut cid);
+        Self::new(LOCAL_CID_LEN as u8, cid)
+    }
+
+    // any length, from `rand`: the same in every process, so only for tests
     pub fn arbitrary() -> Self {
         let cid_len = rand(20) + 1;
         let cid = (0..cid_len).map(|_| rand(255)).collect();
@@ -32,6 +41,49 @@ impl PacketNumber {
     }
 }
 
+// which keys protect a packet.  ordered the way coalesced packets have to appear in a datagram
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub enum EncryptionLevel {
+    Initial,
+    ZeroRtt,
+    Handshake,
+    OneRtt,
+}
+
+impl EncryptionLevel {
+    pub const ALL: [EncryptionLevel; 4] = [
+        EncryptionLevel::Initial,
+        EncryptionLevel::ZeroRtt,
+        EncryptionLevel::Handshake,
+        EncryptionLevel::OneRtt,
+    ];
+
+    // 0-rtt and 1-rtt packets share the application data space (RFC 9000 section 12.3)
+    pub fn space(self) -> PacketSpace {
+        match self {
+            EncryptionLevel::Initial => PacketSpace::Initial,
+            EncryptionLevel::Handshake => PacketSpace::Handshake,
+            EncryptionLevel::ZeroRtt | EncryptionLevel::OneRtt => PacketSpace::ApplicationData,
+        }
+    }
+}
+
+// packet numbers and acknowledgements are tracked separately in each of these
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub enum PacketSpace {
+    Initial,
+    Handshake,
+    ApplicationData,
+}
+
+impl PacketSpace {
+    pub const ALL: [PacketSpace; 3] = [
+        PacketSpace::Initial,
+        PacketSpace::Handshake,
+        PacketSpace::ApplicationData,
+    ];
+}
+
 bits_ext!(SingleBit, crate::bits::BitsExt<u8>, 1, u8);
 bits_ext!(TwoBits, crate::bits::BitsExt<u8>, 2, u8);
 bits_ext!(FourBits, crate::bits::BitsExt<u8>, 4, u8);
@@ -39,6 +91,87 @@ bits_ext!(SevenBits, crate::bits::BitsExt<u8>, 7, u8);
 bits_ext!(LongPacketType, crate::bits::BitsExt<u8>, 2, u8);
 bits_ext!(HeaderForm, crate::bits::BitsExt<u8>, 1, u8);
 
+// a short header's packet number length field: one less than the packet number's length in bytes.
+// its two bits go on the wire reversed, see `BitsExt::invert`
+// a packet number's length in bytes, less one: what the two length bits of a header hold
+#[derive(PartialEq, Debug, Clone)]
+pub struct PnLen(TwoBits);
+
+// never empty, a packet number is 1-4 bytes
+#[allow(clippy::len_without_is_empty)]
+impl PnLen {
+    pub fn from_len(len: usize) -> QuicheResult<Self> {
+        if !(1..=4).contains(&len) {
+            return Err(QuicheError(format!(
+                "PnLen::from_len: a packet number is 1-4 bytes, not {}",
+                len
+            )));
+        }
+        Ok(Self(TwoBits::from_num(len as u8 - 1)))
+    }
+
+    // the packet number's length in bytes
+    pub fn len(&self) -> usize {
+        self.0.to_inner() as usize + 1
+    }
+
+    // read from the first byte of a short header, once header protection is off
+    pub fn from_wire(first_byte: u8) -> Self {
+        Self(TwoBits::from_num(first_byte & 0b11).invert())
+    }
+
+    // the bits that go into the first byte of a short header, reversed like `from_wire` reads them
+    pub fn bits(&self) -> &[bool] {
+        self.0.bits()
+    }
+
+    // the shortest length a packet number can be sent in, for a peer that's received up to at
+    // least `largest_acked`: twice as many numbers as are in flight, so there's no mistaking which
+    // one it is (RFC 9000 section 17.1 and appendix A.2)
+    pub fn for_packet(packet_number: u64, largest_acked: Option<u64>) -> Self {
+        let unacked = match largest_acked {
+            Some(largest) => packet_number.saturating_sub(largest).max(1),
+            None => packet_number + 1,
+        };
+        let min_bits = 64 - (2 * unacked - 1).leading_zeros() as usize;
+        Self(TwoBits::from_num(
+            min_bits.div_ceil(8).clamp(1, 4) as u8 - 1,
+        ))
+    }
+
+    // the low bytes of `packet_number` that go on the wire, big-endian
+    pub fn truncate(&self, packet_number: u64) -> Vec<u8> {
+        packet_number.to_be_bytes()[8 - self.len()..].to_vec()
+    }
+
+    // the type specific bits of an Initial, 0-RTT or Handshake header whose packet number is
+    // this long: the length in the top two, the reserved bits under it left zero
+    pub fn long_header_bits(&self) -> FourBits {
+        FourBits::from_num(self.0.to_inner() << 2)
+    }
+
+    // the inverse, read back out of a long header's type specific bits
+    pub fn from_long_header_bits(bits: &FourBits) -> Self {
+        Self(TwoBits::from_num(bits.to_inner() >> 2))
+    }
+
+    // the full packet number a truncated one of this length stands for: the one closest to
+    // the next expected (RFC 9000 appendix A.3)
+    pub fn expand(&self, truncated: u64, largest_received: Option<u64>) -> u64 {
+        let expected = largest_received.map_or(0, |largest| largest + 1);
+        let window = 1u64 << (8 * self.len());
+        let half_window = window / 2;
+        let candidate = (expected & !(window - 1)) | truncated;
+        if candidate + half_window <= expected && candidate < (1 << 62) - window {
+            candidate + window
+        } else if candidate > expected + half_window && candidate >= window {
+            candidate - window
+        } else {
+            candidate
+        }
+    }
+}
+
 impl Default for SingleBit {
     fn default() -> Self {
         Self::zero()
@@ -0,0 +1,185 @@
+use crate::{
+    consts::{MINI_QUICHE_VERSION, QUIC_V1, QUIC_V2},
+    rand,
+};
+
+// every version we can speak, and what differs between them.  the header codec and key
+// derivation go through here rather than matching on version numbers themselves, so another
+// version is one more variant instead of a hunt through every match
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    // RFC 9000
+    V1,
+    // RFC 9369, v1 with different constants and the long packet types shuffled
+    V2,
+    // our own, laid out as v1
+    MiniQuiche,
+}
+
+impl Version {
+    pub const ALL: [Version; 3] = [Version::V1, Version::V2, Version::MiniQuiche];
+
+    // None for versions we don't speak, reserved (greased) ones included
+    pub fn from_u32(version: u32) -> Option<Self> {
+        match version {
+            QUIC_V1 => Some(Version::V1),
+            QUIC_V2 => Some(Version::V2),
+            MINI_QUICHE_VERSION => Some(Version::MiniQuiche),
+            _ => None,
+        }
+    }
+
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Version::V1 => QUIC_V1,
+            Version::V2 => QUIC_V2,
+            Version::MiniQuiche => MINI_QUICHE_VERSION,
+        }
+    }
+
+    // 0x?a?a?a?a, reserved for exercising version negotiation and never a real version (RFC 9000
+    // section 15)
+    pub fn is_reserved(version: u32) -> bool {
+        version & 0x0f0f_0f0f == 0x0a0a_0a0a
+    }
+
+    // a random reserved version
+    pub fn greased() -> u32 {
+        u32::from_be_bytes(std::array::from_fn(|_| rand(16) << 4 | 0x0a))
+    }
+
+    // the versions a Version Negotiation packet lists: ours, and a reserved one somewhere among
+    // them so clients that choke on a version they don't know get found out early (RFC 9000
+    // section 6.3)
+    pub fn advertised(supported: &[Version]) -> Vec<u32> {
+        let mut versions = supported.iter().map(|v| v.to_u32()).collect::<Vec<_>>();
+        let at = rand(versions.len() as u128 + 1) as usize;
+        versions.insert(at, Self::greased());
+        versions
+    }
+
+    // what a client goes with after a Version Negotiation, the first of `preferred` the server
+    // listed.  the reserved versions a server lists are never picked, they're only there to be
+    // skipped
+    pub fn select(preferred: &[Version], offered: &[u32]) -> Option<Version> {
+        preferred.iter().copied().find(|version| {
+            offered
+                .iter()
+                .any(|&offered| !Self::is_reserved(offered) && offered == version.to_u32())
+        })
+    }
+
+    // how a long header with `version` is laid out.  one we don't speak is read as v1, which is
+    // as good a guess as any and enough to answer it with version negotiation
+    pub fn codec(version: u32) -> Self {
+        Self::from_u32(version).unwrap_or(Version::V1)
+    }
+
+    // what Initial secrets are extracted with, alongside the client's first dst cid (RFC 9001
+    // section 5.2, RFC 9369 section 3.3.1)
+    pub fn initial_salt(self) -> &'static [u8; 20] {
+        match self {
+            Version::V1 | Version::MiniQuiche => &[
+                0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+                0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+            ],
+            Version::V2 => &[
+                0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+                0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+            ],
+        }
+    }
+
+    // the fixed AES-128-GCM key a Retry's integrity tag is computed with (RFC 9001 section 5.8,
+    // RFC 9369 section 3.3.3)
+    pub fn retry_integrity_key(self) -> &'static [u8; 16] {
+        match self {
+            Version::V1 | Version::MiniQuiche => &[
+                0xbe, 0x0c, 0x69, 0x0b, 0x9f, 0x66, 0x57, 0x5a, 0x1d, 0x76, 0x6b, 0x54, 0xe3, 0x68,
+                0xc8, 0x4e,
+            ],
+            Version::V2 => &[
+                0x8f, 0xb4, 0xb0, 0x1b, 0x56, 0xac, 0x48, 0xe2, 0x60, 0xfb, 0xcb, 0xce, 0xad, 0x7c,
+                0xcc, 0x92,
+            ],
+        }
+    }
+
+    pub fn retry_integrity_nonce(self) -> &'static [u8; 12] {
+        match self {
+            Version::V1 | Version::MiniQuiche => &[
+                0x46, 0x15, 0x99, 0xd3, 0x5d, 0x63, 0x2b, 0xf2, 0x23, 0x98, 0x25, 0xbb,
+            ],
+            Version::V2 => &[
+                0xd8, 0x69, 0x69, 0xbc, 0x2d, 0x7c, 0x6d, 0x99, 0x90, 0xef, 0xb0, 0x4a,
+            ],
+        }
+    }
+
+    // the type field on the wire for a long packet type, given as its v1 number (0 Initial,
+    // 1 0-RTT, 2 Handshake, 3 Retry)
+    pub fn encode_packet_type(self, packet_type: u8) -> u8 {
+        match self {
+            Version::V1 | Version::MiniQuiche => packet_type,
+            Version::V2 => (packet_type + 1) & 0b11,
+        }
+    }
+
+    // the inverse, the v1 number of the type field on the wire
+    pub fn decode_packet_type(self, bits: u8) -> u8 {
+        match self {
+            Version::V1 | Version::MiniQuiche => bits,
+            Version::V2 => (bits + 3) & 0b11,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_version() {
+        for version in Version::ALL {
+            assert_eq!(Version::from_u32(version.to_u32()), Some(version));
+            assert!(!Version::is_reserved(version.to_u32()));
+            for packet_type in 0..4 {
+                let bits = version.encode_packet_type(packet_type);
+                assert_eq!(version.decode_packet_type(bits), packet_type);
+            }
+        }
+        // a v2 Initial has the type bits of a v1 0-RTT packet
+        assert_eq!(Version::V2.encode_packet_type(0), 1);
+        assert_eq!(Version::V2.encode_packet_type(3), 0);
+
+        assert!(Version::is_reserved(0x1a2a_3a4a));
+        assert_eq!(Version::from_u32(0x1a2a_3a4a), None);
+        assert_eq!(Version::codec(0x1a2a_3a4a), Version::V1);
+    }
+
+    #[test]
+    fn test_greased_version_negotiation() {
+        for _ in 0..100 {
+            assert!(Version::is_reserved(Version::greased()));
+
+            let advertised = Version::advertised(&[Version::V2, Version::MiniQuiche]);
+            assert_eq!(advertised.len(), 3);
+            assert_eq!(
+                advertised
+                    .iter()
+                    .filter(|&&v| Version::is_reserved(v))
+                    .count(),
+                1
+            );
+            assert_eq!(
+                Version::select(&[Version::V1, Version::MiniQuiche], &advertised),
+                Some(Version::MiniQuiche)
+            );
+        }
+        // nothing in common but grease
+        assert_eq!(
+            Version::select(&Version::ALL, &[Version::greased(), 0xff00_001d]),
+            None
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::result::{require, QuicheError, QuicheResult};
+
+// pcapng (https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html) is a sequence of blocks.
+// every block is laid out as: block type (u32), total length (u32), body, total length again (u32)
+// we only ever write three kinds of blocks:
+// 1. a section header block, once at the start of the file
+// 2. an interface description block describing our single fake "interface"
+// 3. an enhanced packet block per datagram
+pub(crate) const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+pub(crate) const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+pub(crate) const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+pub(crate) const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+// LINKTYPE_RAW, packet data starts with an ipv4 or ipv6 header
+pub(crate) const LINKTYPE_RAW: u16 = 101;
+// LINKTYPE_ETHERNET, what most real captures (tcpdump, wireshark) use
+pub(crate) const LINKTYPE_ETHERNET: u16 = 1;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const UDP_PROTOCOL: u8 = 17;
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+// records every datagram sent or received by a connection so it can be opened in wireshark.
+// datagrams are wrapped in synthetic ip + udp headers built from the socket addresses,
+// that way wireshark's quic dissector picks them up without any extra configuration.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl PcapWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P) -> QuicheResult<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> QuicheResult<Self> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+        // major version 1, minor version 0
+        shb.extend(1u16.to_le_bytes());
+        shb.extend(0u16.to_le_bytes());
+        // section length is unknown, -1 means "not specified"
+        shb.extend((-1i64).to_le_bytes());
+        write_block(&mut writer, SECTION_HEADER_BLOCK, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend(LINKTYPE_RAW.to_le_bytes());
+        // reserved
+        idb.extend(0u16.to_le_bytes());
+        // snap length, 0 means unlimited
+        idb.extend(0u32.to_le_bytes());
+        // no options, so timestamps use the default resolution of microseconds
+        write_block(&mut writer, INTERFACE_DESCRIPTION_BLOCK, &idb)?;
+
+        Ok(Self { writer })
+    }
+
+    // records a single udp datagram travelling from `src` to `dst`
+    pub fn record(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        datagram: &[u8],
+    ) -> QuicheResult<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        self.record_at(timestamp, src, dst, datagram)
+    }
+
+    pub fn record_at(
+        &mut self,
+        timestamp_micros: u64,
+        src: SocketAddr,
+        dst: SocketAddr,
+        datagram: &[u8],
+    ) -> QuicheResult<()> {
+        let packet = encapsulate(src, dst, datagram);
+
+        let mut epb = Vec::with_capacity(20 + packet.len() + 3);
+        // interface id, we only have the one
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(((timestamp_micros >> 32) as u32).to_le_bytes());
+        epb.extend((timestamp_micros as u32).to_le_bytes());
+        // captured length & original length are always the same, we never truncate
+        epb.extend((packet.len() as u32).to_le_bytes());
+        epb.extend((packet.len() as u32).to_le_bytes());
+        epb.extend(&packet);
+        // packet data is padded to a 32 bit boundary
+        epb.resize(epb.len() + (4 - packet.len() % 4) % 4, 0);
+        write_block(&mut self.writer, ENHANCED_PACKET_BLOCK, &epb)
+    }
+
+    pub fn flush(&mut self) -> QuicheResult<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// a udp datagram pulled back out of a capture
+#[derive(PartialEq, Debug, Clone)]
+pub struct CapturedDatagram {
+    // in units of the interface's timestamp resolution, microseconds for anything `PcapWriter` wrote
+    pub timestamp: u64,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+pub fn read_datagrams_from_file<P: AsRef<Path>>(path: P) -> QuicheResult<Vec<CapturedDatagram>> {
+    read_datagrams(BufReader::new(File::open(path)?))
+}
+
+// reads every udp datagram out of a pcapng capture, skipping anything that isn't udp over ip
+// only little-endian sections are supported, which covers everything written on x86 / arm
+pub fn read_datagrams<R: Read>(mut reader: R) -> QuicheResult<Vec<CapturedDatagram>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    // link type of every interface in the current section, indexed by interface id
+    let mut link_types: Vec<u16> = Vec::new();
+    let mut datagrams = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        require(at + 12 <= bytes.len(), "pcap: truncated block header")?;
+        let block_type = read_u32(&bytes, at);
+        let total_len = read_u32(&bytes, at + 4) as usize;
+        require(
+            total_len >= 12 && total_len.is_multiple_of(4) && at + total_len <= bytes.len(),
+            "pcap: invalid block length",
+        )?;
+        let body = &bytes[at + 8..at + total_len - 4];
+        at += total_len;
+
+        match block_type {
+            SECTION_HEADER_BLOCK => {
+                require(
+                    body.len() >= 4 && read_u32(body, 0) == BYTE_ORDER_MAGIC,
+                    "pcap: only little-endian sections are supported",
+                )?;
+                link_types.clear();
+            }
+            INTERFACE_DESCRIPTION_BLOCK => {
+                require(body.len() >= 2, "pcap: truncated interface description")?;
+                link_types.push(u16::from_le_bytes([body[0], body[1]]));
+            }
+            ENHANCED_PACKET_BLOCK => {
+                require(body.len() >= 20, "pcap: truncated enhanced packet")?;
+                let interface_id = read_u32(body, 0) as usize;
+                let timestamp = (read_u32(body, 4) as u64) << 32 | read_u32(body, 8) as u64;
+                let captured_len = read_u32(body, 12) as usize;
+                require(
+                    20 + captured_len <= body.len(),
+                    "pcap: captured length exceeds block",
+                )?;
+                let link_type = *link_types
+                    .get(interface_id)
+                    .ok_or(QuicheError("pcap: unknown interface id".to_string()))?;
+                let packet = &body[20..20 + captured_len];
+                if let Some((src, dst, payload)) = decapsulate(link_type, packet) {
+                    datagrams.push(CapturedDatagram {
+                        timestamp,
+                        src,
+                        dst,
+                        payload: payload.to_vec(),
+                    });
+                }
+            }
+            // name resolution, statistics, etc. aren't interesting
+            _ => {}
+        }
+    }
+
+    Ok(datagrams)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().expect("u32 bytes"))
+}
+
+// the inverse of `encapsulate`, returns `None` for anything that isn't a complete udp datagram
+fn decapsulate(link_type: u16, packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let ip_packet = match link_type {
+        LINKTYPE_RAW => packet,
+        LINKTYPE_ETHERNET => {
+            let ethertype = u16::from_be_bytes(packet.get(12..14)?.try_into().ok()?);
+            if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+                return None;
+            }
+            &packet[ETHERNET_HEADER_LEN..]
+        }
+        _ => return None,
+    };
+
+    let (src_ip, dst_ip, udp) = match ip_packet.first()? >> 4 {
+        4 => {
+            let header_len = ((ip_packet[0] & 0x0F) as usize) * 4;
+            if ip_packet.get(9)? != &UDP_PROTOCOL || header_len < IPV4_HEADER_LEN {
+                return None;
+            }
+            let src: [u8; 4] = ip_packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip_packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                ip_packet.get(header_len..)?,
+            )
+        }
+        // extension headers aren't supported, udp has to be the next header
+        6 => {
+            if ip_packet.get(6)? != &UDP_PROTOCOL {
+                return None;
+            }
+            let src: [u8; 16] = ip_packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip_packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                ip_packet.get(IPV6_HEADER_LEN..)?,
+            )
+        }
+        _ => return None,
+    };
+
+    let src_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let dst_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    let udp_len = u16::from_be_bytes(udp.get(4..6)?.try_into().ok()?) as usize;
+    let payload = udp.get(UDP_HEADER_LEN..udp_len)?;
+
+    Some((
+        SocketAddr::new(src_ip, src_port),
+        SocketAddr::new(dst_ip, dst_port),
+        payload,
+    ))
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> QuicheResult<()> {
+    // type + length + body + length
+    let total_len = (4 + 4 + body.len() + 4) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    Ok(())
+}
+
+// builds a raw ip packet carrying `datagram` as its udp payload
+// mixed address families can't be expressed in a single ip header, so ipv4 addresses are mapped into ipv6
+fn encapsulate(src: SocketAddr, dst: SocketAddr, datagram: &[u8]) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_LEN + datagram.len()) as u16;
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = Vec::with_capacity(IPV4_HEADER_LEN + udp_len as usize);
+            // version 4, header length of 5 words
+            header.push(0x45);
+            // dscp / ecn
+            header.push(0);
+            header.extend((IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+            // identification
+            header.extend(0u16.to_be_bytes());
+            // don't fragment, no fragment offset
+            header.extend(0x4000u16.to_be_bytes());
+            // ttl
+            header.push(64);
+            header.push(UDP_PROTOCOL);
+            // checksum, filled in below
+            header.extend(0u16.to_be_bytes());
+            header.extend(src_ip.octets());
+            header.extend(dst_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            let mut header = Vec::with_capacity(IPV6_HEADER_LEN + udp_len as usize);
+            // version 6, no traffic class, no flow label
+            header.extend(0x6000_0000u32.to_be_bytes());
+            header.extend(udp_len.to_be_bytes());
+            // next header
+            header.push(UDP_PROTOCOL);
+            // hop limit
+            header.push(64);
+            header.extend(to_v6(src_ip).octets());
+            header.extend(to_v6(dst_ip).octets());
+            header
+        }
+    };
+
+    packet.extend(src.port().to_be_bytes());
+    packet.extend(dst.port().to_be_bytes());
+    packet.extend(udp_len.to_be_bytes());
+    // a zero checksum means "no checksum", wireshark doesn't validate udp checksums by default anyway
+    packet.extend(0u16.to_be_bytes());
+    packet.extend(datagram);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pcap_writer() {
+        let client: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+        let server: SocketAddr = "127.0.0.2:443".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.record_at(1, client, server, &[0xC0; 1200]).unwrap();
+        writer.record_at(2, server, client, &[0x40; 3]).unwrap();
+        let bytes = writer.into_inner();
+
+        // walk every block, checking the leading and trailing lengths agree
+        let mut blocks = Vec::new();
+        let mut at = 0;
+        while at < bytes.len() {
+            let block_type = read_u32(&bytes, at);
+            let total_len = read_u32(&bytes, at + 4) as usize;
+            assert_eq!(total_len % 4, 0);
+            assert_eq!(read_u32(&bytes, at + total_len - 4) as usize, total_len);
+            blocks.push((block_type, bytes[at + 8..at + total_len - 4].to_vec()));
+            at += total_len;
+        }
+
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(read_u32(&blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+
+        let (block_type, epb) = &blocks[2];
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        assert_eq!(read_u32(epb, 8), 1);
+        let captured_len = read_u32(epb, 12) as usize;
+        assert_eq!(captured_len, IPV4_HEADER_LEN + UDP_HEADER_LEN + 1200);
+        let packet = &epb[20..20 + captured_len];
+        assert_eq!(packet[0], 0x45);
+        // a valid ipv4 header checksums to zero
+        assert_eq!(ipv4_checksum(&packet[..IPV4_HEADER_LEN]), 0);
+        assert_eq!(
+            &packet[IPV4_HEADER_LEN..IPV4_HEADER_LEN + 2],
+            &4433u16.to_be_bytes()
+        );
+        assert!(packet[IPV4_HEADER_LEN + UDP_HEADER_LEN..]
+            .iter()
+            .all(|&b| b == 0xC0));
+
+        // 3 byte datagrams get padded out to the next 32 bit boundary
+        let (_, epb) = &blocks[3];
+        assert_eq!(
+            read_u32(epb, 12) as usize,
+            IPV4_HEADER_LEN + UDP_HEADER_LEN + 3
+        );
+        assert_eq!(epb.len() % 4, 0);
+    }
+
+    #[test]
+    fn test_pcap_read_back() {
+        let v4_client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let v4_server: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let v6_server: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .record_at(7, v4_client, v4_server, &[1, 2, 3])
+            .unwrap();
+        writer.record_at(8, v4_server, v4_client, &[]).unwrap();
+        writer
+            .record_at(9, v6_server, v4_client, &[4; 1350])
+            .unwrap();
+        let datagrams = read_datagrams(writer.into_inner().as_slice()).unwrap();
+
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(
+            datagrams[0],
+            CapturedDatagram {
+                timestamp: 7,
+                src: v4_client,
+                dst: v4_server,
+                payload: vec![1, 2, 3],
+            }
+        );
+        assert!(datagrams[1].payload.is_empty());
+        // mixed families are written as ipv6, with the ipv4 address mapped
+        assert_eq!(datagrams[2].src, v6_server);
+        assert_eq!(
+            datagrams[2].dst.ip(),
+            IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped())
+        );
+        assert_eq!(datagrams[2].payload, vec![4; 1350]);
+
+        // garbage is an error, not a panic
+        assert!(read_datagrams([0u8; 7].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+// what most users of the crate need, `use mini_quiche::prelude::*` and go.  this is the part of
+// the api we try hardest not to break, everything else is reachable by path but may still move
+pub use crate::{
+    connection::{
+        connection::Connection, CloseReason, ConnectionEvent, ConnectionState, StateObserver,
+    },
+    endpoint::{ConnectionHandle, Endpoint, EndpointConfig, Incoming, ServerConfig},
+    packet::{
+        frame::{Frame, FrameKind},
+        header::Header,
+        packet::Packet,
+        ConnectionId, EncryptionLevel, PacketSpace, Version,
+    },
+    result::{QuicheError, QuicheResult},
+    stream::{SharedRecvStream, SharedSendStream},
+    BitsExt, VarInt,
+};
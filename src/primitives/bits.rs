@@ -9,7 +9,11 @@ pub trait BitsExt<T> {
     fn to_inner(&self) -> T;
     fn zero() -> Self;
     fn one() -> Self;
+    // least significant bit first
     fn bits(&self) -> &[bool];
+    // the same bits in the opposite order, NOT the complement: 0b01 <-> 0b10, 0b00 and 0b11 stay
+    // put.  the header encoders write `bits()` most significant first, so a field goes on the wire
+    // reversed and reading it back as a number gives the inverted value.  inverting twice is a no-op
     fn invert(&self) -> Self;
 }
 
@@ -39,7 +43,7 @@ where
         Self {
             bits: bits
                 .try_into()
-                .expect(&format!("bytes {} fits into Bits of len {}", bytes, N)),
+                .unwrap_or_else(|_| panic!("bytes {} fits into Bits of len {}", bytes, N)),
             _phantom: std::marker::PhantomData,
         }
     }
@@ -66,6 +70,7 @@ where
         &self.bits
     }
 
+    // bit order reversal, see `BitsExt::invert`
     pub fn invert(&self) -> Self {
         let mut inverted = self.clone();
         inverted.bits.reverse();
@@ -101,26 +106,16 @@ pub fn compose_bits(bitvec: &[bool]) -> u8 {
 #[cfg(test)]
 mod test_bits {
     use super::*;
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    fn rand(modulus: u128) -> u8 {
-        (SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-            % modulus) as u8
-    }
-
-    fn generate_random_u8() -> u8 {
-        rand(256)
-    }
+    use crate::rand::Rng;
 
-    fn generate_random_u16() -> u16 {
-        rand(65536).into()
-    }
-
-    fn generate_random_u32() -> u32 {
-        rand(4294967296).into()
+    #[test]
+    fn test_invert() {
+        let two = |n: u8| Bits::<2, u8>::from(n);
+        for (n, inverted) in [(0b00, 0b00), (0b01, 0b10), (0b10, 0b01), (0b11, 0b11)] {
+            assert_eq!(two(n).invert().to_inner(), inverted);
+            assert_eq!(two(n).invert().invert(), two(n));
+        }
+        assert_eq!(Bits::<4, u8>::from(0b0001).invert().to_inner(), 0b1000);
     }
 
     #[test]
@@ -134,8 +129,9 @@ mod test_bits {
         let inner = bits.to_inner();
         assert_eq!(inner, invariant);
 
+        let mut rng = Rng::from_env();
         for _ in 0..100 {
-            let random = generate_random_u8();
+            let random = rng.rand_u64(1 << 8) as u8;
             let bits = Bits::<8, u8>::from(random);
             let inner = bits.to_inner();
             assert_eq!(inner, random);
@@ -156,8 +152,9 @@ mod test_bits {
         let inner = bits.to_inner();
         assert_eq!(inner, invariant);
 
+        let mut rng = Rng::from_env();
         for _ in 0..100 {
-            let random = generate_random_u16();
+            let random = rng.rand_u64(1 << 16) as u16;
             let bits = Bits::<16, u16>::from(random);
             let inner = bits.to_inner();
             assert_eq!(inner, random);
@@ -179,8 +176,9 @@ mod test_bits {
         let inner = bits.to_inner();
         assert_eq!(inner, invariant);
 
+        let mut rng = Rng::from_env();
         for _ in 0..100 {
-            let random = generate_random_u32();
+            let random = rng.rand_u64(1 << 32) as u32;
             let bits = Bits::<32, u32>::from(random);
             let inner = bits.to_inner();
             assert_eq!(inner, random);
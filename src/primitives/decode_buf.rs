@@ -0,0 +1,140 @@
+use bytes::{Buf, Bytes};
+
+use crate::{packet::error::ProtocolError, result::QuicheResult, SmallBytes};
+
+// what the decoders read from.  a `Vec` is drained as it's read, so payloads get copied out of it.
+// a `Bytes` (e.g. a whole received datagram) is split instead, so payloads share its allocation
+pub trait DecodeBuf {
+    fn as_slice(&self) -> &[u8];
+
+    // running out partway through a field is FRAME_ENCODING_ERROR in a frame (RFC 9000 section
+    // 12.4).  header decoders only drop the packet, the error itself doesn't matter to them
+    fn take_u8(&mut self) -> QuicheResult<u8>;
+
+    // for small fixed size fields (cids, tokens, challenges) that end up owned anyway
+    fn take_vec(&mut self, len: usize) -> Vec<u8>;
+
+    // the next `len` bytes of frame payload
+    fn take_payload(&mut self, len: usize) -> SmallBytes;
+
+    // everything that's left, for payloads that run to the end of the packet
+    fn take_rest(&mut self) -> SmallBytes;
+
+    fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+}
+
+impl DecodeBuf for Vec<u8> {
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn take_u8(&mut self) -> QuicheResult<u8> {
+        if self.is_empty() {
+            return Err(ProtocolError::FrameEncodingError.into());
+        }
+        Ok(self.remove(0))
+    }
+
+    fn take_vec(&mut self, len: usize) -> Vec<u8> {
+        self.drain(..len).collect()
+    }
+
+    fn take_payload(&mut self, len: usize) -> SmallBytes {
+        SmallBytes::drain_from(self, len)
+    }
+
+    fn take_rest(&mut self) -> SmallBytes {
+        std::mem::take(self).into()
+    }
+}
+
+impl DecodeBuf for Bytes {
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn take_u8(&mut self) -> QuicheResult<u8> {
+        if self.is_empty() {
+            return Err(ProtocolError::FrameEncodingError.into());
+        }
+        Ok(self.get_u8())
+    }
+
+    fn take_vec(&mut self, len: usize) -> Vec<u8> {
+        self.split_to(len).to_vec()
+    }
+
+    fn take_payload(&mut self, len: usize) -> SmallBytes {
+        SmallBytes::Shared(self.split_to(len))
+    }
+
+    fn take_rest(&mut self) -> SmallBytes {
+        SmallBytes::Shared(std::mem::take(self))
+    }
+}
+
+// a borrowed datagram, read without copying anything but the frame payloads taken out of it
+impl DecodeBuf for &[u8] {
+    fn as_slice(&self) -> &[u8] {
+        self
+    }
+
+    fn take_u8(&mut self) -> QuicheResult<u8> {
+        let (first, rest) = self
+            .split_first()
+            .ok_or(ProtocolError::FrameEncodingError)?;
+        *self = rest;
+        Ok(*first)
+    }
+
+    fn take_vec(&mut self, len: usize) -> Vec<u8> {
+        let (taken, rest) = self.split_at(len);
+        *self = rest;
+        taken.to_vec()
+    }
+
+    fn take_payload(&mut self, len: usize) -> SmallBytes {
+        let (taken, rest) = self.split_at(len);
+        *self = rest;
+        SmallBytes::from_slice(taken)
+    }
+
+    fn take_rest(&mut self) -> SmallBytes {
+        SmallBytes::from_slice(std::mem::take(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_buf() {
+        let datagram = Bytes::from((0..64).collect::<Vec<u8>>());
+        let mut bytes = datagram.clone();
+        let mut vec = datagram.to_vec();
+        let mut slice = &datagram[..];
+
+        assert_eq!(bytes.take_u8().unwrap(), vec.take_u8().unwrap());
+        assert_eq!(slice.take_u8().unwrap(), 0);
+        assert_eq!(bytes.take_vec(3), vec.take_vec(3));
+        assert_eq!(slice.take_vec(3), vec![1, 2, 3]);
+        assert_eq!(slice.take_payload(20), datagram[4..24]);
+
+        let (shared, copied) = (bytes.take_payload(20), vec.take_payload(20));
+        assert_eq!(shared, copied);
+        // the payload points into the datagram, nothing was copied
+        assert!(shared.is_shared());
+        assert_eq!(shared.as_ptr(), datagram[4..].as_ptr());
+
+        assert_eq!(slice.take_rest(), datagram[24..]);
+        assert_eq!(bytes.take_rest(), vec.take_rest());
+        assert!(DecodeBuf::is_empty(&bytes) && DecodeBuf::is_empty(&vec));
+        assert!(DecodeBuf::is_empty(&slice));
+
+        // reading past the end is an error, not a panic
+        assert!(bytes.take_u8().is_err() && vec.take_u8().is_err() && slice.take_u8().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+// where an encoder writes to, the other half of `DecodeBuf`.  a Vec grows as it goes, a
+// `SliceWriter` writes into a buffer that's already there, e.g. a pooled datagram
+pub trait EncodeBuf {
+    fn put_u8(&mut self, byte: u8);
+
+    fn put_slice(&mut self, bytes: &[u8]);
+}
+
+impl EncodeBuf for Vec<u8> {
+    fn put_u8(&mut self, byte: u8) {
+        self.push(byte);
+    }
+
+    fn put_slice(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+// writes nothing, only counts.  encoding into one is how an encoded size is worked out, so the
+// size can never disagree with what actually gets written
+#[derive(Debug, Default)]
+pub struct LenCounter(usize);
+
+impl LenCounter {
+    pub fn len(&self) -> usize {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl EncodeBuf for LenCounter {
+    fn put_u8(&mut self, _: u8) {
+        self.0 += 1;
+    }
+
+    fn put_slice(&mut self, bytes: &[u8]) {
+        self.0 += bytes.len();
+    }
+}
+
+// writes into a fixed slice.  anything that doesn't fit is left out and marks the writer
+// overflowed, so an encoder can write a whole packet and have it checked once at the end
+#[derive(Debug)]
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    // bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+}
+
+impl EncodeBuf for SliceWriter<'_> {
+    fn put_u8(&mut self, byte: u8) {
+        self.put_slice(&[byte]);
+    }
+
+    fn put_slice(&mut self, bytes: &[u8]) {
+        if self.overflowed || bytes.len() > self.remaining() {
+            self.overflowed = true;
+            return;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
@@ -1,7 +1,13 @@
-pub mod bits;
+pub(crate) mod bits;
+pub mod decode_buf;
+pub mod encode_buf;
 pub mod rand;
+pub mod small_bytes;
 pub mod varint;
 
-pub use bits::*;
+pub use bits::BitsExt;
+pub use decode_buf::*;
+pub use encode_buf::*;
 pub use rand::*;
+pub use small_bytes::*;
 pub use varint::*;
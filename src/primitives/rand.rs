@@ -1,7 +1,20 @@
-use std::cell::RefCell;
+use std::{
+    cell::RefCell,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// set this to replay a failing randomized test with the seed it printed
+pub const SEED_ENV_VAR: &str = "MINI_QUICHE_SEED";
 
 thread_local! {
-    static RNG: RefCell<u64> = RefCell::new(0x123456789ABCDEF);
+    static RNG: RefCell<u64> = const { RefCell::new(0x123456789ABCDEF) };
+}
+
+#[inline(always)]
+fn step(state: u64) -> u64 {
+    state
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407)
 }
 
 pub fn rand(modulus: u128) -> u8 {
@@ -11,9 +24,115 @@ pub fn rand(modulus: u128) -> u8 {
 
     RNG.with(|rng| {
         let mut state = rng.borrow_mut();
-        *state = state
-            .wrapping_mul(6364136223846793005)
-            .wrapping_add(1442695040888963407);
+        *state = step(*state);
         (((*state >> 32) as u128) % modulus) as u8
     })
 }
+
+// bytes from the OS's generator, for anything a peer mustn't be able to guess or link: keys,
+// tokens, nonces, path challenges.  `rand` above starts from the same seed in every process, so
+// it's only for tests and grease
+pub fn fill_secure(buf: &mut [u8]) {
+    // there's nothing sensible to fall back to, an OS without a working generator is unusable
+    getrandom::fill(buf).expect("fill_secure: the OS random number generator failed");
+}
+
+pub fn secure_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    fill_secure(&mut bytes);
+    bytes
+}
+
+pub fn secure_u64() -> u64 {
+    u64::from_be_bytes(secure_bytes())
+}
+
+// same generator as `rand`, but with an explicit seed so randomized tests can be replayed.
+// if the thread panics while an `Rng` is alive (i.e. an assert failed), the seed is printed on drop.
+pub struct Rng {
+    seed: u64,
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, state: seed }
+    }
+
+    // uses `MINI_QUICHE_SEED` if it's set, otherwise a fresh seed from the clock
+    pub fn from_env() -> Self {
+        let seed = std::env::var(SEED_ENV_VAR)
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as u64
+            });
+        Self::new(seed)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = step(self.state);
+        // the low bits of an lcg are weak, so stitch two high halves together
+        let high = self.state >> 32;
+        self.state = step(self.state);
+        (high << 32) | (self.state >> 32)
+    }
+
+    // drop-in replacement for `rand`, including its truncation to a u8
+    pub fn rand(&mut self, modulus: u128) -> u8 {
+        if modulus == 0 {
+            return 0;
+        }
+        self.state = step(self.state);
+        (((self.state >> 32) as u128) % modulus) as u8
+    }
+
+    pub fn rand_u64(&mut self, modulus: u128) -> u64 {
+        if modulus == 0 {
+            return 0;
+        }
+        (self.next_u64() as u128 % modulus) as u64
+    }
+}
+
+impl Drop for Rng {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            eprintln!(
+                "randomized test failed with seed {}, rerun with {}={}",
+                self.seed, SEED_ENV_VAR, self.seed
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rng_replays_from_seed() {
+        let mut seeded = Rng::from_env();
+        let mut replayed = Rng::new(seeded.seed());
+        for _ in 0..1_000 {
+            assert_eq!(seeded.next_u64(), replayed.next_u64());
+            assert_eq!(seeded.rand(256), replayed.rand(256));
+        }
+    }
+
+    #[test]
+    fn test_secure() {
+        // 2^-128 odds of a false failure
+        assert_ne!(secure_bytes::<16>(), secure_bytes::<16>());
+        let mut buf = [0; 64];
+        fill_secure(&mut buf);
+        assert!(buf.iter().any(|&byte| byte != 0));
+    }
+}
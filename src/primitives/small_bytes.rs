@@ -0,0 +1,209 @@
+use std::{
+    fmt,
+    ops::{Deref, Range},
+};
+
+use bytes::Bytes;
+
+// most frames carry a handful of bytes (tokens, small crypto / stream chunks),
+// so up to this many bytes are stored inline instead of on the heap
+pub const INLINE_CAPACITY: usize = 32;
+
+// byte storage for frame payloads.  small payloads live inline, anything larger goes to the heap like a `Vec`.
+// payloads decoded out of a `Bytes` datagram share its allocation instead
+#[derive(Clone)]
+pub enum SmallBytes {
+    Inline {
+        len: u8,
+        data: [u8; INLINE_CAPACITY],
+    },
+    Heap(Vec<u8>),
+    Shared(Bytes),
+}
+
+impl SmallBytes {
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Self::Inline {
+            len: 0,
+            data: [0; INLINE_CAPACITY],
+        }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        if bytes.len() <= INLINE_CAPACITY {
+            let mut data = [0; INLINE_CAPACITY];
+            data[..bytes.len()].copy_from_slice(bytes);
+            Self::Inline {
+                len: bytes.len() as u8,
+                data,
+            }
+        } else {
+            Self::Heap(bytes.to_vec())
+        }
+    }
+
+    // moves the first `len` bytes out of `bytes`, without an intermediate allocation for small payloads
+    pub fn drain_from(bytes: &mut Vec<u8>, len: usize) -> Self {
+        let small_bytes = Self::from_slice(&bytes[..len]);
+        bytes.drain(..len);
+        small_bytes
+    }
+
+    #[inline(always)]
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Self::Inline { len, data } => &data[..*len as usize],
+            Self::Heap(bytes) => bytes,
+            Self::Shared(bytes) => bytes,
+        }
+    }
+
+    // a sub-range of the payload.  shared payloads stay shared, the rest are copied
+    pub fn slice(&self, range: Range<usize>) -> Self {
+        match self {
+            Self::Shared(bytes) => Self::Shared(bytes.slice(range)),
+            _ => Self::from_slice(&self.as_slice()[range]),
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, Self::Inline { .. })
+    }
+
+    pub fn is_shared(&self) -> bool {
+        matches!(self, Self::Shared(_))
+    }
+}
+
+impl Default for SmallBytes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for SmallBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl AsRef<[u8]> for SmallBytes {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl From<&[u8]> for SmallBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self::from_slice(bytes)
+    }
+}
+
+impl From<Vec<u8>> for SmallBytes {
+    // a vec that's already been allocated is kept, re-packing it inline wouldn't save anything
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::Heap(bytes)
+    }
+}
+
+impl From<Bytes> for SmallBytes {
+    fn from(bytes: Bytes) -> Self {
+        Self::Shared(bytes)
+    }
+}
+
+impl From<SmallBytes> for Vec<u8> {
+    fn from(bytes: SmallBytes) -> Self {
+        match bytes {
+            SmallBytes::Heap(bytes) => bytes,
+            inline => inline.to_vec(),
+        }
+    }
+}
+
+impl FromIterator<u8> for SmallBytes {
+    fn from_iter<I: IntoIterator<Item = u8>>(iter: I) -> Self {
+        let mut inline = [0; INLINE_CAPACITY];
+        let mut len = 0;
+        let mut iter = iter.into_iter();
+        for byte in iter.by_ref() {
+            if len == INLINE_CAPACITY {
+                let mut heap = inline.to_vec();
+                heap.push(byte);
+                heap.extend(iter);
+                return Self::Heap(heap);
+            }
+            inline[len] = byte;
+            len += 1;
+        }
+        Self::Inline {
+            len: len as u8,
+            data: inline,
+        }
+    }
+}
+
+// equality is by content, an inline and a heap payload holding the same bytes are equal
+impl PartialEq for SmallBytes {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for SmallBytes {}
+
+impl PartialEq<Vec<u8>> for SmallBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl PartialEq<[u8]> for SmallBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_slice() == other
+    }
+}
+
+impl fmt::Debug for SmallBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_small_bytes() {
+        for len in [0, 1, INLINE_CAPACITY - 1, INLINE_CAPACITY] {
+            let bytes = (0..len as u8).collect::<Vec<u8>>();
+            let small = SmallBytes::from_slice(&bytes);
+            assert!(small.is_inline());
+            assert_eq!(small, bytes);
+            assert!(bytes.iter().copied().collect::<SmallBytes>().is_inline());
+        }
+
+        let bytes = (0..INLINE_CAPACITY as u8 + 1).collect::<Vec<u8>>();
+        let small = SmallBytes::from_slice(&bytes);
+        assert!(!small.is_inline());
+        assert_eq!(small, bytes);
+        let collected = bytes.iter().copied().collect::<SmallBytes>();
+        assert!(!collected.is_inline());
+        assert_eq!(collected, small);
+
+        // inline and heap storage of the same bytes compare equal
+        assert_eq!(
+            SmallBytes::from(vec![1, 2, 3]),
+            SmallBytes::from_slice(&[1, 2, 3])
+        );
+
+        let mut buf = vec![1, 2, 3, 4, 5];
+        let drained = SmallBytes::drain_from(&mut buf, 3);
+        assert_eq!(drained, vec![1, 2, 3]);
+        assert_eq!(buf, vec![4, 5]);
+    }
+}
@@ -1,11 +1,15 @@
-use crate::result::{QuicheError, QuicheResult};
+use crate::{
+    consts::MAX_VARINT,
+    result::{require, QuicheError, QuicheResult},
+    DecodeBuf, EncodeBuf,
+};
 
 // heavily inspired by quinn
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct VarInt(pub(crate) u64);
 
 impl VarInt {
-    pub const MAX: Self = Self((1 << 62) - 1);
+    pub const MAX: Self = Self(MAX_VARINT);
 
     #[inline(always)]
     pub const fn new_u32(value: u32) -> Self {
@@ -25,6 +29,8 @@ impl VarInt {
         }
     }
 
+    /// # Safety
+    /// `value` MUST NOT exceed `VarInt::MAX`, otherwise `encode` silently drops the top bits
     pub unsafe fn new_unchecked(value: u64) -> Self {
         Self(value)
     }
@@ -34,14 +40,11 @@ impl VarInt {
         self.0
     }
 
-    // this is horrible and i know it is, but it seems to be fine
+    // anything past 2^32 - 1 doesn't fit on 32-bit targets
     #[inline(always)]
-    pub fn usize(self) -> usize {
-        let value = self.to_inner();
-        if value > usize::MAX as u64 {
-            panic!("Value {} is too large to fit into a usize", value);
-        }
-        value as usize
+    pub fn usize(self) -> QuicheResult<usize> {
+        usize::try_from(self.0)
+            .map_err(|_| QuicheError(format!("VarInt::usize: {} doesn't fit in a usize", self.0)))
     }
 
     pub fn size(self) -> usize {
@@ -58,6 +61,17 @@ impl VarInt {
 
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.size());
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    pub fn encode_to<B: EncodeBuf>(&self, buf: &mut B) {
+        // only `new_unchecked` can get past this, and the top bits would be lost to the prefix
+        debug_assert!(
+            self.0 <= Self::MAX.0,
+            "VarInt::encode: {} doesn't fit in 62 bits",
+            self.0
+        );
         let value = self.0;
         let size = self.size();
 
@@ -68,61 +82,80 @@ impl VarInt {
             8 => 0b11,
             _ => unreachable!(),
         };
-        buf.push((prefix << 6 | (value >> (8 * (size - 1)) & 0x3F)) as u8);
+        buf.put_u8((prefix << 6 | (value >> (8 * (size - 1)) & 0x3F)) as u8);
 
         for i in (0..size - 1).rev() {
-            buf.push(((value >> (8 * i)) & 0xFF) as u8);
+            buf.put_u8(((value >> (8 * i)) & 0xFF) as u8);
         }
-
-        buf
     }
 
-    pub fn decode(bytes: &mut Vec<u8>) -> QuicheResult<Self> {
-        if bytes.is_empty() {
-            return Ok(Self::new_u32(0));
-        }
-        let first_byte = bytes.remove(0);
+    pub fn decode<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Self> {
+        let first_byte = bytes.take_u8()?;
         let disc = (first_byte & 0b11_000000) >> 6;
         let mut val = (first_byte & 0b00_111111) as u64;
 
         for _ in 0..2u64.pow(disc as u32) - 1 {
             val <<= 8;
-            val |= bytes.remove(0) as u64;
+            val |= bytes.take_u8()? as u64;
         }
 
         Self::new_u64(val)
     }
 
+    // like `decode`, but a value that could have been encoded in fewer bytes is an error.  RFC 9000
+    // only asks for that of frame types (section 12.4), anywhere else any length goes
+    pub fn decode_strict<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<Self> {
+        let (value, len) = Self::decode_with_len(bytes)?;
+        require(
+            len == value.size(),
+            &format!(
+                "VarInt::decode_strict: {} in {} bytes rather than {}",
+                value.0,
+                len,
+                value.size()
+            ),
+        )?;
+        Ok(value)
+    }
+
+    // the value, and whether it was in its shortest encoding.  for callers that get to decide
+    // what a longer one means, e.g. under `DecodeMode::Lenient`
+    pub fn decode_minimal<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<(Self, bool)> {
+        let (value, len) = Self::decode_with_len(bytes)?;
+        Ok((value, len == value.size()))
+    }
+
+    // unlike `decode`, never reads past the end of `bytes`
+    fn decode_with_len<B: DecodeBuf>(bytes: &mut B) -> QuicheResult<(Self, usize)> {
+        let Some(&first_byte) = bytes.as_slice().first() else {
+            return Err(QuicheError("VarInt::decode: empty input".to_string()));
+        };
+        let len = 1 << (first_byte >> 6);
+        require(bytes.as_slice().len() >= len, "VarInt::decode: truncated")?;
+        Ok((Self::decode(bytes)?, len))
+    }
+
+    // the arithmetic is checked both ways, below zero and past `VarInt::MAX` are errors
     pub fn sub(&self, other: &Self) -> QuicheResult<Self> {
-        Ok(self
-            .0
-            .checked_sub(other.0)
-            .and_then(|v| Some(Self::new_u64(v).expect("new u64")))
-            .expect("and then"))
+        self.subn(other.0)
     }
 
     pub fn subn(&self, n: u64) -> QuicheResult<Self> {
-        Ok(self
-            .0
+        self.0
             .checked_sub(n)
-            .and_then(|v| Some(Self::new_u64(v).expect("new u64")))
-            .expect("and then"))
+            .ok_or_else(|| QuicheError("VarInt::sub: underflow".to_string()))
+            .and_then(Self::new_u64)
     }
 
     pub fn add(&self, other: &Self) -> QuicheResult<Self> {
-        Ok(self
-            .0
-            .checked_add(other.0)
-            .and_then(|v| Some(Self::new_u64(v).unwrap()))
-            .unwrap())
+        self.addn(other.0)
     }
 
     pub fn addn(&self, n: u64) -> QuicheResult<Self> {
-        Ok(self
-            .0
+        self.0
             .checked_add(n)
-            .and_then(|v| Some(Self::new_u64(v).unwrap()))
-            .unwrap())
+            .ok_or_else(|| QuicheError("VarInt::add: overflow".to_string()))
+            .and_then(Self::new_u64)
     }
 
     pub fn ltn(&self, n: u64) -> bool {
@@ -143,15 +176,7 @@ impl Default for VarInt {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::time::{SystemTime, UNIX_EPOCH};
-
-    pub fn rand_u64(modulus: u128) -> u64 {
-        (SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_nanos()
-            % modulus) as u64
-    }
+    use crate::{rand::Rng, testing::Iterations};
 
     #[test]
     fn test_varint() {
@@ -196,13 +221,77 @@ mod test {
         assert_eq!(varint_large, large_decoded);
     }
 
+    #[test]
+    fn test_decode_strict() {
+        // 37 in each of the four lengths
+        let encodings: [&[u8]; 4] = [
+            &[0x25],
+            &[0x40, 0x25],
+            &[0x80, 0, 0, 0x25],
+            &[0xc0, 0, 0, 0, 0, 0, 0, 0x25],
+        ];
+        for (index, encoding) in encodings.into_iter().enumerate() {
+            let (value, minimal) = VarInt::decode_minimal(&mut encoding.to_vec()).unwrap();
+            assert_eq!(value, VarInt::new_u32(37));
+            assert_eq!(minimal, index == 0);
+            assert_eq!(
+                VarInt::decode_strict(&mut encoding.to_vec()).is_ok(),
+                index == 0
+            );
+        }
+        // 64 needs two
+        assert!(VarInt::decode_strict(&mut vec![0x40, 0x40]).is_ok());
+        assert!(VarInt::decode_strict(&mut vec![0x80, 0, 0]).is_err());
+        assert!(VarInt::decode_strict(&mut Vec::new()).is_err());
+    }
+
     #[test]
     fn test_cast() {
-        let num_casts = 1_000_000;
-        for _ in 0..num_casts {
-            let varint = VarInt::new_u64(rand_u64(u64::MAX as u128 + 1)).unwrap();
-            let casted: usize = varint.usize();
+        let mut rng = Rng::from_env();
+        for _ in 0..Iterations::from_env().varints {
+            let varint = VarInt::new_u64(rng.rand_u64(VarInt::MAX.to_inner() as u128 + 1)).unwrap();
+            let casted: usize = varint.usize().unwrap();
             assert_eq!(varint.to_inner(), casted as u64);
         }
     }
+
+    #[test]
+    fn test_boundaries() {
+        // the largest value of each length, and the one after it
+        let boundaries = [((1 << 6) - 1, 1), ((1 << 14) - 1, 2), ((1 << 30) - 1, 4)];
+        for (largest, size) in boundaries {
+            for (value, size) in [(largest, size), (largest + 1, size * 2)] {
+                let varint = VarInt::new_u64(value).unwrap();
+                assert_eq!(varint.size(), size);
+                let mut encoded = varint.encode();
+                assert_eq!(encoded.len(), size);
+                assert_eq!(VarInt::decode_strict(&mut encoded).unwrap(), varint);
+            }
+        }
+
+        let mut encoded = VarInt::MAX.encode();
+        assert_eq!(encoded, vec![0xff; 8]);
+        assert_eq!(VarInt::decode(&mut encoded).unwrap(), VarInt::MAX);
+        assert!(VarInt::new_u64(VarInt::MAX.to_inner() + 1).is_err());
+        assert!(VarInt::new_u64(u64::MAX).is_err());
+
+        // checked arithmetic stays in 0..2^62
+        assert_eq!(VarInt::MAX.subn(1).unwrap().addn(1).unwrap(), VarInt::MAX);
+        assert!(VarInt::MAX.addn(1).is_err());
+        assert!(VarInt::MAX.add(&VarInt::MAX).is_err());
+        assert!(VarInt::zero().subn(1).is_err());
+        assert_eq!(
+            VarInt::MAX.usize().is_ok(),
+            usize::BITS >= 64,
+            "only 64-bit targets fit every varint"
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "doesn't fit in 62 bits")]
+    fn test_encode_past_max() {
+        let varint = unsafe { VarInt::new_unchecked(VarInt::MAX.to_inner() + 1) };
+        varint.encode();
+    }
 }
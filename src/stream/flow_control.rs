@@ -0,0 +1,433 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    packet::error::ProtocolError,
+    result::{QuicheError, QuicheResult},
+};
+
+// bounds for one receive window.  it starts at `initial` and autotuning keeps it within `min..=max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowConfig {
+    pub initial: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowControlConfig {
+    // per stream, advertised as initial_max_stream_data_* and in MAX_STREAM_DATA
+    pub stream: WindowConfig,
+    // across all streams, advertised as initial_max_data and in MAX_DATA
+    pub connection: WindowConfig,
+    // how many of each type of stream can be open, advertised as initial_max_streams_*.  a stream
+    // id past them is a STREAM_LIMIT_ERROR, so they also bound how many windows are kept
+    pub max_streams_bidi: u64,
+    pub max_streams_uni: u64,
+}
+
+impl Default for FlowControlConfig {
+    fn default() -> Self {
+        Self {
+            stream: WindowConfig {
+                initial: 256 * 1024,
+                min: 64 * 1024,
+                max: 16 * 1024 * 1024,
+            },
+            connection: WindowConfig {
+                initial: 1024 * 1024,
+                min: 256 * 1024,
+                max: 24 * 1024 * 1024,
+            },
+            max_streams_bidi: 100,
+            max_streams_uni: 100,
+        }
+    }
+}
+
+// the receive side of flow control for a stream or a whole connection.  rather than a fixed window,
+// it grows when the application reads through it faster than a couple of round trips, since that
+// means the window (not the application) is what's limiting throughput.  on a high
+// bandwidth-delay path a fixed initial_max_data would cap the connection well below the link
+#[derive(Debug, Clone)]
+pub struct RecvWindow {
+    config: WindowConfig,
+    window: u64,
+    // the limit the peer has been given
+    max_data: u64,
+    // one past the highest byte received
+    received: u64,
+    // bytes the application has read
+    consumed: u64,
+    // when `max_data` was last raised
+    last_update: Option<Instant>,
+}
+
+impl RecvWindow {
+    pub fn new(config: WindowConfig) -> Self {
+        let window = config.initial.clamp(config.min, config.max);
+        Self {
+            config,
+            window,
+            max_data: window,
+            received: 0,
+            consumed: 0,
+            last_update: None,
+        }
+    }
+
+    // picks back up from a snapshot: the limit the peer was given and how much of it it's used.
+    // everything received counts as read, the application that hadn't read it is gone
+    pub fn restore(config: WindowConfig, max_data: u64, received: u64) -> Self {
+        Self {
+            max_data,
+            received,
+            consumed: received,
+            ..Self::new(config)
+        }
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    pub fn max_data(&self) -> u64 {
+        self.max_data
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    // the peer sent data up to `offset`.  going past the advertised limit is a FLOW_CONTROL_ERROR
+    pub fn on_received(&mut self, offset: u64) -> QuicheResult<()> {
+        if offset > self.max_data {
+            return Err(ProtocolError::FlowControlError.into());
+        }
+        self.received = self.received.max(offset);
+        Ok(())
+    }
+
+    pub fn on_consumed(&mut self, len: u64) {
+        self.consumed = (self.consumed + len).min(self.received);
+    }
+
+    // a new limit is worth sending once half the window has been used up
+    pub fn should_update(&self) -> bool {
+        self.max_data - self.consumed < self.window / 2
+    }
+
+    // raises the limit, returning it if it should go out in a MAX_DATA / MAX_STREAM_DATA frame.
+    // if the last raise was less than two round trips ago the window doubles first
+    pub fn update(&mut self, now: Instant, rtt: Duration) -> Option<u64> {
+        if !self.should_update() {
+            return None;
+        }
+        if self
+            .last_update
+            .is_some_and(|last_update| now.duration_since(last_update) < rtt * 2)
+        {
+            self.window = (self.window * 2).min(self.config.max);
+        }
+        self.last_update = Some(now);
+        // a limit can never be taken back (RFC 9000 section 4.1)
+        self.max_data = self.max_data.max(self.consumed + self.window);
+        Some(self.max_data)
+    }
+
+    // lets a connection window keep up with the stream windows inside it
+    pub fn ensure_at_least(&mut self, window: u64) {
+        self.window = self.window.max(window.min(self.config.max));
+    }
+}
+
+// the send side: how much the peer lets us send, from its transport parameters and
+// MAX_DATA / MAX_STREAM_DATA frames
+#[derive(Debug, Clone, Default)]
+pub struct SendWindow {
+    // the highest limit the peer has given
+    max_data: u64,
+    // one past the highest byte we've sent
+    sent: u64,
+}
+
+impl SendWindow {
+    pub fn new(max_data: u64) -> Self {
+        Self { max_data, sent: 0 }
+    }
+
+    // picks back up from a snapshot, with `sent` of `max_data` already used
+    pub fn restore(max_data: u64, sent: u64) -> Self {
+        Self { max_data, sent }
+    }
+
+    pub fn max_data(&self) -> u64 {
+        self.max_data
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    // returns whether the limit went up.  limit frames can arrive out of order, so one that's
+    // lower than what we already have is stale and ignored rather than treated as an error
+    pub fn on_max_data(&mut self, max_data: u64) -> bool {
+        if max_data <= self.max_data {
+            return false;
+        }
+        self.max_data = max_data;
+        true
+    }
+
+    // how much more can be sent right now
+    pub fn available(&self) -> u64 {
+        self.max_data.saturating_sub(self.sent)
+    }
+
+    // we sent data up to `offset`, anything past the limit is a bug on our side
+    pub fn on_sent(&mut self, offset: u64) -> QuicheResult<()> {
+        if offset > self.max_data {
+            return Err(QuicheError(format!(
+                "SendWindow::on_sent: sent up to {} past the peer's limit of {}",
+                offset, self.max_data
+            )));
+        }
+        self.sent = self.sent.max(offset);
+        Ok(())
+    }
+}
+
+// flow control for a connection: the connection-wide windows, and one pair per stream
+#[derive(Debug, Clone)]
+pub struct FlowControl {
+    config: FlowControlConfig,
+    recv: RecvWindow,
+    send: SendWindow,
+    streams: HashMap<u64, StreamWindows>,
+    // the peer's initial_max_stream_data, for streams it hasn't sent MAX_STREAM_DATA for
+    peer_initial_stream_max_data: u64,
+}
+
+#[derive(Debug, Clone)]
+struct StreamWindows {
+    recv: RecvWindow,
+    send: SendWindow,
+}
+
+impl FlowControl {
+    // `peer_max_data` / `peer_stream_max_data` are the peer's initial_max_data and initial_max_stream_data
+    pub fn new(config: FlowControlConfig, peer_max_data: u64, peer_stream_max_data: u64) -> Self {
+        Self {
+            config,
+            recv: RecvWindow::new(config.connection),
+            send: SendWindow::new(peer_max_data),
+            streams: HashMap::new(),
+            peer_initial_stream_max_data: peer_stream_max_data,
+        }
+    }
+
+    // whether `stream_id` is within `config`'s stream limits for its type
+    fn within_stream_limits(&self, stream_id: u64) -> bool {
+        let max_streams = match stream_id & 0x2 {
+            0 => self.config.max_streams_bidi,
+            _ => self.config.max_streams_uni,
+        };
+        stream_id >> 2 < max_streams
+    }
+
+    fn stream(&mut self, stream_id: u64) -> &mut StreamWindows {
+        let (config, peer_max_data) = (self.config.stream, self.peer_initial_stream_max_data);
+        self.streams
+            .entry(stream_id)
+            .or_insert_with(|| StreamWindows {
+                recv: RecvWindow::new(config),
+                send: SendWindow::new(peer_max_data),
+            })
+    }
+
+    pub fn config(&self) -> &FlowControlConfig {
+        &self.config
+    }
+
+    // what each stream's receive window starts at, advertised as initial_max_stream_data_*
+    pub fn initial_stream_max_data(&self) -> u64 {
+        RecvWindow::new(self.config.stream).max_data()
+    }
+
+    // the peer's transport parameters arrived: its initial_max_data, and the
+    // initial_max_stream_data that applies to the streams we send on
+    pub fn on_peer_transport_parameters(&mut self, max_data: u64, stream_max_data: u64) {
+        self.send.on_max_data(max_data);
+        self.peer_initial_stream_max_data = stream_max_data;
+    }
+
+    // picks the connection-wide windows back up from a snapshot, see `RecvWindow::restore`
+    pub fn restore(
+        config: FlowControlConfig,
+        recv: RecvWindow,
+        send: SendWindow,
+        peer_stream_max_data: u64,
+    ) -> Self {
+        Self {
+            recv,
+            send,
+            ..Self::new(config, 0, peer_stream_max_data)
+        }
+    }
+
+    pub fn recv_window(&self) -> &RecvWindow {
+        &self.recv
+    }
+
+    pub fn send_window(&self) -> &SendWindow {
+        &self.send
+    }
+
+    pub fn stream_recv_window(&self, stream_id: u64) -> Option<&RecvWindow> {
+        self.streams.get(&stream_id).map(|stream| &stream.recv)
+    }
+
+    pub fn stream_send_window(&self, stream_id: u64) -> Option<&SendWindow> {
+        self.streams.get(&stream_id).map(|stream| &stream.send)
+    }
+
+    // MAX_DATA, returns whether it raised the limit
+    pub fn on_max_data(&mut self, max_data: u64) -> bool {
+        self.send.on_max_data(max_data)
+    }
+
+    // `len` new bytes of STREAM data went out, on any stream
+    pub fn on_sent(&mut self, len: u64) -> QuicheResult<()> {
+        self.send.on_sent(self.send.sent() + len)
+    }
+
+    // MAX_STREAM_DATA, returns whether it raised the limit.  one for a stream past the limits
+    // can't be for a stream that exists, so it isn't given a window
+    pub fn on_max_stream_data(&mut self, stream_id: u64, max_data: u64) -> bool {
+        self.within_stream_limits(stream_id) && self.stream(stream_id).send.on_max_data(max_data)
+    }
+
+    // STREAM data reaching `offset` arrived.  it's charged to the stream, and whatever it adds past
+    // the stream's highest offset so far is charged to the connection.  going over either limit
+    // we've advertised is a FLOW_CONTROL_ERROR, and neither window moves.  a stream past the
+    // stream limits is a STREAM_LIMIT_ERROR
+    pub fn on_stream_data(&mut self, stream_id: u64, offset: u64) -> QuicheResult<()> {
+        if !self.within_stream_limits(stream_id) {
+            return Err(ProtocolError::StreamLimitError.into());
+        }
+        let received = self
+            .streams
+            .get(&stream_id)
+            .map_or(0, |stream| stream.recv.received);
+        let added = offset.saturating_sub(received);
+        if self.recv.received + added > self.recv.max_data {
+            return Err(ProtocolError::FlowControlError.into());
+        }
+        self.stream(stream_id).recv.on_received(offset)?;
+        self.recv.on_received(self.recv.received + added)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_autotune() {
+        let config = WindowConfig {
+            initial: 100,
+            min: 50,
+            max: 300,
+        };
+        let rtt = Duration::from_millis(50);
+        let now = Instant::now();
+        let mut window = RecvWindow::new(config);
+
+        assert!(window.on_received(101).is_err());
+        window.on_received(100).unwrap();
+        window.on_consumed(40);
+        assert_eq!(window.update(now, rtt), None);
+
+        // first update, nothing to compare against so the window stays put
+        window.on_consumed(20);
+        assert_eq!(window.update(now, rtt), Some(160));
+
+        // read through again within two rtts, so it doubles
+        window.on_received(160).unwrap();
+        window.on_consumed(100);
+        assert_eq!(window.update(now + rtt, rtt), Some(360));
+        assert_eq!(window.window(), 200);
+
+        // and is clamped to the max
+        window.on_received(360).unwrap();
+        window.on_consumed(200);
+        assert_eq!(window.update(now + rtt * 2, rtt), Some(660));
+        assert_eq!(window.window(), 300);
+
+        // a slow reader leaves it where it is
+        window.on_received(660).unwrap();
+        window.on_consumed(300);
+        assert_eq!(window.update(now + rtt * 10, rtt), Some(960));
+        assert_eq!(window.window(), 300);
+    }
+
+    #[test]
+    fn test_reordered_limits() {
+        let mut send = SendWindow::new(1_000);
+        assert!(send.on_max_data(3_000));
+        // a MAX_DATA that was overtaken by a later one
+        assert!(!send.on_max_data(2_000));
+        assert_eq!(send.max_data(), 3_000);
+        send.on_sent(2_500).unwrap();
+        assert_eq!(send.available(), 500);
+        assert!(send.on_sent(3_001).is_err());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let config = FlowControlConfig {
+            stream: WindowConfig {
+                initial: 100,
+                min: 100,
+                max: 100,
+            },
+            connection: WindowConfig {
+                initial: 150,
+                min: 150,
+                max: 150,
+            },
+            max_streams_bidi: 3,
+            max_streams_uni: 1,
+        };
+        let mut flow_control = FlowControl::new(config, 1_000, 500);
+
+        assert!(flow_control.on_max_stream_data(0, 800));
+        assert!(!flow_control.on_max_stream_data(0, 600));
+        assert_eq!(flow_control.stream_send_window(0).unwrap().max_data(), 800);
+        assert!(flow_control.stream_send_window(4).is_none());
+        assert!(!flow_control.on_max_data(900));
+
+        // retransmitted / reordered data below the highest offset isn't charged twice
+        flow_control.on_stream_data(0, 80).unwrap();
+        flow_control.on_stream_data(0, 40).unwrap();
+        flow_control.on_stream_data(4, 60).unwrap();
+        assert_eq!(flow_control.recv_window().received, 140);
+        // over the stream's limit
+        assert!(flow_control.on_stream_data(4, 101).is_err());
+        // within the stream's limit but over the connection's, which leaves the stream as it was
+        assert!(flow_control.on_stream_data(8, 20).is_err());
+        assert!(flow_control.stream_recv_window(8).is_none());
+        flow_control.on_stream_data(0, 90).unwrap();
+        assert!(flow_control.on_stream_data(0, 100).is_err());
+        assert_eq!(flow_control.stream_recv_window(0).unwrap().received, 90);
+        assert_eq!(flow_control.recv_window().received, 150);
+
+        // past the stream limits nothing is kept, whatever the frame
+        assert!(flow_control.on_stream_data(12, 0).is_err());
+        assert!(flow_control.on_stream_data(6, 0).is_err());
+        assert!(!flow_control.on_max_stream_data(12, 100));
+        assert!(flow_control.stream_send_window(12).is_none());
+    }
+}
@@ -0,0 +1,9 @@
+pub mod flow_control;
+pub mod recv_stream;
+pub mod send_stream;
+pub mod shared;
+
+pub use flow_control::*;
+pub use recv_stream::*;
+pub use send_stream::*;
+pub use shared::*;
@@ -0,0 +1,361 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{
+    packet::error::ProtocolError,
+    result::{QuicheError, QuicheResult},
+    SmallBytes,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RecvStreamStats {
+    // STREAM frame payload, repeats included
+    pub bytes_received: u64,
+    // the part of that we already had
+    pub duplicate_bytes: u64,
+    // frames that arrived ahead of a hole, i.e. after something before them was lost or reordered
+    pub gaps: u64,
+    // from the stream being opened to its first byte arriving
+    pub time_to_first_byte: Option<Duration>,
+    // from the FIN arriving to the last of the data before it, how long the stream's end waited on
+    // retransmissions.  zero if nothing was missing
+    pub fin_latency: Option<Duration>,
+}
+
+// the receiving half of a stream.  STREAM frames can arrive in any order, overlap, or repeat, so
+// data is held by offset until it's read.  it can be read in order (`read`), or as it arrives
+// (`read_unordered`) for applications that can use out-of-order data without waiting for the gaps
+#[derive(Debug)]
+pub struct RecvStream {
+    // data that's arrived but hasn't been read, by offset.  chunks never overlap
+    chunks: BTreeMap<u64, SmallBytes>,
+    // every range received so far (read or not) as start -> end, merged, so repeats are dropped
+    received: BTreeMap<u64, u64>,
+    // how far `read` has got
+    read_offset: u64,
+    final_size: Option<u64>,
+    // once data has been read out of order, ordered reads can't be trusted to be contiguous
+    unordered: bool,
+    // a task waiting in `poll_read`, woken when there's something for it
+    reader: Option<Waker>,
+    stats: RecvStreamStats,
+    opened_at: Instant,
+    // when the FIN arrived, until everything before it has too
+    fin_at: Option<Instant>,
+}
+
+impl Default for RecvStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecvStream {
+    pub fn new() -> Self {
+        Self {
+            chunks: BTreeMap::new(),
+            received: BTreeMap::new(),
+            read_offset: 0,
+            final_size: None,
+            unordered: false,
+            reader: None,
+            stats: RecvStreamStats::default(),
+            opened_at: Instant::now(),
+            fin_at: None,
+        }
+    }
+
+    pub fn stats(&self) -> RecvStreamStats {
+        self.stats
+    }
+
+    // the payload of a STREAM frame for this stream
+    pub fn on_data(&mut self, offset: u64, data: SmallBytes, fin: bool) -> QuicheResult<()> {
+        let end = offset + data.len() as u64;
+        let largest = self.largest_received();
+        match self.final_size {
+            // nothing can go past the final size, and it can't change once known (RFC 9000 section 4.5)
+            Some(final_size) if end > final_size || (fin && end != final_size) => {
+                return Err(ProtocolError::FinalSizeError.into());
+            }
+            None if fin && end < largest => return Err(ProtocolError::FinalSizeError.into()),
+            None if fin => self.final_size = Some(end),
+            _ => {}
+        }
+
+        let now = Instant::now();
+        self.stats.bytes_received += data.len() as u64;
+        if !data.is_empty() && self.stats.time_to_first_byte.is_none() {
+            self.stats.time_to_first_byte = Some(now - self.opened_at);
+        }
+        if offset > self.contiguous() {
+            self.stats.gaps += 1;
+        }
+        if fin && self.fin_at.is_none() {
+            self.fin_at = Some(now);
+        }
+        let before = self.received_len();
+
+        // keep only the parts that haven't been seen before
+        let mut cursor = offset;
+        let overlapping = self
+            .received
+            .range(..end)
+            .filter(|(_, range_end)| **range_end > offset)
+            .map(|(start, end)| (*start, *end))
+            .collect::<Vec<_>>();
+        for (start, range_end) in overlapping {
+            if start > cursor {
+                self.insert_chunk(offset, &data, cursor..start);
+            }
+            cursor = cursor.max(range_end);
+        }
+        if cursor < end {
+            self.insert_chunk(offset, &data, cursor..end);
+        }
+
+        // merge the new range with any it overlaps or touches
+        let touching = self
+            .received
+            .range(..=end)
+            .filter(|(_, range_end)| **range_end >= offset)
+            .map(|(start, end)| (*start, *end))
+            .collect::<Vec<_>>();
+        let (mut start, mut range_end) = (offset, end);
+        for (touching_start, touching_end) in touching {
+            self.received.remove(&touching_start);
+            start = start.min(touching_start);
+            range_end = range_end.max(touching_end);
+        }
+        if start < range_end {
+            self.received.insert(start, range_end);
+        }
+        self.stats.duplicate_bytes += data.len() as u64 - (self.received_len() - before);
+        if let (Some(fin_at), Some(final_size)) = (self.fin_at, self.final_size) {
+            if self.stats.fin_latency.is_none() && self.contiguous() == final_size {
+                self.stats.fin_latency = Some(now - fin_at);
+            }
+        }
+
+        if self.readable() > 0 || self.is_finished() {
+            if let Some(waker) = self.reader.take() {
+                waker.wake();
+            }
+        }
+        Ok(())
+    }
+
+    // how far the stream has arrived without holes
+    fn contiguous(&self) -> u64 {
+        self.received.get(&0).copied().unwrap_or(0)
+    }
+
+    fn received_len(&self) -> u64 {
+        self.received.iter().map(|(start, end)| end - start).sum()
+    }
+
+    fn insert_chunk(&mut self, offset: u64, data: &SmallBytes, range: std::ops::Range<u64>) {
+        let chunk = data.slice((range.start - offset) as usize..(range.end - offset) as usize);
+        self.chunks.insert(range.start, chunk);
+    }
+
+    // copies contiguous data into `buf`, returns how much.  0 means nothing is readable yet,
+    // check `is_finished` to tell that apart from the end of the stream
+    pub fn read(&mut self, buf: &mut [u8]) -> QuicheResult<usize> {
+        if self.unordered {
+            return Err(QuicheError(
+                "RecvStream::read: stream has already been read out of order".to_string(),
+            ));
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            let Some(entry) = self.chunks.first_entry() else {
+                break;
+            };
+            // a gap, wait for it to be filled
+            if *entry.key() != self.read_offset {
+                break;
+            }
+            let chunk = entry.remove();
+            let n = chunk.len().min(buf.len() - read);
+            buf[read..read + n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks
+                    .insert(self.read_offset + n as u64, chunk.slice(n..chunk.len()));
+            }
+            read += n;
+            self.read_offset += n as u64;
+        }
+        Ok(read)
+    }
+
+    // like `read`, but registers the task to be woken by the next STREAM frame that makes
+    // something readable.  Ready(0) is the end of the stream
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<QuicheResult<usize>> {
+        let read = self.read(buf)?;
+        if read == 0 && !buf.is_empty() && !self.is_finished() {
+            self.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(read))
+    }
+
+    // the lowest-offset chunk that's arrived, wherever it is in the stream.  nothing is
+    // handed out twice, but after this `read` can't be used on the stream anymore
+    pub fn read_unordered(&mut self) -> Option<(u64, SmallBytes)> {
+        self.unordered = true;
+        self.chunks.pop_first()
+    }
+
+    // bytes that can be read in order right now
+    pub fn readable(&self) -> usize {
+        let mut offset = self.read_offset;
+        for (start, chunk) in self.chunks.iter() {
+            if *start != offset {
+                break;
+            }
+            offset += chunk.len() as u64;
+        }
+        (offset - self.read_offset) as usize
+    }
+
+    // one past the highest byte received, what flow control is charged for
+    pub fn largest_received(&self) -> u64 {
+        self.received.values().next_back().copied().unwrap_or(0)
+    }
+
+    pub fn final_size(&self) -> Option<u64> {
+        self.final_size
+    }
+
+    // everything up to the final size has been received and read
+    pub fn is_finished(&self) -> bool {
+        let Some(final_size) = self.final_size else {
+            return false;
+        };
+        let all_received = final_size == 0 || self.received.get(&0) == Some(&final_size);
+        all_received && self.chunks.is_empty()
+    }
+}
+
+impl AsyncRead for RecvStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let read = match RecvStream::poll_read(self.get_mut(), cx, buf.initialize_unfilled()) {
+            Poll::Ready(result) => result.map_err(|e| io::Error::other(e.0))?,
+            Poll::Pending => return Poll::Pending,
+        };
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data(bytes: &[u8]) -> SmallBytes {
+        SmallBytes::from_slice(bytes)
+    }
+
+    #[test]
+    fn test_ordered_read() {
+        let mut stream = RecvStream::new();
+        stream.on_data(4, data(b"4567"), false).unwrap();
+        stream.on_data(8, data(b"89"), true).unwrap();
+
+        let mut buf = [0; 16];
+        // blocked on the gap at the front
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        // overlaps what's already there on both sides
+        stream.on_data(0, data(b"012345"), false).unwrap();
+        assert_eq!(stream.readable(), 10);
+        assert_eq!(stream.read(&mut buf[..3]).unwrap(), 3);
+        assert_eq!(stream.read(&mut buf[3..]).unwrap(), 7);
+        assert_eq!(&buf[..10], b"0123456789");
+        assert!(stream.is_finished());
+
+        // a repeat of old data changes nothing, moving the final size is an error
+        stream.on_data(2, data(b"23"), false).unwrap();
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.on_data(8, data(b"89a"), true).is_err());
+        assert!(stream.on_data(0, data(b"01"), true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_wakes() {
+        use tokio::io::AsyncReadExt;
+
+        let mut stream = RecvStream::new();
+        let mut buf = [0; 8];
+        // nothing to read, so the read parks instead of returning 0
+        let pending = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            AsyncReadExt::read(&mut stream, &mut buf),
+        )
+        .await;
+        assert!(pending.is_err());
+
+        stream.on_data(0, data(b"abc"), false).unwrap();
+        // the frame that made data readable woke the parked reader
+        assert!(stream.reader.is_none());
+        assert_eq!(AsyncReadExt::read(&mut stream, &mut buf).await.unwrap(), 3);
+        stream.on_data(3, data(b""), true).unwrap();
+        // the end of the stream
+        assert_eq!(AsyncReadExt::read(&mut stream, &mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_recv_stats() {
+        let mut stream = RecvStream::new();
+        assert_eq!(stream.stats().time_to_first_byte, None);
+        // the end arrives first, ahead of a hole
+        stream.on_data(4, data(b"efgh"), true).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        stream.on_data(0, data(b"abcdef"), false).unwrap();
+        stream.on_data(0, data(b"ab"), false).unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(stats.bytes_received, 12);
+        assert_eq!(stats.duplicate_bytes, 4);
+        assert_eq!(stats.gaps, 1);
+        assert!(stats.time_to_first_byte.is_some());
+        // the FIN waited on the start of the stream
+        assert!(stats.fin_latency.unwrap() >= Duration::from_millis(5));
+
+        // nothing missing, nothing to wait for
+        let mut stream = RecvStream::new();
+        stream.on_data(0, data(b"ab"), true).unwrap();
+        assert_eq!(stream.stats().fin_latency, Some(Duration::ZERO));
+        assert_eq!(stream.stats().gaps, 0);
+    }
+
+    #[test]
+    fn test_unordered_read() {
+        let mut stream = RecvStream::new();
+        stream.on_data(6, data(b"ghi"), true).unwrap();
+        stream.on_data(0, data(b"abc"), false).unwrap();
+
+        assert_eq!(stream.read_unordered(), Some((0, data(b"abc"))));
+        assert_eq!(stream.read_unordered(), Some((6, data(b"ghi"))));
+        assert_eq!(stream.read_unordered(), None);
+        assert!(stream.read(&mut [0; 4]).is_err());
+
+        // only the missing middle comes out of a frame that covers everything
+        stream.on_data(0, data(b"abcdefghi"), true).unwrap();
+        assert_eq!(stream.read_unordered(), Some((3, data(b"def"))));
+        assert!(stream.is_finished());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::io::AsyncWrite;
+
+use crate::{
+    result::{QuicheError, QuicheResult},
+    SmallBytes,
+};
+
+use super::SendWindow;
+
+// how much written data a stream holds before it's been put in packets.  when the congestion
+// window (or pacer) stops the connection from sending, this fills up and writes stop being accepted
+pub const DEFAULT_SEND_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendStreamStats {
+    // accepted from the application
+    pub bytes_written: u64,
+    // put in STREAM frames for the first time
+    pub bytes_sent: u64,
+    // put in STREAM frames again after being lost
+    pub bytes_retransmitted: u64,
+    pub bytes_acked: u64,
+    // from `finish` to the peer acknowledging the FIN, None until it has
+    pub fin_latency: Option<Duration>,
+}
+
+// the sending half of a stream.  writes are accepted up to the peer's MAX_STREAM_DATA and the
+// send buffer, past that `poll_write` returns Pending and the writer is woken once MAX_STREAM_DATA
+// raises the limit or the connection takes data out of the buffer.
+// written data is kept as refcounted chunks from the write until it's acknowledged.  a slice
+// write is copied once, into its own chunk, `write_bytes` isn't copied at all: frames refer to
+// the caller's buffer, except one that spans two chunks, which is gathered into a copy
+#[derive(Debug)]
+pub struct SendStream {
+    // written but not handed to a packet yet, starting at `sent`
+    buffered: VecDeque<Bytes>,
+    // the total length of `buffered`
+    buffered_len: usize,
+    // the offset of the first byte in `buffered`
+    sent: u64,
+    window: SendWindow,
+    buffer_size: usize,
+    fin: bool,
+    fin_sent: bool,
+    writer: Option<Waker>,
+    // sent and not acknowledged yet, by offset: the frame's data and whether it carried the FIN
+    in_flight: BTreeMap<u64, (SmallBytes, bool)>,
+    // in frames that were lost, waiting to be sent again ahead of new data
+    lost: BTreeMap<u64, (SmallBytes, bool)>,
+    stats: SendStreamStats,
+    finished_at: Option<Instant>,
+}
+
+impl SendStream {
+    // `max_stream_data` is the peer's initial limit for this stream
+    pub fn new(max_stream_data: u64) -> Self {
+        Self {
+            buffered: VecDeque::new(),
+            buffered_len: 0,
+            sent: 0,
+            window: SendWindow::new(max_stream_data),
+            buffer_size: DEFAULT_SEND_BUFFER_SIZE,
+            fin: false,
+            fin_sent: false,
+            writer: None,
+            in_flight: BTreeMap::new(),
+            lost: BTreeMap::new(),
+            stats: SendStreamStats::default(),
+            finished_at: None,
+        }
+    }
+
+    pub fn stats(&self) -> SendStreamStats {
+        self.stats
+    }
+
+    pub fn set_buffer_size(&mut self, buffer_size: usize) {
+        self.buffer_size = buffer_size;
+        self.wake_writer();
+    }
+
+    // one past the last byte written
+    pub fn offset(&self) -> u64 {
+        self.sent + self.buffered_len as u64
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffered_len
+    }
+
+    // how much a write would accept right now
+    pub fn writable(&self) -> usize {
+        let allowed = self.window.max_data() - self.offset();
+        (allowed as usize).min(self.buffer_size.saturating_sub(self.buffered_len))
+    }
+
+    // the limit we're stuck at, for STREAM_DATA_BLOCKED.  None unless flow control is what's
+    // stopping writes
+    pub fn blocked(&self) -> Option<u64> {
+        (self.offset() == self.window.max_data()).then_some(self.window.max_data())
+    }
+
+    // accepts as much of `buf` as fits, or registers the task to be woken once more does
+    pub fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<QuicheResult<usize>> {
+        match self.write(buf) {
+            Ok(0) if !buf.is_empty() => {
+                self.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            written => Poll::Ready(written),
+        }
+    }
+
+    // accepts as much of `buf` as fits without waiting, for a caller that isn't a task
+    pub fn write(&mut self, buf: &[u8]) -> QuicheResult<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    // `write` for several buffers at once, copied into a single chunk
+    pub fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> QuicheResult<usize> {
+        self.check_writable("SendStream::write_vectored")?;
+        let mut n = self.writable();
+        let mut chunk = Vec::with_capacity(n.min(bufs.iter().map(|buf| buf.len()).sum()));
+        for buf in bufs {
+            let take = n.min(buf.len());
+            chunk.extend_from_slice(&buf[..take]);
+            n -= take;
+        }
+        let written = chunk.len();
+        self.push(Bytes::from(chunk));
+        Ok(written)
+    }
+
+    // queues as much of `bytes` as fits, by reference.  what was taken is split off the front of
+    // `bytes`, what's left is for the next call
+    pub fn write_bytes(&mut self, bytes: &mut Bytes) -> QuicheResult<usize> {
+        self.check_writable("SendStream::write_bytes")?;
+        let chunk = bytes.split_to(self.writable().min(bytes.len()));
+        let written = chunk.len();
+        self.push(chunk);
+        Ok(written)
+    }
+
+    // `write_bytes`, or registers the task to be woken once more fits
+    pub fn poll_write_bytes(
+        &mut self,
+        cx: &mut Context<'_>,
+        bytes: &mut Bytes,
+    ) -> Poll<QuicheResult<usize>> {
+        match self.write_bytes(bytes) {
+            Ok(0) if !bytes.is_empty() => {
+                self.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            written => Poll::Ready(written),
+        }
+    }
+
+    // `poll_write` for several buffers at once
+    pub fn poll_write_vectored(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<QuicheResult<usize>> {
+        match self.write_vectored(bufs) {
+            Ok(0) if bufs.iter().any(|buf| !buf.is_empty()) => {
+                self.writer = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            written => Poll::Ready(written),
+        }
+    }
+
+    fn check_writable(&self, caller: &str) -> QuicheResult<()> {
+        if self.fin {
+            return Err(QuicheError(format!("{}: stream already finished", caller)));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, chunk: Bytes) {
+        if chunk.is_empty() {
+            return;
+        }
+        self.buffered_len += chunk.len();
+        self.stats.bytes_written += chunk.len() as u64;
+        self.buffered.push_back(chunk);
+    }
+
+    // the next `len` buffered bytes.  a slice of the front chunk if it has them all, otherwise
+    // gathered from as many chunks as it takes
+    fn take_buffered(&mut self, len: usize) -> SmallBytes {
+        self.buffered_len -= len;
+        match self.buffered.front_mut() {
+            Some(front) if front.len() >= len => {
+                let data = front.split_to(len);
+                if front.is_empty() {
+                    self.buffered.pop_front();
+                }
+                SmallBytes::from(data)
+            }
+            _ => {
+                let mut data = Vec::with_capacity(len);
+                while data.len() < len {
+                    let front = self.buffered.front_mut().unwrap();
+                    let take = front.len().min(len - data.len());
+                    data.extend_from_slice(&front.split_to(take));
+                    if front.is_empty() {
+                        self.buffered.pop_front();
+                    }
+                }
+                SmallBytes::from(data)
+            }
+        }
+    }
+
+    // no more data after what's been written
+    pub fn finish(&mut self) {
+        if !self.fin {
+            self.finished_at = Some(Instant::now());
+        }
+        self.fin = true;
+    }
+
+    // whether there's data (or a FIN) waiting to be put in a packet, new or lost
+    pub fn has_pending(&self) -> bool {
+        !self.lost.is_empty() || self.buffered_len > 0 || (self.fin && !self.fin_sent)
+    }
+
+    // MAX_STREAM_DATA for this stream, returns whether it raised the limit
+    pub fn on_max_stream_data(&mut self, max_stream_data: u64) -> bool {
+        let raised = self.window.on_max_data(max_stream_data);
+        if raised {
+            self.wake_writer();
+        }
+        raised
+    }
+
+    // up to `max_len` bytes for a STREAM frame as (offset, data, fin), called by the connection
+    // when congestion control lets it send.  lost data goes first.  the room new data frees wakes
+    // a blocked writer
+    pub fn emit(&mut self, max_len: usize) -> QuicheResult<Option<(u64, SmallBytes, bool)>> {
+        if let Some((offset, (data, fin))) = self.lost.pop_first() {
+            let len = max_len.min(data.len());
+            let rest = data.slice(len..data.len());
+            let data = data.slice(0..len);
+            let whole = rest.is_empty();
+            if !whole {
+                self.lost.insert(offset + len as u64, (rest, fin));
+            }
+            let fin = fin && whole;
+            self.stats.bytes_retransmitted += len as u64;
+            self.in_flight.insert(offset, (data.clone(), fin));
+            return Ok(Some((offset, data, fin)));
+        }
+        if !self.has_pending() {
+            return Ok(None);
+        }
+        let len = max_len.min(self.buffered_len);
+        let data = self.take_buffered(len);
+        let offset = self.sent;
+        self.sent += len as u64;
+        self.window.on_sent(self.sent)?;
+
+        let fin = self.fin && self.buffered_len == 0;
+        if fin {
+            self.fin_sent = true;
+        }
+        if len > 0 {
+            self.wake_writer();
+        }
+        self.stats.bytes_sent += len as u64;
+        self.in_flight.insert(offset, (data.clone(), fin));
+        Ok(Some((offset, data, fin)))
+    }
+
+    // the frame `emit` handed out at `offset` was acknowledged at `now`
+    pub fn on_frame_acked(&mut self, offset: u64, now: Instant) {
+        let Some((data, fin)) = self.in_flight.remove(&offset) else {
+            return;
+        };
+        self.stats.bytes_acked += data.len() as u64;
+        if fin {
+            self.stats.fin_latency = self
+                .finished_at
+                .map(|finished_at| now.saturating_duration_since(finished_at));
+        }
+    }
+
+    // the frame at `offset` was lost, its data is sent again before anything new
+    pub fn on_frame_lost(&mut self, offset: u64) {
+        if let Some(frame) = self.in_flight.remove(&offset) {
+            self.lost.insert(offset, frame);
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl AsyncWrite for SendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        SendStream::poll_write(self.get_mut(), cx, buf).map_err(|e| io::Error::other(e.0))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        SendStream::poll_write_vectored(self.get_mut(), cx, bufs).map_err(|e| io::Error::other(e.0))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    // written data goes out as the connection sends, there's nothing to flush here
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().finish();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::task::Wake;
+
+    // counts how often the task was woken
+    #[derive(Default)]
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_write_backpressure() {
+        let wakes = Arc::new(Wakes::default());
+        let waker = Waker::from(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut stream = SendStream::new(10);
+        stream.set_buffer_size(8);
+        assert!(matches!(
+            stream.poll_write(&mut cx, b"0123456789"),
+            Poll::Ready(Ok(8))
+        ));
+        // the send buffer is full, nothing has gone out to make room
+        assert!(stream.poll_write(&mut cx, b"89").is_pending());
+        assert_eq!(stream.blocked(), None);
+
+        // the connection sends some of it, which wakes the writer
+        let (offset, data, fin) = stream.emit(4).unwrap().unwrap();
+        assert_eq!((offset, data.as_slice(), fin), (0, &b"0123"[..], false));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+
+        // now it's flow control: only 2 more bytes are allowed until MAX_STREAM_DATA
+        assert!(matches!(
+            stream.poll_write(&mut cx, b"89ab"),
+            Poll::Ready(Ok(2))
+        ));
+        assert!(stream.poll_write(&mut cx, b"ab").is_pending());
+        assert_eq!(stream.blocked(), Some(10));
+        // a stale limit changes nothing
+        assert!(!stream.on_max_stream_data(8));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        assert!(stream.on_max_stream_data(20));
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 2);
+
+        stream.emit(usize::MAX).unwrap();
+        assert!(matches!(
+            stream.poll_write(&mut cx, b"ab"),
+            Poll::Ready(Ok(2))
+        ));
+        stream.finish();
+        assert!(matches!(
+            stream.poll_write(&mut cx, b"c"),
+            Poll::Ready(Err(_))
+        ));
+        let (offset, data, fin) = stream.emit(usize::MAX).unwrap().unwrap();
+        assert_eq!((offset, data.as_slice(), fin), (10, &b"ab"[..], true));
+        assert!(stream.emit(usize::MAX).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_retransmit_and_stats() {
+        let waker = Waker::from(Arc::new(Wakes::default()));
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = SendStream::new(100);
+        assert!(matches!(
+            stream.poll_write(&mut cx, b"0123456789"),
+            Poll::Ready(Ok(10))
+        ));
+        stream.finish();
+        assert_eq!(stream.emit(4).unwrap().unwrap().0, 0);
+        assert_eq!(stream.emit(usize::MAX).unwrap().unwrap().0, 4);
+
+        // the first frame made it, the one with the FIN didn't
+        let now = Instant::now();
+        stream.on_frame_acked(0, now);
+        stream.on_frame_lost(4);
+        assert!(stream.has_pending());
+        // it goes out again, in pieces if it has to, and only the last piece has the FIN
+        let (offset, data, fin) = stream.emit(4).unwrap().unwrap();
+        assert_eq!((offset, data.as_slice(), fin), (4, &b"4567"[..], false));
+        let (offset, data, fin) = stream.emit(4).unwrap().unwrap();
+        assert_eq!((offset, data.as_slice(), fin), (8, &b"89"[..], true));
+        assert!(stream.emit(4).unwrap().is_none());
+
+        stream.on_frame_acked(4, now);
+        stream.on_frame_acked(8, now + Duration::from_millis(30));
+        // a repeat ACK changes nothing
+        stream.on_frame_acked(8, now + Duration::from_millis(60));
+        let stats = stream.stats();
+        assert_eq!(
+            (
+                stats.bytes_written,
+                stats.bytes_sent,
+                stats.bytes_retransmitted,
+                stats.bytes_acked
+            ),
+            (10, 10, 6, 10)
+        );
+        assert!(stats.fin_latency.unwrap() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_vectored_and_zero_copy_writes() {
+        let waker = Waker::from(Arc::new(Wakes::default()));
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = SendStream::new(100);
+
+        // vectored writes take what fits, in order
+        let bufs = [
+            IoSlice::new(b"0123"),
+            IoSlice::new(b""),
+            IoSlice::new(b"45"),
+        ];
+        assert_eq!(stream.write_vectored(&bufs).unwrap(), 6);
+
+        // a Bytes write is queued as is, and the frames cut from it point into it
+        let body = Bytes::from((0..100).collect::<Vec<u8>>());
+        let mut rest = body.clone();
+        assert!(matches!(
+            stream.poll_write_bytes(&mut cx, &mut rest),
+            Poll::Ready(Ok(94))
+        ));
+        assert_eq!(rest.len(), 6);
+        assert!(stream.poll_write_bytes(&mut cx, &mut rest).is_pending());
+        assert_eq!(stream.buffered(), 100);
+
+        // spanning both chunks is a copy, inside the second one isn't
+        let (_, data, _) = stream.emit(10).unwrap().unwrap();
+        assert_eq!(data.as_slice(), b"012345\0\x01\x02\x03");
+        assert!(!data.is_shared());
+        let (offset, data, _) = stream.emit(50).unwrap().unwrap();
+        assert_eq!(offset, 10);
+        assert!(data.is_shared());
+        assert_eq!(data.as_ptr(), body[4..].as_ptr());
+
+        // still shared when it's sent again
+        stream.on_frame_lost(10);
+        let (_, data, _) = stream.emit(usize::MAX).unwrap().unwrap();
+        assert_eq!(data.as_ptr(), body[4..].as_ptr());
+        assert_eq!(stream.emit(usize::MAX).unwrap().unwrap().1.len(), 40);
+        assert_eq!((stream.buffered(), stream.stats().bytes_written), (0, 100));
+
+        stream.finish();
+        assert!(stream.write_bytes(&mut rest).is_err());
+        assert!(stream.write_vectored(&bufs).is_err());
+    }
+}
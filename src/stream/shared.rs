@@ -0,0 +1,183 @@
+use std::{
+    future::poll_fn,
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::result::QuicheResult;
+
+use super::{RecvStream, SendStream};
+
+// cloneable handles to the two halves of a stream: the application writes (or reads) through one
+// clone while the connection's task feeds it through another with `lock`.  a parked writer or
+// reader is woken through the stream's waker, so nobody polls.  the lock is never held across an
+// await, a plain mutex is enough
+#[derive(Debug, Clone)]
+pub struct SharedSendStream(Arc<Mutex<SendStream>>);
+
+impl SharedSendStream {
+    pub fn new(stream: SendStream) -> Self {
+        Self(Arc::new(Mutex::new(stream)))
+    }
+
+    // for the connection side: `emit`, `on_max_stream_data` and friends
+    pub fn lock(&self) -> MutexGuard<'_, SendStream> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn finish(&self) {
+        self.lock().finish();
+    }
+
+    // queues all of `bytes` by reference, waiting for room as it has to.  see `SendStream`
+    pub async fn write_bytes(&self, mut bytes: Bytes) -> QuicheResult<()> {
+        while !bytes.is_empty() {
+            poll_fn(|cx| self.lock().poll_write_bytes(cx, &mut bytes)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for SharedSendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.lock()).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SharedRecvStream(Arc<Mutex<RecvStream>>);
+
+impl SharedRecvStream {
+    pub fn new(stream: RecvStream) -> Self {
+        Self(Arc::new(Mutex::new(stream)))
+    }
+
+    // for the connection side: `on_data` as STREAM frames arrive
+    pub fn lock(&self) -> MutexGuard<'_, RecvStream> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl AsyncRead for SharedRecvStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.lock()).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::SmallBytes;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_shared_streams() {
+        // an echo: one task reads what "arrives", another writes it back out
+        let recv = SharedRecvStream::new(RecvStream::new());
+        let send = SharedSendStream::new(SendStream::new(4));
+
+        let echo = tokio::spawn({
+            let mut recv = recv.clone();
+            let mut send = send.clone();
+            async move {
+                let mut buf = [0; 16];
+                loop {
+                    let n = recv.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    send.write_all(&buf[..n]).await.unwrap();
+                }
+                send.shutdown().await.unwrap();
+            }
+        });
+
+        // the connection's side: frames in, frames out
+        recv.lock()
+            .on_data(0, SmallBytes::from_slice(b"hello"), true)
+            .unwrap();
+        let mut echoed = Vec::new();
+        let mut fin = false;
+        while !fin {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let mut stream = send.lock();
+            // the writer is stuck at the 4 byte limit until we raise it
+            if stream.blocked().is_some() {
+                stream.on_max_stream_data(16);
+            }
+            if let Some((offset, data, last)) = stream.emit(usize::MAX).unwrap() {
+                assert_eq!(offset, echoed.len() as u64);
+                echoed.extend_from_slice(&data);
+                fin = last;
+            }
+        }
+        tokio::time::timeout(Duration::from_secs(1), echo)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(echoed, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_write_bytes() {
+        let send = SharedSendStream::new(SendStream::new(4));
+        let body = Bytes::from_static(b"0123456789");
+        let writer = tokio::spawn({
+            let send = send.clone();
+            let body = body.clone();
+            async move { send.write_bytes(body).await }
+        });
+
+        let mut sent = Vec::new();
+        while sent.len() < body.len() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let mut stream = send.lock();
+            if let Some((_, data, _)) = stream.emit(usize::MAX).unwrap() {
+                assert!(data.is_shared());
+                sent.extend_from_slice(&data);
+                let max_data = stream.offset() + 4;
+                stream.on_max_stream_data(max_data);
+            }
+        }
+        tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent, body);
+    }
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    consts::MAX_UDP_PAYLOAD_SIZE,
+    packet::{header::Header, types::ConnectionId},
+    result::{QuicheError, QuicheResult},
+};
+
+// how often the poll thread looks up from the socket to see if it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// how many datagrams wait on a route, or for `accept`, before the rest are dropped.  a reader
+// that falls behind loses datagrams (which quic recovers from) rather than growing memory
+pub const QUEUE_LEN: usize = 1_024;
+
+// a datagram the poll thread picked up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Received {
+    pub from: SocketAddr,
+    pub datagram: Vec<u8>,
+}
+
+type Routes = Arc<Mutex<HashMap<Vec<u8>, SyncSender<Received>>>>;
+
+// a blocking endpoint on a std::net::UdpSocket, for tools and tests that don't want an async
+// runtime.  a poll thread reads the socket and routes each datagram by destination cid to whoever
+// registered the cid; anything for a cid nobody has registered (a new client's Initial, say)
+// waits for `accept`.  the connection state machine itself still lives on tokio, this only
+// covers the i/o side
+pub struct Endpoint {
+    socket: UdpSocket,
+    routes: Routes,
+    unrouted: Receiver<Received>,
+    // datagrams too short to hold a cid, nothing can be done with them
+    malformed: Arc<AtomicU64>,
+    // datagrams dropped because their queue was full
+    dropped: Arc<AtomicU64>,
+    shutdown: Arc<AtomicBool>,
+    poller: Option<JoinHandle<()>>,
+}
+
+impl Endpoint {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> QuicheResult<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let routes = Routes::default();
+        let (unrouted_tx, unrouted) = mpsc::sync_channel(QUEUE_LEN);
+        let malformed = Arc::new(AtomicU64::new(0));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let poller = thread::Builder::new()
+            .name("mini-quiche-poll".to_string())
+            .spawn({
+                let socket = socket.try_clone()?;
+                let routes = routes.clone();
+                let malformed = malformed.clone();
+                let dropped = dropped.clone();
+                let shutdown = shutdown.clone();
+                move || poll(socket, routes, unrouted_tx, malformed, dropped, shutdown)
+            })?;
+
+        Ok(Self {
+            socket,
+            routes,
+            unrouted,
+            malformed,
+            dropped,
+            shutdown,
+            poller: Some(poller),
+        })
+    }
+
+    pub fn local_addr(&self) -> QuicheResult<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // datagrams to `cid` from now on, until it's unregistered or the endpoint is dropped
+    pub fn register(&self, cid: &ConnectionId) -> Receiver<Received> {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        self.routes.lock().unwrap().insert(cid.cid.clone(), tx);
+        rx
+    }
+
+    pub fn unregister(&self, cid: &ConnectionId) {
+        self.routes.lock().unwrap().remove(&cid.cid);
+    }
+
+    // the next datagram for a cid nobody has registered, waiting up to `timeout`
+    pub fn accept(&self, timeout: Duration) -> Option<Received> {
+        self.unrouted.recv_timeout(timeout).ok()
+    }
+
+    pub fn send_to(&self, datagram: &[u8], addr: SocketAddr) -> QuicheResult<usize> {
+        Ok(self.socket.send_to(datagram, addr)?)
+    }
+
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(poller) = self.poller.take() {
+            let _ = poller.join();
+        }
+    }
+}
+
+fn poll(
+    socket: UdpSocket,
+    routes: Routes,
+    unrouted: SyncSender<Received>,
+    malformed: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    shutdown: Arc<AtomicBool>,
+) {
+    let mut buf = vec![0; MAX_UDP_PAYLOAD_SIZE];
+    while !shutdown.load(Ordering::Relaxed) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            // e.g. an icmp error from an earlier send, the socket is still usable
+            Err(_) => continue,
+        };
+        let datagram = buf[..len].to_vec();
+        let Some(dst_cid) = Header::peek_dst_cid(&datagram).map(<[u8]>::to_vec) else {
+            malformed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+
+        let mut routes = routes.lock().unwrap();
+        let received = Received { from, datagram };
+        let received = match routes.get(&dst_cid) {
+            // a receiver that's gone away unregisters itself
+            Some(route) => match route.try_send(received) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Err(TrySendError::Disconnected(received)) => {
+                    routes.remove(&dst_cid);
+                    received
+                }
+            },
+            None => received,
+        };
+        drop(routes);
+        // nobody is accepting any more, but the registered routes still work
+        if let Err(TrySendError::Full(_)) = unrouted.try_send(received) {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// waits for the next datagram on a registered route, for symmetry with `Endpoint::accept`
+pub fn recv_timeout(route: &Receiver<Received>, timeout: Duration) -> QuicheResult<Received> {
+    route.recv_timeout(timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => QuicheError("sync::recv_timeout: timed out".to_string()),
+        RecvTimeoutError::Disconnected => {
+            QuicheError("sync::recv_timeout: endpoint is gone".to_string())
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::{frame::Frame, packet::Packet, PnLen, SingleBit, TwoBits},
+        BitsExt,
+    };
+
+    fn one_rtt(cid: &ConnectionId) -> Vec<u8> {
+        Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(1).unwrap(),
+            cid.clone(),
+            vec![0],
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sync_endpoint() {
+        let endpoint = Endpoint::bind("127.0.0.1:0").unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let known = ConnectionId::new(4, vec![1; 4]);
+        let route = endpoint.register(&known);
+        client.send_to(&one_rtt(&known), addr).unwrap();
+        let received = recv_timeout(&route, Duration::from_secs(5)).unwrap();
+        assert_eq!(received.datagram, one_rtt(&known));
+        assert_eq!(received.from, client.local_addr().unwrap());
+
+        // nobody has this cid, so it's up for `accept`
+        let unknown = ConnectionId::new(4, vec![2; 4]);
+        client.send_to(&one_rtt(&unknown), addr).unwrap();
+        let accepted = endpoint.accept(Duration::from_secs(5)).unwrap();
+        assert_eq!(accepted.datagram, one_rtt(&unknown));
+
+        // and once the route is gone, neither is the known one routed
+        endpoint.unregister(&known);
+        client.send_to(&[0], addr).unwrap();
+        client.send_to(&one_rtt(&known), addr).unwrap();
+        assert!(endpoint.accept(Duration::from_secs(5)).is_some());
+        assert!(recv_timeout(&route, Duration::from_millis(50)).is_err());
+        assert_eq!(endpoint.malformed(), 1);
+
+        endpoint.send_to(b"pong", received.from).unwrap();
+        let mut buf = [0; 16];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&b"pong"[..], addr));
+    }
+
+    #[test]
+    fn test_sync_endpoint_queue_full() {
+        let endpoint = Endpoint::bind("127.0.0.1:0").unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        // nobody reads the route, so once it's full the rest are dropped, not queued
+        let cid = ConnectionId::new(4, vec![1; 4]);
+        let route = endpoint.register(&cid);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while endpoint.dropped() == 0 && std::time::Instant::now() < deadline {
+            for _ in 0..64 {
+                client.send_to(&one_rtt(&cid), addr).unwrap();
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(endpoint.dropped() > 0);
+        // let the poll thread get through what's still on the socket before reading any of it
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(route.try_iter().count(), QUEUE_LEN);
+    }
+}
@@ -0,0 +1,3 @@
+pub mod endpoint;
+
+pub use endpoint::*;
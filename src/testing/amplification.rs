@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+use crate::{
+    connection::AMPLIFICATION_FACTOR,
+    packet::{limits::DecodeLimits, packet::Packet, EncryptionLevel},
+    pcap::CapturedDatagram,
+    result::{QuicheError, QuicheResult},
+};
+
+// what `amplification_audit` saw of the server before the client's address was validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmplificationAudit {
+    pub received: usize,
+    pub sent: usize,
+    // the index of the client datagram that validated its address, None if none did
+    pub validated_at: Option<usize>,
+}
+
+// replays one connection's handshake, e.g. a capture `PcapWriter` wrote, and checks the server
+// never sent more than three times what it had received from the client before the client's
+// address was validated (RFC 9000 section 8.1).  the client is whoever sent the first datagram,
+// and its address counts as validated once one of its datagrams carries a Handshake packet.
+// anything between other addresses is ignored.  the error names the first datagram over the limit
+pub fn amplification_audit(trace: &[CapturedDatagram]) -> QuicheResult<AmplificationAudit> {
+    let mut audit = AmplificationAudit {
+        received: 0,
+        sent: 0,
+        validated_at: None,
+    };
+    let Some(first) = trace.first() else {
+        return Ok(audit);
+    };
+    let (client, server) = (first.src, first.dst);
+
+    for (index, datagram) in trace.iter().enumerate() {
+        if audit.validated_at.is_some() {
+            break;
+        }
+        if is_between(datagram, client, server) {
+            // every byte of the datagram counts, whether or not the server could make sense of it
+            audit.received += datagram.payload.len();
+            if carries_handshake(&datagram.payload) {
+                audit.validated_at = Some(index);
+            }
+        } else if is_between(datagram, server, client) {
+            audit.sent += datagram.payload.len();
+            if audit.sent > audit.received * AMPLIFICATION_FACTOR {
+                return Err(QuicheError(format!(
+                    "datagram {} ({} -> {}): {} bytes sent to an unvalidated client after \
+                     receiving {}",
+                    index, server, client, audit.sent, audit.received
+                )));
+            }
+        }
+    }
+    Ok(audit)
+}
+
+fn is_between(datagram: &CapturedDatagram, src: SocketAddr, dst: SocketAddr) -> bool {
+    datagram.src == src && datagram.dst == dst
+}
+
+fn carries_handshake(datagram: &[u8]) -> bool {
+    Packet::decode_coalesced_with_limits(&mut datagram.to_vec(), &DecodeLimits::lenient())
+        .is_ok_and(|packets| {
+            packets
+                .iter()
+                .any(|packet| packet.encryption_level() == Some(EncryptionLevel::Handshake))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::{
+            frame::Frame, header::LongHeaderExtension, ConnectionId, FourBits, LongPacketType,
+            PacketNumber,
+        },
+        BitsExt, VarInt, MINI_QUICHE_VERSION,
+    };
+
+    #[test]
+    fn test_amplification_audit() {
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let datagram = |src, dst, payload| CapturedDatagram {
+            timestamp: 0,
+            src,
+            dst,
+            payload,
+        };
+        let handshake = Packet::long_header(
+            LongPacketType::handshake(),
+            FourBits::zero(),
+            MINI_QUICHE_VERSION,
+            ConnectionId::new(8, vec![1; 8]),
+            ConnectionId::new(8, vec![2; 8]),
+            LongHeaderExtension::Handshake {
+                length: VarInt::new_u32(2),
+                packet_number: PacketNumber(VarInt::zero()),
+            },
+            vec![Frame::Ping],
+        )
+        .encode()
+        .unwrap();
+
+        // exactly three times, then the client's Handshake lifts the limit
+        let trace = vec![
+            datagram(client, server, vec![0; 1_200]),
+            datagram(server, client, vec![0; 1_200]),
+            datagram(server, client, vec![0; 2_400]),
+            datagram(client, server, handshake.clone()),
+            datagram(server, client, vec![0; 10_000]),
+        ];
+        assert_eq!(
+            amplification_audit(&trace).unwrap(),
+            AmplificationAudit {
+                received: 1_200 + handshake.len(),
+                sent: 3_600,
+                validated_at: Some(3),
+            }
+        );
+
+        // one byte over, before anything validated the client
+        let mut over = trace.clone();
+        over.insert(3, datagram(server, client, vec![0; 1]));
+        let err = amplification_audit(&over).unwrap_err();
+        assert!(err.0.starts_with("datagram 3"), "{}", err);
+
+        // another client's datagrams don't count towards this one's limit
+        let other: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+        let mut unrelated = trace[..3].to_vec();
+        unrelated.insert(1, datagram(other, server, vec![0; 10_000]));
+        unrelated.push(datagram(server, client, vec![0; 1]));
+        assert!(amplification_audit(&unrelated).is_err());
+        assert_eq!(amplification_audit(&[]).unwrap().sent, 0);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    packet::{
+        header::{Header, LongHeader, LongHeaderExtension, ShortHeader},
+        ConnectionId, FourBits, LongPacketType, PacketNumber, PnLen, SingleBit, TwoBits, Version,
+    },
+    rand::Rng,
+    BitsExt, VarInt,
+};
+
+// random headers that are always ones a peer could legitimately send (RFC 9000 section 17):
+// - long headers other than version negotiation carry a version we speak, version negotiation
+//   carries 0 and at least one supported version
+// - the fixed bit is set and the reserved bits are zero once header protection is off
+// - retry leaves its type specific bits zero.  they're unused in v1, drafts had the original dst
+//   cid's length there
+// - the client's first Initial has a dst cid of at least 8 bytes, retry tokens are never empty
+// the same seed gives the same headers, so a failure can be replayed with `Rng::new`
+pub struct PacketGenerator {
+    rng: Rng,
+}
+
+impl PacketGenerator {
+    pub fn new(rng: Rng) -> Self {
+        Self { rng }
+    }
+
+    // seeded from `MINI_QUICHE_SEED`, see `Rng::from_env`
+    pub fn from_env() -> Self {
+        Self::new(Rng::from_env())
+    }
+
+    // for anything else a test wants randomized off the same seed
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn version(&mut self) -> u32 {
+        let index = self.rng.rand(Version::ALL.len() as u128) as usize;
+        Version::ALL[index].to_u32()
+    }
+
+    // `min_len..=20` random bytes
+    pub fn connection_id(&mut self, min_len: u8) -> ConnectionId {
+        let len = min_len + self.rng.rand((21 - min_len) as u128);
+        let cid = (0..len).map(|_| self.rng.rand(256)).collect();
+        ConnectionId::new(len, cid)
+    }
+
+    // any of the long header packet types, equally likely
+    pub fn long_header(&mut self) -> Header {
+        match self.rng.rand(5) {
+            0 => self.initial_header(),
+            1 => self.zero_rtt_header(),
+            2 => self.handshake_header(),
+            3 => self.retry_header(),
+            _ => self.version_negotiation_header(),
+        }
+    }
+
+    pub fn initial_header(&mut self) -> Header {
+        let token = self.bytes(0, 40);
+        let type_specific_bits = self.type_specific_bits();
+        let packet_number = self.packet_number(&type_specific_bits);
+        Header::Initial(LongHeader::initial(
+            self.version(),
+            self.connection_id(8),
+            self.connection_id(0),
+            type_specific_bits,
+            VarInt::new_u32(token.len() as u32),
+            token,
+            self.length(),
+            packet_number,
+        ))
+    }
+
+    pub fn zero_rtt_header(&mut self) -> Header {
+        let type_specific_bits = self.type_specific_bits();
+        let extension = LongHeaderExtension::ZeroRTT {
+            length: self.length(),
+            packet_number: self.packet_number(&type_specific_bits),
+        };
+        self.long(LongPacketType::zero_rtt(), type_specific_bits, extension)
+    }
+
+    pub fn handshake_header(&mut self) -> Header {
+        let type_specific_bits = self.type_specific_bits();
+        let extension = LongHeaderExtension::Handshake {
+            length: self.length(),
+            packet_number: self.packet_number(&type_specific_bits),
+        };
+        self.long(LongPacketType::handshake(), type_specific_bits, extension)
+    }
+
+    pub fn retry_header(&mut self) -> Header {
+        let extension = LongHeaderExtension::Retry {
+            retry_token: self.bytes(1, 40),
+            retry_integrity_tag: std::array::from_fn(|_| self.rng.rand(256)),
+        };
+        Header::Retry(LongHeader::new(
+            LongPacketType::retry(),
+            FourBits::zero(),
+            self.version(),
+            self.connection_id(0),
+            self.connection_id(0),
+            extension,
+        ))
+    }
+
+    pub fn version_negotiation_header(&mut self) -> Header {
+        let count = self.rng.rand(Version::ALL.len() as u128) as usize + 1;
+        let supported_versions = (0..count).map(|_| self.version()).collect();
+        Header::VersionNegotiate(LongHeader::version_negotiate(
+            self.connection_id(0),
+            self.connection_id(0),
+            supported_versions,
+        ))
+    }
+
+    pub fn short_header(&mut self) -> Header {
+        let number_len = PnLen::from_len(self.rng.rand(4) as usize + 1).unwrap();
+        let number = (0..number_len.len()).map(|_| self.rng.rand(256)).collect();
+        Header::Short(ShortHeader::new(
+            SingleBit::from_num(self.rng.rand(2)),
+            TwoBits::zero(),
+            SingleBit::from_num(self.rng.rand(2)),
+            number_len,
+            self.connection_id(0),
+            number,
+        ))
+    }
+
+    // a 0-rtt or handshake header, which only differ in their type
+    fn long(
+        &mut self,
+        long_packet_type: LongPacketType,
+        type_specific_bits: FourBits,
+        extension: LongHeaderExtension,
+    ) -> Header {
+        Header::Long(LongHeader::new(
+            long_packet_type,
+            type_specific_bits,
+            self.version(),
+            self.connection_id(0),
+            self.connection_id(0),
+            extension,
+        ))
+    }
+
+    // reserved bits zero, any packet number length (see `LongHeader::initial`)
+    fn type_specific_bits(&mut self) -> FourBits {
+        FourBits::from_num(self.rng.rand(4) << 2)
+    }
+
+    // small enough that a payload of this length is cheap to generate
+    fn length(&mut self) -> VarInt {
+        VarInt::new_u32(self.rng.rand(39) as u32 + 1)
+    }
+
+    // as many bytes as the length bits in `type_specific_bits` say
+    fn packet_number(&mut self, type_specific_bits: &FourBits) -> PacketNumber {
+        let len = PnLen::from_long_header_bits(type_specific_bits).len();
+        PacketNumber(VarInt(self.rng.rand_u64(1 << (8 * len))))
+    }
+
+    fn bytes(&mut self, min_len: usize, max_len: usize) -> Vec<u8> {
+        let len = min_len + self.rng.rand_u64((max_len - min_len + 1) as u128) as usize;
+        (0..len).map(|_| self.rng.rand(256)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::Iterations;
+
+    #[test]
+    fn test_packet_generator() {
+        let mut generator = PacketGenerator::from_env();
+        for _ in 0..Iterations::from_env().headers {
+            let header = generator.long_header();
+            let mut bytes = header.encode().unwrap();
+            let (Header::Initial(long)
+            | Header::Long(long)
+            | Header::Retry(long)
+            | Header::VersionNegotiate(long)) = &header
+            else {
+                unreachable!()
+            };
+            match &header {
+                Header::VersionNegotiate(_) => assert_eq!(long.version(), 0),
+                _ => assert!(Version::from_u32(long.version()).is_some()),
+            }
+            if let Header::Initial(_) = &header {
+                assert!(long.dst_cid().cid_len >= 8);
+            }
+            assert!(long.dst_cid().cid_len <= 20 && long.src_cid().cid_len <= 20);
+            assert_eq!(Header::decode(&mut bytes), header);
+
+            let header = generator.short_header();
+            let mut bytes = header.encode().unwrap();
+            // fixed bit set, reserved bits zero
+            assert_eq!(bytes[0] & 0b0101_1000, 0b0100_0000);
+            assert_eq!(Header::decode(&mut bytes), header);
+        }
+
+        // replayable
+        let seed = generator.rng().seed();
+        let (mut a, mut b) = (
+            PacketGenerator::new(Rng::new(seed)),
+            PacketGenerator::new(Rng::new(seed)),
+        );
+        assert_eq!(a.long_header(), b.long_header());
+        assert_eq!(a.short_header(), b.short_header());
+    }
+}
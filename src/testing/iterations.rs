@@ -0,0 +1,71 @@
+// set this to `thorough` for the full randomized round trip runs, or to a number to run every one
+// that many times.  anything else, or nothing, is quick mode
+pub const ITERATIONS_ENV_VAR: &str = "MINI_QUICHE_TEST_ITERATIONS";
+
+// how many random values each randomized round trip test goes through.  quick mode is the
+// default so `cargo test` stays fast, a soak run in CI can ask for more without editing tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Iterations {
+    pub frames: usize,
+    pub headers: usize,
+    pub packets: usize,
+    pub varints: usize,
+}
+
+impl Iterations {
+    pub const QUICK: Self = Self {
+        frames: 10_000,
+        headers: 1_000,
+        packets: 1_000,
+        varints: 10_000,
+    };
+
+    pub const THOROUGH: Self = Self {
+        frames: 1_000_000,
+        headers: 100_000,
+        packets: 10_000,
+        varints: 1_000_000,
+    };
+
+    pub fn uniform(iterations: usize) -> Self {
+        Self {
+            frames: iterations,
+            headers: iterations,
+            packets: iterations,
+            varints: iterations,
+        }
+    }
+
+    // from `MINI_QUICHE_TEST_ITERATIONS`
+    pub fn from_env() -> Self {
+        Self::parse(std::env::var(ITERATIONS_ENV_VAR).ok().as_deref())
+    }
+
+    fn parse(setting: Option<&str>) -> Self {
+        match setting.map(str::trim) {
+            Some("thorough") => Self::THOROUGH,
+            Some(setting) => setting.parse().map_or(Self::QUICK, Self::uniform),
+            None => Self::QUICK,
+        }
+    }
+}
+
+impl Default for Iterations {
+    fn default() -> Self {
+        Self::QUICK
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_iterations() {
+        assert_eq!(Iterations::parse(None), Iterations::QUICK);
+        assert_eq!(Iterations::parse(Some("quick")), Iterations::QUICK);
+        assert_eq!(Iterations::parse(Some("thorough")), Iterations::THOROUGH);
+        assert_eq!(Iterations::parse(Some(" 42 ")), Iterations::uniform(42));
+        assert_eq!(Iterations::parse(Some("lots")), Iterations::QUICK);
+    }
+}
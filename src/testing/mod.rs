@@ -0,0 +1,11 @@
+pub mod amplification;
+pub mod generator;
+pub mod iterations;
+pub mod replay;
+#[cfg(any(test, feature = "proptest-support"))]
+pub mod strategy;
+
+pub use amplification::*;
+pub use generator::*;
+pub use iterations::*;
+pub use replay::*;
@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use crate::{
+    packet::{limits::DecodeLimits, packet::Packet},
+    pcap::read_datagrams_from_file,
+    result::{require, QuicheError, QuicheResult},
+};
+
+// replays every datagram in a pcapng capture through `Packet::decode_coalesced` and back through `Datagram::encode`.
+// returns the number of datagrams replayed, or an error describing the first datagram that failed to decode
+// or that re-encoded to different bytes than were captured.
+// there is no packet protection yet, so this only makes sense for plaintext captures (i.e. ones `PcapWriter` wrote)
+pub fn replay_pcap<P: AsRef<Path>>(path: P) -> QuicheResult<usize> {
+    let datagrams = read_datagrams_from_file(path)?;
+    for (index, datagram) in datagrams.iter().enumerate() {
+        replay_datagram(&datagram.payload).map_err(|err| {
+            QuicheError(format!(
+                "datagram {} ({} -> {}): {}",
+                index, datagram.src, datagram.dst, err.0
+            ))
+        })?;
+    }
+    Ok(datagrams.len())
+}
+
+pub fn replay_datagram(datagram: &[u8]) -> QuicheResult<()> {
+    // lenient, captures of other implementations are what this is for
+    let decoded =
+        Packet::decode_coalesced_with_limits(&mut datagram.to_vec(), &DecodeLimits::lenient())?;
+    let mut encoded = Vec::with_capacity(datagram.len());
+    for packet in decoded.iter() {
+        packet.encode_into(&mut encoded)?;
+    }
+    require(
+        encoded == datagram,
+        "re-encoded packet diverges from the captured datagram",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::{frame::Frame, ConnectionId, PacketNumber, PnLen, SingleBit, TwoBits},
+        pcap::PcapWriter,
+        BitsExt, SmallBytes, VarInt,
+    };
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_replay_pcap() {
+        let client: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let server: SocketAddr = "127.0.0.1:443".parse().unwrap();
+        let path =
+            std::env::temp_dir().join(format!("mini-quiche-replay-{}.pcapng", std::process::id()));
+
+        let client_hello = Packet::create_client_hello(
+            ConnectionId::new(8, vec![1; 8]),
+            ConnectionId::new(8, vec![3; 8]),
+            None,
+            Frame::Crypto {
+                offset: VarInt::zero(),
+                crypto_length: VarInt::new_u32(4),
+                crypto_data: SmallBytes::from_slice(&[1, 2, 3, 4]),
+            },
+            PacketNumber(VarInt::zero()),
+        );
+        let short = Packet::short_header(
+            SingleBit::zero(),
+            TwoBits::zero(),
+            SingleBit::zero(),
+            PnLen::from_len(2).unwrap(),
+            ConnectionId::new(8, vec![2; 8]),
+            vec![0, 1],
+            vec![Frame::Ping, Frame::Padding],
+        );
+
+        let mut writer = PcapWriter::create(&path).unwrap();
+        writer
+            .record(client, server, &client_hello.encode().unwrap())
+            .unwrap();
+        writer
+            .record(server, client, &short.encode().unwrap())
+            .unwrap();
+        writer.flush().unwrap();
+        assert_eq!(replay_pcap(&path).unwrap(), 2);
+
+        // an empty datagram can't be a quic packet
+        writer.record(client, server, &[]).unwrap();
+        writer.flush().unwrap();
+        let err = replay_pcap(&path).unwrap_err();
+        assert!(err.0.starts_with("datagram 2"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+use proptest::{collection::vec, prelude::*};
+
+use crate::{
+    packet::{
+        frame::{Frame, StreamType},
+        header::{Header, LongHeader, LongHeaderExtension, ShortHeader},
+        packet::Packet,
+        ConnectionId, FourBits, LongPacketType, PacketNumber, PnLen, SingleBit, TwoBits, Version,
+    },
+    BitsExt, SmallBytes, VarInt,
+};
+
+// proptest strategies generating values that round trip through the codecs.
+// everything here only produces valid values, including the codec's own quirks:
+// - a STREAM frame with a zero length runs to the end of the packet, so it only ever comes last
+// except `adversarial_packet`, which breaks them on purpose for the decoder to turn away
+
+pub fn varint() -> impl Strategy<Value = VarInt> {
+    (0..=VarInt::MAX.to_inner()).prop_map(VarInt)
+}
+
+// varints small enough to be used as lengths / counts without blowing up test sizes
+pub fn small_varint() -> impl Strategy<Value = VarInt> {
+    (0u32..16_384).prop_map(VarInt::new_u32)
+}
+
+pub fn bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=max_len)
+}
+
+pub fn connection_id() -> impl Strategy<Value = ConnectionId> {
+    vec(any::<u8>(), 0..=20).prop_map(|cid| ConnectionId::new(cid.len() as u8, cid))
+}
+
+pub fn stream_type() -> impl Strategy<Value = StreamType> {
+    prop_oneof![
+        Just(StreamType::Bidirectional),
+        Just(StreamType::Unidirectional)
+    ]
+}
+
+// (largest_acknowledged, first_ack_range, ack_ranges) which never produce a negative packet number
+fn ack_ranges() -> impl Strategy<Value = (VarInt, VarInt, Vec<(VarInt, VarInt)>)> {
+    (0u64..100_000, any::<u64>(), vec(any::<(u64, u64)>(), 0..8)).prop_map(
+        |(largest, first_seed, seeds)| {
+            let first_ack_range = first_seed % (largest + 1);
+            let mut next_smallest = largest - first_ack_range;
+            let mut ranges = Vec::with_capacity(seeds.len());
+            for (gap_seed, len_seed) in seeds {
+                if next_smallest < 2 {
+                    break;
+                }
+                let gap = gap_seed % (next_smallest - 1);
+                next_smallest -= gap + 2;
+                let len = len_seed % (next_smallest + 1);
+                next_smallest -= len;
+                ranges.push((VarInt(gap), VarInt(len)));
+            }
+            (VarInt(largest), VarInt(first_ack_range), ranges)
+        },
+    )
+}
+
+fn ack() -> impl Strategy<Value = Frame> {
+    (ack_ranges(), small_varint()).prop_map(
+        |((largest_acknowledged, first_ack_range, ack_ranges), ack_delay)| Frame::Ack {
+            largest_acknowledged,
+            ack_delay,
+            ack_range_count: VarInt::new_u32(ack_ranges.len() as u32),
+            first_ack_range,
+            ack_ranges,
+        },
+    )
+}
+
+fn ack_ecn() -> impl Strategy<Value = Frame> {
+    (ack_ranges(), small_varint(), varint(), varint(), varint()).prop_map(
+        |(
+            (largest_acknowledged, first_ack_range, ack_ranges),
+            ack_delay,
+            ect0_count,
+            ect1_count,
+            ecn_ce_count,
+        )| Frame::AckEcn {
+            largest_acknowledged,
+            ack_delay,
+            ack_range_count: VarInt::new_u32(ack_ranges.len() as u32),
+            first_ack_range,
+            ack_ranges,
+            ect0_count,
+            ect1_count,
+            ecn_ce_count,
+        },
+    )
+}
+
+fn stream(open_ended: bool) -> impl Strategy<Value = Frame> {
+    // a length of zero means "no length", so only open ended frames can be empty
+    let data = vec(any::<u8>(), (!open_ended as usize)..=256);
+    (varint(), small_varint(), any::<bool>(), data).prop_map(
+        move |(stream_id, offset, fin, stream_data)| Frame::Stream {
+            stream_id,
+            offset,
+            length: match open_ended {
+                true => VarInt::zero(),
+                false => VarInt::new_u32(stream_data.len() as u32),
+            },
+            fin: SingleBit::from_num(fin as u8),
+            stream_data: SmallBytes::from_slice(&stream_data),
+        },
+    )
+}
+
+fn connection_close() -> impl Strategy<Value = Frame> {
+    let transport = (prop_oneof![0u64..=0x10, 0x0100u64..=0x01ff], 0u8..=0x1e)
+        .prop_map(|(code, frame_type)| (VarInt(code), Some(frame_type)));
+    let application = prop_oneof![0x11u64..0x0100, 0x0200..=VarInt::MAX.to_inner()]
+        .prop_map(|code| (VarInt(code), None));
+    (prop_oneof![transport, application], vec(any::<u8>(), 0..64)).prop_map(
+        |((error_code, frame_type), reason_phrase)| Frame::ConnectionClose {
+            error_code,
+            frame_type,
+            reason_phrase_length: VarInt::new_u32(reason_phrase.len() as u32),
+            reason_phrase: reason_phrase.into(),
+        },
+    )
+}
+
+// any frame that doesn't run to the end of the packet
+pub fn frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        Just(Frame::Padding),
+        Just(Frame::Ping),
+        ack(),
+        ack_ecn(),
+        (varint(), varint(), varint()).prop_map(
+            |(stream_id, application_protocol_error_code, final_size)| Frame::ResetStream {
+                stream_id,
+                application_protocol_error_code,
+                final_size,
+            }
+        ),
+        (varint(), varint()).prop_map(|(stream_id, application_protocol_error_code)| {
+            Frame::StopSending {
+                stream_id,
+                application_protocol_error_code,
+            }
+        }),
+        (small_varint(), bytes(256)).prop_map(|(offset, crypto_data)| Frame::Crypto {
+            offset,
+            crypto_length: VarInt::new_u32(crypto_data.len() as u32),
+            crypto_data: SmallBytes::from_slice(&crypto_data),
+        }),
+        // tokens MUST NOT be empty
+        vec(any::<u8>(), 1..=128).prop_map(|token| Frame::NewToken {
+            token_length: VarInt::new_u32(token.len() as u32),
+            token: SmallBytes::from_slice(&token),
+        }),
+        stream(false),
+        varint().prop_map(Frame::MaxData),
+        (varint(), varint()).prop_map(|(stream_id, max_stream_data)| Frame::MaxStreamData {
+            stream_id,
+            max_stream_data,
+        }),
+        (stream_type(), varint()).prop_map(|(stream_type, max_streams)| Frame::MaxStreams {
+            stream_type,
+            max_streams,
+        }),
+        varint().prop_map(Frame::DataBlocked),
+        (varint(), varint()).prop_map(|(stream_id, stream_data_limit)| {
+            Frame::StreamDataBlocked {
+                stream_id,
+                stream_data_limit,
+            }
+        }),
+        (stream_type(), varint()).prop_map(|(stream_type, max_streams)| {
+            Frame::StreamsBlocked {
+                stream_type,
+                max_streams,
+            }
+        }),
+        (
+            varint(),
+            any::<u64>(),
+            vec(any::<u8>(), 1..=20),
+            any::<[u8; 16]>()
+        )
+            .prop_map(
+                |(sequence_number, retire_seed, cid, stateless_reset_token)| {
+                    Frame::NewConnectionId {
+                        sequence_number,
+                        retire_prior_to: VarInt(retire_seed % (sequence_number.to_inner() + 1)),
+                        connection_id: ConnectionId::new(cid.len() as u8, cid),
+                        stateless_reset_token,
+                    }
+                }
+            ),
+        varint().prop_map(Frame::RetireConnectionId),
+        any::<[u8; 8]>().prop_map(Frame::PathChallenge),
+        any::<[u8; 8]>().prop_map(Frame::PathResponse),
+        connection_close(),
+        Just(Frame::HandshakeDone),
+    ]
+}
+
+// reserved (greased) versions are refused by the header codec
+pub fn version() -> impl Strategy<Value = u32> {
+    any::<u32>().prop_filter("reserved version", |version| {
+        !Version::is_reserved(*version)
+    })
+}
+
+// the low bytes of `number`, as many as the length bits in `type_specific_bits` say
+fn packet_number(type_specific_bits: &FourBits, number: u32) -> PacketNumber {
+    let len = PnLen::from_long_header_bits(type_specific_bits).len();
+    PacketNumber(VarInt(number as u64 & ((1 << (8 * len)) - 1)))
+}
+
+pub fn long_header() -> impl Strategy<Value = Header> {
+    let initial = (
+        version(),
+        connection_id(),
+        connection_id(),
+        0u8..16,
+        bytes(64),
+        small_varint(),
+        any::<u32>(),
+    )
+        .prop_map(
+            |(version_id, dst_cid, src_cid, type_specific, token, length, number)| {
+                let type_specific_bits = FourBits::from_num(type_specific);
+                let packet_number = packet_number(&type_specific_bits, number);
+                Header::Initial(LongHeader::initial(
+                    version_id,
+                    dst_cid,
+                    src_cid,
+                    type_specific_bits,
+                    VarInt::new_u32(token.len() as u32),
+                    token,
+                    length,
+                    packet_number,
+                ))
+            },
+        );
+    let zero_rtt_or_handshake = (
+        any::<bool>(),
+        version(),
+        connection_id(),
+        connection_id(),
+        0u8..16,
+        small_varint(),
+        any::<u32>(),
+    )
+        .prop_map(
+            |(zero_rtt, version_id, dst_cid, src_cid, type_specific, length, number)| {
+                let type_specific_bits = FourBits::from_num(type_specific);
+                let packet_number = packet_number(&type_specific_bits, number);
+                let (long_packet_type, extension) = match zero_rtt {
+                    true => (
+                        LongPacketType::zero_rtt(),
+                        LongHeaderExtension::ZeroRTT {
+                            length,
+                            packet_number,
+                        },
+                    ),
+                    false => (
+                        LongPacketType::handshake(),
+                        LongHeaderExtension::Handshake {
+                            length,
+                            packet_number,
+                        },
+                    ),
+                };
+                Header::Long(LongHeader::new(
+                    long_packet_type,
+                    type_specific_bits,
+                    version_id,
+                    dst_cid,
+                    src_cid,
+                    extension,
+                ))
+            },
+        );
+    let retry = (
+        version(),
+        connection_id(),
+        connection_id(),
+        0u8..16,
+        bytes(64),
+        any::<[u8; 16]>(),
+    )
+        .prop_map(
+            |(version_id, dst_cid, src_cid, type_specific, retry_token, retry_integrity_tag)| {
+                Header::Retry(LongHeader::new(
+                    LongPacketType::retry(),
+                    FourBits::from_num(type_specific),
+                    version_id,
+                    dst_cid,
+                    src_cid,
+                    LongHeaderExtension::Retry {
+                        retry_token,
+                        retry_integrity_tag,
+                    },
+                ))
+            },
+        );
+    let version_negotiate = (connection_id(), connection_id(), vec(any::<u32>(), 1..8)).prop_map(
+        |(dst_cid, src_cid, supported_versions)| {
+            Header::VersionNegotiate(LongHeader::version_negotiate(
+                dst_cid,
+                src_cid,
+                supported_versions,
+            ))
+        },
+    );
+    prop_oneof![initial, zero_rtt_or_handshake, retry, version_negotiate]
+}
+
+pub fn short_header() -> impl Strategy<Value = Header> {
+    (
+        any::<bool>(),
+        0u8..4,
+        any::<bool>(),
+        connection_id(),
+        vec(any::<u8>(), 4),
+    )
+        .prop_flat_map(|(spin_bit, reserved_bits, key_phase, dst_cid, number)| {
+            (0u8..4).prop_map(move |number_len| {
+                Header::Short(ShortHeader::new(
+                    SingleBit::from_num(spin_bit as u8),
+                    TwoBits::from_num(reserved_bits),
+                    SingleBit::from_num(key_phase as u8),
+                    PnLen::from_len(number_len as usize + 1).unwrap(),
+                    dst_cid.clone(),
+                    number[..number_len as usize + 1].to_vec(),
+                ))
+            })
+        })
+}
+
+pub fn header() -> impl Strategy<Value = Header> {
+    prop_oneof![long_header(), short_header()]
+}
+
+// frames which are allowed in a packet carrying `header`, retry and version negotiation packets
+// don't carry any.  see `FrameKind::permitted`
+fn allowed_in(header: &Header, frame: &Frame) -> bool {
+    header
+        .encryption_level()
+        .is_some_and(|level| frame.kind().is_permitted(level))
+}
+
+pub fn packet() -> impl Strategy<Value = Packet> {
+    header().prop_flat_map(|header| {
+        let frames_header = header.clone();
+        let frames = vec(frame(), 0..8).prop_map(move |frames| {
+            frames
+                .into_iter()
+                .filter(|frame| allowed_in(&frames_header, frame))
+                .collect::<Vec<Frame>>()
+        });
+        let open_ended_stream = match header {
+            Header::Short(_) => proptest::option::of(stream(true)).boxed(),
+            _ => Just(None).boxed(),
+        };
+        (Just(header), frames, open_ended_stream).prop_map(
+            |(header, mut payload, open_ended_stream)| {
+                payload.extend(open_ended_stream);
+                let mut packet = Packet {
+                    header,
+                    payload,
+                    warnings: Vec::new(),
+                };
+                packet.update_length();
+                packet
+            },
+        )
+    })
+}
+
+// how `adversarial_packet` breaks an otherwise valid packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    // cut off after this many bytes, mod the packet's length
+    Truncate(usize),
+    // sets the header's reserved bits, which have to be zero (RFC 9000 section 17.2 / 17.3.1)
+    ReservedBits,
+    // ends the payload with frames whose varints are longer than they have to be
+    NonMinimalVarint,
+    // ends the payload with a CRYPTO frame whose length runs well past the end of the packet
+    OversizedLength,
+    // flips every bit of the byte at this index, mod the packet's length
+    Corrupt(usize),
+}
+
+pub fn mutation() -> impl Strategy<Value = Mutation> {
+    prop_oneof![
+        any::<usize>().prop_map(Mutation::Truncate),
+        Just(Mutation::ReservedBits),
+        Just(Mutation::NonMinimalVarint),
+        Just(Mutation::OversizedLength),
+        any::<usize>().prop_map(Mutation::Corrupt),
+    ]
+}
+
+// a valid packet, how it was broken and the bytes that came out.  a long header's Length still
+// covers whatever was appended, so it's the frames that are wrong rather than the framing
+pub fn adversarial_packet() -> impl Strategy<Value = (Packet, Mutation, Vec<u8>)> {
+    (packet(), mutation()).prop_map(|(packet, mutation)| {
+        let appended: &[u8] = match mutation {
+            // PING with a two byte type, MAX_DATA with an eight byte 5
+            Mutation::NonMinimalVarint => &[0x40, 0x01, 0x10, 0xc0, 0, 0, 0, 0, 0, 0, 0x05],
+            // offset 0, length 2^30 - 1, then three bytes of it
+            Mutation::OversizedLength => &[0x06, 0x00, 0xbf, 0xff, 0xff, 0xff, 1, 2, 3],
+            _ => &[],
+        };
+        let mut broken = packet.clone();
+        if let (Some(length), Header::Initial(header) | Header::Long(header)) =
+            (broken.header.length(), &mut broken.header)
+        {
+            header.set_length(VarInt::new_u32((length + appended.len()) as u32));
+        }
+        let mut bytes = broken.encode().unwrap();
+        bytes.extend_from_slice(appended);
+        match mutation {
+            Mutation::Truncate(at) => bytes.truncate(at % bytes.len()),
+            Mutation::ReservedBits => match packet.header {
+                Header::Initial(_) | Header::Long(_) => bytes[0] |= 0b0000_1100,
+                Header::Short(_) => bytes[0] |= 0b0001_1000,
+                Header::Retry(_) | Header::VersionNegotiate(_) => {}
+            },
+            Mutation::Corrupt(at) => {
+                let at = at % bytes.len();
+                bytes[at] = !bytes[at];
+            }
+            Mutation::NonMinimalVarint | Mutation::OversizedLength => {}
+        }
+        (packet, mutation, bytes)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::packet::limits::DecodeLimits;
+
+    proptest! {
+        #[test]
+        fn test_varint_round_trip(varint in varint()) {
+            prop_assert_eq!(VarInt::decode(&mut varint.encode()).unwrap(), varint);
+        }
+
+        #[test]
+        fn test_varint_minimal(varint in varint()) {
+            let mut encoded = varint.encode();
+            prop_assert_eq!(encoded.len(), varint.size());
+            prop_assert_eq!(VarInt::decode_strict(&mut encoded).unwrap(), varint);
+        }
+
+        #[test]
+        fn test_packet_number_expansion(
+            largest_acked in proptest::option::of(0u64..1 << 40),
+            // any more than 2^31 in flight can't be told apart in 4 bytes
+            in_flight in 1u64..1 << 31,
+            received in any::<u64>(),
+        ) {
+            let packet_number = largest_acked.map_or(in_flight - 1, |acked| acked + in_flight);
+            // the peer has received at least what it acked, and nothing from this packet on
+            let floor = largest_acked.unwrap_or(0);
+            let largest_received = (packet_number > floor)
+                .then(|| floor + received % (packet_number - floor))
+                .filter(|_| largest_acked.is_some() || received % 2 == 0)
+                .or(largest_acked);
+            let len = PnLen::for_packet(packet_number, largest_acked);
+            let truncated = len
+                .truncate(packet_number)
+                .iter()
+                .fold(0, |number, byte| number << 8 | *byte as u64);
+            prop_assert_eq!(len.expand(truncated, largest_received), packet_number);
+        }
+
+        #[test]
+        fn test_frame_round_trip(frame in frame()) {
+            prop_assert_eq!(Frame::decode(&mut frame.encode()).unwrap(), frame);
+        }
+
+        #[test]
+        fn test_header_round_trip(header in header()) {
+            prop_assert_eq!(Header::decode(&mut header.encode().unwrap()), header);
+        }
+
+        #[test]
+        fn test_packet_round_trip(packet in packet()) {
+            // headers here can have their reserved bits set, which only a lenient decode lets through
+            let mut decoded = Packet::decode_with_limits(
+                &mut packet.encode().unwrap(),
+                &DecodeLimits::lenient(),
+            )
+            .unwrap();
+            prop_assert_eq!(
+                Packet::decode(&mut packet.encode().unwrap()).is_ok(),
+                decoded.warnings.is_empty()
+            );
+            decoded.warnings.clear();
+            prop_assert_eq!(decoded, packet);
+        }
+
+        #[test]
+        fn test_adversarial_decode((packet, mutation, bytes) in adversarial_packet()) {
+            // whatever comes in, the decoders turn it away rather than panic
+            let strict = Packet::decode(&mut bytes.clone());
+            let _ = Packet::decode_with_limits(&mut bytes.clone(), &DecodeLimits::lenient());
+            let _ = Packet::decode_coalesced(&mut bytes.clone());
+            // a STREAM frame without a length would take the appended frame as its data
+            let open_ended = matches!(
+                packet.payload.last(),
+                Some(Frame::Stream { length, .. }) if length.to_inner() == 0
+            );
+            match mutation {
+                Mutation::ReservedBits if packet.contains_frames() => prop_assert!(strict.is_err()),
+                Mutation::OversizedLength if packet.contains_frames() && !open_ended => {
+                    prop_assert!(strict.is_err())
+                }
+                _ => {}
+            }
+        }
+    }
+}